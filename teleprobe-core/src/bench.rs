@@ -0,0 +1,56 @@
+//! Benchmark results firmware records with `teleprobe_meta::benchmark`, timed with the DWT cycle counter.
+
+use probe_rs::{Core, MemoryInterface};
+
+use crate::Benchmark;
+
+const DEMCR: u64 = 0xE000_EDFC;
+/// Enables the DWT.
+const DEMCR_TRCENA: u32 = 1 << 24;
+const DWT_CTRL: u64 = 0xE000_1000;
+const DWT_CTRL_CYCCNTENA: u32 = 1 << 0;
+const DWT_CYCCNT: u64 = 0xE000_1004;
+
+/// Size of a `BenchmarkSlot`: name pointer and length, cycles and maximum cycles.
+const SLOT_SIZE: usize = 16;
+/// Slots in `teleprobe_meta`'s table, so a count clobbered by the firmware doesn't make us read past it.
+const MAX_BENCHMARKS: usize = 32;
+/// Longest benchmark name read.
+const MAX_NAME_LEN: u32 = 256;
+
+/// Where the firmware records its benchmarks: `_TELEPROBE_BENCHMARKS` and `_TELEPROBE_BENCHMARK_COUNT`.
+#[derive(Debug, Clone, Copy)]
+pub struct Slots {
+    pub slots: u32,
+    pub count: u32,
+}
+
+/// Start the cycle counter from zero.
+pub fn enable_cycle_counter(core: &mut Core) -> anyhow::Result<()> {
+    let demcr = core.read_word_32(DEMCR)?;
+    core.write_word_32(DEMCR, demcr | DEMCR_TRCENA)?;
+    core.write_word_32(DWT_CYCCNT, 0)?;
+    let ctrl = core.read_word_32(DWT_CTRL)?;
+    core.write_word_32(DWT_CTRL, ctrl | DWT_CTRL_CYCCNTENA)?;
+    Ok(())
+}
+
+/// The benchmarks the firmware recorded so far.
+pub fn read(core: &mut Core, slots: Slots) -> anyhow::Result<Vec<Benchmark>> {
+    let count = (core.read_word_32(slots.count as _)? as usize).min(MAX_BENCHMARKS);
+    let mut res = Vec::new();
+    for i in 0..count {
+        let mut slot = [0u32; SLOT_SIZE / 4];
+        core.read_32((slots.slots as usize + i * SLOT_SIZE) as _, &mut slot)?;
+        let [name_ptr, name_len, cycles, max_cycles] = slot;
+
+        let mut name = vec![0; name_len.min(MAX_NAME_LEN) as usize];
+        core.read_8(name_ptr as _, &mut name)?;
+        res.push(Benchmark {
+            name: String::from_utf8_lossy(&name).into_owned(),
+            cycles,
+            max_cycles: (max_cycles != 0).then_some(max_cycles),
+        });
+    }
+    Ok(res)
+}
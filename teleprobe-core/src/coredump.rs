@@ -0,0 +1,100 @@
+//! ELF core files of a halted core, for post-mortem debugging with gdb.
+
+use anyhow::bail;
+use object::elf::{
+    ELFCLASS32, ELFDATA2LSB, EM_ARM, ET_CORE, EV_CURRENT, NT_PRSTATUS, PF_R, PF_W, PF_X, PT_LOAD, PT_NOTE,
+};
+use probe_rs::config::MemoryRegion;
+use probe_rs::{MemoryInterface, Session};
+
+const EHDR_SIZE: usize = 52;
+const PHDR_SIZE: usize = 32;
+/// Size of the 32-bit ARM `elf_prstatus`, and the offset of `pr_reg` in it.
+const PRSTATUS_SIZE: usize = 148;
+const PRSTATUS_REG_OFFSET: usize = 72;
+
+/// Dump the RAM and the registers of core 0, which must be halted. `registers` are R0-R12, SP, LR, PC and XPSR.
+pub fn capture(sess: &mut Session, registers: &[u32]) -> anyhow::Result<Vec<u8>> {
+    if sess.target().architecture() != probe_rs::Architecture::Arm {
+        bail!("core dumps are only supported on ARM");
+    }
+
+    let memory_map = sess.target().memory_map.clone();
+    let mut core = sess.core(0)?;
+    let mut segments = Vec::new();
+    for region in &memory_map {
+        if let MemoryRegion::Ram(r) = region {
+            let mut data = vec![0; (r.range.end - r.range.start) as usize];
+            core.read(r.range.start, &mut data)?;
+            segments.push((r.range.start as u32, data));
+        }
+    }
+
+    Ok(write_elf(&segments, registers))
+}
+
+fn write_elf(segments: &[(u32, Vec<u8>)], registers: &[u32]) -> Vec<u8> {
+    // The registers go in an NT_PRSTATUS note, where `pr_reg` holds R0-R15 and then CPSR, the
+    // closest thing to XPSR.
+    let mut prstatus = vec![0u8; PRSTATUS_SIZE];
+    for (i, reg) in registers.iter().take(17).enumerate() {
+        prstatus[PRSTATUS_REG_OFFSET + i * 4..][..4].copy_from_slice(&reg.to_le_bytes());
+    }
+    let mut note = Vec::new();
+    push_u32(&mut note, 5); // name size, including the NUL
+    push_u32(&mut note, PRSTATUS_SIZE as u32);
+    push_u32(&mut note, NT_PRSTATUS);
+    note.extend_from_slice(b"CORE\0\0\0\0");
+    note.extend_from_slice(&prstatus);
+
+    let phnum = 1 + segments.len();
+    let mut out = Vec::new();
+    out.extend_from_slice(&[0x7f, b'E', b'L', b'F', ELFCLASS32, ELFDATA2LSB, EV_CURRENT, 0]);
+    out.extend_from_slice(&[0; 8]);
+    push_u16(&mut out, ET_CORE);
+    push_u16(&mut out, EM_ARM);
+    push_u32(&mut out, EV_CURRENT as u32);
+    push_u32(&mut out, 0); // entry
+    push_u32(&mut out, EHDR_SIZE as u32); // program headers offset
+    push_u32(&mut out, 0); // section headers offset
+    push_u32(&mut out, 0); // flags
+    push_u16(&mut out, EHDR_SIZE as u16);
+    push_u16(&mut out, PHDR_SIZE as u16);
+    push_u16(&mut out, phnum as u16);
+    push_u16(&mut out, 0); // section header size
+    push_u16(&mut out, 0); // section header count
+    push_u16(&mut out, 0); // section name table index
+
+    let mut offset = EHDR_SIZE + phnum * PHDR_SIZE;
+    push_phdr(&mut out, PT_NOTE, offset, 0, note.len(), 0);
+    offset += note.len();
+    for (addr, data) in segments {
+        push_phdr(&mut out, PT_LOAD, offset, *addr, data.len(), PF_R | PF_W | PF_X);
+        offset += data.len();
+    }
+
+    out.extend_from_slice(&note);
+    for (_, data) in segments {
+        out.extend_from_slice(data);
+    }
+    out
+}
+
+fn push_phdr(out: &mut Vec<u8>, kind: u32, offset: usize, addr: u32, size: usize, flags: u32) {
+    push_u32(out, kind);
+    push_u32(out, offset as u32);
+    push_u32(out, addr); // virtual address
+    push_u32(out, addr); // physical address
+    push_u32(out, size as u32); // size in the file
+    push_u32(out, size as u32); // size in memory
+    push_u32(out, flags);
+    push_u32(out, if kind == PT_LOAD { 4 } else { 0 }); // alignment
+}
+
+fn push_u16(out: &mut Vec<u8>, v: u16) {
+    out.extend_from_slice(&v.to_le_bytes());
+}
+
+fn push_u32(out: &mut Vec<u8>, v: u32) {
+    out.extend_from_slice(&v.to_le_bytes());
+}
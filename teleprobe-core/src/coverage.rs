@@ -0,0 +1,255 @@
+//! Code coverage of firmware built with `-C instrument-coverage`. The counters LLVM instruments it with are read
+//! from the target after the run, into a `.profraw` file for `llvm-profdata merge` and `llvm-cov`.
+
+use anyhow::{anyhow, bail};
+use object::read::File as ElfFile;
+use object::{Object as _, ObjectSection as _, ObjectSymbol as _};
+use probe_rs::{Core, MemoryInterface};
+
+/// `\xfflprofR\x81`, the magic of profiles of 32-bit targets.
+const RAW_MAGIC_32: u64 = 0xff6c_7072_6f66_5281;
+/// Version flag of profiles with one byte counters, instead of 64-bit ones.
+const VARIANT_MASK_BYTE_COVERAGE: u64 = 1 << 60;
+
+/// Build a `.profraw` from the counters in the target's memory. `None` if the ELF isn't instrumented.
+pub fn capture(core: &mut Core, elf_bytes: &[u8]) -> anyhow::Result<Option<Vec<u8>>> {
+    let elf = ElfFile::parse(elf_bytes)?;
+    let (Some(data), Some(counters), Some(names)) = (
+        elf.section_by_name("__llvm_prf_data"),
+        elf.section_by_name("__llvm_prf_cnts"),
+        elf.section_by_name("__llvm_prf_names"),
+    ) else {
+        return Ok(None);
+    };
+    let bitmap = elf.section_by_name("__llvm_prf_bits");
+
+    // The counters and bitmap are the only parts the firmware writes to.
+    let mut counter_bytes = vec![0; counters.size() as usize];
+    core.read_8(counters.address(), &mut counter_bytes)?;
+    let mut bitmap_bytes = Vec::new();
+    if let Some(bitmap) = &bitmap {
+        bitmap_bytes.resize(bitmap.size() as usize, 0);
+        core.read_8(bitmap.address(), &mut bitmap_bytes)?;
+    }
+
+    let profile = Profile {
+        raw_version: profile_version(&elf)?,
+        data: data.data()?,
+        data_address: data.address(),
+        counters: &counter_bytes,
+        counters_address: counters.address(),
+        bitmap: &bitmap_bytes,
+        bitmap_address: bitmap.as_ref().map(|b| b.address()),
+        names: names.data()?,
+        names_address: names.address(),
+    };
+    profile.profraw().map(Some)
+}
+
+/// The parts of a profile, and where they are in the target's memory.
+struct Profile<'a> {
+    /// `__llvm_profile_raw_version`.
+    raw_version: u64,
+    data: &'a [u8],
+    data_address: u64,
+    counters: &'a [u8],
+    counters_address: u64,
+    bitmap: &'a [u8],
+    bitmap_address: Option<u64>,
+    names: &'a [u8],
+    names_address: u64,
+}
+
+impl Profile<'_> {
+    /// The `.profraw` file of the profile.
+    fn profraw(&self) -> anyhow::Result<Vec<u8>> {
+        let (raw_version, data_bytes, names_bytes) = (self.raw_version, self.data, self.names);
+        let (counter_bytes, bitmap_bytes) = (self.counters, self.bitmap);
+        let version = raw_version & 0xff;
+        // Size of a `__llvm_prf_data` record with 32-bit pointers, which grew with bitmaps in version 9.
+        let record_size = match version {
+            8 => 40,
+            9 | 10 => 48,
+            _ => bail!("unsupported LLVM profile version {}, only 8 to 10 are", version),
+        };
+        let counter_size = if raw_version & VARIANT_MASK_BYTE_COVERAGE != 0 {
+            1
+        } else {
+            8
+        };
+
+        // Deltas are between 32-bit addresses, so they wrap around like they do on the target.
+        let delta = |addr: u64| (addr as u32).wrapping_sub(self.data_address as u32) as u64;
+        let mut header = vec![
+            RAW_MAGIC_32,
+            raw_version,
+            // Binary IDs size.
+            0,
+            data_bytes.len() as u64 / record_size,
+            // Padding before the counters.
+            0,
+            counter_bytes.len() as u64 / counter_size,
+            padding(counter_bytes.len()) as u64,
+        ];
+        if version >= 9 {
+            header.extend([bitmap_bytes.len() as u64, padding(bitmap_bytes.len()) as u64]);
+        }
+        header.push(names_bytes.len() as u64);
+        header.push(delta(self.counters_address));
+        if version >= 9 {
+            header.push(self.bitmap_address.map_or(0, delta));
+        }
+        header.push(self.names_address);
+        if version >= 10 {
+            // No virtual tables, nor their names.
+            header.extend([0, 0]);
+        }
+        // The last value profiling kind, which version 10 added virtual table targets to.
+        header.push(if version >= 10 { 2 } else { 1 });
+
+        let mut out: Vec<u8> = header.iter().flat_map(|v| v.to_le_bytes()).collect();
+        out.extend_from_slice(data_bytes);
+        for section in [counter_bytes, bitmap_bytes, names_bytes] {
+            out.extend_from_slice(section);
+            out.resize(out.len() + padding(section.len()), 0);
+        }
+        Ok(out)
+    }
+}
+
+/// `__llvm_profile_raw_version`: the format version, with flags for the kind of instrumentation in the top bits.
+fn profile_version(elf: &ElfFile) -> anyhow::Result<u64> {
+    let symbol = elf
+        .symbols()
+        .find(|s| s.name().ok() == Some("__llvm_profile_raw_version"))
+        .ok_or_else(|| anyhow!("`__llvm_profile_raw_version` symbol not found"))?;
+    let section = elf
+        .sections()
+        .find(|s| (s.address()..s.address() + s.size()).contains(&symbol.address()))
+        .ok_or_else(|| anyhow!("`__llvm_profile_raw_version` is in no section"))?;
+    let offset = (symbol.address() - section.address()) as usize;
+    let bytes = section
+        .data()?
+        .get(offset..offset + 8)
+        .ok_or_else(|| anyhow!("`__llvm_profile_raw_version` is truncated"))?;
+    // NOTE (unwrap): the slice is 8 bytes long.
+    Ok(u64::from_le_bytes(bytes.try_into().unwrap()))
+}
+
+/// Bytes to pad a part of `len` bytes with, to align the next one to 8 bytes.
+fn padding(len: usize) -> usize {
+    (8 - len % 8) % 8
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn words(bytes: &[u8]) -> Vec<u64> {
+        // NOTE (unwrap): chunks are 8 bytes long.
+        bytes
+            .chunks(8)
+            .map(|c| u64::from_le_bytes(c.try_into().unwrap()))
+            .collect()
+    }
+
+    #[test]
+    fn padding_to_8_bytes() {
+        assert_eq!(padding(0), 0);
+        assert_eq!(padding(1), 7);
+        assert_eq!(padding(8), 0);
+        assert_eq!(padding(13), 3);
+    }
+
+    #[test]
+    fn version_8() {
+        let data = [0xdd; 80];
+        let counters = [0xcc; 24];
+        let names = [0xee; 5];
+        let profile = Profile {
+            raw_version: 8,
+            data: &data,
+            data_address: 0x2000_0100,
+            counters: &counters,
+            counters_address: 0x2000_0000,
+            bitmap: &[],
+            bitmap_address: None,
+            names: &names,
+            names_address: 0x0800_1000,
+        };
+        let out = profile.profraw().unwrap();
+
+        let header = words(&out[..11 * 8]);
+        assert_eq!(header, [RAW_MAGIC_32, 8, 0, 2, 0, 3, 0, 5, 0xffff_ff00, 0x0800_1000, 1]);
+        let body = &out[11 * 8..];
+        assert_eq!(&body[..80], &data);
+        assert_eq!(&body[80..104], &counters);
+        assert_eq!(&body[104..109], &names);
+        assert_eq!(&body[109..], &[0; 3]);
+    }
+
+    #[test]
+    fn version_10_with_bitmap() {
+        let data = [0xdd; 48];
+        let counters = [0xcc; 3];
+        let bitmap = [0xbb; 2];
+        let names = [0xee; 8];
+        let profile = Profile {
+            raw_version: 10 | VARIANT_MASK_BYTE_COVERAGE,
+            data: &data,
+            data_address: 0x2000_0000,
+            counters: &counters,
+            counters_address: 0x2000_0100,
+            bitmap: &bitmap,
+            bitmap_address: Some(0x2000_0200),
+            names: &names,
+            names_address: 0x0800_1000,
+        };
+        let out = profile.profraw().unwrap();
+
+        let header = words(&out[..16 * 8]);
+        assert_eq!(
+            header,
+            [
+                RAW_MAGIC_32,
+                10 | VARIANT_MASK_BYTE_COVERAGE,
+                0,
+                1,
+                0,
+                // One byte counters.
+                3,
+                5,
+                2,
+                6,
+                8,
+                0x100,
+                0x200,
+                0x0800_1000,
+                0,
+                0,
+                2,
+            ]
+        );
+        let body = &out[16 * 8..];
+        assert_eq!(body.len(), 48 + 8 + 8 + 8);
+        assert_eq!(&body[48..51], &counters);
+        assert_eq!(&body[56..58], &bitmap);
+        assert_eq!(&body[64..], &names);
+    }
+
+    #[test]
+    fn unsupported_version() {
+        let profile = Profile {
+            raw_version: 7,
+            data: &[],
+            data_address: 0,
+            counters: &[],
+            counters_address: 0,
+            bitmap: &[],
+            bitmap_address: None,
+            names: &[],
+            names_address: 0,
+        };
+        assert!(profile.profraw().is_err());
+    }
+}
@@ -0,0 +1,304 @@
+//! Decoding what the firmware writes to its log channel. The format is picked per run from the ELF's
+//! `.teleprobe.log_format`, so firmware logging in another format than defmt only needs a [`Decoder`] for it.
+//!
+//! defmt's encoding, raw or rzcobs, comes from the ELF's defmt table, and its wire format version is checked
+//! against the ones the decoder supports before decoding anything.
+
+use std::collections::BTreeMap;
+use std::fmt::Write as _;
+use std::path::PathBuf;
+
+use defmt_decoder::{DecodeError, Location, StreamDecoder, Table};
+use serde::{Deserialize, Serialize};
+
+use crate::{mismatch, LogFrame};
+
+/// Format of the firmware's logs on RTT up channel 0.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "snake_case")]
+pub enum Format {
+    /// defmt, in whichever encoding the ELF's defmt table says, raw or rzcobs.
+    #[default]
+    Defmt,
+    /// Plain text, one frame per line.
+    Text,
+    /// postcard-rpc frames on RTT up channel 1, COBS-encoded and ended by a zero byte like on its byte stream
+    /// transports. They're logged as hex, since their types aren't in the ELF.
+    PostcardRpc,
+}
+
+pub enum Decoded {
+    /// The next frame. It has no `host_time`, that's up to the caller.
+    Frame(LogFrame),
+    /// The data received so far ends in the middle of a frame.
+    Incomplete,
+    /// The data doesn't decode. If `recoverable`, the bad frame was skipped and decoding goes on after it.
+    Malformed { recoverable: bool },
+}
+
+/// Turns the bytes read from the firmware's log channel into frames.
+pub trait Decoder {
+    /// Name of the format, for logs.
+    fn name(&self) -> String;
+
+    /// The RTT up channel the format is read from.
+    fn channel(&self) -> usize {
+        0
+    }
+
+    /// Feed data read from the channel.
+    fn received(&mut self, data: &[u8]);
+
+    /// Decode the next frame from the data fed so far.
+    fn decode(&mut self) -> Decoded;
+}
+
+/// The decoder for an ELF's logs, in `format`.
+pub fn for_elf(elf_bytes: &[u8], format: Format) -> anyhow::Result<Box<dyn Decoder>> {
+    Ok(match format {
+        Format::Defmt => Box::new(DefmtDecoder::new(elf_bytes)?),
+        Format::Text => Box::new(TextDecoder::default()),
+        Format::PostcardRpc => Box::new(PostcardRpcDecoder::default()),
+    })
+}
+
+pub(crate) struct DefmtDecoder {
+    // Borrows `table`, so it's declared first, to be dropped before it.
+    stream: Box<dyn StreamDecoder>,
+    table: Box<Table>,
+    locs: BTreeMap<u64, Location>,
+    /// Locations are shown relative to it.
+    current_dir: PathBuf,
+}
+
+/// Unbind a reference from the lifetime of what it points to.
+///
+/// # Safety
+///
+/// The referent must outlive every use of the returned reference, and not move.
+unsafe fn extend_lifetime<'a, 'b, T>(r: &'a T) -> &'b T {
+    std::mem::transmute(r)
+}
+
+impl DefmtDecoder {
+    pub(crate) fn new(elf_bytes: &[u8]) -> anyhow::Result<Self> {
+        let table = mismatch::parse_table(elf_bytes)?.ok_or_else(|| anyhow::anyhow!("defmt table not found"))?;
+        let table = Box::new(table);
+        let locs = table.get_locations(elf_bytes)?;
+        if !table.is_empty() && locs.is_empty() {
+            log::warn!("insufficient DWARF info; compile your program with `debug = 2` to enable location info");
+        }
+        //if !table.indices().all(|idx| locs.contains_key(&(idx as u64))) {
+        //    bail!("(BUG) location info is incomplete; it will be omitted from the output");
+        //}
+
+        // SAFETY: the table is boxed, so it doesn't move along with the decoder, and `stream` is dropped before it.
+        let stream = unsafe { extend_lifetime(&*table) }.new_stream_decoder();
+        Ok(Self {
+            stream,
+            table,
+            locs,
+            current_dir: std::env::current_dir().unwrap_or_default(),
+        })
+    }
+}
+
+impl Decoder for DefmtDecoder {
+    fn name(&self) -> String {
+        format!("defmt ({:?})", self.table.encoding())
+    }
+
+    fn received(&mut self, data: &[u8]) {
+        self.stream.received(data)
+    }
+
+    fn decode(&mut self) -> Decoded {
+        let frame = match self.stream.decode() {
+            Ok(frame) => frame,
+            Err(DecodeError::UnexpectedEof) => return Decoded::Incomplete,
+            Err(DecodeError::Malformed) => {
+                return Decoded::Malformed {
+                    recoverable: self.table.encoding().can_recover(),
+                }
+            }
+        };
+
+        let (mut file, mut line, mut module) = (None, None, None);
+        if let Some(loc) = self.locs.get(&frame.index()) {
+            let relpath = if let Ok(relpath) = loc.file.strip_prefix(&self.current_dir) {
+                relpath
+            } else {
+                // not relative; use full path
+                &loc.file
+            };
+            file = Some(relpath.display().to_string());
+            line = Some(loc.line as u32);
+            module = Some(loc.module.clone());
+        }
+
+        Decoded::Frame(LogFrame {
+            level: frame.level().map(|l| l.as_str().to_string()),
+            timestamp: frame.display_timestamp().map(|ts| ts.to_string()),
+            host_time: None,
+            message: frame.display_message().to_string(),
+            file,
+            line,
+            module,
+        })
+    }
+}
+
+/// Plain text, for firmware that doesn't use defmt. Lines have no level, so they're logged as info.
+#[derive(Default)]
+pub struct TextDecoder {
+    buf: Vec<u8>,
+}
+
+impl Decoder for TextDecoder {
+    fn name(&self) -> String {
+        "text".to_string()
+    }
+
+    fn received(&mut self, data: &[u8]) {
+        self.buf.extend_from_slice(data)
+    }
+
+    fn decode(&mut self) -> Decoded {
+        let Some(end) = self.buf.iter().position(|&b| b == b'\n') else {
+            return Decoded::Incomplete;
+        };
+        let line: Vec<u8> = self.buf.drain(..=end).collect();
+        let text = String::from_utf8_lossy(&line[..end]);
+        Decoded::Frame(LogFrame {
+            level: None,
+            timestamp: None,
+            host_time: None,
+            message: text.trim_end_matches('\r').to_string(),
+            file: None,
+            line: None,
+            module: None,
+        })
+    }
+}
+
+/// postcard-rpc frames, read from RTT up channel 1.
+#[derive(Default)]
+pub struct PostcardRpcDecoder {
+    buf: Vec<u8>,
+}
+
+impl Decoder for PostcardRpcDecoder {
+    fn name(&self) -> String {
+        "postcard-rpc".to_string()
+    }
+
+    fn channel(&self) -> usize {
+        1
+    }
+
+    fn received(&mut self, data: &[u8]) {
+        self.buf.extend_from_slice(data)
+    }
+
+    fn decode(&mut self) -> Decoded {
+        loop {
+            let Some(end) = self.buf.iter().position(|&b| b == 0) else {
+                return Decoded::Incomplete;
+            };
+            let frame: Vec<u8> = self.buf.drain(..=end).collect();
+            // Senders may end frames with extra zeros, to resync the receiver.
+            if end == 0 {
+                continue;
+            }
+            let Some(data) = cobs_decode(&frame[..end]) else {
+                return Decoded::Malformed { recoverable: true };
+            };
+            let mut message = format!("postcard-rpc frame ({} bytes):", data.len());
+            for b in data {
+                write!(message, " {:02x}", b).unwrap();
+            }
+            return Decoded::Frame(LogFrame {
+                level: None,
+                timestamp: None,
+                host_time: None,
+                message,
+                file: None,
+                line: None,
+                module: None,
+            });
+        }
+    }
+}
+
+/// Undo COBS. Each code byte `n` is followed by `n - 1` data bytes, and stands for a zero after them, unless it's
+/// 0xff or ends the frame.
+fn cobs_decode(frame: &[u8]) -> Option<Vec<u8>> {
+    let mut data = Vec::with_capacity(frame.len());
+    let mut i = 0;
+    while i < frame.len() {
+        let code = frame[i] as usize;
+        if code == 0 || i + code > frame.len() {
+            return None;
+        }
+        data.extend_from_slice(&frame[i + 1..i + code]);
+        i += code;
+        if code < 0xff && i < frame.len() {
+            data.push(0);
+        }
+    }
+    Some(data)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message(decoded: Decoded) -> String {
+        match decoded {
+            Decoded::Frame(frame) => frame.message,
+            Decoded::Incomplete => panic!("incomplete"),
+            Decoded::Malformed { .. } => panic!("malformed"),
+        }
+    }
+
+    #[test]
+    fn text_lines() {
+        let mut decoder = TextDecoder::default();
+        decoder.received(b"hello\r\nwor");
+        assert_eq!(message(decoder.decode()), "hello");
+        assert!(matches!(decoder.decode(), Decoded::Incomplete));
+        decoder.received(b"ld\n\n");
+        assert_eq!(message(decoder.decode()), "world");
+        assert_eq!(message(decoder.decode()), "");
+        assert!(matches!(decoder.decode(), Decoded::Incomplete));
+    }
+
+    #[test]
+    fn cobs() {
+        assert_eq!(cobs_decode(&[0x01]).unwrap(), b"");
+        assert_eq!(cobs_decode(&[0x01, 0x01]).unwrap(), [0]);
+        assert_eq!(
+            cobs_decode(&[0x03, 0x11, 0x22, 0x02, 0x33]).unwrap(),
+            [0x11, 0x22, 0, 0x33]
+        );
+        let mut long = vec![0xff];
+        long.extend(1..=254);
+        long.extend([0x02, 0x01]);
+        assert_eq!(cobs_decode(&long).unwrap(), (1..=254).chain([1]).collect::<Vec<u8>>());
+        assert!(cobs_decode(&[0x05, 0x11]).is_none());
+        assert!(cobs_decode(&[0x02, 0x11, 0x00]).is_none());
+    }
+
+    #[test]
+    fn postcard_rpc_frames() {
+        let mut decoder = PostcardRpcDecoder::default();
+        assert_eq!(decoder.channel(), 1);
+        decoder.received(&[0x00, 0x00, 0x03, 0x11, 0x22, 0x02]);
+        assert!(matches!(decoder.decode(), Decoded::Incomplete));
+        decoder.received(&[0x33, 0x00, 0x04, 0x11, 0x00]);
+        assert_eq!(message(decoder.decode()), "postcard-rpc frame (4 bytes): 11 22 00 33");
+        assert!(matches!(decoder.decode(), Decoded::Malformed { recoverable: true }));
+        decoder.received(&[0x01, 0x00]);
+        assert_eq!(message(decoder.decode()), "postcard-rpc frame (0 bytes):");
+    }
+}
@@ -0,0 +1,108 @@
+//! Power control through smart USB hubs and external commands, for farms whose hubs don't expose per-port power
+//! in sysfs, and for hosts other than linux.
+
+use std::process::Command;
+
+use anyhow::{bail, Context as _, Result};
+use serde::{Deserialize, Serialize};
+
+/// How the power of a probe's USB port gets switched.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PowerControl {
+    /// The `disable` file of the probe's port in sysfs. Linux only.
+    #[default]
+    Sysfs,
+    /// Hub class requests to a hub that switches power per port, like uhubctl does. Hubs that only switch all
+    /// their ports together power off everything plugged into them. On hosts other than linux, this runs the
+    /// `uhubctl` tool.
+    Uhubctl {
+        /// USB port path of the hub, like `1-1.4`, as uhubctl names it.
+        hub: String,
+        /// Port of the hub the probe is plugged into, from 1.
+        port: u16,
+    },
+    /// A Yepkit YKUSH hub, through its `ykushcmd` tool.
+    Ykush {
+        /// Serial number of the hub, if there's more than one.
+        #[serde(default)]
+        serial: Option<String>,
+        /// Downstream port the probe is plugged into, from 1.
+        port: u8,
+    },
+    /// Commands that switch the power, like ones driving a relay board or a smart plug. Each is a program and its
+    /// arguments.
+    Command { off: Vec<String>, on: Vec<String> },
+}
+
+/// Switch a port of a hub at USB port path `hub` with a hub class request.
+#[cfg(target_os = "linux")]
+pub fn set_port_power(hub: &str, port: u16, on: bool) -> Result<()> {
+    use std::time::Duration;
+
+    use nusb::transfer::{Control, ControlType, Recipient};
+
+    use crate::probe::usb_device_at;
+
+    /// Hub class requests, and the port feature they set or clear.
+    const CLEAR_FEATURE: u8 = 0x01;
+    const SET_FEATURE: u8 = 0x03;
+    const PORT_POWER: u16 = 8;
+
+    let dev = usb_device_at(hub)?
+        .open()
+        .with_context(|| format!("failed to open hub at USB port path {}", hub))?;
+    let control = Control {
+        control_type: ControlType::Class,
+        recipient: Recipient::Other,
+        request: if on { SET_FEATURE } else { CLEAR_FEATURE },
+        value: PORT_POWER,
+        index: port,
+    };
+    dev.control_out_blocking(control, &[], Duration::from_secs(1))
+        .with_context(|| format!("failed to switch port {} of hub {}", port, hub))?;
+    Ok(())
+}
+
+/// Switch a port of a hub at USB port path `hub` with uhubctl, which knows how to send hub requests on this host.
+#[cfg(not(target_os = "linux"))]
+pub fn set_port_power(hub: &str, port: u16, on: bool) -> Result<()> {
+    let mut cmd = Command::new("uhubctl");
+    cmd.arg("-l")
+        .arg(hub)
+        .arg("-p")
+        .arg(port.to_string())
+        .arg("-a")
+        .arg(if on { "on" } else { "off" });
+    run(cmd)
+}
+
+/// Switch a port of a YKUSH hub.
+pub fn set_ykush_power(serial: Option<&str>, port: u8, on: bool) -> Result<()> {
+    let mut cmd = Command::new("ykushcmd");
+    if let Some(serial) = serial {
+        cmd.arg("-s").arg(serial);
+    }
+    cmd.arg(if on { "-u" } else { "-d" }).arg(port.to_string());
+    run(cmd)
+}
+
+/// Switch the power with one of the commands of [`PowerControl::Command`].
+pub fn run_power_command(argv: &[String]) -> Result<()> {
+    let Some((program, args)) = argv.split_first() else {
+        bail!("power command is empty");
+    };
+    let mut cmd = Command::new(program);
+    cmd.args(args);
+    run(cmd)
+}
+
+fn run(mut cmd: Command) -> Result<()> {
+    log::debug!("running {:?}", cmd);
+    let program = cmd.get_program().to_string_lossy().into_owned();
+    let status = cmd.status().with_context(|| format!("failed to run `{}`", program))?;
+    if !status.success() {
+        bail!("`{}` exited with {}", program, status);
+    }
+    Ok(())
+}
@@ -0,0 +1,109 @@
+//! The teleprobe run engine: connects to a board through a debug probe, flashes firmware, and runs it
+//! while streaming its defmt logs, until it finishes, crashes or times out.
+//!
+//! This is what the `teleprobe` CLI and server use for every run. To embed it, describe the board
+//! with a [`TargetSpec`] and hand images to a [`Runner`]:
+//!
+//! ```no_run
+//! use teleprobe_core::run::{Image, Options};
+//! use teleprobe_core::{ImageFormat, Runner, TargetSpec};
+//!
+//! let runner = Runner::new(TargetSpec::new("nRF52840_xxAA"));
+//! let image = Image {
+//!     data: std::fs::read("firmware.elf").unwrap().into(),
+//!     format: ImageFormat::Elf,
+//!     base_address: None,
+//! };
+//! let report = runner.run(&image, Options::default());
+//! println!("{:?}", report.failure);
+//! ```
+//!
+//! Progress and device output are logged with the `log` crate, device lines under the `device` target.
+
+mod bench;
+pub mod coredump;
+mod coverage;
+pub mod decode;
+mod hub;
+pub mod meta;
+mod mismatch;
+pub mod probe;
+mod quirks;
+mod report;
+pub mod run;
+pub mod selector;
+mod stack;
+mod usage;
+mod watchdog;
+
+use std::cell::RefCell;
+use std::time::Instant;
+
+pub use probe::TargetSpec;
+use probe_rs::Session;
+pub use report::{
+    BacktraceFrame, Benchmark, ExceptionInfo, ExitReason, LogFrame, MemoryUsage, PanicInfo, PhaseTimings, RunError,
+    RunReport, SectionUsage,
+};
+pub use run::ImageFormat;
+
+/// Runs firmware on one board.
+pub struct Runner {
+    spec: TargetSpec,
+    keep_session: bool,
+    /// Left open by the last run, with `keep_session`.
+    session: RefCell<Option<Session>>,
+}
+
+impl Runner {
+    pub fn new(spec: TargetSpec) -> Self {
+        Self {
+            spec,
+            keep_session: false,
+            session: RefCell::new(None),
+        }
+    }
+
+    /// Keep the probe session open after runs, for the next ones to reuse instead of connecting again, which
+    /// saves the USB enumeration and attach time. The board isn't power reset between runs then. The session is
+    /// checked before it's reused, and reopened after runs that failed on the probe rather than the firmware.
+    pub fn keep_session(mut self, keep: bool) -> Self {
+        self.keep_session = keep;
+        self
+    }
+
+    pub fn spec(&self) -> &TargetSpec {
+        &self.spec
+    }
+
+    /// Connect to the board, flash `image` and run it until it finishes.
+    pub fn run(&self, image: &run::Image, opts: run::Options) -> RunReport {
+        let start = Instant::now();
+        let kept = self.session.borrow_mut().take().and_then(|mut sess| {
+            match sess.core(0).and_then(|mut core| core.status()) {
+                Ok(_) => Some(sess),
+                Err(e) => {
+                    log::warn!("kept probe session is broken, reconnecting: {:?}", e);
+                    None
+                }
+            }
+        });
+        let mut sess = match kept.map_or_else(|| probe::connect(&self.spec), Ok) {
+            Ok(sess) => sess,
+            Err(e) => return RunReport::default().failed(e, RunError::ProbeNotFound),
+        };
+        let probe_attach = start.elapsed().as_secs_f64();
+
+        let mut report = run::run_image(&mut sess, image, opts);
+        report.timings.probe_attach = Some(probe_attach);
+
+        let probe_failed = matches!(
+            report.failure,
+            Some(RunError::ProbeNotFound | RunError::FlashError | RunError::RttAttachFailed)
+        );
+        if self.keep_session && !probe_failed {
+            *self.session.borrow_mut() = Some(sess);
+        }
+        report
+    }
+}
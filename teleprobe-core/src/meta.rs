@@ -0,0 +1,89 @@
+//! Metadata embedded into firmware by the `teleprobe-meta` crate.
+
+use log::warn;
+use object::{Object, ObjectSection};
+
+use crate::decode::Format;
+use crate::run::RunFrom;
+use crate::selector::Selector;
+
+#[derive(Clone, Debug, Default)]
+pub struct ElfMetadata {
+    /// Name of the target to run on, from `.teleprobe.target`.
+    pub target: Option<String>,
+    /// Selector for the targets it can run on, from `.teleprobe.labels`.
+    pub labels: Option<Selector>,
+    /// Timeout in seconds, from `.teleprobe.timeout`.
+    pub timeout: Option<u64>,
+    /// Whether it runs from RAM or flash, from `.teleprobe.run_from`.
+    pub run_from: Option<RunFrom>,
+    /// Format of the firmware's logs, from `.teleprobe.log_format`.
+    pub log_format: Option<Format>,
+}
+
+impl ElfMetadata {
+    /// Read the metadata of an ELF. Sections with invalid contents are ignored with a warning.
+    pub fn parse(elf: &[u8]) -> anyhow::Result<Self> {
+        let mut target = None;
+        let mut labels = None;
+        let mut timeout = None;
+        let mut run_from = None;
+        let mut log_format = None;
+
+        let obj_file = object::File::parse(elf)?;
+
+        if let Some(section) = obj_file.section_by_name(".teleprobe.target") {
+            let data = section.data()?;
+            if !data.is_empty() {
+                match String::from_utf8(data.to_vec()) {
+                    Ok(s) => target = Some(s),
+                    Err(_) => warn!(".teleprobe.target contents are not a valid utf8 string."),
+                }
+            }
+        }
+
+        if let Some(section) = obj_file.section_by_name(".teleprobe.labels") {
+            let data = section.data()?;
+            if !data.is_empty() {
+                match std::str::from_utf8(data).map(str::parse) {
+                    Ok(Ok(s)) => labels = Some(s),
+                    Ok(Err(e)) => warn!(".teleprobe.labels contents are not a valid selector: {}", e),
+                    Err(_) => warn!(".teleprobe.labels contents are not a valid utf8 string."),
+                }
+            }
+        }
+
+        if let Some(section) = obj_file.section_by_name(".teleprobe.timeout") {
+            let data = section.data()?;
+            if data.len() == 4 {
+                timeout = Some(u32::from_le_bytes(data.try_into().unwrap()) as u64)
+            } else {
+                warn!(".teleprobe.timeout contents are not a valid u32.")
+            }
+        }
+
+        if let Some(section) = obj_file.section_by_name(".teleprobe.run_from") {
+            let data = section.data()?;
+            match std::str::from_utf8(data).map(|s| <RunFrom as clap::ValueEnum>::from_str(s, false)) {
+                Ok(Ok(r)) => run_from = Some(r),
+                _ => warn!(".teleprobe.run_from contents are not `auto`, `ram` or `flash`."),
+            }
+        }
+
+        if let Some(section) = obj_file.section_by_name(".teleprobe.log_format") {
+            let data = section.data()?;
+            match std::str::from_utf8(data).map(|s| <Format as clap::ValueEnum>::from_str(s, false)) {
+                Ok(Ok(f)) => log_format = Some(f),
+                _ => warn!(".teleprobe.log_format contents are not `defmt`, `text` or `postcard-rpc`."),
+            }
+        }
+
+        Ok(Self {
+            target,
+            labels,
+            timeout,
+            run_from,
+            log_format,
+        })
+    }
+}
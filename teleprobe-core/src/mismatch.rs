@@ -0,0 +1,52 @@
+//! Telling why the firmware's defmt data doesn't decode, instead of just that it doesn't. It's almost always one
+//! of two things: the firmware was built with a defmt of another wire format than teleprobe's decoder, or the
+//! ELF isn't the one the board runs, like when attaching to firmware flashed by an earlier run.
+
+use anyhow::{anyhow, bail};
+use defmt_decoder::{Table, DEFMT_VERSIONS};
+use object::read::File as ElfFile;
+use object::{Object as _, ObjectSymbol as _};
+
+/// defmt names a symbol after the wire format version it was built with.
+const VERSION_SYMBOL: &str = "_defmt_version_ = ";
+
+/// Frames in a row that fail to decode, before a single one did, for the data to be taken as not matching the
+/// table at all rather than corrupted here and there.
+pub const MAX_FAILURES_BEFORE_FIRST_FRAME: u32 = 8;
+
+/// The defmt wire format version the firmware was built with.
+fn firmware_version(elf_bytes: &[u8]) -> Option<String> {
+    let elf = ElfFile::parse(elf_bytes).ok()?;
+    elf.symbols()
+        .find_map(|sym| sym.name().ok()?.strip_prefix(VERSION_SYMBOL).map(str::to_string))
+}
+
+/// Parse the ELF's defmt table, explaining a wire format version mismatch.
+pub fn parse_table(elf_bytes: &[u8]) -> anyhow::Result<Option<Table>> {
+    if let Some(version) = firmware_version(elf_bytes) {
+        if !DEFMT_VERSIONS.contains(&version.as_str()) {
+            bail!(
+                "the firmware was built with defmt wire format version {}, which teleprobe can't decode, it \
+                 supports {}. Build it with a defmt release using one of those, or update teleprobe",
+                version,
+                DEFMT_VERSIONS.join(", ")
+            );
+        }
+    }
+    Table::parse(elf_bytes)
+}
+
+/// Error for defmt data that doesn't decode with the ELF's table, with its likely cause.
+pub fn decode_error(attach: bool) -> anyhow::Error {
+    if attach {
+        anyhow!(
+            "failed to decode defmt data: the board runs firmware flashed before, which doesn't match the ELF. \
+             Attaching doesn't flash, so submit the ELF of the firmware on the board, or run without attaching"
+        )
+    } else {
+        anyhow!(
+            "failed to decode defmt data: it doesn't match the ELF's defmt table. The firmware's crates may use \
+             different defmt versions, or the ELF isn't the one that was flashed"
+        )
+    }
+}
@@ -0,0 +1,709 @@
+use std::fmt;
+use std::time::{Duration, Instant};
+
+use anyhow::{anyhow, bail, Context, Result};
+use clap::Parser;
+use probe_rs::architecture::arm::{DapAccess, DpAddress};
+use probe_rs::config::TargetSelector;
+use probe_rs::probe::cmsisdap::{self, CmsisDap};
+use probe_rs::probe::list::Lister;
+use probe_rs::probe::{DebugProbeInfo, DebugProbeSelector, Probe};
+use probe_rs::{Architecture, MemoryInterface, Permissions, Session};
+use serde::{Deserialize, Serialize};
+
+pub use crate::hub::PowerControl;
+use crate::{hub, quirks, RunError};
+
+const SETTLE_REPROBE_INTERVAL: Duration = Duration::from_millis(250);
+/// Probe clock speeds tried with `auto_speed`, in kHz, fastest first.
+const AUTO_SPEEDS: [u32; 8] = [24000, 12000, 8000, 4000, 2000, 1000, 400, 100];
+/// Times the link is tested by reading a register, with `auto_speed`.
+const LINK_TEST_READS: usize = 64;
+/// Cortex-M CPUID register, read for the link test.
+const CPUID: u64 = 0xE000_ED00;
+
+/// Command line options for reaching a board, turned into a [`TargetSpec`].
+#[derive(Clone, Parser)]
+pub struct Opts {
+    /// The probe to use (specified by eg. `VID:PID`, `VID:PID:Serial`, or just `Serial`).
+    #[clap(long, env = "PROBE_RUN_PROBE")]
+    pub probe: Option<DebugProbeSelector>,
+
+    /// Physical USB port of the probe, like `1-1.4.2`, to tell apart probes of the same model. Linux only.
+    #[clap(long)]
+    pub usb_path: Option<String>,
+
+    /// The probe clock frequency in kHz
+    #[clap(long)]
+    pub speed: Option<u32>,
+
+    /// Use the fastest probe clock the link works at, from `speed` or 24 MHz down, backing off on errors. For
+    /// long cables, where a fixed speed is either slow or unreliable.
+    #[clap(long)]
+    pub auto_speed: bool,
+
+    /// Chip name
+    #[clap(long)]
+    pub chip: String,
+
+    /// Connect to device when NRST is pressed.
+    #[clap(long)]
+    pub connect_under_reset: bool,
+
+    // If the target should be tried to be power cycled via USB
+    #[clap(long)]
+    pub power_reset: bool,
+
+    #[clap(long, default_value = "1")]
+    pub cycle_delay_seconds: f64,
+
+    /// How to switch the probe's power for `power_reset`.
+    #[clap(skip)]
+    pub power_control: PowerControl,
+
+    #[clap(long, default_value = "2000")]
+    pub max_settle_time_millis: u64,
+
+    /// Times to try attaching to the chip, for probes that are slow to enumerate or chips that don't answer right away.
+    #[clap(long, default_value = "10")]
+    pub connect_attempts: u32,
+
+    /// Time to wait between attempts to attach.
+    #[clap(long, default_value = "300")]
+    pub connect_retry_interval_millis: u64,
+
+    /// Erase the chip to unlock it if attaching fails because it's read-protected. Supported on nRF52 and nRF91.
+    #[clap(long)]
+    pub auto_recover: bool,
+
+    /// Identity to verify after connecting.
+    #[clap(skip)]
+    pub identity: Option<BoardIdentity>,
+}
+
+impl From<Opts> for TargetSpec {
+    fn from(opts: Opts) -> Self {
+        Self {
+            probe: opts.probe,
+            usb_path: opts.usb_path,
+            speed: opts.speed,
+            auto_speed: opts.auto_speed,
+            chip: opts.chip,
+            connect_under_reset: opts.connect_under_reset,
+            power_reset: opts.power_reset,
+            cycle_delay_seconds: opts.cycle_delay_seconds,
+            power_control: opts.power_control,
+            max_settle_time_millis: opts.max_settle_time_millis,
+            connect_attempts: opts.connect_attempts,
+            connect_retry_interval_millis: opts.connect_retry_interval_millis,
+            auto_recover: opts.auto_recover,
+            identity: opts.identity,
+        }
+    }
+}
+
+/// How to reach a board: its chip, and the probe it's attached to.
+#[derive(Clone)]
+pub struct TargetSpec {
+    /// The probe to use. Without it, the only connected one.
+    pub probe: Option<DebugProbeSelector>,
+    /// Physical USB port of the probe, like `1-1.4.2`, to tell apart probes of the same model. Linux only.
+    pub usb_path: Option<String>,
+    /// The probe clock frequency in kHz.
+    pub speed: Option<u32>,
+    /// Use the fastest probe clock the link works at, from `speed` or 24 MHz down, backing off on errors.
+    pub auto_speed: bool,
+    /// probe-rs name of the chip.
+    pub chip: String,
+    /// Connect to the chip while holding it in reset.
+    pub connect_under_reset: bool,
+    /// Power cycle the probe before connecting, with `power_control`.
+    pub power_reset: bool,
+    pub cycle_delay_seconds: f64,
+    /// How to switch the probe's power for `power_reset`.
+    pub power_control: PowerControl,
+    pub max_settle_time_millis: u64,
+    /// Times to try attaching to the chip.
+    pub connect_attempts: u32,
+    /// Time to wait between attempts to attach.
+    pub connect_retry_interval_millis: u64,
+    /// Erase the chip to unlock it if attaching fails because it's read-protected. Supported on nRF52 and nRF91.
+    pub auto_recover: bool,
+    /// Identity to verify after connecting.
+    pub identity: Option<BoardIdentity>,
+}
+
+impl TargetSpec {
+    /// A board with chip `chip`, on the only connected probe, with the same defaults as the command line.
+    pub fn new(chip: impl Into<String>) -> Self {
+        Self {
+            probe: None,
+            usb_path: None,
+            speed: None,
+            auto_speed: false,
+            chip: chip.into(),
+            connect_under_reset: false,
+            power_reset: false,
+            cycle_delay_seconds: 1.0,
+            power_control: PowerControl::default(),
+            max_settle_time_millis: 2000,
+            connect_attempts: 10,
+            connect_retry_interval_millis: 300,
+            auto_recover: false,
+            identity: None,
+        }
+    }
+}
+
+/// Words of memory that identify a board, like a device ID register or a value written to OTP.
+#[derive(Clone, Deserialize, Serialize)]
+pub struct BoardIdentity {
+    pub address: u64,
+    /// 32-bit words expected at `address`.
+    pub expected: Vec<u32>,
+}
+
+/// A connected probe.
+#[derive(Serialize)]
+pub struct ProbeListing {
+    pub vendor_id: u16,
+    pub product_id: u16,
+    pub serial_number: Option<String>,
+    pub probe_type: String,
+    pub identifier: String,
+    /// probe-rs name of the chip behind the probe, if it was identified.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub chip: Option<String>,
+    /// IDCODE of the chip's ARM debug port, if it was identified and has one.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub idcode: Option<u32>,
+}
+
+impl fmt::Display for ProbeListing {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{:04x}:{:04x}:{} -- {} {}",
+            self.vendor_id,
+            self.product_id,
+            self.serial_number.as_deref().unwrap_or("SN unspecified"),
+            self.probe_type,
+            self.identifier,
+        )?;
+        match (&self.chip, self.idcode) {
+            (Some(chip), Some(idcode)) => write!(f, " -- {} (IDCODE {:08x})", chip, idcode)?,
+            (Some(chip), None) => write!(f, " -- {}", chip)?,
+            _ => {}
+        }
+        Ok(())
+    }
+}
+
+/// List the connected probes. With `identify`, also attach to each of them briefly to find out which chip is
+/// behind it, which resets nothing but interrupts debugging sessions on them.
+pub fn list(identify: bool) -> Vec<ProbeListing> {
+    Lister::new()
+        .list_all()
+        .into_iter()
+        .map(|probe| {
+            let (chip, idcode) = match identify.then(|| identify_chip(&probe)) {
+                Some(Ok(chip)) => (Some(chip.name), chip.idcode),
+                Some(Err(e)) => {
+                    log::warn!("failed to identify the chip behind probe {}: {:?}", probe.identifier, e);
+                    (None, None)
+                }
+                None => (None, None),
+            };
+            ProbeListing {
+                vendor_id: probe.vendor_id,
+                product_id: probe.product_id,
+                serial_number: probe.serial_number.clone(),
+                probe_type: probe.probe_type().to_string(),
+                identifier: probe.identifier.clone(),
+                chip,
+                idcode,
+            }
+        })
+        .collect()
+}
+
+/// Register a probe-rs target description YAML file, so its chips can be used like the builtin ones.
+pub fn load_chip_description(path: &str) -> Result<()> {
+    let file = std::fs::File::open(path).with_context(|| format!("failed to open chip description {}", path))?;
+    probe_rs::config::add_target_from_yaml(file)
+        .with_context(|| format!("failed to load chip description {}", path))?;
+    log::info!("Loaded chip description {}", path);
+    Ok(())
+}
+
+/// The chip behind a probe.
+pub struct ChipId {
+    /// probe-rs name of the chip.
+    pub name: String,
+    /// IDCODE of its ARM debug port, which tells the designer and the debug port version. `None` on other
+    /// architectures, like RISC-V.
+    pub idcode: Option<u32>,
+}
+
+/// Attach to the chip behind a probe, and find out which chip it is.
+pub fn identify_chip(probe: &DebugProbeInfo) -> Result<ChipId> {
+    const DPIDR: u8 = 0x0;
+
+    let mut sess = probe.open()?.attach(TargetSelector::Auto, Permissions::new())?;
+    let idcode = match sess.get_arm_interface() {
+        Ok(iface) => Some(iface.read_raw_dp_register(DpAddress::Default, DPIDR)?),
+        Err(_) => None,
+    };
+    Ok(ChipId {
+        name: sess.target().name.clone(),
+        idcode,
+    })
+}
+
+pub fn connect(opts: &TargetSpec) -> Result<Session> {
+    if opts.power_reset {
+        let serial = opts.probe.as_ref().and_then(|s| s.serial_number.as_deref());
+        if matches!(opts.power_control, PowerControl::Sysfs) && serial.is_none() && opts.usb_path.is_none() {
+            bail!("power reset requires a serial number or USB port path");
+        };
+
+        log::debug!("probe power reset");
+        let res = set_power(
+            opts.probe.as_ref(),
+            opts.usb_path.as_deref(),
+            &opts.power_control,
+            PowerAction::Cycle,
+            1.0,
+        );
+        if let Err(err) = res {
+            log::warn!("power reset failed for: {}", err);
+        }
+    }
+
+    let end: Instant = Instant::now() + Duration::from_millis(opts.max_settle_time_millis);
+    let probe = loop {
+        if Instant::now() > end {
+            bail!("Probe did not appear after the max settle time.")
+        }
+        std::thread::sleep(SETTLE_REPROBE_INTERVAL);
+        match open_probe(opts) {
+            Ok(probe) => break probe,
+            Err(e) => log::debug!("failed to open probe, will retry: {:?}", e),
+        }
+    };
+
+    // The first attempt uses the probe that just appeared, later ones reopen it.
+    let mut probe = Some(probe);
+    let mut attempt = 1;
+    loop {
+        let res = match probe.take() {
+            Some(probe) => Ok(probe),
+            None => open_probe(opts),
+        }
+        .and_then(|probe| attach_probe(probe, opts));
+        match res {
+            Ok(sess) => return Ok(sess),
+            // Retrying won't make it the right board.
+            Err(e) if attempt < opts.connect_attempts && e.downcast_ref::<RunError>().is_none() => {
+                log::warn!(
+                    "failed to connect (attempt {}/{}), will retry: {:?}",
+                    attempt,
+                    opts.connect_attempts,
+                    e
+                );
+                std::thread::sleep(Duration::from_millis(opts.connect_retry_interval_millis));
+                attempt += 1;
+            }
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+fn attach_probe(probe: Probe, opts: &TargetSpec) -> Result<Session> {
+    let quirk = quirks::for_chip(&opts.chip);
+    let probe = quirk.pre_attach(probe, opts)?;
+
+    log::debug!("opened probe");
+
+    let speeds: Vec<Option<u32>> = match opts.auto_speed {
+        true => {
+            let max = opts.speed.unwrap_or(AUTO_SPEEDS[0]);
+            std::iter::once(max)
+                .chain(AUTO_SPEEDS.into_iter().filter(|s| *s < max))
+                .map(Some)
+                .collect()
+        }
+        false => vec![opts.speed],
+    };
+    let attach = |mut probe: Probe| -> Result<Session> {
+        for (i, &speed) in speeds.iter().enumerate() {
+            match attach_at(probe, opts, speed) {
+                Ok(sess) => return Ok(sess),
+                Err(e) if i + 1 < speeds.len() => {
+                    log::debug!("attaching at {:?} kHz failed, trying slower: {:?}", speed, e);
+                }
+                Err(e) => return Err(e),
+            }
+            probe = open_probe(opts)?;
+        }
+        Err(anyhow!("no probe speed to attach at"))
+    };
+    let mut sess = match attach(probe) {
+        Ok(sess) => sess,
+        Err(e) if opts.auto_recover => {
+            log::warn!("failed to attach, checking if the chip is locked: {:?}", e);
+            if !quirk.recover(open_probe(opts)?)? {
+                return Err(e);
+            }
+            log::warn!("recovered locked chip by erasing it");
+            attach(open_probe(opts)?)?
+        }
+        Err(e) => return Err(e),
+    };
+    log::debug!("started session");
+
+    quirk.post_attach(&mut sess)?;
+
+    if let Some(identity) = &opts.identity {
+        verify_identity(&mut sess, identity).context(RunError::WrongBoard)?;
+    }
+
+    Ok(sess)
+}
+
+fn attach_at(mut probe: Probe, opts: &TargetSpec, speed: Option<u32>) -> Result<Session> {
+    if let Some(speed) = speed {
+        let actual = probe.set_speed(speed)?;
+        log::debug!("probe speed set to {} kHz", actual);
+    }
+
+    let perms = Permissions::new().allow_erase_all();
+
+    let target = probe_rs::config::get_target_by_name(&opts.chip)?;
+
+    let mut sess = if opts.connect_under_reset {
+        probe.attach_under_reset(target, perms)?
+    } else {
+        probe.attach(target, perms)?
+    };
+
+    // A link that's too fast can get through attaching, and fail on the first longer transfer. Other
+    // architectures have no register at a fixed address to read, so their link isn't tested.
+    if opts.auto_speed && sess.architecture() == Architecture::Arm {
+        let mut core = sess.core(0)?;
+        let expected = core.read_word_32(CPUID)?;
+        for _ in 0..LINK_TEST_READS {
+            let cpuid = core.read_word_32(CPUID)?;
+            if cpuid != expected {
+                bail!("read CPUID {:08x}, then {:08x}", expected, cpuid);
+            }
+        }
+    }
+
+    Ok(sess)
+}
+
+/// Erase the whole chip, unlocking it first if it's read-protected and teleprobe knows how to, to recover boards
+/// that bad firmware made impossible to run on.
+pub fn erase(opts: &TargetSpec) -> Result<()> {
+    let opts = TargetSpec {
+        auto_recover: true,
+        ..opts.clone()
+    };
+    let mut sess = connect(&opts)?;
+
+    log::info!("erasing chip...");
+    probe_rs::flashing::erase_all(&mut sess, None)?;
+    log::info!("erasing done!");
+    Ok(())
+}
+
+/// Attach to a chip without disturbing the firmware running on it: no power reset, no reset under
+/// connect, and no chip-specific reset hacks.
+pub fn attach(opts: &TargetSpec) -> Result<Session> {
+    let mut probe = open_probe(opts)?;
+    if let Some(speed) = opts.speed {
+        probe.set_speed(speed)?;
+    }
+
+    let target = probe_rs::config::get_target_by_name(&opts.chip)?;
+    let mut sess = probe.attach(target, Permissions::new())?;
+    log::debug!("attached to running target");
+
+    if let Some(identity) = &opts.identity {
+        verify_identity(&mut sess, identity).context(RunError::WrongBoard)?;
+    }
+
+    Ok(sess)
+}
+
+fn verify_identity(sess: &mut Session, identity: &BoardIdentity) -> Result<()> {
+    let mut actual = vec![0; identity.expected.len()];
+    sess.core(0)?.read_32(identity.address, &mut actual)?;
+    if actual != identity.expected {
+        bail!(
+            "expected {:08x?} at {:#x}, but read {:08x?}",
+            identity.expected,
+            identity.address,
+            actual
+        );
+    }
+    log::debug!("verified board identity");
+    Ok(())
+}
+
+pub(crate) fn open_probe(opts: &TargetSpec) -> Result<Probe> {
+    let lister = Lister::new();
+
+    if let Some(path) = &opts.usb_path {
+        let probe = resolve_usb_path(path, opts.probe.as_ref())?;
+        if probe.shared_serial {
+            return open_cmsisdap_at(path);
+        }
+        return Ok(lister.open(&probe.selector)?);
+    }
+
+    match &opts.probe {
+        None => {
+            let probes = lister.list_all();
+            if probes.is_empty() {
+                bail!("no probe was found")
+            }
+            if probes.len() > 1 {
+                bail!("more than one probe found; use --probe to specify which one to use");
+            }
+
+            Ok(probes[0].open()?)
+        }
+        Some(selector) => Ok(lister.open(selector)?),
+    }
+}
+
+/// A probe found by the USB port it's plugged into.
+pub struct UsbProbe {
+    pub selector: DebugProbeSelector,
+    /// Whether other connected probes have the same VID, PID and serial number, so `selector` doesn't tell them
+    /// apart. Cheap CMSIS-DAP clones often do.
+    pub shared_serial: bool,
+}
+
+/// Find the probe plugged into the USB port at `path`, checking it's the `wanted` one.
+pub fn resolve_usb_path(path: &str, wanted: Option<&DebugProbeSelector>) -> Result<UsbProbe> {
+    let dev = usb_device_at(path)?;
+    let serial = dev.serial_number().unwrap_or_default();
+    let same_serial = |s: &str| s == serial || s == to_hex(serial);
+
+    if let Some(wanted) = wanted {
+        if wanted.vendor_id != dev.vendor_id()
+            || wanted.product_id != dev.product_id()
+            || !wanted.serial_number.as_deref().map_or(true, same_serial)
+        {
+            bail!(
+                "probe at USB port path {} is {:04x}:{:04x}:{}, not the configured one",
+                path,
+                dev.vendor_id(),
+                dev.product_id(),
+                serial
+            );
+        }
+    }
+
+    let probes: Vec<_> = Lister::new()
+        .list_all()
+        .into_iter()
+        .filter(|p| {
+            p.vendor_id == dev.vendor_id()
+                && p.product_id == dev.product_id()
+                && p.serial_number.as_deref().map_or(serial.is_empty(), same_serial)
+        })
+        .collect();
+    let Some(probe) = probes.first() else {
+        bail!("device at USB port path {} is not a supported probe", path)
+    };
+    Ok(UsbProbe {
+        selector: DebugProbeSelector {
+            vendor_id: probe.vendor_id,
+            product_id: probe.product_id,
+            serial_number: probe.serial_number.clone(),
+        },
+        shared_serial: probes.len() > 1,
+    })
+}
+
+/// Open the probe at USB port path `path` by the USB device itself. probe-rs opens probes by selector, which picks
+/// whichever of the probes sharing a serial number it finds first. Only CMSIS-DAP v2 probes can be opened this way.
+fn open_cmsisdap_at(path: &str) -> Result<Probe> {
+    let dev = usb_device_at(path)?;
+    let device = cmsisdap::tools::open_v2_device(&dev).ok_or_else(|| {
+        anyhow!(
+            "probe at USB port path {} shares its serial number with other probes, and isn't a CMSIS-DAP v2 probe, \
+             so it can't be told apart from them",
+            path
+        )
+    })?;
+    let probe = CmsisDap::new_from_device(device)?;
+    log::debug!("opened CMSIS-DAP probe at USB port path {}", path);
+    Ok(Probe::from_specific_probe(Box::new(probe)))
+}
+
+#[cfg(not(target_os = "linux"))]
+pub(crate) fn usb_device_at(_path: &str) -> Result<nusb::DeviceInfo> {
+    bail!("USB port paths are only supported on linux")
+}
+
+/// Find the USB device plugged into a physical port, like `1-1.4.2`.
+#[cfg(target_os = "linux")]
+pub(crate) fn usb_device_at(path: &str) -> Result<nusb::DeviceInfo> {
+    // sysfs names devices by their port path.
+    nusb::list_devices()?
+        .find(|d| d.sysfs_path().file_name().is_some_and(|n| n == path))
+        .ok_or_else(|| anyhow!("no USB device at port path {}", path))
+}
+
+/// What to do with the USB port power of a probe.
+#[derive(Clone, Copy, Debug, clap::ValueEnum, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PowerAction {
+    Off,
+    On,
+    /// Off, then on again after a delay.
+    Cycle,
+}
+
+/// Switch the power of the USB port a probe is plugged into, and of the board if it's powered through the probe.
+///
+/// With sysfs power control, powering a port back on needs its `usb_path`: once it's off, the probe is gone, and
+/// can't be found by serial number.
+pub fn set_power(
+    probe: Option<&DebugProbeSelector>,
+    usb_path: Option<&str>,
+    control: &PowerControl,
+    action: PowerAction,
+    cycle_delay_seconds: f64,
+) -> Result<()> {
+    log::debug!("probe power {:?}", action);
+    match control {
+        PowerControl::Sysfs => {
+            let serial = probe.and_then(|s| s.serial_number.as_deref());
+            port_power(serial, usb_path, action, cycle_delay_seconds)
+        }
+        PowerControl::Uhubctl { hub, port } => {
+            switch_power(action, cycle_delay_seconds, |on| hub::set_port_power(hub, *port, on))
+        }
+        PowerControl::Ykush { serial, port } => switch_power(action, cycle_delay_seconds, |on| {
+            hub::set_ykush_power(serial.as_deref(), *port, on)
+        }),
+        PowerControl::Command { off, on } => switch_power(action, cycle_delay_seconds, |power| {
+            hub::run_power_command(if power { on } else { off })
+        }),
+    }
+}
+
+/// Do `action` with a function that switches the power on or off.
+fn switch_power(action: PowerAction, cycle_delay_seconds: f64, mut set: impl FnMut(bool) -> Result<()>) -> Result<()> {
+    match action {
+        PowerAction::Off => set(false),
+        PowerAction::On => set(true),
+        PowerAction::Cycle => {
+            set(false)?;
+            std::thread::sleep(Duration::from_secs_f64(cycle_delay_seconds));
+            set(true)
+        }
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn port_power(
+    _probe_serial: Option<&str>,
+    _usb_path: Option<&str>,
+    _action: PowerAction,
+    _cycle_delay_seconds: f64,
+) -> Result<()> {
+    anyhow::bail!("sysfs USB power control is only supported on linux, use a hub or command `power_control`")
+}
+
+#[cfg(target_os = "linux")]
+fn port_power(
+    probe_serial: Option<&str>,
+    usb_path: Option<&str>,
+    action: PowerAction,
+    cycle_delay_seconds: f64,
+) -> Result<()> {
+    use std::ffi::CString;
+    use std::fs::{File, OpenOptions};
+    use std::io::Write;
+    use std::os::fd::{AsRawFd, FromRawFd};
+    use std::os::unix::fs::OpenOptionsExt;
+
+    let port_path = match (usb_path, probe_serial) {
+        (Some(path), _) => match usb_device_at(path) {
+            Ok(dev) => dev.sysfs_path().join("port"),
+            // Powered off, the device is gone but its port isn't.
+            Err(_) => hub_port_path(path)?,
+        },
+        (None, Some(probe_serial)) => nusb::list_devices()?
+            .find(|d| {
+                let serial = d.serial_number().unwrap_or_default();
+
+                serial == probe_serial || to_hex(serial) == probe_serial
+            })
+            .ok_or_else(|| anyhow!("device with serial {} not found", probe_serial))?
+            .sysfs_path()
+            .join("port"),
+        (None, None) => bail!("USB power control requires a serial number or USB port path"),
+    };
+
+    // The USB device goes away when we disable power to it.
+    // If we open the port dir we can keep a "handle" to it even if the device goes away, so
+    // we can write `disable=0` with openat() to reenable it.
+    let port = OpenOptions::new()
+        .read(true)
+        .custom_flags(libc::O_DIRECTORY | libc::O_CLOEXEC)
+        .open(&port_path)?;
+
+    let disable_path = CString::new("disable").unwrap();
+    let set_disabled = |disabled: bool| -> Result<()> {
+        let disable_fd =
+            unsafe { libc::openat(port.as_raw_fd(), disable_path.as_ptr(), libc::O_WRONLY | libc::O_TRUNC) };
+        if disable_fd < 0 {
+            return Err(std::io::Error::last_os_error().into());
+        }
+        unsafe { File::from_raw_fd(disable_fd) }.write_all(if disabled { b"1" } else { b"0" })?;
+        Ok(())
+    };
+
+    switch_power(action, cycle_delay_seconds, |on| set_disabled(!on))
+}
+
+/// The port directory of the hub a device at `path`, like `1-1.4.2`, is plugged into.
+#[cfg(target_os = "linux")]
+fn hub_port_path(path: &str) -> Result<std::path::PathBuf> {
+    let devices = std::path::PathBuf::from("/sys/bus/usb/devices");
+    let port_dir = match path.rsplit_once('.') {
+        Some((hub, port)) => devices
+            .join(hub)
+            .join(format!("{}:1.0", hub))
+            .join(format!("{}-port{}", hub, port)),
+        None => {
+            let (bus, port) = path
+                .split_once('-')
+                .ok_or_else(|| anyhow!("invalid USB port path {}", path))?;
+            devices
+                .join(format!("usb{}", bus))
+                .join(format!("{}-0:1.0", bus))
+                .join(format!("usb{}-port{}", bus, port))
+        }
+    };
+    if !port_dir.exists() {
+        bail!("no USB port at port path {}", path);
+    }
+    Ok(port_dir)
+}
+
+fn to_hex(s: &str) -> String {
+    use std::fmt::Write;
+    s.as_bytes().iter().fold(String::new(), |mut s, b| {
+        let _ = write!(s, "{b:02X}"); // Writing a String never fails
+        s
+    })
+}
@@ -0,0 +1,270 @@
+//! Workarounds for chips that need more than probe-rs does to connect and run reliably, keyed by chip family,
+//! so `probe::connect` and the run engine don't grow a special case for each of them.
+
+use std::time::{Duration, Instant};
+
+use anyhow::{bail, Result};
+use probe_rs::architecture::arm::{ApAddress, DapAccess, DpAddress};
+use probe_rs::probe::Probe;
+use probe_rs::{Core, MemoryInterface, Permissions, Session};
+
+use crate::probe::{open_probe, TargetSpec};
+use crate::run::Options;
+
+/// Hooks called at each step of connecting to a chip and starting a run on it. They all do nothing by default.
+pub trait Quirk: Sync {
+    /// Before attaching, with the probe open. Returns the probe to attach with, which can be a reopened one.
+    fn pre_attach(&self, probe: Probe, _opts: &TargetSpec) -> Result<Probe> {
+        Ok(probe)
+    }
+
+    /// Right after attaching.
+    fn post_attach(&self, _sess: &mut Session) -> Result<()> {
+        Ok(())
+    }
+
+    /// After flashing, with the core halted on reset, before the firmware starts.
+    fn pre_run(&self, _core: &mut Core, _opts: &Options) -> Result<()> {
+        Ok(())
+    }
+
+    /// After attaching failed, unlock the chip if it's read-protected, by erasing it. Returns false if it isn't
+    /// locked, or the quirk doesn't know how to unlock it.
+    fn recover(&self, _probe: Probe) -> Result<bool> {
+        Ok(false)
+    }
+}
+
+struct Family {
+    /// Start of the probe-rs target names of the family, lowercase.
+    prefix: &'static str,
+    quirk: &'static dyn Quirk,
+}
+
+const FAMILIES: &[Family] = &[
+    Family {
+        prefix: "rp2040",
+        quirk: &Rp2040Reset,
+    },
+    Family {
+        prefix: "nrf52",
+        quirk: &Nrf52Approtect,
+    },
+    Family {
+        prefix: "nrf91",
+        quirk: &Nrf91Approtect,
+    },
+    Family {
+        prefix: "stm32f42",
+        quirk: &Stm32DualBank::F4,
+    },
+    Family {
+        prefix: "stm32f43",
+        quirk: &Stm32DualBank::F4,
+    },
+    Family {
+        prefix: "stm32f469",
+        quirk: &Stm32DualBank::F4,
+    },
+    Family {
+        prefix: "stm32f479",
+        quirk: &Stm32DualBank::F4,
+    },
+    Family {
+        prefix: "stm32l4",
+        quirk: &Stm32DualBank::L4_G4,
+    },
+    Family {
+        prefix: "stm32g4",
+        quirk: &Stm32DualBank::L4_G4,
+    },
+];
+
+/// The quirks of `chip`, or none if it has no known problems.
+pub fn for_chip(chip: &str) -> &'static dyn Quirk {
+    let chip = chip.to_ascii_lowercase();
+    FAMILIES
+        .iter()
+        .find(|f| chip.starts_with(f.prefix))
+        .map(|f| f.quirk)
+        .unwrap_or(&NoQuirks)
+}
+
+struct NoQuirks;
+
+impl Quirk for NoQuirks {}
+
+/// probe-rs only resets core 0, and the other one can be left running old firmware, so reset both cores,
+/// and the SIO between them, with the watchdog before attaching.
+///
+/// Ideally this would be a custom sequence in probe-rs: https://github.com/probe-rs/probe-rs/pull/1603
+struct Rp2040Reset;
+
+impl Quirk for Rp2040Reset {
+    fn pre_attach(&self, mut probe: Probe, opts: &TargetSpec) -> Result<Probe> {
+        const PSM_WDSEL: u64 = 0x40010008;
+
+        const PSM_SEL_SIO: u32 = 1 << 14;
+        const PSM_SEL_PROC0: u32 = 1 << 15;
+        const PSM_SEL_PROC1: u32 = 1 << 16;
+
+        const WATCHDOG_CTRL: u64 = 0x40058000;
+        const WATCHDOG_CTRL_TRIGGER: u32 = 1 << 31;
+        const WATCHDOG_CTRL_ENABLE: u32 = 1 << 30;
+
+        if let Some(speed) = opts.speed {
+            probe.set_speed(speed)?;
+        }
+
+        let perms = Permissions::new().allow_erase_all();
+        let target = probe_rs::config::get_target_by_name(&opts.chip)?;
+        let mut sess = probe.attach(target, perms)?;
+        let mut core = sess.core(0)?;
+
+        log::debug!("rp2040: resetting SIO and processors");
+        core.write_word_32(PSM_WDSEL, PSM_SEL_SIO | PSM_SEL_PROC0 | PSM_SEL_PROC1)?;
+        core.write_word_32(WATCHDOG_CTRL, WATCHDOG_CTRL_ENABLE)?;
+        core.write_word_32(WATCHDOG_CTRL, WATCHDOG_CTRL_ENABLE | WATCHDOG_CTRL_TRIGGER)?;
+        log::debug!("rp2040: reset done, reattaching");
+
+        drop(core);
+        drop(sess);
+        open_probe(opts)
+    }
+}
+
+/// Newer nRF52 revisions turn access port protection back on at every reset, unless UICR says not to. probe-rs
+/// recovers locked chips by erasing them, so without this, every run after the first would erase the chip before
+/// flashing it. Older revisions read the same UICR value as protection enabled, so it's only written on the
+/// revisions known to have the new protection.
+struct Nrf52Approtect;
+
+impl Nrf52Approtect {
+    const FICR_INFO_PART: u64 = 0x1000_0100;
+    const FICR_INFO_VARIANT: u64 = 0x1000_0104;
+    const UICR_APPROTECT: u64 = 0x1000_1208;
+    const UICR_APPROTECT_HW_DISABLED: u32 = 0x5A;
+    const UICR_ERASED: u32 = 0xFFFF_FFFF;
+    const NVMC_READY: u64 = 0x4001_E400;
+    const NVMC_CONFIG: u64 = 0x4001_E504;
+    const NVMC_CONFIG_WEN: u32 = 1;
+
+    /// Parts, and the first build code (the third letter of their variant, like `F` in `AAF0`) of the revision
+    /// with the new protection.
+    const NEW_APPROTECT: &'static [(u32, u8)] = &[(0x52832, b'G'), (0x52833, b'B'), (0x52840, b'F')];
+
+    fn has_new_approtect(core: &mut Core) -> Result<bool> {
+        let part = core.read_word_32(Self::FICR_INFO_PART)?;
+        let build = (core.read_word_32(Self::FICR_INFO_VARIANT)? >> 8) as u8;
+        Ok(Self::NEW_APPROTECT.iter().any(|&(p, b)| p == part && build >= b))
+    }
+}
+
+impl Quirk for Nrf52Approtect {
+    fn pre_run(&self, core: &mut Core, opts: &Options) -> Result<()> {
+        if !opts.disable_approtect || !Self::has_new_approtect(core)? {
+            return Ok(());
+        }
+        match core.read_word_32(Self::UICR_APPROTECT)? {
+            Self::UICR_APPROTECT_HW_DISABLED => return Ok(()),
+            Self::UICR_ERASED => {}
+            // Flash bits can only be cleared, so only erasing UICR can change it from here.
+            value => {
+                log::warn!(
+                    "nrf52: UICR.APPROTECT is {:#x}, erase the chip to disable access port protection",
+                    value
+                );
+                return Ok(());
+            }
+        }
+
+        log::info!("nrf52: disabling access port protection in UICR");
+        core.write_word_32(Self::NVMC_CONFIG, Self::NVMC_CONFIG_WEN)?;
+        core.write_word_32(Self::UICR_APPROTECT, Self::UICR_APPROTECT_HW_DISABLED)?;
+        let deadline = Instant::now() + Duration::from_millis(100);
+        while core.read_word_32(Self::NVMC_READY)? == 0 {
+            if Instant::now() > deadline {
+                core.write_word_32(Self::NVMC_CONFIG, 0)?;
+                bail!("nrf52: timed out writing UICR.APPROTECT");
+            }
+        }
+        core.write_word_32(Self::NVMC_CONFIG, 0)?;
+        Ok(())
+    }
+
+    fn recover(&self, probe: Probe) -> Result<bool> {
+        ctrl_ap_erase_all(probe, 1)
+    }
+}
+
+struct Nrf91Approtect;
+
+impl Quirk for Nrf91Approtect {
+    fn recover(&self, probe: Probe) -> Result<bool> {
+        ctrl_ap_erase_all(probe, 4)
+    }
+}
+
+/// Erase a locked nRF chip through its CTRL-AP, the access port that stays reachable while the others are
+/// protected, and reset it.
+fn ctrl_ap_erase_all(probe: Probe, ap: u8) -> Result<bool> {
+    const RESET: u8 = 0x000;
+    const ERASEALL: u8 = 0x004;
+    const ERASEALLSTATUS: u8 = 0x008;
+    const APPROTECTSTATUS: u8 = 0x00C;
+
+    let ap = ApAddress {
+        dp: DpAddress::Default,
+        ap,
+    };
+    let mut iface = probe
+        .try_into_arm_interface()
+        .map_err(|(_, e)| e)?
+        .initialize_unspecified()
+        .map_err(|(_, e)| e)?;
+    if iface.read_raw_ap_register(ap, APPROTECTSTATUS)? != 0 {
+        log::debug!("nrf: access port protection is off, nothing to recover");
+        return Ok(false);
+    }
+
+    log::debug!("nrf: erasing the chip through CTRL-AP");
+    iface.write_raw_ap_register(ap, ERASEALL, 1)?;
+    let deadline = Instant::now() + Duration::from_secs(15);
+    while iface.read_raw_ap_register(ap, ERASEALLSTATUS)? != 0 {
+        if Instant::now() > deadline {
+            bail!("timed out erasing the chip through CTRL-AP");
+        }
+        std::thread::sleep(Duration::from_millis(10));
+    }
+    iface.write_raw_ap_register(ap, RESET, 1)?;
+    iface.write_raw_ap_register(ap, RESET, 0)?;
+    Ok(true)
+}
+
+/// Dual-bank STM32s with the BFB2 option bit set boot from bank 2 when it holds valid firmware, so the image
+/// flashed to bank 1 may never run, and the run times out with no logs.
+struct Stm32DualBank {
+    /// Option bytes register, and the BFB2 bit in it.
+    bfb2: (u64, u32),
+}
+
+impl Stm32DualBank {
+    /// FLASH_OPTCR.
+    const F4: Self = Self {
+        bfb2: (0x4002_3C14, 1 << 4),
+    };
+    /// FLASH_OPTR.
+    const L4_G4: Self = Self {
+        bfb2: (0x4002_2020, 1 << 20),
+    };
+}
+
+impl Quirk for Stm32DualBank {
+    fn post_attach(&self, sess: &mut Session) -> Result<()> {
+        let (addr, bit) = self.bfb2;
+        if sess.core(0)?.read_word_32(addr)? & bit != 0 {
+            log::warn!("BFB2 option bit is set, the chip boots from bank 2 and the flashed firmware may not run");
+        }
+        Ok(())
+    }
+}
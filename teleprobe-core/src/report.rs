@@ -0,0 +1,353 @@
+use std::fmt;
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+/// Why a run failed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RunError {
+    /// The probe couldn't be opened, or couldn't attach to the target.
+    #[serde(alias = "probe")]
+    ProbeNotFound,
+    /// Flashing the firmware failed.
+    #[serde(alias = "flash")]
+    FlashError,
+    /// The board doesn't have the identity configured for the target, so it's not the right one.
+    WrongBoard,
+    /// The firmware's RTT control block couldn't be found.
+    RttAttachFailed,
+    /// The firmware stayed blocked on a full RTT buffer, with the `fail` overflow strategy.
+    RttOverflow,
+    /// The firmware didn't finish before the deadline.
+    Timeout,
+    /// The firmware crashed.
+    HardFault,
+    /// The chip reset while the firmware was running, like from a watchdog.
+    UnexpectedReset,
+    /// Any other failure of the firmware or the run.
+    #[serde(alias = "run")]
+    TestFailed,
+    /// The job was cancelled, or dropped before it could run.
+    Cancelled,
+}
+
+impl RunError {
+    /// HTTP status of a synchronous run that failed with this error.
+    pub fn http_status(&self) -> u16 {
+        match self {
+            RunError::ProbeNotFound | RunError::FlashError | RunError::WrongBoard | RunError::RttAttachFailed => 502,
+            RunError::Timeout => 504,
+            RunError::RttOverflow | RunError::HardFault | RunError::UnexpectedReset | RunError::TestFailed => 422,
+            RunError::Cancelled => 409,
+        }
+    }
+}
+
+impl fmt::Display for RunError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let s = match self {
+            RunError::ProbeNotFound => "probe not found",
+            RunError::FlashError => "flashing failed",
+            RunError::WrongBoard => "wrong board",
+            RunError::RttAttachFailed => "RTT attach failed",
+            RunError::RttOverflow => "device blocked on RTT",
+            RunError::Timeout => "deadline exceeded",
+            RunError::HardFault => "firmware crashed",
+            RunError::UnexpectedReset => "device reset unexpectedly",
+            RunError::TestFailed => "test failed",
+            RunError::Cancelled => "cancelled",
+        };
+        f.write_str(s)
+    }
+}
+
+impl std::error::Error for RunError {}
+
+/// Wall-clock time spent in each phase of a run, in seconds. Phases that weren't reached are unset.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct PhaseTimings {
+    pub probe_attach: Option<f64>,
+    pub flash: Option<f64>,
+    pub rtt_attach: Option<f64>,
+    pub execution: Option<f64>,
+}
+
+impl PhaseTimings {
+    pub fn phases(&self) -> [(&'static str, Option<f64>); 4] {
+        [
+            ("probe_attach", self.probe_attach),
+            ("flash", self.flash),
+            ("rtt_attach", self.rtt_attach),
+            ("execution", self.execution),
+        ]
+    }
+}
+
+/// Outcome of a run.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct RunReport {
+    /// How the firmware stopped. Unset if it didn't get to run, or the run was aborted by an error.
+    pub exit: Option<ExitReason>,
+    /// Why the run failed, or `None` if it succeeded.
+    pub failure: Option<RunError>,
+    /// The full error of a failed run, with its causes.
+    pub error: Option<String>,
+    /// State of the core, if it stopped in an exception handler or was halted at the deadline.
+    pub exception: Option<ExceptionInfo>,
+    /// Call stack where the core stopped, innermost frame first.
+    #[serde(default)]
+    pub backtrace: Vec<BacktraceFrame>,
+    /// The firmware's panic, if a failed run panicked.
+    #[serde(default)]
+    pub panic: Option<PanicInfo>,
+    /// Flash and RAM used by the firmware, for ELF runs.
+    #[serde(default)]
+    pub memory: Option<MemoryUsage>,
+    /// Benchmarks the firmware recorded with `teleprobe_meta::benchmark`.
+    #[serde(default)]
+    pub benchmarks: Vec<Benchmark>,
+    /// Decoded defmt frames the firmware logged.
+    #[serde(default)]
+    pub logs: Vec<LogFrame>,
+    pub timings: PhaseTimings,
+    /// ELF core file of a crashed or timed out run, if requested with `Options::core_dump`.
+    #[serde(skip)]
+    pub core_dump: Option<Vec<u8>>,
+    /// LLVM `.profraw` coverage profile, if requested with `Options::coverage` and the firmware is instrumented.
+    #[serde(skip)]
+    pub coverage: Option<Vec<u8>>,
+    /// The undecoded defmt stream, if requested with `Options::raw_defmt`. It decodes with the ELF's defmt table,
+    /// like `defmt-print` does.
+    #[serde(skip)]
+    pub defmt_raw: Option<Vec<u8>>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ExitReason {
+    /// The firmware halted by itself, like with a `bkpt` instruction.
+    Halted,
+    HardFault,
+    /// The chip reset, and was halted before running any of the firmware again.
+    Reset,
+    /// The deadline passed before the firmware halted.
+    Timeout,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ExceptionInfo {
+    /// Exception number from XPSR: 0 in thread mode, 3 for HardFault.
+    pub number: u32,
+    /// R0-R12, SP, LR, PC and XPSR.
+    pub registers: Vec<u32>,
+    /// HardFault status register, for HardFaults.
+    pub hfsr: Option<u32>,
+    /// Configurable fault status register, if the HardFault was escalated from another fault.
+    pub cfsr: Option<u32>,
+    /// Address of the bus fault, if it's known.
+    pub bfar: Option<u32>,
+    /// The stack pointer was at the end of the stack or past it, or the fault was on the stack guard, so the crash
+    /// was probably a stack overflow.
+    #[serde(default)]
+    pub stack_overflow: bool,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BacktraceFrame {
+    pub function: String,
+    pub pc: u64,
+    pub inlined: bool,
+    /// Path of the source file.
+    pub file: Option<String>,
+    pub line: Option<u64>,
+    pub column: Option<u64>,
+}
+
+/// A benchmark result recorded by the firmware.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Benchmark {
+    pub name: String,
+    /// Cycles it took, as counted by the DWT cycle counter.
+    pub cycles: u32,
+    /// Most cycles it may take, or the run fails.
+    pub max_cycles: Option<u32>,
+}
+
+/// Flash and RAM used by firmware.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MemoryUsage {
+    /// Bytes of flash taken by the image, including the initial values of `.data`.
+    pub flash: u64,
+    /// Size of the flash regions the image is in.
+    pub flash_size: u64,
+    /// Bytes of RAM taken by sections, like `.data` and `.bss`. Doesn't include the stack and heap.
+    pub ram: u64,
+    /// Size of the RAM regions the sections are in.
+    pub ram_size: u64,
+    /// The sections the image takes memory with.
+    pub sections: Vec<SectionUsage>,
+    /// Most stack the firmware used, if measured with `Options::stack_usage`.
+    pub stack_peak: Option<u64>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SectionUsage {
+    pub name: String,
+    pub address: u64,
+    pub size: u64,
+}
+
+/// A firmware panic, found in its logs or its panic-persist buffer.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PanicInfo {
+    pub message: String,
+    /// Path of the source file.
+    pub file: Option<String>,
+    pub line: Option<u32>,
+    pub column: Option<u32>,
+}
+
+impl PanicInfo {
+    /// Parse a panic as formatted by `core::panic::PanicInfo`, either `panicked at src/main.rs:5:9:\nmessage`
+    /// or, before Rust 1.73, `panicked at 'message', src/main.rs:5:9`. `None` if `s` isn't a panic.
+    pub(crate) fn parse(s: &str) -> Option<Self> {
+        const PREFIX: &str = "panicked at ";
+        let rest = &s[s.find(PREFIX)? + PREFIX.len()..];
+
+        let (message, location) = match rest.strip_prefix('\'') {
+            Some(quoted) => match quoted.rsplit_once("', ") {
+                Some((message, location)) => (message, Some(location)),
+                None => (quoted.trim_end_matches('\''), None),
+            },
+            None => match rest.split_once(":\n") {
+                Some((location, message)) => (message, Some(location)),
+                None => (rest, None),
+            },
+        };
+
+        let mut info = Self {
+            message: message.trim().to_string(),
+            file: None,
+            line: None,
+            column: None,
+        };
+        if let Some(location) = location {
+            let mut parts = location.trim().rsplitn(3, ':');
+            let (column, line, file) = (parts.next(), parts.next(), parts.next());
+            if let (Some(column), Some(line), Some(file)) = (column, line, file) {
+                info.file = Some(file.to_string());
+                info.line = line.parse().ok();
+                info.column = column.parse().ok();
+            }
+        }
+        Some(info)
+    }
+}
+
+impl fmt::Display for PanicInfo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "panicked at '{}'", self.message)?;
+        if let Some(file) = &self.file {
+            write!(f, ", {}", file)?;
+            if let Some(line) = self.line {
+                write!(f, ":{}", line)?;
+                if let Some(column) = self.column {
+                    write!(f, ":{}", column)?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LogFrame {
+    pub level: Option<String>,
+    /// The frame's timestamp, as formatted by the firmware's defmt timestamp.
+    pub timestamp: Option<String>,
+    /// When the host decoded the frame, to line device logs up with what happened around the board, like power
+    /// cycles and stimulus. Frames come in batches, so it's only as precise as the RTT polling.
+    #[serde(default)]
+    pub host_time: Option<DateTime<Utc>>,
+    pub message: String,
+    pub file: Option<String>,
+    pub line: Option<u32>,
+    pub module: Option<String>,
+}
+
+impl RunReport {
+    pub fn succeeded(&self) -> bool {
+        self.failure.is_none()
+    }
+
+    /// Record a failure, classified by the `RunError` in the error's context, or as `default` if there's none.
+    pub(crate) fn failed(mut self, e: anyhow::Error, default: RunError) -> Self {
+        self.failure = Some(e.downcast_ref::<RunError>().copied().unwrap_or(default));
+        self.error = Some(format!("{:?}", e));
+        self
+    }
+
+    /// Record the result of running the firmware.
+    pub(crate) fn finish(mut self, res: anyhow::Result<()>) -> Self {
+        match res {
+            Ok(()) => {
+                self.exit = Some(ExitReason::Halted);
+                self
+            }
+            Err(e) => {
+                self.exit = match e.downcast_ref::<RunError>() {
+                    Some(RunError::HardFault) => Some(ExitReason::HardFault),
+                    Some(RunError::UnexpectedReset) => Some(ExitReason::Reset),
+                    Some(RunError::Timeout) => Some(ExitReason::Timeout),
+                    _ => None,
+                };
+                self.failed(e, RunError::TestFailed)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_panic() {
+        let info = PanicInfo::parse("panicked at src/main.rs:5:9:\nboom").unwrap();
+        assert_eq!(
+            info,
+            PanicInfo {
+                message: "boom".to_string(),
+                file: Some("src/main.rs".to_string()),
+                line: Some(5),
+                column: Some(9),
+            }
+        );
+        assert_eq!(PanicInfo::parse("panicked at 'boom', src/main.rs:5:9").unwrap(), info);
+        assert_eq!(
+            PanicInfo::parse("ERROR 0.000123 panicked at src/main.rs:5:9:\nboom").unwrap(),
+            info
+        );
+    }
+
+    #[test]
+    fn parse_without_location() {
+        let info = PanicInfo::parse("panicked at 'boom'").unwrap();
+        assert_eq!(info.message, "boom");
+        assert_eq!((info.file, info.line, info.column), (None, None, None));
+    }
+
+    #[test]
+    fn parse_not_a_panic() {
+        assert_eq!(PanicInfo::parse("hello world"), None);
+    }
+
+    #[test]
+    fn display_panic() {
+        let info = PanicInfo::parse("panicked at src/main.rs:5:9:\nboom").unwrap();
+        assert_eq!(info.to_string(), "panicked at 'boom', src/main.rs:5:9");
+        let info = PanicInfo::parse("panicked at 'boom'").unwrap();
+        assert_eq!(info.to_string(), "panicked at 'boom'");
+    }
+}
@@ -0,0 +1,1742 @@
+use std::convert::TryInto;
+use std::fmt::{self, Write};
+use std::io::Cursor;
+use std::num::ParseIntError;
+use std::ops::Range;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{mpsc, Arc};
+use std::time::{Duration, Instant};
+
+use anyhow::{anyhow, bail, Context as _};
+use bytes::Bytes;
+use chrono::{SecondsFormat, Utc};
+use log::{info, warn};
+use object::elf::PT_LOAD;
+use object::read::elf::{ElfFile32, ProgramHeader as _};
+use object::read::{File as ElfFile, Object as _, ObjectSection as _};
+use object::{Endianness, ObjectSymbol};
+use probe_rs::config::MemoryRegion;
+use probe_rs::debug::{DebugInfo, DebugRegisters};
+use probe_rs::flashing::{BinOptions, DownloadOptions, FlashLoader, FlashProgress};
+use probe_rs::rtt::{DownChannel, Rtt, ScanRegion, UpChannel};
+use probe_rs::{Core, MemoryInterface, RegisterId, Session};
+use serde::{Deserialize, Serialize};
+
+use crate::decode::{self, Decoded, Decoder};
+use crate::meta::ElfMetadata;
+use crate::{
+    bench, coredump, coverage, mismatch, quirks, stack, usage, watchdog, BacktraceFrame, ExceptionInfo, LogFrame,
+    PanicInfo, PhaseTimings, RunError, RunReport,
+};
+
+pub const R0: RegisterId = RegisterId(0);
+pub const LR: RegisterId = RegisterId(14);
+pub const PC: RegisterId = RegisterId(15);
+pub const SP: RegisterId = RegisterId(13);
+pub const XPSR: RegisterId = RegisterId(16);
+
+const THUMB_BIT: u32 = 1;
+const TIMEOUT: Duration = Duration::from_secs(1);
+
+const POLL_SLEEP_MILLIS: u64 = 100;
+/// Most of the raw defmt stream kept, with `raw_defmt`. The rest of it is dropped.
+const MAX_RAW_DEFMT: usize = 16 * 1024 * 1024;
+
+/// Size of the RTT control block ID and channel counts.
+const RTT_HEADER_SIZE: u32 = 24;
+/// Offset of up channel 0's write offset, followed by its read offset.
+const RTT_UP0_WRITE_OFFSET: u32 = RTT_HEADER_SIZE + 12;
+/// Offset of up channel 0's flags, which hold the channel mode.
+const RTT_UP0_FLAGS_OFFSET: u32 = RTT_HEADER_SIZE + 20;
+const RTT_MODE_NO_BLOCK_SKIP: u32 = 0;
+const RTT_MODE_BLOCK_IF_FULL: u32 = 2;
+/// Written over the RTT control block ID before starting the firmware, to tell when it's set up again.
+const RTT_CLOBBERED: u32 = 0xdeadc0de;
+/// Written by panic-persist at `_panic_dump_start`, followed by the length of the message and the message.
+const PANIC_PERSIST_MAGIC: u32 = 0x0FACADE0;
+
+/// Debug Exception and Monitor Control Register.
+const DEMCR: u64 = 0xE000_EDFC;
+/// Halt on entry to the HardFault handler.
+const DEMCR_VC_HARDERR: u32 = 1 << 10;
+/// Halt on reset.
+const DEMCR_VC_CORERESET: u32 = 1 << 0;
+/// Debug Fault Status Register.
+const DFSR: u64 = 0xE000_ED30;
+/// The core halted on a vector catch.
+const DFSR_VCATCH: u32 = 1 << 3;
+/// `bkpt #0x54` in `teleprobe_meta::_teleprobe_exit`, with the exit code in R0.
+const EXIT_BKPT: u16 = 0xbe54;
+
+/// Debug Halting Control and Status Register.
+const DHCSR: u64 = 0xE000_EDF0;
+/// The core was reset since DHCSR was last read.
+const DHCSR_S_RESET_ST: u32 = 1 << 25;
+
+pub struct Options {
+    pub do_flash: bool,
+    pub deadline: Option<Instant>,
+    pub flash: FlashOptions,
+    /// Images to flash along with the one being run, like a bootloader or a softdevice.
+    pub preload: Vec<Image>,
+    /// Let the firmware push the deadline back. Ignored if there's no deadline.
+    pub keepalive: Option<Keepalive>,
+    /// Signaled once the firmware starts running.
+    pub started: Option<mpsc::Sender<()>>,
+    pub rtt_overflow: RttOverflowOptions,
+    /// Capture a core dump of ELF runs that crash or time out.
+    pub core_dump: bool,
+    /// Stops the run with [`RunError::Cancelled`] once set.
+    pub cancel: Option<Arc<AtomicBool>>,
+    /// Attach to the firmware that's already running instead of flashing and starting it. Nothing is
+    /// reset, and `.teleprobe.stdin` isn't sent.
+    pub attach: bool,
+    /// Whether the firmware runs from RAM or flash. Overrides `.teleprobe.run_from`.
+    pub run_from: RunFrom,
+    /// Stop the chip's watchdogs from counting while the core is halted, if teleprobe knows them, so they
+    /// don't reset the chip while it's being flashed and set up.
+    pub freeze_watchdogs: bool,
+    /// Turn off access port protection in UICR on nRF52 revisions that turn it back on at every reset, so later
+    /// runs can attach without erasing the chip. Only written while UICR is erased.
+    pub disable_approtect: bool,
+    /// Measure the most stack the firmware used, for the report. The firmware must paint its stack, with
+    /// cortex-m-rt's `paint-stack` feature.
+    pub stack_usage: bool,
+    /// Guard the end of the firmware's stack with an MPU region, so overflows fault right away. Needs an
+    /// ARMv7-M MPU, and firmware that doesn't set up the MPU itself.
+    pub stack_guard: bool,
+    /// Read the coverage counters of firmware built with `-C instrument-coverage` after the run, into an LLVM
+    /// `.profraw` profile.
+    pub coverage: bool,
+    /// Only log the device log frames it lets through. The report keeps all of them.
+    pub log_filter: Option<LogFilter>,
+    /// Prefix logged device log frames with the host's wall-clock time, before the device timestamp.
+    pub host_timestamps: bool,
+    /// Keep the raw defmt stream the firmware sent, for decoding it again later.
+    pub raw_defmt: bool,
+}
+
+/// Deadline extension for firmware that signals progress by bumping `_TELEPROBE_KEEPALIVE`.
+pub struct Keepalive {
+    /// Every keepalive moves the deadline to this far from now.
+    pub extension: Duration,
+    /// Keepalives can't move the deadline past this.
+    pub max_deadline: Instant,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Self {
+            do_flash: true,
+            deadline: None,
+            flash: FlashOptions::default(),
+            preload: Vec::new(),
+            keepalive: None,
+            started: None,
+            rtt_overflow: RttOverflowOptions::default(),
+            core_dump: false,
+            cancel: None,
+            attach: false,
+            run_from: RunFrom::default(),
+            freeze_watchdogs: false,
+            disable_approtect: false,
+            stack_usage: false,
+            stack_guard: false,
+            coverage: false,
+            log_filter: None,
+            host_timestamps: false,
+            raw_defmt: false,
+        }
+    }
+}
+
+/// Levels of device log frames to log, by the module that logged them, from `RUST_LOG`-style directives like
+/// `warn,net=trace`, matched by env_logger like `RUST_LOG` is. Frames no directive matches aren't logged, and
+/// frames without a level count as `info`.
+#[derive(Clone)]
+pub struct LogFilter {
+    /// The directives, to pass the filter on.
+    spec: String,
+    filter: Arc<env_logger::filter::Filter>,
+}
+
+impl LogFilter {
+    pub fn enabled(&self, module: Option<&str>, level: log::Level) -> bool {
+        let metadata = log::Metadata::builder()
+            .target(module.unwrap_or_default())
+            .level(level)
+            .build();
+        self.filter.enabled(&metadata)
+    }
+}
+
+impl FromStr for LogFilter {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        // env_logger only warns about bad levels on stderr, and leaves the directive out.
+        for directive in s.split(',').map(str::trim).filter(|d| !d.is_empty()) {
+            if let Some((_, level)) = directive.split_once('=') {
+                // An empty level logs everything from the module.
+                if !level.trim().is_empty() && level.trim().parse::<log::LevelFilter>().is_err() {
+                    bail!("invalid level `{}` in `{}`", level.trim(), directive);
+                }
+            }
+        }
+        Ok(LogFilter {
+            spec: s.to_string(),
+            filter: Arc::new(env_logger::filter::Builder::new().parse(s).build()),
+        })
+    }
+}
+
+impl fmt::Debug for LogFilter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("LogFilter").field(&self.spec).finish()
+    }
+}
+
+/// Back to the directives, to pass the filter on.
+impl fmt::Display for LogFilter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.spec)
+    }
+}
+
+/// Whether firmware runs from RAM, loaded there and started at its reset vector, or is flashed and
+/// started by a reset.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "snake_case")]
+pub enum RunFrom {
+    /// From the memory region the vector table is linked at. Wrong on chips that alias flash at
+    /// addresses probe-rs maps as RAM, like flash remapped to 0x0.
+    #[default]
+    Auto,
+    Ram,
+    Flash,
+}
+
+/// What to do when the firmware writes logs faster than they're read, and stays blocked on
+/// the full RTT buffer.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "snake_case")]
+pub enum RttOverflowStrategy {
+    /// Read the whole buffer at once, and stop sleeping between polls.
+    #[default]
+    PollFaster,
+    /// Switch the channel to non-blocking mode. The firmware stops stalling, but drops logs.
+    NoBlock,
+    /// Fail the run.
+    Fail,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct RttOverflowOptions {
+    pub strategy: RttOverflowStrategy,
+    /// How long the firmware has to stay blocked before `strategy` kicks in.
+    pub threshold_millis: u64,
+}
+
+impl Default for RttOverflowOptions {
+    fn default() -> Self {
+        Self {
+            strategy: RttOverflowStrategy::default(),
+            threshold_millis: 500,
+        }
+    }
+}
+
+/// Format of a firmware image.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "snake_case")]
+pub enum ImageFormat {
+    #[default]
+    Elf,
+    /// Raw binary, loaded at a given base address.
+    Bin,
+    /// Intel HEX.
+    Hex,
+}
+
+/// A firmware image, and where to load it if its format doesn't say.
+#[derive(Clone)]
+pub struct Image {
+    pub data: Bytes,
+    pub format: ImageFormat,
+    /// Load address of `bin` images. Defaults to the start of flash.
+    pub base_address: Option<u64>,
+}
+
+/// Knobs passed through to probe-rs when flashing.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct FlashOptions {
+    /// Verify the flash contents after writing them.
+    pub verify: bool,
+    /// Preserve the bytes of partially written sectors, instead of leaving them erased.
+    #[serde(alias = "restore_unwritten")]
+    pub keep_unwritten_bytes: bool,
+    /// Erase the whole chip before flashing, instead of only the sectors being written.
+    pub chip_erase: bool,
+    /// Don't erase before writing. Only safe if the flash is known to be erased already.
+    pub skip_erase: bool,
+    /// Log flashing progress.
+    pub progress: bool,
+    /// Read back the flash first, and skip flashing if it already contains the program.
+    pub skip_unchanged: bool,
+}
+
+impl Default for FlashOptions {
+    fn default() -> Self {
+        Self {
+            verify: true,
+            keep_unwritten_bytes: true,
+            chip_erase: false,
+            skip_erase: false,
+            progress: false,
+            skip_unchanged: false,
+        }
+    }
+}
+
+/// Flash and run an ELF. Progress and device output are logged as well as reported.
+pub fn run(sess: &mut Session, elf_bytes: &[u8], opts: Options) -> RunReport {
+    let mut report = RunReport::default();
+    let res = run_elf(sess, elf_bytes, opts, &mut report);
+    report.finish(res)
+}
+
+/// Run an image in any of the supported formats.
+pub fn run_image(sess: &mut Session, image: &Image, opts: Options) -> RunReport {
+    match image.format {
+        ImageFormat::Elf => run(sess, &image.data, opts),
+        _ => {
+            let mut report = RunReport::default();
+            let res = run_raw(sess, image, opts, &mut report.timings);
+            report.finish(res)
+        }
+    }
+}
+
+fn run_elf(sess: &mut Session, elf_bytes: &[u8], opts: Options, report: &mut RunReport) -> anyhow::Result<()> {
+    match usage::measure(elf_bytes, &sess.target().memory_map) {
+        Ok(memory) => {
+            info!(
+                "memory usage: flash {}/{} bytes, RAM {}/{} bytes",
+                memory.flash, memory.flash_size, memory.ram, memory.ram_size
+            );
+            report.memory = Some(memory);
+        }
+        Err(e) => warn!("failed to measure memory usage: {:?}", e),
+    }
+
+    let started = opts.started.clone();
+    let mut r = Runner::new(sess, elf_bytes, opts, &mut report.timings)?;
+    if let Some(started) = started {
+        let _ = started.send(());
+    }
+    let start = Instant::now();
+    let mut res = r.run(sess);
+    report.timings.execution = Some(start.elapsed().as_secs_f64());
+
+    let crashed = matches!(
+        res.as_ref().err().and_then(|e| e.downcast_ref::<RunError>()),
+        Some(RunError::HardFault | RunError::Timeout)
+    );
+    if r.opts.core_dump && crashed {
+        if let Some(exception) = &r.exception {
+            match coredump::capture(sess, &exception.registers) {
+                Ok(dump) => {
+                    info!("captured core dump of {} bytes", dump.len());
+                    report.core_dump = Some(dump);
+                }
+                Err(e) => warn!("failed to capture core dump: {:?}", e),
+            }
+        }
+    }
+
+    if let (true, Some(memory)) = (r.opts.stack_usage, &mut report.memory) {
+        match &r.stack {
+            Some(stack) => {
+                let res = sess.core(0).map_err(Into::into);
+                match res.and_then(|mut core| stack::peak(&mut core, stack)) {
+                    Ok(peak) => {
+                        info!("stack usage: {} bytes", peak);
+                        memory.stack_peak = Some(peak);
+                    }
+                    Err(e) => warn!("failed to measure stack usage: {:?}", e),
+                }
+            }
+            None => warn!("can't measure stack usage, `_stack_start` or `_stack_end` is missing"),
+        }
+    }
+
+    if let (Some(exception), Some(stack)) = (&mut r.exception, &r.stack) {
+        let sp = exception.registers[SP.0 as usize];
+        let overflowed = sess
+            .core(0)
+            .map_err(Into::into)
+            .and_then(|mut core| stack::overflowed(&mut core, stack, sp));
+        match overflowed {
+            Ok(true) => {
+                warn!("probable stack overflow, SP: {:08x}", sp);
+                exception.stack_overflow = true;
+                res = res.context("probable stack overflow");
+            }
+            Ok(false) => {}
+            Err(e) => warn!("failed to check for a stack overflow: {:?}", e),
+        }
+    }
+
+    if let Some(slots) = r.benchmarks {
+        let benchmarks = sess
+            .core(0)
+            .map_err(Into::into)
+            .and_then(|mut core| bench::read(&mut core, slots));
+        match benchmarks {
+            Ok(benchmarks) => report.benchmarks = benchmarks,
+            Err(e) => warn!("failed to read benchmarks: {:?}", e),
+        }
+        for b in &report.benchmarks {
+            match b.max_cycles {
+                Some(max) if b.cycles > max => {
+                    warn!("benchmark {}: {} cycles, over its maximum of {}", b.name, b.cycles, max);
+                    if res.is_ok() {
+                        res = Err(anyhow!(
+                            "benchmark {} took {} cycles, over its maximum of {}",
+                            b.name,
+                            b.cycles,
+                            max
+                        ));
+                    }
+                }
+                _ => info!("benchmark {}: {} cycles", b.name, b.cycles),
+            }
+        }
+    }
+
+    if r.opts.coverage {
+        let profile = sess
+            .core(0)
+            .map_err(Into::into)
+            .and_then(|mut core| coverage::capture(&mut core, elf_bytes));
+        match profile {
+            Ok(Some(profile)) => {
+                info!("captured coverage profile of {} bytes", profile.len());
+                report.coverage = Some(profile);
+            }
+            Ok(None) => warn!("no coverage counters, build the firmware with `-C instrument-coverage`"),
+            Err(e) => warn!("failed to capture coverage: {:?}", e),
+        }
+    }
+
+    if res.is_err() {
+        report.panic = r.find_panic(sess);
+        if let Some(panic) = &report.panic {
+            info!("firmware {}", panic);
+        }
+    }
+
+    report.exception = r.exception.take();
+    report.backtrace = std::mem::take(&mut r.backtrace);
+    report.logs = std::mem::take(&mut r.logs);
+    report.defmt_raw = r.opts.raw_defmt.then(|| std::mem::take(&mut r.defmt_raw));
+    res
+}
+
+/// Attach to the firmware running on the board, and log everything it writes to RTT up channel 0 as text,
+/// one `device` log line per line of output. For firmware that doesn't use defmt, or whose ELF isn't at hand.
+///
+/// Runs until the deadline or cancellation, if any, or forever.
+pub fn monitor_text(sess: &mut Session, opts: &Options) -> anyhow::Result<()> {
+    let memory_map = sess.target().memory_map.clone();
+    let mut core = sess.core(0)?;
+    let mut rtt = Rtt::attach_region(&mut core, &memory_map, &ScanRegion::Ram).context(RunError::RttAttachFailed)?;
+    let channel = rtt
+        .up_channels()
+        .take(0)
+        .ok_or_else(|| anyhow!("RTT up channel 0 not found"))?;
+
+    let mut buf = vec![0; 1024];
+    let mut line = Vec::new();
+    loop {
+        if opts.cancel.as_ref().is_some_and(|c| c.load(Ordering::Relaxed))
+            || opts.deadline.is_some_and(|d| Instant::now() > d)
+        {
+            return Ok(());
+        }
+
+        let n = channel.read(&mut core, &mut buf)?;
+        if n == 0 {
+            std::thread::sleep(Duration::from_millis(POLL_SLEEP_MILLIS));
+            continue;
+        }
+        for &b in &buf[..n] {
+            if b == b'\n' {
+                let text = String::from_utf8_lossy(&line);
+                log::info!(target: "device", "{}", text.trim_end_matches('\r'));
+                line.clear();
+            } else {
+                line.push(b);
+            }
+        }
+    }
+}
+
+/// Flash a non-ELF image and run it until the core halts.
+///
+/// Without an ELF there are no symbols, so there's no RTT output and no backtrace. The run
+/// succeeds if the firmware halts (for example with a `bkpt` instruction) without crashing.
+fn run_raw(sess: &mut Session, image: &Image, opts: Options, timings: &mut PhaseTimings) -> anyhow::Result<()> {
+    for (i, _) in sess.list_cores() {
+        if i != 0 {
+            sess.core(i)?.reset()?;
+        }
+    }
+
+    let chip = sess.target().name.clone();
+    if !opts.do_flash {
+        log::info!("skipped flashing");
+    } else {
+        let start = Instant::now();
+        reset_and_halt(&mut sess.core(0)?, &chip, &opts)?;
+
+        log::info!("flashing program...");
+        let mut loader = sess.target().flash_loader();
+        for image in opts.preload.iter().chain([image]) {
+            load_image(&mut loader, image)?;
+        }
+        loader
+            .commit(sess, download_options(&opts.flash))
+            .context(RunError::FlashError)?;
+        log::info!("flashing done!");
+        timings.flash = Some(start.elapsed().as_secs_f64());
+    }
+
+    let mut core = sess.core(0)?;
+    reset_and_halt(&mut core, &chip, &opts)?;
+    quirks::for_chip(&chip).pre_run(&mut core, &opts)?;
+
+    catch_faults(&mut core)?;
+
+    log::debug!("starting device");
+    core.run()?;
+    if let Some(started) = &opts.started {
+        let _ = started.send(());
+    }
+    let start = Instant::now();
+    let res = wait_for_halt(&mut core, opts.deadline);
+    timings.execution = Some(start.elapsed().as_secs_f64());
+    res
+}
+
+/// Reset the chip and halt the core, with the watchdogs frozen if requested.
+fn reset_and_halt(core: &mut Core, chip: &str, opts: &Options) -> anyhow::Result<()> {
+    core.reset_and_halt(TIMEOUT)?;
+    if opts.freeze_watchdogs && !watchdog::freeze(core, chip)? {
+        warn!("watchdogs of {} are unknown, they can't be frozen", chip);
+    }
+    // Clear the sticky reset bit set by this reset, for `check_no_reset`.
+    core.read_word_32(DHCSR)?;
+    Ok(())
+}
+
+/// Fail if the chip reset since [`reset_and_halt`] and [`catch_faults`], like from a watchdog firing while the
+/// core was halted. Polling the core clears the sticky reset bit, so resets while it ran are found by vector
+/// catch instead.
+fn check_no_reset(core: &mut Core, chip: &str) -> anyhow::Result<()> {
+    let reset = core.read_word_32(DHCSR)? & DHCSR_S_RESET_ST != 0;
+    if !reset && !(core.core_halted()? && halted_on_reset(core)?) {
+        return Ok(());
+    }
+    let cause = match watchdog::caused_reset(core, chip)? {
+        Some(true) => ", by a watchdog. Try freezing watchdogs while halted (`freeze_watchdogs`)",
+        Some(false) => ", not by a watchdog",
+        None => "",
+    };
+    warn!("Device reset while being set up{}", cause);
+    Err(anyhow!("device reset while being set up{}", cause).context(RunError::UnexpectedReset))
+}
+
+/// Halt the core on entry to HardFault and on reset, with vector catch. Unlike a breakpoint on the
+/// handler, this doesn't depend on where the firmware put its handler, and uses no breakpoint units.
+fn catch_faults(core: &mut Core) -> anyhow::Result<()> {
+    let demcr = core.read_word_32(DEMCR)?;
+    core.write_word_32(DEMCR, demcr | DEMCR_VC_HARDERR | DEMCR_VC_CORERESET)?;
+    // Clear vector catches from before, like the one resetting the core. The bit is write-one-to-clear.
+    core.write_word_32(DFSR, DFSR_VCATCH)?;
+    Ok(())
+}
+
+/// Whether the halted core stopped on a reset caught by [`catch_faults`], like a watchdog's or a
+/// `SYSRESETREQ`.
+fn halted_on_reset(core: &mut Core) -> anyhow::Result<bool> {
+    let dfsr = core.read_word_32(DFSR)?;
+    let xpsr: u32 = core.read_core_reg(XPSR)?;
+    Ok(dfsr & DFSR_VCATCH != 0 && xpsr & 0xff == 0)
+}
+
+/// Wait for the firmware to set up the RTT control block at `rtt_addr` again after it was clobbered, which
+/// happens before `main`. `false` if the core halted before that, like on a crash.
+fn wait_for_rtt_init(core: &mut Core, rtt_addr: u32) -> anyhow::Result<bool> {
+    let start = Instant::now();
+    while core.read_word_32(rtt_addr as _)? == RTT_CLOBBERED {
+        if core.core_halted()? {
+            return Ok(false);
+        }
+        if start.elapsed() > Duration::from_secs(5) {
+            bail!("RTT control block was not initialized within 5s of starting the firmware");
+        }
+        std::thread::sleep(Duration::from_millis(1));
+    }
+    Ok(true)
+}
+
+fn wait_for_halt(core: &mut Core, deadline: Option<Instant>) -> anyhow::Result<()> {
+    while !core.core_halted()? {
+        if let Some(deadline) = deadline {
+            if Instant::now() > deadline {
+                core.halt(TIMEOUT)?;
+                let pc: u32 = core.read_core_reg(PC)?;
+                warn!("Deadline exceeded! PC: {:08x}", pc);
+                bail!(RunError::Timeout)
+            }
+        }
+        std::thread::sleep(Duration::from_millis(POLL_SLEEP_MILLIS));
+    }
+
+    let pc: u32 = core.read_core_reg(PC)?;
+    let xpsr: u32 = core.read_core_reg(XPSR)?;
+    let lr: u32 = core.read_core_reg(LR)?;
+    info!("halted, PC: {:08x} LR: {:08x} XPSR: {:08x}", pc, lr, xpsr);
+    if xpsr & 0xff == 3 {
+        info!("Hard Fault!");
+        bail!(RunError::HardFault);
+    }
+    if halted_on_reset(core)? {
+        warn!("Device reset unexpectedly!");
+        bail!(RunError::UnexpectedReset);
+    }
+    check_exit_code(core)
+}
+
+/// If the halted core stopped in `teleprobe_meta::exit` or `exit_fail`, fail on a nonzero exit code.
+fn check_exit_code(core: &mut Core) -> anyhow::Result<()> {
+    let pc: u32 = core.read_core_reg(PC)?;
+    let mut insn = [0u16];
+    core.read_16(pc as _, &mut insn)?;
+    if insn[0] != EXIT_BKPT {
+        return Ok(());
+    }
+
+    let code: u32 = core.read_core_reg(R0)?;
+    info!("firmware exited with code {}", code);
+    if code != 0 {
+        bail!("firmware exited with code {}", code);
+    }
+    Ok(())
+}
+
+/// Check an ELF for problems that would make the run fail, without touching the hardware.
+///
+/// If `chip` is given, also check that the ELF is built for it.
+pub fn check_elf(elf_bytes: &[u8], chip: Option<&str>) -> anyhow::Result<()> {
+    let elf = ElfFile::parse(elf_bytes)?;
+
+    let (rtt, _) = get_rtt_main_from(&elf)?;
+    if rtt.is_none() {
+        bail!("`_SEGGER_RTT` symbol not found, is the firmware using `defmt-rtt`?");
+    }
+    let format = ElfMetadata::parse(elf_bytes)?.log_format.unwrap_or_default();
+    if format == decode::Format::Defmt && mismatch::parse_table(elf_bytes)?.is_none() {
+        bail!("defmt table not found, is the firmware using `defmt`?");
+    }
+    let vector_table = elf
+        .section_by_name(".vector_table")
+        .ok_or_else(|| anyhow!("`.vector_table` section is missing"))?
+        .address();
+
+    let Some(chip) = chip else { return Ok(()) };
+    let target = probe_rs::config::get_target_by_name(chip)?;
+
+    let arch = match target.architecture() {
+        probe_rs::Architecture::Arm => Some(object::Architecture::Arm),
+        probe_rs::Architecture::Riscv => Some(object::Architecture::Riscv32),
+        _ => None,
+    };
+    if arch.is_some_and(|arch| arch != elf.architecture()) {
+        bail!(
+            "ELF is built for {:?}, but {} is {:?}",
+            elf.architecture(),
+            chip,
+            target.architecture()
+        );
+    }
+
+    let in_memory = target.memory_map.iter().any(|r| match r {
+        MemoryRegion::Ram(r) => r.range.contains(&vector_table),
+        MemoryRegion::Generic(r) => r.range.contains(&vector_table),
+        MemoryRegion::Nvm(r) => r.range.contains(&vector_table),
+    });
+    if !in_memory {
+        bail!(
+            "vector table at {:#010x} is not in any memory region of {}, is the ELF built for another chip?",
+            vector_table,
+            chip
+        );
+    }
+
+    Ok(())
+}
+
+/// Parse a memory address, in hex with a `0x` prefix or in decimal.
+pub fn parse_address(s: &str) -> Result<u64, ParseIntError> {
+    match s.strip_prefix("0x").or_else(|| s.strip_prefix("0X")) {
+        Some(hex) => u64::from_str_radix(hex, 16),
+        None => s.parse(),
+    }
+}
+
+fn load_image(loader: &mut FlashLoader, image: &Image) -> anyhow::Result<()> {
+    let mut data = Cursor::new(&image.data[..]);
+    match image.format {
+        ImageFormat::Elf => loader.load_elf_data(&mut data)?,
+        ImageFormat::Bin => {
+            let bin = BinOptions {
+                base_address: image.base_address,
+                skip: 0,
+            };
+            loader.load_bin_data(&mut data, bin)?
+        }
+        ImageFormat::Hex => loader.load_hex_data(&mut data)?,
+    }
+    Ok(())
+}
+
+fn download_options(flash: &FlashOptions) -> DownloadOptions {
+    let mut dopts = DownloadOptions::new();
+    dopts.keep_unwritten_bytes = flash.keep_unwritten_bytes;
+    dopts.verify = flash.verify;
+    dopts.do_chip_erase = flash.chip_erase;
+    dopts.skip_erase = flash.skip_erase;
+    if flash.progress {
+        dopts.progress = Some(FlashProgress::new(|event| log::info!("flash progress: {:?}", event)));
+    }
+    dopts
+}
+
+struct Runner {
+    opts: Options,
+
+    rtt_addr: u32,
+    main_addr: u32,
+    vector_table: VectorTable,
+
+    /// `None` if the firmware halted before setting up RTT, so there's nothing to read.
+    defmt: Option<UpChannel>,
+    /// Holds a whole RTT buffer, for reading it at once with `fast_poll`.
+    read_buf: Vec<u8>,
+    /// Bytes the last poll read. The RTT buffer can't have been full if it was empty.
+    last_read: usize,
+    decoder: Box<dyn Decoder>,
+    /// Frames decoded so far.
+    decoded_frames: usize,
+    /// Frames that failed to decode since the last one that didn't.
+    decode_failures: u32,
+
+    di: DebugInfo,
+
+    /// Remaining `.teleprobe.stdin` data to write to the firmware.
+    stdin: Vec<u8>,
+    stdin_channel: Option<DownChannel>,
+
+    /// Address and last seen value of the `_TELEPROBE_KEEPALIVE` counter.
+    keepalive: Option<(u32, u32)>,
+    /// Start and end of panic-persist's buffer, if the firmware uses it.
+    panic_dump: Option<(u32, u32)>,
+    /// The firmware's stack, if the ELF has its symbols.
+    stack: Option<Range<u64>>,
+    /// Where the firmware records benchmarks, if it uses `teleprobe_meta::benchmark`.
+    benchmarks: Option<bench::Slots>,
+
+    /// When the RTT buffer was first seen full, if it's been full ever since.
+    rtt_blocked_since: Option<Instant>,
+    /// Total time the firmware was seen blocked on RTT.
+    rtt_blocked_total: Duration,
+    /// Whether the overflow strategy was applied already.
+    rtt_overflow_handled: bool,
+    fast_poll: bool,
+
+    /// Collected for the report.
+    exception: Option<ExceptionInfo>,
+    backtrace: Vec<BacktraceFrame>,
+    logs: Vec<LogFrame>,
+    /// Everything read from the defmt channel, with `raw_defmt`, up to `MAX_RAW_DEFMT`.
+    defmt_raw: Vec<u8>,
+}
+
+impl Runner {
+    fn new(sess: &mut Session, elf_bytes: &[u8], opts: Options, timings: &mut PhaseTimings) -> anyhow::Result<Self> {
+        let elf = ElfFile::parse(elf_bytes)?;
+
+        let di = DebugInfo::from_raw(elf_bytes)?;
+
+        let meta = ElfMetadata::parse(elf_bytes)?;
+        let decoder = decode::for_elf(elf_bytes, meta.log_format.unwrap_or_default())?;
+        log::debug!("decoding logs as {}", decoder.name());
+
+        // sections used in cortex-m-rt
+        // NOTE we won't load `.uninit` so it is not included here
+        // NOTE we don't load `.bss` because the app (cortex-m-rt) will zero it
+        let candidates = [".vector_table", ".text", ".rodata", ".data"];
+
+        let mut vector_table = None;
+        let mut loaded_sections: Vec<(&str, u32, &[u8])> = Vec::new();
+        for sect in elf.sections() {
+            if let Ok(name) = sect.name() {
+                let size = sect.size();
+                // skip empty sections
+                if candidates.contains(&name) && size != 0 {
+                    let start = sect.address();
+                    if size % 4 != 0 || start % 4 != 0 {
+                        // we could support unaligned sections but let's not do that now
+                        bail!("section `{}` is not 4-byte aligned", name);
+                    }
+
+                    let start = start.try_into()?;
+                    let data = sect.data()?;
+                    loaded_sections.push((name, start, data));
+
+                    let data = data
+                        .chunks_exact(4)
+                        .map(|chunk| u32::from_le_bytes(chunk.try_into().unwrap()))
+                        .collect::<Vec<_>>();
+
+                    if name == ".vector_table" {
+                        vector_table = Some(VectorTable {
+                            location: start,
+                            // Initial stack pointer
+                            initial_sp: data[0],
+                            reset: data[1],
+                        });
+                    }
+                }
+            }
+        }
+
+        let vector_table = vector_table.ok_or_else(|| anyhow!("`.vector_table` section is missing"))?;
+        log::debug!("vector table: {:x?}", vector_table);
+
+        // reset ALL cores other than the main one.
+        // This is needed for rp2040 core1.
+        if !opts.attach {
+            for (i, _) in sess.list_cores() {
+                if i != 0 {
+                    sess.core(i)?.reset()?;
+                }
+            }
+        }
+
+        let run_from = match opts.run_from {
+            RunFrom::Auto => meta.run_from.unwrap_or_default(),
+            run_from => run_from,
+        };
+        let chip = sess.target().name.clone();
+        let mut run_from_ram = match run_from {
+            RunFrom::Auto => None,
+            RunFrom::Ram => Some(true),
+            RunFrom::Flash => Some(false),
+        };
+        if run_from == RunFrom::Auto {
+            for r in &sess.target().memory_map {
+                match r {
+                    MemoryRegion::Ram(r) => {
+                        if r.range.contains(&(vector_table.location as u64)) {
+                            run_from_ram = Some(true);
+                        }
+                    }
+                    MemoryRegion::Generic(r) => {
+                        if r.range.contains(&(vector_table.location as u64)) {
+                            run_from_ram = Some(true);
+                        }
+                    }
+                    MemoryRegion::Nvm(r) => {
+                        if r.range.contains(&(vector_table.location as u64)) {
+                            run_from_ram = Some(false);
+                        }
+                    }
+                }
+            }
+        }
+
+        let run_from_ram = run_from_ram.ok_or_else(|| {
+            anyhow!(
+                "vector table at {:#010x} is in no memory region of the chip, set `run_from` to `ram` or `flash`",
+                vector_table.location
+            )
+        })?;
+        info!("run_from_ram: {:?} ({:?})", run_from_ram, run_from);
+
+        let (rtt, main_addr) = get_rtt_main_from(&elf)?;
+        let symbol = |name: &str| {
+            elf.symbols()
+                .find(|s| s.name().ok() == Some(name))
+                .map(|s| s.address() as u32)
+        };
+        let keepalive_addr = symbol("_TELEPROBE_KEEPALIVE");
+        let panic_dump = symbol("_panic_dump_start").zip(symbol("_panic_dump_end"));
+        let stack = stack::range(elf_bytes)?;
+        let benchmarks = symbol("_TELEPROBE_BENCHMARKS")
+            .zip(symbol("_TELEPROBE_BENCHMARK_COUNT"))
+            .map(|(slots, count)| bench::Slots { slots, count });
+        let (rtt_addr, rtt_size) = rtt.ok_or_else(|| anyhow!("RTT is missing"))?;
+
+        // When running from RAM the RTT control block is initialized by the load itself,
+        // so it must be part of the image, and nothing may clobber it afterwards.
+        let rtt_image = if run_from_ram {
+            Some(check_ram_layout(&loaded_sections, &vector_table, rtt_addr, rtt_size)?)
+        } else {
+            None
+        };
+
+        if opts.attach {
+            log::info!("attaching to the running firmware");
+            let mut core = sess.core(0)?;
+            catch_faults(&mut core)?;
+        } else if !opts.do_flash {
+            log::info!("skipped flashing");
+        } else {
+            let start = Instant::now();
+            reset_and_halt(&mut sess.core(0)?, &chip, &opts)?;
+
+            // The readback only checks the ELF, so it can't tell if preloaded images changed.
+            let can_skip = opts.flash.skip_unchanged && opts.preload.is_empty() && !run_from_ram;
+            if can_skip && flash_matches_elf(sess, elf_bytes)? {
+                log::info!("flash contents unchanged, skipped flashing");
+            } else {
+                log::info!("flashing program...");
+                let mut loader = sess.target().flash_loader();
+                for image in &opts.preload {
+                    load_image(&mut loader, image)?;
+                }
+                loader.load_elf_data(&mut Cursor::new(&elf_bytes))?;
+                loader
+                    .commit(sess, download_options(&opts.flash))
+                    .context(RunError::FlashError)?;
+
+                //flashing::download_file_with_options(sess, &opts.elf, Format::Elf, dopts)?;
+                log::info!("flashing done!");
+            }
+            timings.flash = Some(start.elapsed().as_secs_f64());
+        }
+
+        // Whether the firmware set up its RTT control block. Without breakpoints, it may crash before.
+        let mut rtt_ready = true;
+        if !opts.attach {
+            let mut core = sess.core(0)?;
+
+            if let (Some(rtt_image), true) = (rtt_image, opts.do_flash) {
+                // Some chips' loading sequences use the same RAM we're loading into.
+                // Check the control block survived, and write it last if it didn't.
+                let mut current = vec![0; rtt_image.len()];
+                core.read_8(rtt_addr as _, &mut current)?;
+                if current != rtt_image {
+                    warn!(
+                        "RTT control block at {:#010x} was clobbered while loading, rewriting it",
+                        rtt_addr
+                    );
+                    core.write_8(rtt_addr as _, rtt_image)?;
+                }
+            }
+
+            if run_from_ram {
+                // On STM32H7 due to RAM ECC (I think?) it's possible that the
+                // last written word doesn't "stick" on reset because it's "half written"
+                // https://www.st.com/resource/en/application_note/dm00623136-error-correction-code-ecc-management-for-internal-memories-protection-on-stm32h7-series-stmicroelectronics.pdf
+                //
+                // Do one dummy write to ensure the last word sticks.
+                let data = core.read_word_32(vector_table.location as _)?;
+                core.write_word_32(vector_table.location as _, data)?;
+            }
+
+            reset_and_halt(&mut core, &chip, &opts)?;
+            quirks::for_chip(&chip).pre_run(&mut core, &opts)?;
+
+            log::debug!("starting device");
+            let has_breakpoints = core.available_breakpoint_units()? != 0;
+            catch_faults(&mut core)?;
+
+            // panic-persist's buffer is neither loaded nor zeroed, so it survives reflashing, and a panic left by
+            // earlier firmware would be reported for this run.
+            if let Some((start, _)) = panic_dump {
+                core.write_word_32(start as _, 0)?;
+            }
+
+            if run_from_ram {
+                core.write_core_reg(PC, vector_table.reset)?;
+                core.write_core_reg(SP, vector_table.initial_sp)?;
+
+                // Write VTOR
+                // NOTE this DOES NOT play nice with the softdevice.
+                core.write_word_32(0xE000ED08, vector_table.location)?;
+                let got_vtor = core.read_word_32(0xE000ED08)?;
+                if got_vtor != vector_table.location {
+                    return Err(anyhow!(
+                        "failed to set VTOR, got {:08x} want {:08x}",
+                        got_vtor,
+                        vector_table.location
+                    )
+                    .context(RunError::FlashError));
+                }
+
+                // Hacks to get the softdevice to think we're doing a cold boot here.
+                //core.write_32(0x2000_005c, &[0]).unwrap();
+                //core.write_32(0x2000_0000, &[0x1000, vector_table.location]).unwrap();
+            }
+
+            if !run_from_ram {
+                // Corrupt the rtt control block so that it's setup fresh again
+                // Only do this when running from flash, because when running from RAM the
+                // "fake-flashing to RAM" is what initializes it.
+                core.write_word_32(rtt_addr as _, RTT_CLOBBERED)?;
+
+                // RTT control block is initialized pre-main. Run until main before
+                // changing to BlockIfFull.
+                if has_breakpoints {
+                    core.set_hw_breakpoint(main_addr as _)?;
+                    core.run()?;
+                    core.wait_for_core_halted(Duration::from_secs(5))?;
+                    core.clear_hw_breakpoint(main_addr as _)?;
+                } else {
+                    // Without breakpoints, keep it running and switch as soon as the control block is
+                    // set up. Logs that overflow the buffer before that are lost.
+                    core.run()?;
+                    rtt_ready = wait_for_rtt_init(&mut core, rtt_addr)?;
+                }
+            }
+
+            check_no_reset(&mut core, &chip)?;
+            if benchmarks.is_some() {
+                bench::enable_cycle_counter(&mut core)?;
+            }
+            if opts.stack_guard {
+                match &stack {
+                    Some(stack) if stack::install_guard(&mut core, stack)? => {}
+                    Some(_) => warn!("no ARMv7-M MPU, can't guard the stack"),
+                    None => warn!("can't guard the stack, `_stack_start` or `_stack_end` is missing"),
+                }
+            }
+            if rtt_ready {
+                core.write_word_32((rtt_addr + RTT_UP0_FLAGS_OFFSET) as _, RTT_MODE_BLOCK_IF_FULL)?;
+            }
+
+            // Without breakpoints, firmware running from flash was left running, or halted on a crash.
+            if run_from_ram || has_breakpoints {
+                core.run()?;
+            }
+        }
+
+        // A core that halted before, on a crash, is left for `run` to report.
+        let (defmt, stdin_channel) = if rtt_ready {
+            let start = Instant::now();
+            let (defmt, stdin_channel) =
+                setup_logging_channel(rtt_addr, sess, decoder.channel()).context(RunError::RttAttachFailed)?;
+            timings.rtt_attach = Some(start.elapsed().as_secs_f64());
+            (Some(defmt), stdin_channel)
+        } else {
+            warn!("firmware halted before setting up RTT");
+            (None, None)
+        };
+
+        let stdin = match elf.section_by_name(".teleprobe.stdin") {
+            Some(section) if !opts.attach => section.data()?.to_vec(),
+            _ => Vec::new(),
+        };
+        if !stdin.is_empty() && rtt_ready {
+            if stdin_channel.is_none() {
+                bail!("ELF has `.teleprobe.stdin` data, but the firmware has no RTT down channel to receive it");
+            }
+            log::debug!("writing {} bytes of stdin data", stdin.len());
+        }
+
+        Ok(Self {
+            opts,
+            rtt_addr,
+            main_addr,
+            vector_table,
+            read_buf: vec![0; defmt.as_ref().map_or(0, |c| c.buffer_size())],
+            last_read: 0,
+            defmt,
+            decoder,
+            decoded_frames: 0,
+            decode_failures: 0,
+            di,
+            stdin,
+            stdin_channel,
+            keepalive: keepalive_addr.map(|addr| (addr, 0)),
+            panic_dump,
+            stack,
+            benchmarks,
+            rtt_blocked_since: None,
+            rtt_blocked_total: Duration::ZERO,
+            rtt_overflow_handled: false,
+            fast_poll: false,
+            exception: None,
+            backtrace: Vec::new(),
+            logs: Vec::new(),
+            defmt_raw: Vec::new(),
+        })
+    }
+
+    fn poll(&mut self, sess: &mut Session) -> anyhow::Result<()> {
+        if let Some(down) = &mut self.stdin_channel {
+            if !self.stdin.is_empty() {
+                let n = down.write(&mut sess.core(0)?, &self.stdin)?;
+                self.stdin.drain(..n);
+            }
+        }
+
+        let Some(defmt) = &mut self.defmt else {
+            return Ok(());
+        };
+        let len = if self.fast_poll {
+            self.read_buf.len()
+        } else {
+            self.read_buf.len().min(1024)
+        };
+        let read_buf = &mut self.read_buf[..len];
+        self.last_read = defmt.read(&mut sess.core(0).unwrap(), read_buf)?;
+        match self.last_read {
+            0 => {
+                // Sleep to reduce CPU usage when defmt didn't return any data.
+                if !self.fast_poll {
+                    std::thread::sleep(Duration::from_millis(POLL_SLEEP_MILLIS));
+                }
+                return Ok(());
+            }
+            n => {
+                if self.opts.raw_defmt && self.defmt_raw.len() < MAX_RAW_DEFMT {
+                    let keep = n.min(MAX_RAW_DEFMT - self.defmt_raw.len());
+                    self.defmt_raw.extend_from_slice(&read_buf[..keep]);
+                    if keep < n {
+                        warn!("raw defmt stream reached {} bytes, not keeping the rest", MAX_RAW_DEFMT);
+                    }
+                }
+                self.decoder.received(&read_buf[..n])
+            }
+        }
+
+        loop {
+            match self.decoder.decode() {
+                Decoded::Frame(mut frame) => {
+                    self.decoded_frames += 1;
+                    self.decode_failures = 0;
+
+                    let host_time = Utc::now();
+                    let mut timestamp = String::new();
+                    if self.opts.host_timestamps {
+                        timestamp = format!("{} ", host_time.to_rfc3339_opts(SecondsFormat::Micros, true));
+                    }
+                    if let Some(ts) = &frame.timestamp {
+                        write!(timestamp, "{} ", ts).unwrap();
+                    }
+
+                    let level = match frame.level.as_deref() {
+                        Some(level) => match level {
+                            "trace" => log::Level::Trace,
+                            "debug" => log::Level::Debug,
+                            "info" => log::Level::Info,
+                            "warn" => log::Level::Warn,
+                            "error" => log::Level::Error,
+                            _ => log::Level::Error,
+                        },
+                        None => log::Level::Info,
+                    };
+                    let enabled = match &self.opts.log_filter {
+                        Some(filter) => filter.enabled(frame.module.as_deref(), level),
+                        None => true,
+                    };
+                    if enabled {
+                        log::logger().log(
+                            &log::Record::builder()
+                                .level(level)
+                                .file(frame.file.as_deref())
+                                .line(frame.line)
+                                .target("device")
+                                .args(format_args!("{}{}", timestamp, frame.message))
+                                .build(),
+                        );
+                    }
+
+                    frame.host_time = Some(host_time);
+                    self.logs.push(frame);
+                }
+                Decoded::Incomplete => break,
+                Decoded::Malformed { recoverable } => {
+                    self.decode_failures += 1;
+                    // Data that never decoded isn't corrupted, it's for another table.
+                    let mismatched = self.decoded_frames == 0
+                        && (!recoverable || self.decode_failures >= mismatch::MAX_FAILURES_BEFORE_FIRST_FRAME);
+                    if mismatched {
+                        return Err(mismatch::decode_error(self.opts.attach));
+                    }
+                    match recoverable {
+                        // if recovery is impossible, abort
+                        false => bail!("failed to decode {} data", self.decoder.name()),
+                        // if recovery is possible, skip the current frame and continue with new data
+                        true => log::warn!("failed to decode {} data", self.decoder.name()),
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// The firmware's panic: from panic-persist's buffer, where it survives the reset panic-persist does, or
+    /// the last panic the firmware logged, like panic-probe and `defmt::panic!` do.
+    fn find_panic(&self, sess: &mut Session) -> Option<PanicInfo> {
+        if let Some((start, end)) = self.panic_dump {
+            let res = sess.core(0).map_err(Into::into);
+            match res.and_then(|mut core| read_panic_dump(&mut core, start, end)) {
+                Ok(Some(message)) => {
+                    return Some(PanicInfo::parse(&message).unwrap_or(PanicInfo {
+                        message,
+                        file: None,
+                        line: None,
+                        column: None,
+                    }))
+                }
+                Ok(None) => {}
+                Err(e) => warn!("failed to read the panic-persist buffer: {:?}", e),
+            }
+        }
+
+        self.logs.iter().rev().find_map(|frame| {
+            let mut panic = PanicInfo::parse(&frame.message)?;
+            // `defmt::panic!` has no location in the message, but in the frame.
+            if panic.file.is_none() {
+                panic.file = frame.file.clone();
+                panic.line = frame.line;
+            }
+            Some(panic)
+        })
+    }
+
+    /// Push the deadline back if the firmware bumped its keepalive counter since the last check.
+    fn check_keepalive(&mut self, sess: &mut Session) -> anyhow::Result<()> {
+        let (Some(keepalive), Some((addr, last))) = (&self.opts.keepalive, &mut self.keepalive) else {
+            return Ok(());
+        };
+
+        let value = sess.core(0)?.read_word_32(*addr as _)?;
+        if value == *last {
+            return Ok(());
+        }
+        *last = value;
+
+        let extended = (Instant::now() + keepalive.extension).min(keepalive.max_deadline);
+        if let Some(deadline) = &mut self.opts.deadline {
+            if extended > *deadline {
+                log::debug!("keepalive received, extending deadline by {:?}", extended - *deadline);
+                *deadline = extended;
+            }
+        }
+        Ok(())
+    }
+
+    /// Detect the firmware being blocked on a full RTT buffer, and apply the overflow strategy
+    /// if it stays blocked for too long.
+    fn check_rtt_overflow(&mut self, sess: &mut Session) -> anyhow::Result<()> {
+        let full = self.last_read > 0 && {
+            let mut offsets = [0u32; 2];
+            sess.core(0)?
+                .read_32((self.rtt_addr + RTT_UP0_WRITE_OFFSET) as _, &mut offsets)?;
+            let [write, read] = offsets;
+            let size = self.defmt.as_ref().map_or(0, |c| c.buffer_size()) as u32;
+            size != 0 && (write + 1) % size == read
+        };
+
+        let threshold = Duration::from_millis(self.opts.rtt_overflow.threshold_millis);
+        if !full {
+            if let Some(since) = self.rtt_blocked_since.take() {
+                let blocked = since.elapsed();
+                self.rtt_blocked_total += blocked;
+                if blocked >= threshold {
+                    warn!("device blocked on RTT for {} ms", blocked.as_millis());
+                }
+            }
+            return Ok(());
+        }
+
+        let blocked = self.rtt_blocked_since.get_or_insert_with(Instant::now).elapsed();
+        if blocked < threshold || self.rtt_overflow_handled {
+            return Ok(());
+        }
+        self.rtt_overflow_handled = true;
+        warn!("device blocked on RTT for {} ms", blocked.as_millis());
+        match self.opts.rtt_overflow.strategy {
+            RttOverflowStrategy::PollFaster => {
+                warn!("reading RTT faster, consider logging less or enlarging the RTT buffer");
+                self.fast_poll = true;
+            }
+            RttOverflowStrategy::NoBlock => {
+                warn!("switching RTT to non-blocking mode, device logs may be lost from now on");
+                sess.core(0)?
+                    .write_word_32((self.rtt_addr + RTT_UP0_FLAGS_OFFSET) as _, RTT_MODE_NO_BLOCK_SKIP)?;
+            }
+            RttOverflowStrategy::Fail => bail!(RunError::RttOverflow),
+        }
+        Ok(())
+    }
+
+    fn run(&mut self, sess: &mut Session) -> anyhow::Result<()> {
+        let res = self.run_inner(sess);
+        let blocked = self.rtt_blocked_total + self.rtt_blocked_since.map_or(Duration::ZERO, |s| s.elapsed());
+        if !blocked.is_zero() {
+            info!("device was blocked on RTT for {} ms in total", blocked.as_millis());
+        }
+        res
+    }
+
+    fn run_inner(&mut self, sess: &mut Session) -> anyhow::Result<()> {
+        let mut was_halted = false;
+
+        loop {
+            self.check_keepalive(sess)?;
+            self.check_rtt_overflow(sess)?;
+
+            if self.opts.cancel.as_ref().is_some_and(|c| c.load(Ordering::Relaxed)) {
+                warn!("Run cancelled");
+                bail!(RunError::Cancelled)
+            }
+
+            if let Some(deadline) = self.opts.deadline {
+                if Instant::now() > deadline {
+                    warn!("Deadline exceeded!");
+                    let mut core = sess.core(0)?;
+                    self.dump_state(&mut core, true)?;
+                    bail!(RunError::Timeout)
+                }
+            }
+
+            self.poll(sess)?;
+
+            let mut core = sess.core(0)?;
+            let is_halted = core.core_halted()?;
+
+            if is_halted && was_halted {
+                break;
+            }
+            was_halted = is_halted;
+        }
+
+        let mut core = sess.core(0)?;
+
+        let is_hardfault = self.dump_state(&mut core, false)?;
+        if is_hardfault {
+            bail!(RunError::HardFault);
+        }
+        if halted_on_reset(&mut core)? {
+            warn!("Device reset unexpectedly!");
+            bail!(RunError::UnexpectedReset);
+        }
+        check_exit_code(&mut core)
+    }
+
+    fn traceback(&mut self, core: &mut Core) -> anyhow::Result<()> {
+        let mut r = [0; 17];
+        for (i, val) in r.iter_mut().enumerate() {
+            *val = core.read_core_reg::<u32>(i as u16)?;
+        }
+        info!(
+            "  R0: {:08x}   R1: {:08x}   R2: {:08x}   R3: {:08x}",
+            r[0], r[1], r[2], r[3],
+        );
+        info!(
+            "  R4: {:08x}   R5: {:08x}   R6: {:08x}   R7: {:08x}",
+            r[4], r[5], r[6], r[7],
+        );
+        info!(
+            "  R8: {:08x}   R9: {:08x}  R10: {:08x}  R11: {:08x}",
+            r[8], r[9], r[10], r[11],
+        );
+        info!(
+            " R12: {:08x}   SP: {:08x}   LR: {:08x}   PC: {:08x}",
+            r[12], r[13], r[14], r[15],
+        );
+        info!("XPSR: {:08x}", r[16]);
+        self.exception = Some(ExceptionInfo {
+            number: r[16] & 0xff,
+            registers: r.to_vec(),
+            hfsr: None,
+            cfsr: None,
+            bfar: None,
+            stack_overflow: false,
+        });
+
+        info!("");
+        info!("Stack:");
+        let mut stack = [0u32; 32];
+        core.read_32(r[13] as _, &mut stack)?;
+        for i in 0..(stack.len() / 4) {
+            info!(
+                "{:08x}: {:08x} {:08x} {:08x} {:08x}",
+                r[13] + i as u32 * 16,
+                stack[i * 4 + 0],
+                stack[i * 4 + 1],
+                stack[i * 4 + 2],
+                stack[i * 4 + 3],
+            );
+        }
+
+        info!("");
+        info!("Backtrace:");
+        let di = &self.di;
+        let initial_registers = DebugRegisters::from_core(core);
+        let exception_handler = probe_rs::exception_handler_for_core(core.core_type());
+        let instruction_set = core.instruction_set().ok();
+        let stack_frames = di.unwind(core, initial_registers, exception_handler.as_ref(), instruction_set)?;
+
+        for (i, frame) in stack_frames.iter().enumerate() {
+            let mut s = String::new();
+            write!(&mut s, "Frame {}: {} @ {}", i, frame.function_name, frame.pc).unwrap();
+
+            if frame.is_inlined {
+                write!(&mut s, " inline").unwrap();
+            }
+
+            let (mut file, mut line, mut column) = (None, None, None);
+            if let Some(location) = &frame.source_location {
+                if location.directory.is_some() || location.file.is_some() {
+                    let mut path = String::new();
+
+                    if let Some(dir) = &location.directory {
+                        write!(&mut path, "{}", dir.to_string_lossy()).unwrap();
+                    }
+
+                    if let Some(file) = &location.file {
+                        write!(&mut path, "/{file}").unwrap();
+
+                        line = location.line;
+                        if line.is_some() {
+                            column = location.column.map(|col| match col {
+                                probe_rs::debug::ColumnType::LeftEdge => 1,
+                                probe_rs::debug::ColumnType::Column(c) => c,
+                            });
+                        }
+                    }
+
+                    write!(&mut s, "\n       {}", path).unwrap();
+                    if let Some(line) = line {
+                        write!(&mut s, ":{line}").unwrap();
+                    }
+                    if let Some(column) = column {
+                        write!(&mut s, ":{column}").unwrap();
+                    }
+                    file = Some(path);
+                }
+            }
+
+            info!("{}", s);
+
+            self.backtrace.push(BacktraceFrame {
+                function: frame.function_name.clone(),
+                pc: frame.pc.try_into().unwrap_or_default(),
+                inlined: frame.is_inlined,
+                file,
+                line,
+                column,
+            });
+        }
+
+        Ok(())
+    }
+
+    fn dump_state(&mut self, core: &mut Core, force: bool) -> anyhow::Result<bool> {
+        core.halt(TIMEOUT)?;
+
+        // determine if the target is handling an interupt
+        let xpsr: u32 = core.read_core_reg(XPSR)?;
+        let exception_number = xpsr & 0xff;
+        match exception_number {
+            0 => {
+                //info!("No exception!");
+                if force {
+                    self.traceback(core)?;
+                }
+                Ok(false)
+            }
+            3 => {
+                self.traceback(core)?;
+                info!("Hard Fault!");
+
+                // Get reason for hard fault
+                let hfsr = core.read_word_32(0xE000_ED2C)?;
+                let (mut fault_cfsr, mut fault_bfar) = (None, None);
+
+                if hfsr & (1 << 30) != 0 {
+                    info!("-> configurable priority exception has been escalated to hard fault!");
+
+                    // read cfsr
+                    let cfsr = core.read_word_32(0xE000_ED28)?;
+                    fault_cfsr = Some(cfsr);
+
+                    let ufsr = (cfsr >> 16) & 0xffff;
+                    let bfsr = (cfsr >> 8) & 0xff;
+                    let mmfsr = (cfsr) & 0xff;
+
+                    if ufsr != 0 {
+                        info!("\tUsage Fault     - UFSR: {:#06x}", ufsr);
+                    }
+
+                    if bfsr != 0 {
+                        info!("\tBus Fault       - BFSR: {:#04x}", bfsr);
+
+                        if bfsr & (1 << 7) != 0 {
+                            // Read address from BFAR
+                            let bfar = core.read_word_32(0xE000_ED38)?;
+                            info!("\t Location       - BFAR: {:#010x}", bfar);
+                            fault_bfar = Some(bfar);
+                        }
+                    }
+
+                    if mmfsr != 0 {
+                        info!("\tMemManage Fault - BFSR: {:04x}", bfsr);
+                    }
+                }
+
+                if let Some(exception) = &mut self.exception {
+                    exception.hfsr = Some(hfsr);
+                    exception.cfsr = fault_cfsr;
+                    exception.bfar = fault_bfar;
+                }
+                Ok(true)
+            }
+            // Ignore other exceptions for now
+            _ => {
+                self.traceback(core)?;
+                info!("Exception {}", exception_number);
+                Ok(false)
+            }
+        }
+    }
+}
+
+fn setup_logging_channel(
+    rtt_addr: u32,
+    sess: &mut Session,
+    channel: usize,
+) -> anyhow::Result<(UpChannel, Option<DownChannel>)> {
+    const NUM_RETRIES: usize = 10; // picked at random, increase if necessary
+    let mut rtt_res: Result<Rtt, probe_rs::rtt::Error> = Err(probe_rs::rtt::Error::ControlBlockNotFound);
+
+    let memory_map = sess.target().memory_map.clone();
+    let mut core = sess.core(0).unwrap();
+
+    for try_index in 0..=NUM_RETRIES {
+        rtt_res = Rtt::attach_region(&mut core, &memory_map, &ScanRegion::Exact(rtt_addr));
+        match rtt_res {
+            Ok(_) => {
+                log::debug!("Successfully attached RTT");
+                break;
+            }
+            Err(probe_rs::rtt::Error::ControlBlockNotFound) => {
+                if try_index < NUM_RETRIES {
+                    log::trace!(
+                        "Could not attach because the target's RTT control block isn't initialized (yet). retrying"
+                    );
+                } else {
+                    log::error!("Max number of RTT attach retries exceeded.");
+                    return Err(anyhow!(probe_rs::rtt::Error::ControlBlockNotFound));
+                }
+            }
+            Err(e) => {
+                return Err(anyhow!(e));
+            }
+        }
+    }
+
+    // this block is only executed when rtt was successfully attached before
+    let mut rtt = rtt_res.expect("unreachable");
+    for ch in rtt.up_channels().iter() {
+        log::debug!(
+            "up channel {}: {:?}, buffer size {} bytes",
+            ch.number(),
+            ch.name(),
+            ch.buffer_size()
+        );
+    }
+    for ch in rtt.down_channels().iter() {
+        log::debug!(
+            "down channel {}: {:?}, buffer size {} bytes",
+            ch.number(),
+            ch.name(),
+            ch.buffer_size()
+        );
+    }
+
+    let defmt = rtt
+        .up_channels()
+        .take(channel)
+        .ok_or_else(|| anyhow!("RTT up channel {} not found", channel))?;
+    let stdin = rtt.down_channels().take(0);
+
+    Ok((defmt, stdin))
+}
+
+/// Validate the layout of an image that runs from RAM, and return the bytes of the RTT
+/// control block as they're loaded.
+fn check_ram_layout<'a>(
+    loaded_sections: &[(&str, u32, &'a [u8])],
+    vector_table: &VectorTable,
+    rtt_addr: u32,
+    rtt_size: u32,
+) -> anyhow::Result<&'a [u8]> {
+    // The first push after reset goes right below the initial stack pointer.
+    let stack_top = vector_table.initial_sp.wrapping_sub(4);
+    for &(name, start, data) in loaded_sections {
+        let end = start + data.len() as u32;
+        if (start..end).contains(&stack_top) {
+            bail!(
+                "section `{}` ({:#010x}..{:#010x}) overlaps the initial stack at {:#010x}, check your linker script",
+                name,
+                start,
+                end,
+                vector_table.initial_sp
+            );
+        }
+    }
+
+    let rtt_end = rtt_addr + rtt_size;
+    for &(_, start, data) in loaded_sections {
+        let end = start + data.len() as u32;
+        if start <= rtt_addr && rtt_end <= end {
+            let offset = (rtt_addr - start) as usize;
+            return Ok(&data[offset..][..rtt_size as usize]);
+        }
+    }
+
+    bail!(
+        "RTT control block at {:#010x} is not in any loaded section. When running from RAM it must be in `.data`, not `.bss` or `.uninit`.",
+        rtt_addr
+    )
+}
+
+/// Check whether the flash already contains all the loadable segments of the ELF.
+fn flash_matches_elf(sess: &mut Session, elf_bytes: &[u8]) -> anyhow::Result<bool> {
+    let elf = ElfFile32::<Endianness>::parse(elf_bytes)?;
+    let endian = elf.endian();
+    let memory_map = sess.target().memory_map.clone();
+    let mut core = sess.core(0)?;
+
+    for segment in elf.raw_segments() {
+        if segment.p_type(endian) != PT_LOAD {
+            continue;
+        }
+        // The load address, which differs from the run address for sections like `.data`.
+        let addr = segment.p_paddr(endian) as u64;
+        let in_flash = memory_map
+            .iter()
+            .any(|r| matches!(r, MemoryRegion::Nvm(r) if r.range.contains(&addr)));
+        if !in_flash {
+            continue;
+        }
+
+        let data = segment
+            .data(endian, elf_bytes)
+            .map_err(|_| anyhow!("invalid ELF segment at {:#010x}", addr))?;
+        let mut current = vec![0; data.len()];
+        core.read_8(addr, &mut current)?;
+        if current != data {
+            log::debug!("flash contents differ in segment at {:#010x}", addr);
+            return Ok(false);
+        }
+    }
+
+    Ok(true)
+}
+
+/// Read the message panic-persist left in its buffer, if there's one.
+fn read_panic_dump(core: &mut Core, start: u32, end: u32) -> anyhow::Result<Option<String>> {
+    let mut header = [0u32; 2];
+    core.read_32(start as _, &mut header)?;
+    let [magic, len] = header;
+    if magic != PANIC_PERSIST_MAGIC {
+        return Ok(None);
+    }
+    let len = (len as usize).min(end.saturating_sub(start + 8) as usize);
+    let mut message = vec![0; len];
+    core.read_8(start as u64 + 8, &mut message)?;
+    Ok(Some(String::from_utf8_lossy(&message).into_owned()))
+}
+
+fn get_rtt_main_from(elf: &ElfFile) -> anyhow::Result<(Option<(u32, u32)>, u32)> {
+    let mut rtt = None;
+    let mut main = None;
+
+    for symbol in elf.symbols() {
+        let name = match symbol.name() {
+            Ok(name) => name,
+            Err(_) => continue,
+        };
+
+        match name {
+            "main" => main = Some(symbol.address() as u32 & !THUMB_BIT),
+            "_SEGGER_RTT" => {
+                // Some toolchains don't emit symbol sizes, fall back to the control block header.
+                let size = match symbol.size() {
+                    0 => RTT_HEADER_SIZE,
+                    size => size as u32,
+                };
+                rtt = Some((symbol.address() as u32, size))
+            }
+            _ => {}
+        }
+    }
+
+    Ok((rtt, main.ok_or_else(|| anyhow!("`main` symbol not found"))?))
+}
+
+/// The contents of the vector table
+#[derive(Debug)]
+struct VectorTable {
+    location: u32,
+    // entry 0
+    initial_sp: u32,
+    // entry 1: Reset handler
+    reset: u32,
+}
+
+#[cfg(test)]
+mod tests {
+    use log::Level;
+
+    use super::*;
+
+    #[test]
+    fn log_filter() {
+        let filter: LogFilter = "warn,net=trace".parse().unwrap();
+        assert!(filter.enabled(Some("app"), Level::Warn));
+        assert!(!filter.enabled(Some("app"), Level::Info));
+        assert!(filter.enabled(Some("net::tcp"), Level::Trace));
+        assert!(!filter.enabled(None, Level::Info));
+        assert_eq!(filter.to_string(), "warn,net=trace");
+    }
+
+    #[test]
+    fn log_filter_bare_module() {
+        let filter: LogFilter = "net".parse().unwrap();
+        assert!(filter.enabled(Some("net::tcp"), Level::Trace));
+        assert!(!filter.enabled(Some("app"), Level::Error));
+        assert!(!filter.enabled(None, Level::Error));
+    }
+
+    #[test]
+    fn log_filter_rejects_bad_levels() {
+        assert!("net=loud".parse::<LogFilter>().is_err());
+        assert!("warn,net=".parse::<LogFilter>().is_ok());
+    }
+}
@@ -0,0 +1,137 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::str::FromStr;
+
+use anyhow::bail;
+use serde::{Deserialize, Deserializer};
+
+/// A label selector, like `chip-family=stm32,has-ethernet=true`.
+///
+/// Each comma-separated term is either `key=value`, matching targets where the label
+/// has exactly that value, or a bare `key`, matching targets that have the label at all.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Selector {
+    terms: Vec<(String, Option<String>)>,
+}
+
+impl Selector {
+    pub fn matches(&self, labels: &BTreeMap<String, String>) -> bool {
+        self.terms.iter().all(|(k, v)| match (labels.get(k), v) {
+            (Some(_), None) => true,
+            (Some(actual), Some(wanted)) => actual == wanted,
+            (None, _) => false,
+        })
+    }
+}
+
+impl FromStr for Selector {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut terms = Vec::new();
+        for term in s.split(',').map(str::trim).filter(|t| !t.is_empty()) {
+            let (key, value) = match term.split_once('=') {
+                Some((k, v)) => (k.trim(), Some(v.trim().to_string())),
+                None => (term, None),
+            };
+            if key.is_empty() {
+                bail!("empty label name in selector term `{}`", term);
+            }
+            terms.push((key.to_string(), value));
+        }
+        if terms.is_empty() {
+            bail!("selector is empty");
+        }
+        Ok(Self { terms })
+    }
+}
+
+impl fmt::Display for Selector {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, (k, v)) in self.terms.iter().enumerate() {
+            if i != 0 {
+                write!(f, ",")?;
+            }
+            match v {
+                Some(v) => write!(f, "{}={}", k, v)?,
+                None => write!(f, "{}", k)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+impl<'de> Deserialize<'de> for Selector {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        String::deserialize(deserializer)?
+            .parse()
+            .map_err(serde::de::Error::custom)
+    }
+}
+
+/// Deserialize target labels, accepting any YAML scalar as a value so that
+/// `has-ethernet: true` works without quoting.
+pub fn deserialize_labels<'de, D>(deserializer: D) -> Result<BTreeMap<String, String>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let raw = BTreeMap::<String, serde_yaml::Value>::deserialize(deserializer)?;
+    raw.into_iter()
+        .map(|(k, v)| {
+            let v = match v {
+                serde_yaml::Value::String(s) => s,
+                serde_yaml::Value::Bool(b) => b.to_string(),
+                serde_yaml::Value::Number(n) => n.to_string(),
+                _ => return Err(serde::de::Error::custom(format!("label `{}` must be a scalar", k))),
+            };
+            Ok((k, v))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn labels(pairs: &[(&str, &str)]) -> BTreeMap<String, String> {
+        pairs.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect()
+    }
+
+    #[test]
+    fn matches() {
+        let selector: Selector = "chip-family=stm32, has-ethernet".parse().unwrap();
+        assert!(selector.matches(&labels(&[("chip-family", "stm32"), ("has-ethernet", "false")])));
+        assert!(!selector.matches(&labels(&[("chip-family", "nrf"), ("has-ethernet", "true")])));
+        assert!(!selector.matches(&labels(&[("chip-family", "stm32")])));
+    }
+
+    #[test]
+    fn display_roundtrip() {
+        let selector: Selector = " chip-family = stm32 ,has-ethernet,".parse().unwrap();
+        assert_eq!(selector.to_string(), "chip-family=stm32,has-ethernet");
+        assert_eq!(selector.to_string().parse::<Selector>().unwrap(), selector);
+    }
+
+    #[test]
+    fn invalid() {
+        assert!("".parse::<Selector>().is_err());
+        assert!(" , ".parse::<Selector>().is_err());
+        assert!("=x".parse::<Selector>().is_err());
+    }
+
+    #[test]
+    fn scalar_labels() {
+        #[derive(Deserialize)]
+        struct Target {
+            #[serde(deserialize_with = "deserialize_labels")]
+            labels: BTreeMap<String, String>,
+        }
+
+        let target: Target = serde_yaml::from_str("labels: {chip: stm32, has-ethernet: true, flash-kb: 512}").unwrap();
+        assert_eq!(
+            target.labels,
+            labels(&[("chip", "stm32"), ("has-ethernet", "true"), ("flash-kb", "512")])
+        );
+        assert!(serde_yaml::from_str::<Target>("labels: {chip: [a, b]}").is_err());
+    }
+}
@@ -0,0 +1,93 @@
+//! The firmware's stack, as laid out by cortex-m-rt: growing down from `_stack_start` to `_stack_end`.
+
+use std::ops::Range;
+
+use object::read::elf::ElfFile32;
+use object::{Endianness, Object as _, ObjectSymbol as _};
+use probe_rs::{Core, MemoryInterface};
+
+/// Value cortex-m-rt's `paint-stack` feature fills the stack with before `main`.
+const STACK_PAINT: u32 = 0xCCCC_CCCC;
+
+/// Size of the guard region at the end of the stack, the smallest MPU region ARMv7-M allows.
+const GUARD_SIZE: u64 = 32;
+/// Room for the exception frame the core pushes when it faults.
+const EXCEPTION_FRAME_SIZE: u64 = 32;
+
+const CPUID: u64 = 0xE000_ED00;
+const CFSR: u64 = 0xE000_ED28;
+/// MemManage fault on pushing the exception frame.
+const CFSR_MSTKERR: u32 = 1 << 4;
+const CFSR_MMARVALID: u32 = 1 << 7;
+const MMFAR: u64 = 0xE000_ED34;
+const MPU_TYPE: u64 = 0xE000_ED90;
+const MPU_CTRL: u64 = 0xE000_ED94;
+const MPU_CTRL_ENABLE: u32 = 1 << 0;
+/// Keep the default memory map for everything outside the regions.
+const MPU_CTRL_PRIVDEFENA: u32 = 1 << 2;
+const MPU_RNR: u64 = 0xE000_ED98;
+const MPU_RBAR: u64 = 0xE000_ED9C;
+const MPU_RASR: u64 = 0xE000_EDA0;
+const MPU_RASR_ENABLE: u32 = 1 << 0;
+/// Never execute. Access permissions are left at 0, no access.
+const MPU_RASR_XN: u32 = 1 << 28;
+
+/// Addresses of the stack, from the symbols of cortex-m-rt 0.7.4 and up. `None` if they're missing.
+pub fn range(elf_bytes: &[u8]) -> anyhow::Result<Option<Range<u64>>> {
+    let elf = ElfFile32::<Endianness>::parse(elf_bytes)?;
+    let symbol = |name: &str| elf.symbols().find(|s| s.name().ok() == Some(name)).map(|s| s.address());
+    match (symbol("_stack_end"), symbol("_stack_start")) {
+        (Some(end), Some(start)) if end < start => Ok(Some(end..start)),
+        _ => Ok(None),
+    }
+}
+
+/// Most stack the firmware used so far, going by how much of cortex-m-rt's stack paint got overwritten.
+pub fn peak(core: &mut Core, stack: &Range<u64>) -> anyhow::Result<u64> {
+    // The stack grows down, so the lowest overwritten word is the peak.
+    let mut words = vec![0; ((stack.end - stack.start) / 4) as usize];
+    core.read_32(stack.start, &mut words)?;
+    let painted = words.iter().take_while(|w| **w == STACK_PAINT).count() as u64;
+    Ok(stack.end - stack.start - painted * 4)
+}
+
+/// Make the end of the stack inaccessible with the highest priority MPU region, so overflowing it faults right
+/// away instead of corrupting the statics below it. Returns false on cores without an ARMv7-M MPU. Firmware
+/// that sets up the MPU itself replaces the guard.
+pub fn install_guard(core: &mut Core, stack: &Range<u64>) -> anyhow::Result<bool> {
+    // Cortex-M3, M4 and M7.
+    let part = (core.read_word_32(CPUID)? >> 4) & 0xfff;
+    let regions = (core.read_word_32(MPU_TYPE)? >> 8) & 0xff;
+    if !matches!(part, 0xC23 | 0xC24 | 0xC27) || regions == 0 {
+        return Ok(false);
+    }
+
+    let base = (stack.start + GUARD_SIZE - 1) & !(GUARD_SIZE - 1);
+    // SIZE encodes 2^(SIZE+1) bytes.
+    let size = GUARD_SIZE.trailing_zeros() - 1;
+    core.write_word_32(MPU_RNR, regions - 1)?;
+    core.write_word_32(MPU_RBAR, base as u32)?;
+    core.write_word_32(MPU_RASR, MPU_RASR_XN | size << 1 | MPU_RASR_ENABLE)?;
+    core.write_word_32(MPU_CTRL, MPU_CTRL_ENABLE | MPU_CTRL_PRIVDEFENA)?;
+    log::debug!("stack guard at {:#010x}", base);
+    Ok(true)
+}
+
+/// Whether a crash with the stack pointer at `sp` was probably a stack overflow: the stack pointer is at the
+/// end of the stack or past it, or the fault was on the guard region.
+pub fn overflowed(core: &mut Core, stack: &Range<u64>, sp: u32) -> anyhow::Result<bool> {
+    let sp = sp as u64;
+    if sp < stack.start + GUARD_SIZE + EXCEPTION_FRAME_SIZE || sp > stack.end {
+        return Ok(true);
+    }
+
+    let cfsr = core.read_word_32(CFSR)?;
+    if cfsr & CFSR_MSTKERR != 0 {
+        return Ok(true);
+    }
+    if cfsr & CFSR_MMARVALID != 0 {
+        let addr = core.read_word_32(MMFAR)? as u64;
+        return Ok((stack.start..stack.start + 2 * GUARD_SIZE).contains(&addr));
+    }
+    Ok(false)
+}
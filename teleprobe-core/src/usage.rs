@@ -0,0 +1,92 @@
+//! Flash and RAM used by firmware, for tracking size regressions.
+
+use std::ops::Range;
+
+use object::elf::{PT_LOAD, SHF_ALLOC};
+use object::read::elf::{ElfFile32, ProgramHeader as _};
+use object::{Endianness, Object as _, ObjectSection as _, SectionFlags};
+use probe_rs::config::MemoryRegion;
+
+use crate::{MemoryUsage, SectionUsage};
+
+/// Flash and RAM taken by the image, by the address its segments load at and its sections run at.
+pub fn measure(elf_bytes: &[u8], memory_map: &[MemoryRegion]) -> anyhow::Result<MemoryUsage> {
+    let elf = ElfFile32::<Endianness>::parse(elf_bytes)?;
+    let endian = elf.endian();
+
+    let flash_regions: Vec<Range<u64>> = memory_map
+        .iter()
+        .filter_map(|r| match r {
+            MemoryRegion::Nvm(r) => Some(r.range.clone()),
+            _ => None,
+        })
+        .collect();
+    let ram_regions: Vec<Range<u64>> = memory_map
+        .iter()
+        .filter_map(|r| match r {
+            MemoryRegion::Ram(r) => Some(r.range.clone()),
+            MemoryRegion::Generic(r) => Some(r.range.clone()),
+            _ => None,
+        })
+        .collect();
+
+    let mut flash = Used::default();
+    for segment in elf.raw_segments() {
+        if segment.p_type(endian) != PT_LOAD {
+            continue;
+        }
+        // The load address, so `.data` counts in flash, where its initial values are.
+        let addr = segment.p_paddr(endian) as u64;
+        flash.add(&flash_regions, addr, segment.p_filesz(endian) as u64);
+    }
+
+    let mut ram = Used::default();
+    let mut sections = Vec::new();
+    for section in elf.sections() {
+        let alloc = match section.flags() {
+            SectionFlags::Elf { sh_flags } => sh_flags & u64::from(SHF_ALLOC) != 0,
+            _ => false,
+        };
+        if !alloc || section.size() == 0 {
+            continue;
+        }
+        ram.add(&ram_regions, section.address(), section.size());
+        sections.push(SectionUsage {
+            name: section.name()?.to_string(),
+            address: section.address(),
+            size: section.size(),
+        });
+    }
+
+    Ok(MemoryUsage {
+        flash: flash.bytes,
+        flash_size: flash.region_size(),
+        ram: ram.bytes,
+        ram_size: ram.region_size(),
+        sections,
+        stack_peak: None,
+    })
+}
+
+#[derive(Default)]
+struct Used {
+    bytes: u64,
+    /// Regions anything was found in.
+    regions: Vec<Range<u64>>,
+}
+
+impl Used {
+    fn add(&mut self, regions: &[Range<u64>], addr: u64, size: u64) {
+        let Some(region) = regions.iter().find(|r| r.contains(&addr)) else {
+            return;
+        };
+        self.bytes += size;
+        if !self.regions.contains(region) {
+            self.regions.push(region.clone());
+        }
+    }
+
+    fn region_size(&self) -> u64 {
+        self.regions.iter().map(|r| r.end - r.start).sum()
+    }
+}
@@ -0,0 +1,103 @@
+//! Watchdogs of the chip families teleprobe knows, so they can be kept from resetting the chip while
+//! it's halted for flashing and setup, and their resets can be told apart from other ones. Those are STM32 F1,
+//! F2, F3, F4, F7, G4, H7, L4 and WB, RP2040 and nRF52. Other STM32 families, like F0, L0, G0 and U5, aren't.
+
+use probe_rs::{Core, MemoryInterface};
+
+struct Family {
+    /// Start of the probe-rs target names of the family, lowercase.
+    prefix: &'static str,
+    /// Debug register, and the bits to set in it so the watchdogs stop counting while the core is halted.
+    freeze: Option<(u64, u32)>,
+    /// Reset reason register, and the bits in it that flag a watchdog reset.
+    reset_reason: Option<(u64, u32)>,
+}
+
+const FAMILIES: &[Family] = &[
+    // DBGMCU_APB1_FZ: DBG_IWDG_STOP, DBG_WWDG_STOP. RCC_CSR: IWDGRSTF, WWDGRSTF.
+    Family {
+        prefix: "stm32f2",
+        freeze: Some((0xE004_2008, 0b11 << 11)),
+        reset_reason: Some((0x4002_3874, 0b11 << 29)),
+    },
+    Family {
+        prefix: "stm32f4",
+        freeze: Some((0xE004_2008, 0b11 << 11)),
+        reset_reason: Some((0x4002_3874, 0b11 << 29)),
+    },
+    Family {
+        prefix: "stm32f7",
+        freeze: Some((0xE004_2008, 0b11 << 11)),
+        reset_reason: Some((0x4002_3874, 0b11 << 29)),
+    },
+    Family {
+        prefix: "stm32f1",
+        freeze: Some((0xE004_2004, 0b11 << 8)),
+        reset_reason: Some((0x4002_1024, 0b11 << 29)),
+    },
+    Family {
+        prefix: "stm32f3",
+        freeze: Some((0xE004_2008, 0b11 << 11)),
+        reset_reason: Some((0x4002_1024, 0b11 << 29)),
+    },
+    // DBGMCU_APB1FZR1: DBG_IWDG_STOP, DBG_WWDG_STOP. RCC_CSR: IWDGRSTF, WWDGRSTF.
+    Family {
+        prefix: "stm32l4",
+        freeze: Some((0xE004_2008, 0b11 << 11)),
+        reset_reason: Some((0x4002_1094, 0b11 << 29)),
+    },
+    Family {
+        prefix: "stm32g4",
+        freeze: Some((0xE004_2008, 0b11 << 11)),
+        reset_reason: Some((0x4002_1094, 0b11 << 29)),
+    },
+    Family {
+        prefix: "stm32wb",
+        freeze: Some((0xE004_203C, 0b11 << 11)),
+        reset_reason: Some((0x5800_0094, 0b11 << 29)),
+    },
+    // DBGMCU_APB4FZ1: WDGLSD1. RCC_RSR: IWDG1RSTF, WWDG1RSTF.
+    Family {
+        prefix: "stm32h7",
+        freeze: Some((0x5C00_1054, 1 << 18)),
+        reset_reason: Some((0x5802_44D0, 0b101 << 26)),
+    },
+    // The WDT pauses while the core is halted by default, and its config is locked once it's running.
+    // POWER.RESETREAS: DOG.
+    Family {
+        prefix: "nrf52",
+        freeze: None,
+        reset_reason: Some((0x4000_0400, 1 << 1)),
+    },
+    // WATCHDOG.CTRL: PAUSE_DBG0, PAUSE_DBG1, PAUSE_JTAG. WATCHDOG.REASON: TIMER, FORCE.
+    Family {
+        prefix: "rp2040",
+        freeze: Some((0x4005_8000, 0b111 << 24)),
+        reset_reason: Some((0x4005_8008, 0b11)),
+    },
+];
+
+fn family(chip: &str) -> Option<&'static Family> {
+    let chip = chip.to_ascii_lowercase();
+    FAMILIES.iter().find(|f| chip.starts_with(f.prefix))
+}
+
+/// Stop the chip's watchdogs from counting while the core is halted. Returns false if teleprobe doesn't know
+/// how to on this chip.
+pub fn freeze(core: &mut Core, chip: &str) -> anyhow::Result<bool> {
+    let Some((addr, bits)) = family(chip).and_then(|f| f.freeze) else {
+        return Ok(false);
+    };
+    let value = core.read_word_32(addr)?;
+    core.write_word_32(addr, value | bits)?;
+    Ok(true)
+}
+
+/// Whether the chip's reset flags show a watchdog reset, or `None` if teleprobe doesn't know them. The flags
+/// are sticky, so they can also be left over from before, if the firmware doesn't clear them.
+pub fn caused_reset(core: &mut Core, chip: &str) -> anyhow::Result<Option<bool>> {
+    let Some((addr, bits)) = family(chip).and_then(|f| f.reset_reason) else {
+        return Ok(None);
+    };
+    Ok(Some(core.read_word_32(addr)? & bits != 0))
+}
@@ -0,0 +1,98 @@
+//! Benchmark results, for teleprobe to read from the firmware's memory after the run. Behind the `benchmarks`
+//! feature, so firmware that doesn't record any doesn't get the table in its RAM.
+
+/// Most benchmark results one run can record. Later ones are dropped.
+const MAX_BENCHMARKS: usize = 32;
+
+#[doc(hidden)]
+#[repr(C)]
+pub struct BenchmarkSlot {
+    name: *const u8,
+    name_len: u32,
+    cycles: u32,
+    /// 0 for no limit.
+    max_cycles: u32,
+}
+
+impl BenchmarkSlot {
+    const EMPTY: Self = Self {
+        name: core::ptr::null(),
+        name_len: 0,
+        cycles: 0,
+        max_cycles: 0,
+    };
+}
+
+#[doc(hidden)]
+#[used]
+#[no_mangle]
+pub static mut _TELEPROBE_BENCHMARKS: [BenchmarkSlot; MAX_BENCHMARKS] = [BenchmarkSlot::EMPTY; MAX_BENCHMARKS];
+
+#[doc(hidden)]
+#[used]
+#[no_mangle]
+pub static mut _TELEPROBE_BENCHMARK_COUNT: u32 = 0;
+
+/// Read the DWT cycle counter, which teleprobe enables before starting firmware that records benchmarks.
+///
+/// Always 0 on ARMv6-M, whose cores have no cycle counter to read.
+pub fn cycles() -> u32 {
+    // ARMv6-M is the only M profile without 32-bit atomics, and has no DWT to read.
+    #[cfg(all(target_arch = "arm", target_has_atomic = "32"))]
+    // SAFETY: DWT_CYCCNT is always readable on cores that have a DWT.
+    return unsafe { (0xE000_1004 as *const u32).read_volatile() };
+    #[cfg(not(all(target_arch = "arm", target_has_atomic = "32")))]
+    0
+}
+
+/// Record a benchmark result, in cycles, for teleprobe to report along with the run.
+///
+/// ```rust,no_run
+/// let start = teleprobe_meta::cycles();
+/// // ...
+/// teleprobe_meta::benchmark("sha256_1k", teleprobe_meta::cycles().wrapping_sub(start));
+/// ```
+pub fn benchmark(name: &'static str, cycles: u32) {
+    record_benchmark(name, cycles, 0)
+}
+
+/// Record a benchmark result, and fail the test if it took more than `max_cycles`, to catch performance
+/// regressions.
+///
+/// ```rust,no_run
+/// teleprobe_meta::benchmark_max("sha256_1k", 1234, 20_000);
+/// ```
+pub fn benchmark_max(name: &'static str, cycles: u32, max_cycles: u32) {
+    record_benchmark(name, cycles, max_cycles.max(1))
+}
+
+/// Time `f` with the cycle counter, and record it as a benchmark.
+///
+/// ```rust,no_run
+/// let sum = teleprobe_meta::measure("sum", || (0..1000u32).sum::<u32>());
+/// ```
+pub fn measure<R>(name: &'static str, f: impl FnOnce() -> R) -> R {
+    let start = cycles();
+    let res = f();
+    benchmark(name, cycles().wrapping_sub(start));
+    res
+}
+
+fn record_benchmark(name: &'static str, cycles: u32, max_cycles: u32) {
+    // SAFETY: only written by the firmware, teleprobe only reads them.
+    unsafe {
+        let count = core::ptr::addr_of_mut!(_TELEPROBE_BENCHMARK_COUNT);
+        let n = count.read_volatile() as usize;
+        if n >= MAX_BENCHMARKS {
+            return;
+        }
+        let slot = core::ptr::addr_of_mut!(_TELEPROBE_BENCHMARKS[n]);
+        slot.write_volatile(BenchmarkSlot {
+            name: name.as_ptr(),
+            name_len: name.len() as u32,
+            cycles,
+            max_cycles,
+        });
+        count.write_volatile(n as u32 + 1);
+    }
+}
@@ -2,6 +2,8 @@
 #![doc = include_str!("../README.md")]
 #![warn(missing_docs)]
 
+use core::sync::atomic::{AtomicU32, Ordering};
+
 /// Set the teleprobe target.
 ///
 /// ```rust
@@ -33,3 +35,149 @@ macro_rules! timeout {
         static _TELEPROBE_TIMEOUT: u32 = $val;
     };
 }
+
+/// Set a teleprobe label selector, to run the binary on any target with matching labels.
+///
+/// ```rust
+/// teleprobe_meta::labels!(b"chip=stm32f4,feature=eth");
+/// ```
+///
+/// Note that you MUST use binary strings `b""`. Regular strings `""` will not work.
+#[macro_export]
+macro_rules! labels {
+    ($val:literal) => {
+        #[link_section = ".teleprobe.labels"]
+        #[used]
+        #[no_mangle] // prevent invoking the macro multiple times
+        static _TELEPROBE_LABELS: [u8; $val.len()] = *$val;
+    };
+}
+
+/// Set whether the firmware runs from RAM or flash, instead of deciding from the memory region its
+/// vector table is linked at. Needed on chips that alias flash at addresses probe-rs maps as RAM.
+///
+/// ```rust
+/// teleprobe_meta::run_from!(flash);
+/// ```
+#[macro_export]
+macro_rules! run_from {
+    (ram) => {
+        $crate::run_from!(@bytes b"ram");
+    };
+    (flash) => {
+        $crate::run_from!(@bytes b"flash");
+    };
+    (@bytes $val:literal) => {
+        #[link_section = ".teleprobe.run_from"]
+        #[used]
+        #[no_mangle] // prevent invoking the macro multiple times
+        static _TELEPROBE_RUN_FROM: [u8; $val.len()] = *$val;
+    };
+}
+
+/// Set the format of the firmware's logs on RTT up channel 0, for firmware that doesn't use defmt. They're
+/// decoded as defmt by default. `postcard_rpc` reads COBS-framed postcard-rpc frames from up channel 1 instead,
+/// and logs them as hex.
+///
+/// ```rust
+/// teleprobe_meta::log_format!(text);
+/// ```
+#[macro_export]
+macro_rules! log_format {
+    (defmt) => {
+        $crate::log_format!(@bytes b"defmt");
+    };
+    (text) => {
+        $crate::log_format!(@bytes b"text");
+    };
+    (postcard_rpc) => {
+        $crate::log_format!(@bytes b"postcard-rpc");
+    };
+    (@bytes $val:literal) => {
+        #[link_section = ".teleprobe.log_format"]
+        #[used]
+        #[no_mangle] // prevent invoking the macro multiple times
+        static _TELEPROBE_LOG_FORMAT: [u8; $val.len()] = *$val;
+    };
+}
+
+/// Set input data for the test. Teleprobe writes it to RTT down channel 0 once the firmware
+/// is running, so it can be read like stdin.
+///
+/// ```rust
+/// teleprobe_meta::stdin!(b"hello\n");
+/// ```
+///
+/// It also accepts `include_bytes!`, to embed test data from a file.
+#[macro_export]
+macro_rules! stdin {
+    ($val:expr) => {
+        #[link_section = ".teleprobe.stdin"]
+        #[used]
+        #[no_mangle] // prevent invoking the macro multiple times
+        static _TELEPROBE_STDIN: [u8; $val.len()] = *$val;
+    };
+}
+
+/// End the test successfully. Teleprobe stops the run as soon as it's called, instead of waiting for the
+/// firmware to halt.
+///
+/// ```rust,no_run
+/// teleprobe_meta::exit();
+/// ```
+pub fn exit() -> ! {
+    _teleprobe_exit(0)
+}
+
+/// End the test as failed.
+///
+/// ```rust,no_run
+/// teleprobe_meta::exit_fail();
+/// ```
+pub fn exit_fail() -> ! {
+    _teleprobe_exit(1)
+}
+
+/// Halts the core with a `bkpt` teleprobe recognizes by its immediate, with the exit code in `r0`: zero for
+/// success, anything else for failure. Exported so debuggers can break on it too.
+#[doc(hidden)]
+#[no_mangle]
+#[inline(never)]
+pub extern "C" fn _teleprobe_exit(code: u32) -> ! {
+    loop {
+        #[cfg(target_arch = "arm")]
+        // SAFETY: only halts the core, for the debugger to read `r0`.
+        unsafe {
+            core::arch::asm!("bkpt #0x54", in("r0") code)
+        };
+        #[cfg(not(target_arch = "arm"))]
+        let _ = code;
+        core::hint::spin_loop();
+    }
+}
+
+#[doc(hidden)]
+#[used]
+#[no_mangle]
+pub static _TELEPROBE_KEEPALIVE: AtomicU32 = AtomicU32::new(0);
+
+/// Tell teleprobe the test is still making progress, to extend its timeout.
+///
+/// Every call moves the deadline to the test's timeout from now, up to a maximum run time
+/// configured on the server. Call it regularly from long-running tests so they aren't killed
+/// while they're still progressing.
+///
+/// ```rust
+/// teleprobe_meta::keepalive();
+/// ```
+pub fn keepalive() {
+    // A load and a store, since not all cores have atomic read-modify-write. Teleprobe only looks for the counter
+    // changing, so a call lost to a race with another one doesn't matter.
+    let n = _TELEPROBE_KEEPALIVE.load(Ordering::Relaxed);
+    _TELEPROBE_KEEPALIVE.store(n.wrapping_add(1), Ordering::Relaxed);
+}
+
+#[cfg(feature = "benchmarks")]
+mod bench;
+#[cfg(feature = "benchmarks")]
+pub use bench::*;
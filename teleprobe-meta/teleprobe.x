@@ -8,4 +8,16 @@ SECTIONS
   {
     KEEP(*(.teleprobe.timeout));
   }
+  .teleprobe.labels (INFO) :
+  {
+    KEEP(*(.teleprobe.labels));
+  }
+  .teleprobe.stdin (INFO) :
+  {
+    KEEP(*(.teleprobe.stdin));
+  }
+  .teleprobe.log_format (INFO) :
+  {
+    KEEP(*(.teleprobe.log_format));
+  }
 }
\ No newline at end of file
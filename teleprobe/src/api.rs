@@ -1,20 +1,417 @@
+use std::collections::BTreeMap;
+
+use chrono::{DateTime, Utc};
 use probe_rs::probe::DebugProbeSelector;
 use serde::{Deserialize, Serialize};
+pub use teleprobe_core::{BacktraceFrame, ImageFormat, PanicInfo, PhaseTimings, RunError};
+
+/// Header with the server's version, on all of its replies.
+pub const VERSION_HEADER: &str = "x-teleprobe-version";
+
+/// Header with the ID of the job a run created, on the replies of runs. The server's log lines about the job carry
+/// it too.
+pub const JOB_ID_HEADER: &str = "x-teleprobe-job-id";
+
+/// Version of the HTTP API, bumped on changes clients of the previous one can't cope with. Additions that don't
+/// break them are listed in [`Version::features`] instead.
+pub const API_VERSION: u32 = 1;
+
+/// Optional parts of the API, as listed by `GET /api/version`.
+pub mod feature {
+    /// `?async=true` runs, polled with `GET /jobs/{id}`.
+    pub const ASYNC_JOBS: &str = "async_jobs";
+    /// `DELETE /jobs/{id}`.
+    pub const CANCELLATION: &str = "cancellation";
+    /// Target labels, and runs on any target matching a selector with `POST /run`.
+    pub const LABELS: &str = "labels";
+    /// `exclude` of selector runs.
+    pub const EXCLUDE_TARGETS: &str = "exclude_targets";
+    /// Chunked ELF uploads with `POST /blobs`.
+    pub const CHUNKED_UPLOADS: &str = "chunked_uploads";
+    /// `GET /jobs/{id}/report`.
+    pub const REPORTS: &str = "reports";
+    /// `raw_defmt` runs, and `GET /jobs/{id}/defmt`.
+    pub const RAW_DEFMT: &str = "raw_defmt";
+    /// `log_filter` of runs.
+    pub const LOG_FILTER: &str = "log_filter";
+    /// `host_timestamps` of runs.
+    pub const HOST_TIMESTAMPS: &str = "host_timestamps";
+    /// `/targets/{name}/lease`.
+    pub const LEASES: &str = "leases";
+    /// JSON bodies of error replies, see [`super::Error`].
+    pub const JSON_ERRORS: &str = "json_errors";
+    /// `/workers`, for workers registering their targets.
+    pub const WORKERS: &str = "workers";
+    /// Runs with an empty body and `elf_hash`, of an image uploaded before.
+    pub const IMAGES_BY_HASH: &str = "images_by_hash";
+
+    /// All of them. The server supports every feature this build knows of.
+    pub const ALL: &[&str] = &[
+        ASYNC_JOBS,
+        CANCELLATION,
+        LABELS,
+        EXCLUDE_TARGETS,
+        CHUNKED_UPLOADS,
+        REPORTS,
+        RAW_DEFMT,
+        LOG_FILTER,
+        HOST_TIMESTAMPS,
+        LEASES,
+        JSON_ERRORS,
+        WORKERS,
+        IMAGES_BY_HASH,
+    ];
+}
+
+/// Reply of `GET /api/version`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Version {
+    pub version: String,
+    pub api_version: u32,
+    /// Names of the optional parts of the API the server supports, from [`feature`].
+    pub features: Vec<String>,
+}
+
+/// Reply of `GET /readyz`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Readiness {
+    pub ready: bool,
+    /// Why the server isn't ready, if it isn't.
+    #[serde(default)]
+    pub problems: Vec<String>,
+}
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Target {
     pub name: String,
     pub chip: String,
     pub probe: DebugProbeSelector,
+    #[serde(default)]
+    pub usb_path: Option<String>,
     pub connect_under_reset: bool,
     pub speed: Option<u32>,
     pub up: bool,
+    /// Taken out of service by an admin, so no runs are scheduled on it.
+    #[serde(default)]
+    pub maintenance: bool,
     pub power_reset: bool,
     pub cycle_delay_seconds: f64,
     pub max_settle_time_millis: u64,
+    #[serde(default)]
+    pub labels: BTreeMap<String, String>,
+}
+
+/// Body of `PUT /workers/{name}`, with which a worker registers its targets with the API server, replacing the
+/// ones it registered before.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct WorkerRegistration {
+    /// Version of teleprobe the worker runs.
+    pub version: String,
+    pub targets: Vec<Target>,
+    /// Jobs the worker took and still has, running or with a result to hand back. The API server hands out the
+    /// other jobs it gave the worker again, like after the worker restarted. Unset by older workers.
+    #[serde(default)]
+    pub jobs: Option<Vec<String>>,
+}
+
+/// Body of `POST /workers/{name}/heartbeat`, which a worker sends periodically to say it's still there.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Heartbeat {
+    /// Names of the worker's targets whose probes are plugged in.
+    pub up: Vec<String>,
+}
+
+/// A worker registered with the API server.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Worker {
+    pub name: String,
+    pub version: String,
+    /// Whether its last heartbeat is recent enough. Targets of workers that aren't alive are down.
+    pub alive: bool,
+    pub last_heartbeat: DateTime<Utc>,
+    /// Names of its targets.
+    pub targets: Vec<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct WorkerList {
+    pub workers: Vec<Worker>,
+}
+
+/// A job handed to a worker by `GET /workers/{name}/jobs`, with the images to run and how.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct WorkerJob {
+    pub job: Job,
+    /// The image that's run, base64 encoded.
+    pub image: String,
+    /// Images flashed before it, base64 encoded, in the order of `job.preload`.
+    #[serde(default)]
+    pub preload: Vec<String>,
+    #[serde(default)]
+    pub log_filter: Option<String>,
+    #[serde(default)]
+    pub host_timestamps: bool,
+    #[serde(default)]
+    pub raw_defmt: bool,
+}
+
+/// Body of `POST /workers/{name}/jobs/{id}/result`, with which a worker hands back a finished job.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct WorkerJobResult {
+    /// The job as the worker finished it, with its status, failure, timings and backtrace.
+    pub job: Job,
+    /// The run logs, base64 encoded.
+    pub logs: String,
+}
+
+/// What a worker relays with `POST /workers/{name}/jobs/{id}/relay/{kind}?offset={offset}` while it runs a job.
+pub mod relay {
+    /// The run logs so far.
+    pub const LOGS: &str = "logs";
+    /// The run report as JSON. The artifacts in it are relayed on their own.
+    pub const REPORT: &str = "report";
+    pub const CORE_DUMP: &str = "core_dump";
+    pub const COVERAGE: &str = "coverage";
+    /// The raw defmt stream.
+    pub const DEFMT: &str = "defmt";
+
+    pub const ALL: &[&str] = &[LOGS, REPORT, CORE_DUMP, COVERAGE, DEFMT];
+}
+
+/// Body of the server's error replies, as opposed to the logs failed runs reply with.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Error {
+    /// What went wrong, for scripts to tell errors apart, like `target_not_found` or `unauthorized`.
+    pub code: String,
+    pub message: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub details: Option<serde_json::Value>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct TargetList {
     pub targets: Vec<Target>,
 }
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum JobStatus {
+    Queued,
+    Running,
+    Succeeded,
+    Failed,
+}
+
+impl JobStatus {
+    pub fn is_finished(&self) -> bool {
+        matches!(self, JobStatus::Succeeded | JobStatus::Failed)
+    }
+}
+
+/// A GitHub check run to create for a job.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CheckRequest {
+    /// `owner/name` of the repository.
+    pub repo: String,
+    /// Commit the check run is created on.
+    pub sha: String,
+    pub name: String,
+}
+
+/// A GitHub check run created for a job.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CheckRun {
+    /// `owner/name` of the repository.
+    pub repo: String,
+    pub id: u64,
+}
+
+/// An image flashed before the one that's run, like a bootloader or a softdevice.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PreloadImage {
+    pub name: String,
+    pub format: ImageFormat,
+    #[serde(default)]
+    pub base_address: Option<u64>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Job {
+    pub id: String,
+    pub target: String,
+    pub status: JobStatus,
+    /// Timeout in seconds.
+    pub timeout: u64,
+    /// ID shared by all jobs submitted by one client invocation.
+    #[serde(default)]
+    pub run_group: Option<String>,
+    #[serde(default)]
+    pub failure: Option<RunError>,
+    #[serde(default)]
+    pub format: ImageFormat,
+    /// Load address of `bin` images.
+    #[serde(default)]
+    pub base_address: Option<u64>,
+    /// Images flashed before the one that's run, in order.
+    #[serde(default)]
+    pub preload: Vec<PreloadImage>,
+    /// Hex BLAKE2b-256 hash of the image that's run, to find the ELF to decode its raw defmt stream with.
+    #[serde(default)]
+    pub elf_hash: Option<String>,
+    /// `RUST_LOG`-style filter of the device logs returned, kept so runs resumed after a restart filter them too.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub log_filter: Option<String>,
+    /// Whether the logs returned have the host's wall-clock time on each line.
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub host_timestamps: bool,
+    /// Whether the raw defmt stream is kept, for `GET /jobs/{id}/defmt`.
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub raw_defmt: bool,
+    /// ID of the caller that submitted the job, to charge its run time to.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub caller: Option<String>,
+    /// The caller's weight, for scheduling.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub weight: Option<f64>,
+    /// Job whose passing result was reused from the server's result cache, instead of running.
+    #[serde(default)]
+    pub cached_from: Option<String>,
+    /// Server of the cluster that runs the job, once one has claimed it. Unset outside of clusters.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub node: Option<String>,
+    /// Worker whose target the job is on, on the API server it registered with.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub worker: Option<String>,
+    /// Timings of the last attempt at running the job.
+    #[serde(default)]
+    pub timings: PhaseTimings,
+    /// Where the firmware was when it crashed or timed out, innermost frame first.
+    #[serde(default)]
+    pub backtrace: Vec<BacktraceFrame>,
+    /// The firmware's panic, if it failed by panicking.
+    #[serde(default)]
+    pub panic: Option<PanicInfo>,
+    /// GitHub check run to create for the job, by the server that runs it.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub check_request: Option<CheckRequest>,
+    /// GitHub check run the job's result is reported to, once it's created.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub check_run: Option<CheckRun>,
+    pub created_at: DateTime<Utc>,
+    pub started_at: Option<DateTime<Utc>>,
+    pub finished_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Group {
+    pub id: String,
+    /// Overall status: running until all jobs are finished, then failed if any job failed.
+    pub status: JobStatus,
+    pub jobs: usize,
+    pub queued: usize,
+    pub running: usize,
+    pub succeeded: usize,
+    pub failed: usize,
+    /// Sum of the run time of all finished jobs, in seconds.
+    pub duration_secs: f64,
+    pub failures: Vec<Job>,
+}
+
+/// Progress of a chunked image upload, returned by `POST /blobs` and `PATCH /blobs/{hash}`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BlobUpload {
+    pub hash: String,
+    pub size: usize,
+    /// Bytes received so far. The next chunk has to start here.
+    pub offset: usize,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RunRecord {
+    pub job_id: String,
+    pub target: String,
+    pub status: JobStatus,
+    #[serde(default)]
+    pub failure: Option<RunError>,
+    #[serde(default)]
+    pub timings: PhaseTimings,
+    pub started_at: Option<DateTime<Utc>>,
+    pub finished_at: DateTime<Utc>,
+}
+
+/// Something an admin or a caller did to the farm, as kept in the audit log.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AuditEvent {
+    pub at: DateTime<Utc>,
+    /// Who did it, like `token #0`.
+    pub caller: String,
+    /// What was done, like `drain`, `maintenance_start`, `erase`, `power`, `lease`, `lease_release` or `cancel`.
+    pub action: String,
+    #[serde(default)]
+    pub target: Option<String>,
+    #[serde(default)]
+    pub job_id: Option<String>,
+}
+
+/// Live state of all targets, shown by the dashboard.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Status {
+    pub version: String,
+    pub draining: bool,
+    pub targets: Vec<TargetStatus>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TargetStatus {
+    pub name: String,
+    pub chip: String,
+    pub up: bool,
+    #[serde(default)]
+    pub maintenance: bool,
+    /// The job currently running on the target, if any.
+    pub running: Option<RunningJob>,
+    /// Number of jobs waiting for the target.
+    pub queued: usize,
+    /// The last runs on the target, newest first.
+    pub history: Vec<RunRecord>,
+}
+
+/// What the dashboard shows of a running job. It's served without auth, so it has nothing of who submitted the job
+/// or what it runs.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RunningJob {
+    pub id: String,
+    pub status: JobStatus,
+    pub started_at: Option<DateTime<Utc>>,
+}
+
+/// A gdb session on a target, served by the teleprobe server. Runs on the target wait until it ends.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct GdbSession {
+    pub target: String,
+    /// Port on the server for gdb to connect to, with `target extended-remote`.
+    pub port: u16,
+    /// When the session is stopped, if it isn't before.
+    pub expires_at: DateTime<Utc>,
+}
+
+fn is_false(b: &bool) -> bool {
+    !*b
+}
+
+/// The targets a server of a cluster last reported up, so the others know which jobs one of them can claim.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct NodeTargets {
+    pub node: String,
+    pub targets: Vec<String>,
+    pub reported_at: DateTime<Utc>,
+}
+
+/// A target leased by a caller, so runs leave it alone.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Lease {
+    pub target: String,
+    /// ID of the caller holding the lease.
+    pub holder: String,
+    pub expires_at: DateTime<Utc>,
+}
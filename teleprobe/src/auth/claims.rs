@@ -0,0 +1,174 @@
+use regex::Regex;
+use serde::{Deserialize, Deserializer};
+use serde_json::Value;
+
+/// Condition on the value of one OIDC token claim.
+///
+/// A plain string matches claims with exactly that value. Otherwise it's a map with any of
+/// `prefix`, `glob` (where `*` matches any run of characters and `?` any single one), `regex`
+/// (which must match the whole value) and `contains` (for array claims, like `aud`), all of
+/// which must match.
+#[derive(Clone, Deserialize)]
+#[serde(untagged)]
+pub enum ClaimMatcher {
+    Exact(String),
+    Pattern(ClaimPattern),
+}
+
+#[derive(Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ClaimPattern {
+    #[serde(default)]
+    prefix: Option<String>,
+    #[serde(default)]
+    glob: Option<String>,
+    #[serde(default, deserialize_with = "deserialize_regex")]
+    regex: Option<Regex>,
+    #[serde(default)]
+    contains: Option<String>,
+}
+
+impl ClaimMatcher {
+    pub fn matches(&self, value: Option<&Value>) -> bool {
+        match self {
+            ClaimMatcher::Exact(wanted) => value.and_then(Value::as_str) == Some(wanted.as_str()),
+            ClaimMatcher::Pattern(p) => p.matches(value),
+        }
+    }
+}
+
+impl ClaimPattern {
+    /// Patterns without conditions, or with an empty one, match nothing, so a rule left empty by mistake doesn't
+    /// let everyone in.
+    fn is_empty(&self) -> bool {
+        let empty = |s: &Option<String>| s.as_deref() == Some("");
+        let none = self.prefix.is_none() && self.glob.is_none() && self.regex.is_none() && self.contains.is_none();
+        none || empty(&self.prefix) || empty(&self.glob) || empty(&self.contains)
+    }
+
+    fn matches(&self, value: Option<&Value>) -> bool {
+        let Some(value) = value else {
+            return false;
+        };
+        if self.is_empty() {
+            return false;
+        }
+
+        if let Some(wanted) = &self.contains {
+            let found = match value {
+                Value::Array(items) => items.iter().any(|v| v.as_str() == Some(wanted.as_str())),
+                Value::String(s) => s == wanted,
+                _ => false,
+            };
+            if !found {
+                return false;
+            }
+        }
+
+        if self.prefix.is_none() && self.glob.is_none() && self.regex.is_none() {
+            return true;
+        }
+        let Some(s) = value.as_str() else {
+            return false;
+        };
+        self.prefix.as_ref().map_or(true, |p| s.starts_with(p.as_str()))
+            && self.glob.as_ref().map_or(true, |g| glob_matches(g, s))
+            && self.regex.as_ref().map_or(true, |r| r.is_match(s))
+    }
+}
+
+fn deserialize_regex<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<Regex>, D::Error> {
+    let pattern = String::deserialize(deserializer)?;
+    // Anchor it, so that a rule like `embassy-rs/.*` can't be satisfied by `evil/embassy-rs/x`.
+    Regex::new(&format!("^(?:{})$", pattern))
+        .map(Some)
+        .map_err(serde::de::Error::custom)
+}
+
+/// An empty pattern matches nothing, not even an empty string.
+fn glob_matches(pattern: &str, s: &str) -> bool {
+    if pattern.is_empty() {
+        return false;
+    }
+    let p: Vec<char> = pattern.chars().collect();
+    let s: Vec<char> = s.chars().collect();
+
+    // Greedy matching with backtracking to the last `*`.
+    let (mut pi, mut si) = (0, 0);
+    let mut star: Option<(usize, usize)> = None;
+    while si < s.len() {
+        if pi < p.len() && (p[pi] == '?' || p[pi] == s[si]) {
+            pi += 1;
+            si += 1;
+        } else if pi < p.len() && p[pi] == '*' {
+            star = Some((pi, si));
+            pi += 1;
+        } else if let Some((star_pi, star_si)) = star {
+            pi = star_pi + 1;
+            si = star_si + 1;
+            star = Some((star_pi, star_si + 1));
+        } else {
+            return false;
+        }
+    }
+    p[pi..].iter().all(|&c| c == '*')
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn matcher(yaml: &str) -> ClaimMatcher {
+        serde_yaml::from_str(yaml).unwrap()
+    }
+
+    #[test]
+    fn glob() {
+        assert!(glob_matches("embassy-rs/*", "embassy-rs/teleprobe"));
+        assert!(glob_matches("*/teleprobe", "embassy-rs/teleprobe"));
+        assert!(glob_matches("embassy-rs/tele?robe", "embassy-rs/teleprobe"));
+        assert!(glob_matches("*a*b*", "xxaxxbxx"));
+        assert!(glob_matches("*", ""));
+        assert!(!glob_matches("embassy-rs/*", "evil/embassy-rs/x"));
+        assert!(!glob_matches("embassy-rs/?", "embassy-rs/"));
+        assert!(!glob_matches("a*b", "ac"));
+    }
+
+    #[test]
+    fn empty_glob_matches_nothing() {
+        assert!(!glob_matches("", ""));
+        assert!(!glob_matches("", "anything"));
+    }
+
+    #[test]
+    fn exact() {
+        let m = matcher("embassy-rs/teleprobe");
+        assert!(m.matches(Some(&json!("embassy-rs/teleprobe"))));
+        assert!(!m.matches(Some(&json!("embassy-rs/teleprobe2"))));
+        assert!(!m.matches(None));
+    }
+
+    #[test]
+    fn empty_pattern_matches_nothing() {
+        assert!(!matcher("{}").matches(Some(&json!("anything"))));
+        assert!(!matcher("glob: ''").matches(Some(&json!(""))));
+        assert!(!matcher("prefix: ''").matches(Some(&json!("anything"))));
+    }
+
+    #[test]
+    fn pattern() {
+        let m = matcher("{ prefix: refs/heads/, glob: '*/release-*' }");
+        assert!(m.matches(Some(&json!("refs/heads/release-1.0"))));
+        assert!(!m.matches(Some(&json!("refs/tags/release-1.0"))));
+
+        let m = matcher("regex: 'embassy-rs/.*'");
+        assert!(m.matches(Some(&json!("embassy-rs/teleprobe"))));
+        assert!(!m.matches(Some(&json!("evil/embassy-rs/teleprobe"))));
+
+        let m = matcher("contains: teleprobe");
+        assert!(m.matches(Some(&json!(["other", "teleprobe"]))));
+        assert!(!m.matches(Some(&json!(["other"]))));
+    }
+}
@@ -1 +1,2 @@
+pub mod claims;
 pub mod oidc;
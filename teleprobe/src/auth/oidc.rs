@@ -1,8 +1,20 @@
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
 use anyhow::{anyhow, bail};
 use jsonwebtoken::{Algorithm, DecodingKey, Validation};
+use log::{info, warn};
+use parking_lot::Mutex;
 use serde::de::DeserializeOwned;
 use serde::Deserialize;
 
+/// How often the keys are refetched, so rotated keys are picked up before any token uses them.
+const KEYS_REFRESH_INTERVAL: Duration = Duration::from_secs(60 * 60);
+/// Minimum time between refetches, so tokens with bogus key IDs can't make us flood the issuer.
+const KEYS_MIN_REFETCH_INTERVAL: Duration = Duration::from_secs(30);
+/// Longest wait before retrying after failed refetches.
+const KEYS_MAX_BACKOFF: Duration = Duration::from_secs(60 * 60);
+
 #[allow(dead_code)]
 #[derive(Clone, Deserialize)]
 struct OpenIDConfiguration {
@@ -33,7 +45,15 @@ struct JsonWebKey {
 #[derive(Clone)]
 pub struct Client {
     oidc_config: OpenIDConfiguration,
+    keys: Arc<Mutex<KeyCache>>,
+}
+
+struct KeyCache {
     keys: JsonWebKeySet,
+    /// No refetch is attempted before this.
+    next_fetch: Instant,
+    /// Number of refetches that failed in a row.
+    failures: u32,
 }
 
 impl Client {
@@ -44,24 +64,90 @@ impl Client {
         }
         config_url.push_str(".well-known/openid-configuration");
         let oidc_config: OpenIDConfiguration = reqwest::get(config_url).await?.json().await?;
-        let keys: JsonWebKeySet = reqwest::get(&oidc_config.jwks_uri).await?.json().await?;
+        let keys = fetch_keys(&oidc_config.jwks_uri).await?;
+
+        Ok(Self {
+            oidc_config,
+            keys: Arc::new(Mutex::new(KeyCache {
+                keys,
+                next_fetch: Instant::now() + KEYS_MIN_REFETCH_INTERVAL,
+                failures: 0,
+            })),
+        })
+    }
 
-        Ok(Self { oidc_config, keys })
+    /// Periodically refetch the keys in the background.
+    pub fn spawn_refresh(&self) {
+        let client = self.clone();
+        tokio::spawn(async move {
+            loop {
+                tokio::time::sleep(KEYS_REFRESH_INTERVAL).await;
+                client.refresh_keys().await;
+            }
+        });
     }
 
-    pub fn validate_token<T>(&self, token: &str) -> anyhow::Result<T>
+    /// Refetch the keys, unless the last attempt was too recent. Failures are retried with exponential backoff.
+    async fn refresh_keys(&self) {
+        {
+            let mut cache = self.keys.lock();
+            if Instant::now() < cache.next_fetch {
+                return;
+            }
+            // Claim this attempt, so concurrent callers don't fetch too.
+            cache.next_fetch = Instant::now() + KEYS_MIN_REFETCH_INTERVAL;
+        }
+
+        let res = fetch_keys(&self.oidc_config.jwks_uri).await;
+
+        let mut cache = self.keys.lock();
+        match res {
+            Ok(keys) => {
+                info!(
+                    "Fetched {} OIDC keys from {}",
+                    keys.keys.len(),
+                    self.oidc_config.jwks_uri
+                );
+                cache.keys = keys;
+                cache.failures = 0;
+            }
+            Err(e) => {
+                cache.failures += 1;
+                let backoff = (KEYS_MIN_REFETCH_INTERVAL * (1 << cache.failures.min(8))).min(KEYS_MAX_BACKOFF);
+                cache.next_fetch = Instant::now() + backoff;
+                warn!(
+                    "Failed to fetch OIDC keys from {}: {}, retrying in {:?}",
+                    self.oidc_config.jwks_uri, e, backoff
+                );
+            }
+        }
+    }
+
+    /// Whether any of the issuer's keys were fetched, so tokens can be validated.
+    pub fn has_keys(&self) -> bool {
+        !self.keys.lock().keys.keys.is_empty()
+    }
+
+    fn find_key(&self, kid: &str) -> Option<JsonWebKey> {
+        self.keys.lock().keys.keys.iter().find(|k| k.kid == kid).cloned()
+    }
+
+    pub async fn validate_token<T>(&self, token: &str) -> anyhow::Result<T>
     where
         T: DeserializeOwned,
     {
         let header = jsonwebtoken::decode_header(token)?;
         let kid = header.kid.ok_or_else(|| anyhow!("header.kid empty"))?;
 
-        let key = self
-            .keys
-            .keys
-            .iter()
-            .find(|k| k.kid == kid)
-            .ok_or_else(|| anyhow!("key with kid {} not found in set", kid))?;
+        let key = match self.find_key(&kid) {
+            Some(key) => key,
+            None => {
+                // The issuer might have rotated its keys.
+                self.refresh_keys().await;
+                self.find_key(&kid)
+                    .ok_or_else(|| anyhow!("key with kid {} not found in set", kid))?
+            }
+        };
 
         if key.alg != header.alg {
             bail!("Key alg mismatch");
@@ -79,3 +165,7 @@ impl Client {
         }
     }
 }
+
+async fn fetch_keys(jwks_uri: &str) -> anyhow::Result<JsonWebKeySet> {
+    Ok(reqwest::get(jwks_uri).await?.error_for_status()?.json().await?)
+}
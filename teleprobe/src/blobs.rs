@@ -0,0 +1,303 @@
+//! Recently uploaded images, by hash, so clients running the same binary on several targets only
+//! have to upload it once. Large images can also be uploaded in chunks, so a dropped connection only
+//! means resending the chunk that was in flight.
+
+use std::collections::{HashMap, VecDeque};
+
+use anyhow::bail;
+use bytes::{Bytes, BytesMut};
+use parking_lot::Mutex;
+
+/// Total size of the images kept and of the chunked uploads in progress. The oldest images are dropped first, then
+/// the uploads last written to longest ago.
+const MAX_BYTES: usize = 256 * 1024 * 1024;
+/// Chunked uploads in progress. Starting more drops the one that was last written to longest ago.
+const MAX_PARTIAL: usize = 16;
+
+#[derive(Default)]
+pub struct Store {
+    inner: Mutex<Inner>,
+}
+
+#[derive(Default)]
+struct Inner {
+    blobs: HashMap<String, Bytes>,
+    /// Hashes, oldest first.
+    order: VecDeque<String>,
+    /// Size of the images, plus the full size of the uploads in progress, which they may grow to.
+    size: usize,
+    partial: HashMap<String, Partial>,
+}
+
+struct Partial {
+    size: usize,
+    data: BytesMut,
+    /// Bumped on every write, to find the stalest upload.
+    generation: u64,
+}
+
+/// Progress of a chunked upload.
+pub struct Progress {
+    pub size: usize,
+    /// How much has been received so far, where the next chunk goes.
+    pub offset: usize,
+}
+
+/// Hex BLAKE2b-256 hash of `data`, the key images are stored under.
+pub fn hash(data: &[u8]) -> String {
+    // NOTE (unwrap): the digest size is fixed and valid.
+    hex::encode(orion::hash::digest(data).unwrap())
+}
+
+impl Store {
+    pub fn get(&self, hash: &str) -> Option<Bytes> {
+        self.inner.lock().blobs.get(hash).cloned()
+    }
+
+    /// Start a chunked upload of an image of `size` bytes with hash `hash`, or look up how far it got.
+    /// Images the store already has are complete right away.
+    pub fn start(&self, hash: &str, size: usize) -> anyhow::Result<Progress> {
+        if size == 0 || size > MAX_BYTES {
+            bail!("Image size must be between 1 and {} bytes", MAX_BYTES);
+        }
+
+        let mut inner = self.inner.lock();
+        if let Some(data) = inner.blobs.get(hash) {
+            return Ok(Progress {
+                size: data.len(),
+                offset: data.len(),
+            });
+        }
+        if let Some(partial) = inner.partial.get(hash) {
+            if partial.size == size {
+                return Ok(partial.progress());
+            }
+        }
+
+        // Starting over with another size.
+        inner.remove_partial(hash);
+        if inner.partial.len() >= MAX_PARTIAL {
+            // NOTE (unwrap): the map is full, so not empty.
+            let stalest = inner.stalest_partial().unwrap();
+            inner.remove_partial(&stalest);
+        }
+        inner.make_room(size);
+        inner.size += size;
+        // The buffer grows as chunks arrive, so declaring a large size doesn't reserve memory on its own.
+        let partial = Partial {
+            size,
+            data: BytesMut::new(),
+            generation: next_generation(&inner),
+        };
+        let progress = partial.progress();
+        inner.partial.insert(hash.to_string(), partial);
+        Ok(progress)
+    }
+
+    /// Append a chunk at `offset` to a chunked upload. Once all of it is there, it's checked against
+    /// its hash and stored.
+    pub fn write(&self, hash: &str, offset: usize, chunk: &[u8]) -> anyhow::Result<Progress> {
+        let mut inner = self.inner.lock();
+        let generation = next_generation(&inner);
+        let Some(partial) = inner.partial.get_mut(hash) else {
+            if let Some(data) = inner.blobs.get(hash) {
+                return Ok(Progress {
+                    size: data.len(),
+                    offset: data.len(),
+                });
+            }
+            bail!("No upload in progress for {}", hash);
+        };
+        if offset != partial.data.len() {
+            bail!(
+                "Chunk is at offset {}, but the upload is at {}",
+                offset,
+                partial.data.len()
+            );
+        }
+        if offset + chunk.len() > partial.size {
+            bail!("Chunk goes past the image size {}", partial.size);
+        }
+        partial.data.extend_from_slice(chunk);
+        partial.generation = generation;
+
+        let progress = partial.progress();
+        if progress.offset < progress.size {
+            return Ok(progress);
+        }
+
+        // NOTE (unwrap): it was found above.
+        let partial = inner.remove_partial(hash).unwrap();
+        drop(inner);
+        let data = partial.data.freeze();
+        let digest = self.insert(data);
+        if digest != hash {
+            bail!("Uploaded image hashes to {}, not {}", digest, hash);
+        }
+        Ok(progress)
+    }
+
+    /// Keep `data` around, and return its hash.
+    pub fn insert(&self, data: Bytes) -> String {
+        let hash = hash(&data);
+        if data.len() > MAX_BYTES {
+            return hash;
+        }
+
+        let mut inner = self.inner.lock();
+        if inner.blobs.contains_key(&hash) {
+            return hash;
+        }
+        inner.make_room(data.len());
+        inner.size += data.len();
+        inner.order.push_back(hash.clone());
+        inner.blobs.insert(hash.clone(), data);
+        hash
+    }
+}
+
+impl Inner {
+    /// Drop the oldest images, then the stalest uploads, until `len` more bytes fit.
+    fn make_room(&mut self, len: usize) {
+        while self.size + len > MAX_BYTES {
+            if let Some(oldest) = self.order.pop_front() {
+                // NOTE (unwrap): hashes in `order` are in `blobs`.
+                self.size -= self.blobs.remove(&oldest).unwrap().len();
+            } else {
+                // NOTE (unwrap): size is only nonzero while there are images or uploads.
+                let stalest = self.stalest_partial().unwrap();
+                self.remove_partial(&stalest);
+            }
+        }
+    }
+
+    /// The upload last written to longest ago.
+    fn stalest_partial(&self) -> Option<String> {
+        self.partial
+            .iter()
+            .min_by_key(|(_, p)| p.generation)
+            .map(|(hash, _)| hash.clone())
+    }
+
+    fn remove_partial(&mut self, hash: &str) -> Option<Partial> {
+        let partial = self.partial.remove(hash)?;
+        self.size -= partial.size;
+        Some(partial)
+    }
+}
+
+impl Partial {
+    fn progress(&self) -> Progress {
+        Progress {
+            size: self.size,
+            offset: self.data.len(),
+        }
+    }
+}
+
+fn next_generation(inner: &Inner) -> u64 {
+    inner.partial.values().map(|p| p.generation + 1).max().unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn insert_and_get() {
+        let store = Store::default();
+        let hash = store.insert(Bytes::from_static(b"firmware"));
+        assert_eq!(hash, super::hash(b"firmware"));
+        assert_eq!(store.get(&hash).unwrap(), &b"firmware"[..]);
+        assert!(store.get(&super::hash(b"other")).is_none());
+    }
+
+    #[test]
+    fn chunked_upload() {
+        let store = Store::default();
+        let hash = super::hash(b"firmware");
+        let progress = store.start(&hash, 8).unwrap();
+        assert_eq!((progress.size, progress.offset), (8, 0));
+        assert_eq!(store.write(&hash, 0, b"firm").unwrap().offset, 4);
+        assert!(store.get(&hash).is_none());
+        assert_eq!(store.write(&hash, 4, b"ware").unwrap().offset, 8);
+        assert_eq!(store.get(&hash).unwrap(), &b"firmware"[..]);
+    }
+
+    #[test]
+    fn chunk_at_wrong_offset() {
+        let store = Store::default();
+        let hash = super::hash(b"firmware");
+        store.start(&hash, 8).unwrap();
+        store.write(&hash, 0, b"firm").unwrap();
+        assert!(store.write(&hash, 2, b"rmwa").is_err());
+        assert!(store.write(&hash, 4, b"ware!").is_err());
+        assert!(store.write(&super::hash(b"other"), 0, b"x").is_err());
+    }
+
+    #[test]
+    fn hash_mismatch() {
+        let store = Store::default();
+        let hash = super::hash(b"firmware");
+        store.start(&hash, 8).unwrap();
+        assert!(store.write(&hash, 0, b"hardware").is_err());
+        assert!(store.get(&hash).is_none());
+    }
+
+    #[test]
+    fn size_out_of_range() {
+        let store = Store::default();
+        assert!(store.start("abc", 0).is_err());
+        assert!(store.start("abc", MAX_BYTES + 1).is_err());
+    }
+
+    #[test]
+    fn start_stored_image() {
+        let store = Store::default();
+        let hash = store.insert(Bytes::from_static(b"firmware"));
+        let progress = store.start(&hash, 8).unwrap();
+        assert_eq!((progress.size, progress.offset), (8, 8));
+    }
+
+    #[test]
+    fn restart_resumes() {
+        let store = Store::default();
+        let hash = super::hash(b"firmware");
+        store.start(&hash, 8).unwrap();
+        store.write(&hash, 0, b"firm").unwrap();
+        assert_eq!(store.start(&hash, 8).unwrap().offset, 4);
+        // Another size starts over.
+        assert_eq!(store.start(&hash, 9).unwrap().offset, 0);
+    }
+
+    #[test]
+    fn stalest_partial_is_dropped() {
+        let store = Store::default();
+        let hashes: Vec<String> = (0..MAX_PARTIAL).map(|i| super::hash(&[i as u8])).collect();
+        for hash in &hashes {
+            store.start(hash, 2).unwrap();
+        }
+        // Writing to the first makes the second the stalest.
+        store.write(&hashes[0], 0, b"x").unwrap();
+        store.start(&super::hash(b"new"), 2).unwrap();
+        assert_eq!(store.start(&hashes[0], 2).unwrap().offset, 1);
+        assert!(store.write(&hashes[1], 0, b"x").is_err());
+    }
+
+    #[test]
+    fn uploads_count_against_the_size() {
+        let store = Store::default();
+        let stored = store.insert(Bytes::from_static(b"firmware"));
+        // Making room for an upload drops the images first.
+        let large = super::hash(b"large");
+        store.start(&large, MAX_BYTES).unwrap();
+        assert!(store.get(&stored).is_none());
+
+        // Then the uploads.
+        let small = super::hash(b"small");
+        store.start(&small, 5).unwrap();
+        assert!(store.write(&large, 0, b"x").is_err());
+        assert_eq!(store.write(&small, 0, b"small").unwrap().offset, 5);
+        assert_eq!(store.get(&small).unwrap(), &b"small"[..]);
+    }
+}
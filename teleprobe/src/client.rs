@@ -1,55 +1,149 @@
-use std::collections::{HashMap, HashSet};
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::{self, Write as _};
 use std::fs::File;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use std::pin::pin;
+use std::time::Duration;
 
-use anyhow::{bail, Context};
+use anyhow::{anyhow, bail, Context};
+use chrono::{DateTime, Utc};
+use futures::future::{self, Either};
 use futures::{stream, StreamExt};
 use log::{error, info, warn};
 use object::{Object, ObjectSection};
 use orion::hazardous::hash::blake2::blake2b::Blake2b;
 use reqwest::Client;
 use serde::{Deserialize, Serialize};
+use teleprobe_core::meta::ElfMetadata;
+use teleprobe_core::RunReport;
+use tokio::sync::{watch, Semaphore};
+use tokio::time::Instant;
 use walkdir::WalkDir;
 
-use crate::api;
+use crate::logutil::VERBATIM_TARGET;
+use crate::selector::Selector;
+use crate::{api, blobs};
 
 #[derive(clap::Parser)]
 pub struct Command {
     #[clap(flatten)]
-    credentials: Credentials,
+    credentials: CredentialArgs,
+
+    #[clap(flatten)]
+    retry: RetryArgs,
 
     #[clap(subcommand)]
     cmd: Subcommand,
 }
 
+/// Server credentials. Optional on the command line because `check` doesn't need them.
 #[derive(clap::Parser)]
-struct Credentials {
+struct CredentialArgs {
     #[clap(long, env = "TELEPROBE_TOKEN")]
-    token: String,
+    token: Option<String>,
 
     #[clap(long, env = "TELEPROBE_HOST")]
+    host: Option<String>,
+}
+
+impl CredentialArgs {
+    fn get(self, retry: RetryArgs) -> anyhow::Result<Credentials> {
+        let (Some(token), Some(host)) = (self.token, self.host) else {
+            bail!("`--token` and `--host` are required to talk to the server.");
+        };
+        if !host.starts_with("http") {
+            bail!("Host must start with `http`.");
+        }
+        Ok(Credentials { token, host, retry })
+    }
+}
+
+struct Credentials {
+    token: String,
     host: String,
+    /// How requests to the server are retried.
+    retry: RetryArgs,
 }
 
+/// Retries of requests that fail for transient reasons: network errors, rate limiting and server errors.
+/// Runs that failed are never retried.
+#[derive(clap::Parser, Clone, Copy)]
+struct RetryArgs {
+    /// How many times to retry a failed request.
+    #[clap(long, default_value_t = 3)]
+    retries: u32,
+
+    /// Delay before the first retry, in milliseconds. It doubles with every retry, with random jitter.
+    #[clap(long, default_value_t = 1000)]
+    retry_delay_ms: u64,
+}
+
+impl RetryArgs {
+    /// How long to wait before retry number `attempt`, counting from 0.
+    fn backoff(&self, attempt: u32) -> Duration {
+        let delay = Duration::from_millis(self.retry_delay_ms)
+            .saturating_mul(1u32 << attempt.min(16))
+            .min(MAX_RETRY_DELAY);
+        // Spread retries of concurrent jobs out, between half and all of the delay.
+        let mut jitter = [0u8; 1];
+        // NOTE (unwrap): only fails if the OS RNG is unavailable.
+        orion::util::secure_rand_bytes(&mut jitter).unwrap();
+        delay.mul_f64(0.5 + jitter[0] as f64 / 510.0)
+    }
+}
+
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(60);
+
 #[derive(clap::Parser)]
 enum Subcommand {
     ListTargets,
     Run(RunCommand),
+    Check(CheckCommand),
+    /// Lease a target, so runs leave it alone until it's released. Waits for the job running on it, if any.
+    Lease {
+        target: String,
+
+        /// How long to lease it for, in seconds. Defaults to the longest the server allows.
+        #[clap(long)]
+        ttl: Option<u64>,
+
+        /// Extend a lease held already to `--ttl` from now, instead of taking a new one.
+        #[clap(long)]
+        renew: bool,
+    },
+    /// Release a leased target.
+    Release {
+        target: String,
+    },
 }
 
 #[derive(clap::Parser)]
 pub struct RunCommand {
     /// Teleprobe target to run the ELFs in.
-    /// If not specified, it will be autodetected based on the value of the `.teleprobe.target` section from the ELF.
+    /// If not specified, it will be autodetected based on the value of the `.teleprobe.target`
+    /// or `.teleprobe.labels` section from the ELF.
     #[clap(long)]
     target: Option<String>,
 
+    /// Label selector, like `chip-family=stm32,has-ethernet=true`.
+    /// Runs the ELFs on any target matching it, picked by the server.
+    #[clap(long, conflicts_with = "target")]
+    selector: Option<Selector>,
+
+    /// Run each ELF on all targets matching `--selector`, instead of on any one of them.
+    #[clap(long, requires = "selector")]
+    all_targets: bool,
+
     /// Cache file to avoid re-running binaries.
     /// If not specified, all binaries will be run.
     /// If specified, only the binaries that have changed will be run.
     #[clap(long, env = "TELEPROBE_CACHE")]
     cache: Option<String>,
 
+    /// How long, in seconds, a passed run in the cache is trusted before the binary is run again.
+    #[clap(long, default_value_t = 7 * 24 * 60 * 60)]
+    cache_max_age: u64,
+
     /// ELF files to flash+run
     files: Vec<String>,
 
@@ -61,90 +155,454 @@ pub struct RunCommand {
     #[clap(short)]
     show_output: bool,
 
-    /// Override job timeout
-    #[clap(short)]
+    /// How to print the results and logs of jobs.
+    #[clap(long, value_enum, default_value_t = OutputStyle::Auto)]
+    output_style: OutputStyle,
+
+    /// Override job timeout, in seconds. Takes precedence over the timeout embedded in the ELF.
+    #[clap(short, long)]
     timeout: Option<u64>,
+
+    /// Give up on all jobs that haven't finished after this many seconds, and report them as failed.
+    /// Job timeouts are clamped so they end before it.
+    #[clap(long)]
+    overall_deadline: Option<u64>,
+
+    /// ID to tag all jobs of this invocation with, so the server can report on them as a whole
+    /// with `GET /groups/{id}`. If not specified, a random one is generated.
+    #[clap(long, env = "TELEPROBE_RUN_GROUP")]
+    run_group: Option<String>,
+
+    /// Submit jobs asynchronously and poll the server for their results,
+    /// instead of keeping the HTTP connection open for the whole run.
+    #[clap(long)]
+    poll: bool,
+
+    /// Write a JSON report of each run to this directory, named after the ELF and the target: how it
+    /// ended, the backtrace, the decoded logs and timings.
+    #[clap(long, requires = "poll")]
+    report_dir: Option<PathBuf>,
+
+    /// Write the raw defmt stream of each run to this directory, named after the ELF and the target, to decode
+    /// it again later, like with `defmt-print -e <elf>`.
+    #[clap(long, requires = "poll")]
+    raw_defmt_dir: Option<PathBuf>,
+
+    /// How to compress uploaded ELFs.
+    #[clap(long, value_enum, default_value_t = Compression::Gzip)]
+    compression: Compression,
+
+    /// Upload ELFs larger than this many bytes in chunks of this size, retrying failed chunks
+    /// without starting over. Servers take chunks of up to 16 MiB.
+    #[clap(long, default_value_t = 1024 * 1024)]
+    chunk_size: usize,
+
+    /// Whether the server takes runs of images uploaded before by hash. From the server's features.
+    #[clap(skip)]
+    images_by_hash: bool,
+
+    /// How many jobs to run at once on each target.
+    #[clap(long, default_value_t = 2)]
+    jobs_per_target: usize,
+
+    /// How many jobs to run at once in total, across all targets. Unlimited if not specified.
+    #[clap(long)]
+    max_total_jobs: Option<usize>,
+
+    /// Order in which each target's jobs are submitted.
+    #[clap(long, value_enum, default_value_t = Order::Given)]
+    order: Order,
+
+    /// Stop at the first failed job: jobs that haven't started are skipped, and running ones are cancelled.
+    #[clap(long)]
+    fail_fast: bool,
+
+    /// Run all jobs, even if some of them fail. This is the default, and overrides an earlier `--fail-fast`.
+    #[clap(long, overrides_with = "fail_fast")]
+    keep_going: bool,
+
+    /// What to do with jobs for targets the server reports as down. Without it, they're submitted like any other.
+    #[clap(long, value_enum)]
+    down_targets: Option<DownTargets>,
+
+    /// Run the ELFs even if the server has a recent passing result for them, on servers with a result cache.
+    #[clap(long)]
+    no_server_cache: bool,
+
+    /// Resubmit jobs for a selector that failed because of the probe or the board, like with `probe_not_found`
+    /// or `flash_error`, to up to this many other targets matching it.
+    #[clap(long, default_value_t = 0, requires = "poll")]
+    retry_other_targets: usize,
+
+    /// Probe clock speed in kHz, instead of the target's.
+    #[clap(long)]
+    speed: Option<u32>,
+
+    /// Only return the device logs this `RUST_LOG`-style filter lets through, like `warn,net=trace`.
+    #[clap(long)]
+    log_filter: Option<String>,
+
+    /// Prefix each log line with the server's wall-clock time, to line device logs up with power cycles,
+    /// stimulus and UART output.
+    #[clap(long)]
+    host_timestamps: bool,
+
+    /// GitHub repository (`owner/name`) to report each job to as a check run, on servers set up for it.
+    #[clap(long, env = "GITHUB_REPOSITORY", requires = "github_sha")]
+    github_repo: Option<String>,
+
+    /// Commit to create the check runs on.
+    #[clap(long, env = "GITHUB_SHA")]
+    github_sha: Option<String>,
 }
 
-pub async fn main(cmd: Command) -> anyhow::Result<()> {
-    if !cmd.credentials.host.starts_with("http") {
-        anyhow::bail!("Host must start with `http`.");
+#[derive(Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+enum OutputStyle {
+    /// `github` on GitHub Actions, `plain` elsewhere.
+    Auto,
+    /// Log lines.
+    Plain,
+    /// GitHub Actions workflow commands: each job's logs in a collapsible group, and failures annotated at
+    /// their source location.
+    Github,
+    /// A colored header for each job, followed by its logs with errors and warnings highlighted.
+    Ansi,
+}
+
+impl OutputStyle {
+    fn resolve(self) -> Self {
+        match self {
+            OutputStyle::Auto if std::env::var("GITHUB_ACTIONS").is_ok_and(|v| v == "true") => OutputStyle::Github,
+            OutputStyle::Auto => OutputStyle::Plain,
+            style => style,
+        }
+    }
+
+    /// Print how a job went, and its logs if given. Jobs run concurrently, so everything is printed at once, for
+    /// the logs of different jobs not to interleave.
+    fn print_result(
+        self,
+        job: &Job,
+        error: Option<&anyhow::Error>,
+        logs: Option<&str>,
+        location: Option<(&str, Option<u32>)>,
+    ) {
+        let title = format!("{} {}", job.target, job.path.display());
+        let status = match error {
+            Some(e) => format!("FAILED: {}", e),
+            None => "OK".to_string(),
+        };
+        let mut out = String::new();
+        match self {
+            OutputStyle::Auto | OutputStyle::Plain => {
+                match error {
+                    Some(_) => error!("=== {}: {}", title, status),
+                    None => info!("=== {}: {}", title, status),
+                }
+                if let Some(logs) = logs {
+                    match error {
+                        Some(_) => error!("{}", logs),
+                        None => info!("{}", logs),
+                    }
+                }
+                return;
+            }
+            OutputStyle::Github => {
+                if let Some(e) = error {
+                    write!(out, "::error ").unwrap();
+                    if let Some((file, line)) = location.and_then(|(f, l)| Some((workspace_path(f)?, l))) {
+                        write!(out, "file={},", escape_property(&file)).unwrap();
+                        if let Some(line) = line {
+                            write!(out, "line={},", line).unwrap();
+                        }
+                    }
+                    writeln!(
+                        out,
+                        "title={}::{}",
+                        escape_property(&title),
+                        escape_data(&e.to_string())
+                    )
+                    .unwrap();
+                }
+                match logs {
+                    Some(logs) => {
+                        writeln!(out, "::group::=== {}: {}", title, status).unwrap();
+                        writeln!(out, "{}", logs.trim_end()).unwrap();
+                        writeln!(out, "::endgroup::").unwrap();
+                    }
+                    None => writeln!(out, "=== {}: {}", title, status).unwrap(),
+                }
+            }
+            OutputStyle::Ansi => {
+                let color = if error.is_some() { RED } else { GREEN };
+                writeln!(out, "{}=== {}:{} {}{}{}", BOLD, title, RESET, color, status, RESET).unwrap();
+                for line in logs.unwrap_or_default().lines() {
+                    match line.split_once(" - ").map(|(level, _)| level) {
+                        Some("ERROR") => writeln!(out, "{}{}{}", RED, line, RESET).unwrap(),
+                        Some("WARN") => writeln!(out, "{}{}{}", YELLOW, line, RESET).unwrap(),
+                        _ => writeln!(out, "{}", line).unwrap(),
+                    }
+                }
+            }
+        }
+        // Through the logger, for the output not to interleave with the log lines.
+        info!(target: VERBATIM_TARGET, "{}", out.trim_end());
+    }
+}
+
+/// `file` relative to the GitHub Actions workspace, or the current directory elsewhere, for annotations to attach
+/// to it. None for a file outside of it, like a dependency's in the cargo registry. Relative paths are kept.
+fn workspace_path(file: &str) -> Option<String> {
+    let path = Path::new(file);
+    if path.is_relative() {
+        return Some(file.to_string());
+    }
+    let workspace = match std::env::var_os("GITHUB_WORKSPACE") {
+        Some(w) => PathBuf::from(w),
+        None => std::env::current_dir().ok()?,
+    };
+    let relative = path.strip_prefix(workspace).ok()?;
+    Some(relative.to_str()?.replace('\\', "/"))
+}
+
+const BOLD: &str = "\x1b[1m";
+const RED: &str = "\x1b[31m";
+const GREEN: &str = "\x1b[32m";
+const YELLOW: &str = "\x1b[33m";
+const RESET: &str = "\x1b[0m";
+
+/// Escape the message of a GitHub Actions workflow command.
+fn escape_data(s: &str) -> String {
+    s.replace('%', "%25").replace('\r', "%0D").replace('\n', "%0A")
+}
+
+/// Escape a property of a GitHub Actions workflow command, like `file=`.
+fn escape_property(s: &str) -> String {
+    escape_data(s).replace(':', "%3A").replace(',', "%2C")
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+enum DownTargets {
+    /// Submit them anyway.
+    Warn,
+    /// Don't submit them, without failing the run.
+    Skip,
+    /// Don't submit them, and count them as failed.
+    Fail,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+enum Order {
+    /// In the order the files were given.
+    Given,
+    /// Jobs with the shortest timeout first, so quick tests report early. Jobs without one go last.
+    ShortestFirst,
+}
+
+/// How ELFs are sent to the server.
+#[derive(Clone, Copy)]
+struct UploadOptions {
+    compression: Compression,
+    chunk_size: usize,
+}
+
+/// How a job went.
+struct Outcome {
+    result: anyhow::Result<()>,
+    logs: String,
+    report: Option<RunReport>,
+    /// The job as the server last reported it, when polling.
+    info: Option<api::Job>,
+}
+
+impl Outcome {
+    fn new(result: anyhow::Result<()>, logs: String) -> Self {
+        Self {
+            result,
+            logs,
+            report: None,
+            info: None,
+        }
+    }
+}
+
+/// How a job is submitted and waited for.
+struct SubmitOptions {
+    upload: UploadOptions,
+    /// Fetch the job's report once it's done. Only when polling.
+    fetch_report: bool,
+    /// Write the job's raw defmt stream here once it's done. Only when polling.
+    defmt_path: Option<PathBuf>,
+    /// The job is cancelled once this turns true.
+    cancel: watch::Receiver<bool>,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+enum Compression {
+    None,
+    Gzip,
+    Zstd,
+}
+
+impl Compression {
+    /// Compress `data`, returning the `Content-Encoding` to send it with.
+    fn compress(self, data: &[u8]) -> (Option<&'static str>, Vec<u8>) {
+        use std::io::Write;
+
+        // NOTE (unwrap): writing to a Vec can't fail.
+        match self {
+            Compression::None => (None, data.to_vec()),
+            Compression::Gzip => {
+                let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+                encoder.write_all(data).unwrap();
+                (Some("gzip"), encoder.finish().unwrap())
+            }
+            Compression::Zstd => (Some("zstd"), zstd::encode_all(data, 0).unwrap()),
+        }
     }
+}
+
+/// Check ELFs for problems that would make their runs fail, without contacting the server.
+#[derive(clap::Parser)]
+pub struct CheckCommand {
+    /// Chip the ELFs are built for. If specified, also checks the vector table is in its memory.
+    #[clap(long)]
+    chip: Option<String>,
 
+    /// ELF files to check
+    files: Vec<String>,
+
+    /// Recursively check all files under the given directories
+    #[clap(short)]
+    recursive: bool,
+}
+
+pub async fn main(cmd: Command) -> anyhow::Result<()> {
     match cmd.cmd {
-        Subcommand::ListTargets => list_targets(&cmd.credentials).await,
-        Subcommand::Run(scmd) => run(&cmd.credentials, scmd).await,
+        Subcommand::ListTargets => list_targets(&cmd.credentials.get(cmd.retry)?).await,
+        Subcommand::Run(scmd) => run(&cmd.credentials.get(cmd.retry)?, scmd).await,
+        Subcommand::Check(scmd) => check(scmd),
+        Subcommand::Lease { target, ttl, renew } => lease(&cmd.credentials.get(cmd.retry)?, &target, ttl, renew).await,
+        Subcommand::Release { target } => release(&cmd.credentials.get(cmd.retry)?, &target).await,
     }
 }
 
 #[derive(Serialize, Deserialize, Default)]
 struct Cache {
-    /// A map of file checksums that have passed the test.
-    files: HashSet<String>,
+    /// Runs that passed.
+    #[serde(default)]
+    entries: Vec<CacheEntry>,
 }
 
-#[derive(Clone, Debug)]
-struct ElfMetadata {
-    target: Option<String>,
-    timeout: Option<u64>,
+#[derive(Clone, Serialize, Deserialize)]
+struct CacheEntry {
+    /// Hash of the ELF and the run settings, from `elf_hasher`.
+    hash: String,
+    /// Target, or `[selector]`, it passed on.
+    target: String,
+    /// Version of the server it passed on. Runs are repeated after server upgrades.
+    server_version: Option<String>,
+    passed_at: DateTime<Utc>,
 }
 
-impl ElfMetadata {
-    fn from_elf(elf: &[u8]) -> anyhow::Result<(Self, Blake2b)> {
-        let mut target = None;
-        let mut timeout = None;
+impl Cache {
+    /// The entry for a run of `hash` on `target`, if it's still valid.
+    fn get(&self, hash: &str, target: &str, server_version: Option<&str>, max_age: Duration) -> Option<&CacheEntry> {
+        let cutoff = Utc::now() - chrono::Duration::from_std(max_age).unwrap_or(chrono::Duration::zero());
+        self.entries.iter().find(|e| {
+            e.hash == hash
+                && e.target == target
+                && e.server_version.as_deref() == server_version
+                && e.passed_at > cutoff
+        })
+    }
 
-        let obj_file = object::File::parse(elf)?;
+    fn insert(&mut self, entry: CacheEntry) {
+        self.entries
+            .retain(|e| e.hash != entry.hash || e.target != entry.target);
+        self.entries.push(entry);
+    }
 
-        if let Some(section) = obj_file.section_by_name(".teleprobe.target") {
-            let data = section.data()?;
-            if !data.is_empty() {
-                match String::from_utf8(data.to_vec()) {
-                    Ok(s) => target = Some(s),
-                    Err(_) => warn!(".teleprobe.target contents are not a valid utf8 string."),
-                }
-            }
-        }
+    /// Write the cache to a temporary file first, so an interrupted run can't leave a truncated cache behind.
+    fn save(&self, path: &str) -> anyhow::Result<()> {
+        let tmp = format!("{}.tmp", path);
+        serde_json::to_writer(File::create(&tmp)?, self)?;
+        std::fs::rename(&tmp, path)?;
+        Ok(())
+    }
+}
 
-        if let Some(section) = obj_file.section_by_name(".teleprobe.timeout") {
-            let data = section.data()?;
-            if data.len() == 4 {
-                timeout = Some(u32::from_le_bytes(data.try_into().unwrap()) as u64)
-            } else {
-                warn!(".teleprobe.timeout contents are not a valid u32.")
-            }
+/// Hash of the parts of an ELF that affect how it runs, ignoring debug info.
+fn elf_hasher(elf: &[u8]) -> anyhow::Result<Blake2b> {
+    let obj_file = object::File::parse(elf)?;
+
+    let mut hasher = Blake2b::new(32)?;
+    for section in &mut obj_file.sections() {
+        let section_name = match section.name() {
+            Ok(name) => name,
+            _ => continue,
+        };
+
+        if section_name == "" || section_name.starts_with(".debug_") {
+            continue;
         }
 
-        let mut hasher = Blake2b::new(32)?;
-        for section in &mut obj_file.sections() {
-            let section_name = match section.name() {
-                Ok(name) => name,
-                _ => continue,
-            };
+        let section_data = match section.data() {
+            Ok(data) => data,
+            _ => continue,
+        };
 
-            if section_name == "" || section_name.starts_with(".debug_") {
-                continue;
-            }
+        let section_address = section.address();
 
-            let section_data = match section.data() {
-                Ok(data) => data,
-                _ => continue,
-            };
+        hasher.update(section_name.as_bytes())?;
+        hasher.update(section_data)?;
+        hasher.update(&section_address.to_le_bytes())?;
+    }
+
+    Ok(hasher)
+}
 
-            let section_address = section.address();
+/// Where to run a job: on a specific target, or on any target matching a label selector.
+#[derive(Clone)]
+enum Destination {
+    Target(String),
+    Selector(Selector),
+}
 
-            hasher.update(section_name.as_bytes())?;
-            hasher.update(section_data)?;
-            hasher.update(&section_address.to_le_bytes())?;
+impl Destination {
+    fn run_url(&self, host: &str) -> String {
+        match self {
+            Destination::Target(name) => format!("{}/targets/{}/run", host, name),
+            Destination::Selector(_) => format!("{}/run", host),
         }
+    }
 
-        Ok((Self { target, timeout }, hasher))
+    /// Whether the server reports the target, or any of the targets matching the selector, as up and in
+    /// service. Unknown targets count as up, for the server to reject them.
+    fn is_up(&self, targets: &[api::Target]) -> bool {
+        let usable = |t: &api::Target| t.up && !t.maintenance;
+        match self {
+            Destination::Target(name) => targets.iter().find(|t| t.name == *name).map_or(true, usable),
+            Destination::Selector(selector) => {
+                let mut matching = targets.iter().filter(|t| selector.matches(&t.labels)).peekable();
+                matching.peek().is_none() || matching.any(usable)
+            }
+        }
+    }
+}
+
+impl fmt::Display for Destination {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Destination::Target(name) => write!(f, "{}", name),
+            Destination::Selector(selector) => write!(f, "[{}]", selector),
+        }
     }
 }
 
 struct Job {
     path: PathBuf,
-    target: String,
+    target: Destination,
     elf: Vec<u8>,
     hash: String,
     timeout: Option<u64>,
@@ -154,51 +612,770 @@ struct Job {
 struct RunArgs {
     #[serde(default)]
     timeout: Option<u64>,
+    #[serde(default, rename = "async")]
+    asynchronous: bool,
+    #[serde(default)]
+    run_group: Option<String>,
+    #[serde(default)]
+    selector: Option<String>,
+    #[serde(default)]
+    exclude: Option<String>,
+    #[serde(default)]
+    elf_hash: Option<String>,
+    #[serde(default)]
+    name: Option<String>,
+    #[serde(default)]
+    no_cache: bool,
+    #[serde(default)]
+    github_repo: Option<String>,
+    #[serde(default)]
+    github_sha: Option<String>,
+    #[serde(default)]
+    speed: Option<u32>,
+    #[serde(default)]
+    log_filter: Option<String>,
+    #[serde(default)]
+    host_timestamps: bool,
+    #[serde(default)]
+    raw_defmt: bool,
+}
+
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Consecutive failed requests after which a chunked upload is given up.
+const MAX_CHUNK_FAILURES: u32 = 5;
+const CHUNK_RETRY_DELAY: Duration = Duration::from_secs(2);
+
+async fn run_job(
+    client: &Client,
+    creds: &Credentials,
+    job: Job,
+    cmd: &RunCommand,
+    run_group: &str,
+    deadline: Option<Instant>,
+    cancel: &watch::Sender<bool>,
+) -> (SummaryRow, String) {
+    let mut row = SummaryRow {
+        target: job.target.to_string(),
+        path: job.path.clone(),
+        status: SummaryStatus::Cancelled,
+        duration: None,
+    };
+    if *cancel.borrow() {
+        info!("=== {} {}: CANCELLED", job.target, job.path.display());
+        return (row, String::new());
+    }
+    let start = Instant::now();
+
+    let report_dir = cmd.report_dir.as_deref();
+    let timeout = match deadline {
+        Some(deadline) => {
+            let remaining = deadline.saturating_duration_since(Instant::now()).as_secs().max(1);
+            Some(job.timeout.map_or(remaining, |t| t.min(remaining)))
+        }
+        None => job.timeout,
+    };
+    let opts = SubmitOptions {
+        upload: UploadOptions {
+            compression: cmd.compression,
+            chunk_size: cmd.chunk_size,
+        },
+        fetch_report: report_dir.is_some(),
+        defmt_path: cmd.raw_defmt_dir.as_ref().map(|dir| {
+            let name = job.path.file_name().unwrap_or_default().to_string_lossy();
+            dir.join(format!("{}.{}.defmt", name, job.target))
+        }),
+        cancel: cancel.subscribe(),
+    };
+    let mut args = RunArgs {
+        timeout,
+        asynchronous: cmd.poll,
+        run_group: Some(run_group.to_string()),
+        selector: match &job.target {
+            Destination::Selector(selector) => Some(selector.to_string()),
+            Destination::Target(_) => None,
+        },
+        exclude: None,
+        elf_hash: cmd.images_by_hash.then(|| blobs::hash(&job.elf)),
+        name: job.path.file_name().map(|n| n.to_string_lossy().into_owned()),
+        no_cache: cmd.no_server_cache,
+        github_repo: cmd.github_repo.clone(),
+        github_sha: cmd.github_sha.clone(),
+        speed: cmd.speed,
+        log_filter: cmd.log_filter.clone(),
+        host_timestamps: cmd.host_timestamps,
+        raw_defmt: cmd.raw_defmt_dir.is_some(),
+    };
+    // Targets the job failed on because of the probe or the board.
+    let mut failed_targets = Vec::new();
+    // The outcome on the last of them, reported if no other target takes the job.
+    let mut infra_outcome: Option<Outcome> = None;
+    let outcome = loop {
+        let run = async {
+            if cmd.poll {
+                submit_and_poll(client, creds, &job.target, &job.elf, &args, &opts).await
+            } else {
+                let submit = submit(client, creds, &job.target, &job.elf, &args, &opts);
+                let mut cancel = opts.cancel.clone();
+                let cancelled = async move {
+                    if cancel.wait_for(|c| *c).await.is_err() {
+                        future::pending::<()>().await;
+                    }
+                };
+                match future::select(pin!(submit), pin!(cancelled)).await {
+                    Either::Left(((result, logs), _)) => Outcome::new(result, logs),
+                    // Dropping the request makes the server cancel the run.
+                    Either::Right(_) => Outcome::new(Err(anyhow!("cancelled after another job failed")), String::new()),
+                }
+            }
+        };
+        let outcome = match deadline {
+            Some(deadline) => match tokio::time::timeout_at(deadline, run).await {
+                Ok(x) => x,
+                Err(_) => Outcome::new(
+                    Err(anyhow!(
+                        "overall deadline of {}s exceeded",
+                        cmd.overall_deadline.unwrap_or_default()
+                    )),
+                    String::new(),
+                ),
+            },
+            None => run.await,
+        };
+
+        // No job was started for the resubmission, like when no other target matches the selector anymore.
+        if outcome.info.is_none() && outcome.result.is_err() {
+            if let Some(previous) = infra_outcome.take() {
+                warn!(
+                    "=== {} {}: no other target took the job: {:#}",
+                    job.target,
+                    job.path.display(),
+                    outcome.result.unwrap_err()
+                );
+                failed_targets.pop();
+                break previous;
+            }
+        }
+
+        let infra_failure = outcome.info.as_ref().filter(|info| {
+            matches!(
+                info.failure,
+                Some(api::RunError::ProbeNotFound | api::RunError::FlashError | api::RunError::WrongBoard)
+            )
+        });
+        match (&job.target, infra_failure) {
+            (Destination::Selector(_), Some(info))
+                if failed_targets.len() < cmd.retry_other_targets && !*cancel.borrow() =>
+            {
+                warn!(
+                    "=== {} {}: failed on target {} with {:?}, retrying on another target",
+                    job.target,
+                    job.path.display(),
+                    info.target,
+                    info.failure.unwrap()
+                );
+                failed_targets.push(info.target.clone());
+                args.exclude = Some(failed_targets.join(","));
+                infra_outcome = Some(outcome);
+            }
+            _ => break outcome,
+        }
+    };
+    let Outcome {
+        result,
+        logs,
+        report,
+        info,
+    } = outcome;
+    if let Some(info) = &info {
+        row.target = info.target.clone();
+        if !failed_targets.is_empty() {
+            info!(
+                "=== {} {}: ran on target {} after failing on {}",
+                job.target,
+                job.path.display(),
+                info.target,
+                failed_targets.join(", ")
+            );
+        }
+    }
+
+    if let (Some(dir), Some(report)) = (report_dir, report) {
+        let name = job.path.file_name().unwrap_or_default().to_string_lossy();
+        let path = dir.join(format!("{}.{}.json", name, job.target));
+        // NOTE (unwrap): error in this call is caused by programmer error and should never be caused by the user data
+        if let Err(e) = std::fs::write(&path, serde_json::to_vec_pretty(&report).unwrap()) {
+            warn!("Failed to write report to {}: {}", path.display(), e);
+        }
+    }
+
+    row.duration = Some(start.elapsed());
+    let style = cmd.output_style.resolve();
+    match result {
+        Ok(()) => {
+            let logs = if cmd.show_output { Some(logs.as_str()) } else { None };
+            style.print_result(&job, None, logs, None);
+            row.status = match info.and_then(|info| info.cached_from) {
+                Some(_) => SummaryStatus::ServerCached,
+                None => SummaryStatus::Passed,
+            };
+            (row, job.hash.clone())
+        }
+        Err(e) => {
+            let location = info.as_ref().and_then(failure_location);
+            style.print_result(&job, Some(&e), Some(&logs), location);
+            if cmd.fail_fast && !cmd.keep_going && !cancel.send_replace(true) {
+                warn!("Cancelling the remaining jobs because of --fail-fast");
+            }
+            row.status = SummaryStatus::Failed;
+            (row, String::new())
+        }
+    }
+}
+
+/// How a job ended, for the summary. Failures sort first.
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum SummaryStatus {
+    Failed,
+    /// Counted as failed, with `--down-targets fail`.
+    Down,
+    /// Not run after another job failed, with `--fail-fast`.
+    Cancelled,
+    Passed,
+    /// Passed earlier, by the server's result cache.
+    ServerCached,
+    /// Passed earlier, by the `--cache` file.
+    Cached,
+    /// Not run because the target is down, with `--down-targets skip`.
+    Skipped,
+}
+
+impl SummaryStatus {
+    fn failed(self) -> bool {
+        self <= SummaryStatus::Cancelled
+    }
 }
 
-async fn run_job(client: &Client, creds: &Credentials, job: Job, show_output: bool) -> (bool, String) {
-    let res = client
-        .post(format!("{}/targets/{}/run", creds.host, job.target))
-        .query(&RunArgs { timeout: job.timeout })
-        .body(job.elf)
-        .bearer_auth(&creds.token)
-        .send()
+impl fmt::Display for SummaryStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.pad(match self {
+            SummaryStatus::Failed => "FAILED",
+            SummaryStatus::Down => "DOWN",
+            SummaryStatus::Cancelled => "CANCELLED",
+            SummaryStatus::Passed => "ok",
+            SummaryStatus::ServerCached => "ok (server cache)",
+            SummaryStatus::Cached => "ok (cached)",
+            SummaryStatus::Skipped => "skipped",
+        })
+    }
+}
+
+/// A job in the summary printed at the end of `client run`.
+struct SummaryRow {
+    target: String,
+    path: PathBuf,
+    status: SummaryStatus,
+    /// From submitting the job to its result, for jobs that were submitted.
+    duration: Option<Duration>,
+}
+
+/// Print a table of all jobs, failures first, and the totals.
+fn print_summary(mut rows: Vec<SummaryRow>, elapsed: Duration) {
+    rows.sort_by(|a, b| (a.status, &a.target, &a.path).cmp(&(b.status, &b.target, &b.path)));
+    let target_width = rows
+        .iter()
+        .map(|r| r.target.len())
+        .max()
+        .unwrap_or(0)
+        .max("TARGET".len());
+
+    let mut out = String::new();
+    writeln!(
+        out,
+        "{:<17}  {:>8}  {:<width$}  FILE",
+        "RESULT",
+        "TIME",
+        "TARGET",
+        width = target_width
+    )
+    .unwrap();
+    for row in &rows {
+        let duration = match row.duration {
+            Some(d) => format!("{:.1}s", d.as_secs_f64()),
+            None => "-".to_string(),
+        };
+        writeln!(
+            out,
+            "{:<17}  {:>8}  {:<width$}  {}",
+            row.status,
+            duration,
+            row.target,
+            row.path.display(),
+            width = target_width
+        )
+        .unwrap();
+    }
+
+    let count = |f: fn(SummaryStatus) -> bool| rows.iter().filter(|r| f(r.status)).count();
+    writeln!(
+        out,
+        "{} jobs in {:.1}s: {} passed, {} cached, {} failed, {} skipped",
+        rows.len(),
+        elapsed.as_secs_f64(),
+        count(|s| s == SummaryStatus::Passed),
+        count(|s| matches!(s, SummaryStatus::ServerCached | SummaryStatus::Cached)),
+        count(SummaryStatus::failed),
+        count(|s| s == SummaryStatus::Skipped),
+    )
+    .unwrap();
+    print!("{}", out);
+}
+
+/// Start a run. The server is first asked to run the ELF by its hash, and it's only uploaded if the server
+/// doesn't have it yet.
+async fn send_run(
+    client: &Client,
+    creds: &Credentials,
+    target: &Destination,
+    elf: &[u8],
+    args: &RunArgs,
+    upload: UploadOptions,
+) -> anyhow::Result<reqwest::Response> {
+    // With the hash, the server runs the image it already has, and only asks for it if it doesn't.
+    if args.elf_hash.is_some() {
+        let res = send(
+            client
+                .post(target.run_url(&creds.host))
+                .query(args)
+                .bearer_auth(&creds.token),
+            &creds.retry,
+        )
+        .await?;
+        if res.status() != reqwest::StatusCode::PRECONDITION_FAILED {
+            return Ok(res);
+        }
+    }
+
+    if args.elf_hash.is_some() && elf.len() > upload.chunk_size {
+        upload_chunked(client, creds, elf, upload).await?;
+        let res = send(
+            client
+                .post(target.run_url(&creds.host))
+                .query(args)
+                .bearer_auth(&creds.token),
+            &creds.retry,
+        )
+        .await?;
+        return Ok(res);
+    }
+
+    let (encoding, body) = upload.compression.compress(elf);
+    let mut req = client
+        .post(target.run_url(&creds.host))
+        .query(args)
+        .body(body)
+        .bearer_auth(&creds.token);
+    if let Some(encoding) = encoding {
+        req = req.header(reqwest::header::CONTENT_ENCODING, encoding);
+    }
+    let res = send(req, &creds.retry).await?;
+    match encoding {
+        Some(encoding) if res.status() == reqwest::StatusCode::UNSUPPORTED_MEDIA_TYPE => {
+            warn!("Server doesn't support {} uploads, sending uncompressed", encoding);
+            let res = send(
+                client
+                    .post(target.run_url(&creds.host))
+                    .query(args)
+                    .body(elf.to_vec())
+                    .bearer_auth(&creds.token),
+                &creds.retry,
+            )
+            .await?;
+            Ok(res)
+        }
+        _ => Ok(res),
+    }
+}
+
+/// Upload an ELF with `POST /blobs` and then chunk by chunk, resuming where the server says it got to
+/// when a chunk fails.
+async fn upload_chunked(client: &Client, creds: &Credentials, elf: &[u8], upload: UploadOptions) -> anyhow::Result<()> {
+    let hash = blobs::hash(elf);
+    let mut compression = upload.compression;
+    let size = elf.len().to_string();
+    let mut failures = 0;
+    loop {
+        let res = send(
+            client
+                .post(format!("{}/blobs", creds.host))
+                .query(&[("hash", &hash), ("size", &size)])
+                .bearer_auth(&creds.token),
+            &creds.retry,
+        )
         .await;
+        let mut offset = match chunk_result(res).await {
+            Ok(progress) => progress.offset,
+            Err(e) => {
+                failures += 1;
+                if failures >= MAX_CHUNK_FAILURES {
+                    return Err(e.context("Chunked upload failed"));
+                }
+                warn!("Starting chunked upload failed, retrying: {:#}", e);
+                tokio::time::sleep(CHUNK_RETRY_DELAY).await;
+                continue;
+            }
+        };
+
+        while offset < elf.len() {
+            let chunk = &elf[offset..elf.len().min(offset + upload.chunk_size)];
+            let (encoding, body) = compression.compress(chunk);
+            let mut req = client
+                .patch(format!("{}/blobs/{}", creds.host, hash))
+                .query(&[("offset", offset)])
+                .body(body)
+                .bearer_auth(&creds.token);
+            if let Some(encoding) = encoding {
+                req = req.header(reqwest::header::CONTENT_ENCODING, encoding);
+            }
+            let res = send(req, &creds.retry).await;
+            if let (Ok(res), Some(encoding)) = (&res, encoding) {
+                if res.status() == reqwest::StatusCode::UNSUPPORTED_MEDIA_TYPE {
+                    warn!("Server doesn't support {} uploads, sending uncompressed", encoding);
+                    compression = Compression::None;
+                    continue;
+                }
+            }
+            match chunk_result(res).await {
+                Ok(progress) => {
+                    offset = progress.offset;
+                    failures = 0;
+                }
+                Err(e) => {
+                    failures += 1;
+                    if failures >= MAX_CHUNK_FAILURES {
+                        return Err(e.context("Chunked upload failed"));
+                    }
+                    warn!("Uploading chunk at offset {} failed, resuming: {:#}", offset, e);
+                    tokio::time::sleep(CHUNK_RETRY_DELAY).await;
+                    break;
+                }
+            }
+        }
+        if offset >= elf.len() {
+            return Ok(());
+        }
+    }
+}
+
+async fn chunk_result(res: reqwest::Result<reqwest::Response>) -> anyhow::Result<api::BlobUpload> {
+    let res = res.context("HTTP request failed")?;
+    Ok(check_response(res).await?.json().await?)
+}
+
+async fn submit(
+    client: &Client,
+    creds: &Credentials,
+    target: &Destination,
+    elf: &[u8],
+    args: &RunArgs,
+    opts: &SubmitOptions,
+) -> (anyhow::Result<()>, String) {
+    let res = send_run(client, creds, target, elf, args, opts.upload).await;
 
     let mut logs = String::new();
     let result = match res.context("HTTP request failed") {
         Ok(res) => {
             let status = res.status();
-            logs = res.text().await.unwrap_or_else(|_| "empty".to_string());
-            if status.is_success() {
+            // For finding the run in the server logs.
+            let job = res
+                .headers()
+                .get(api::JOB_ID_HEADER)
+                .and_then(|id| id.to_str().ok())
+                .map(|id| format!(" (job {})", id))
+                .unwrap_or_default();
+            if !status.is_success() && is_json(&res) {
+                // Rejected before running, so there are no logs.
+                Err(anyhow!(
+                    "HTTP request failed with status code: {}: {}{}",
+                    status.as_u16(),
+                    error_body(res).await,
+                    job
+                ))
+            } else if status.is_success() {
+                logs = res.text().await.unwrap_or_else(|_| "empty".to_string());
                 Ok(())
             } else {
+                logs = res.text().await.unwrap_or_else(|_| "empty".to_string());
                 Err(anyhow::anyhow!(
-                    "HTTP request failed with status code: {}: {}",
+                    "HTTP request failed with status code: {}: {}{}",
                     status.as_u16(),
-                    status.canonical_reason().unwrap_or("unknown")
+                    status.canonical_reason().unwrap_or("unknown"),
+                    job
                 ))
             }
         }
         Err(e) => Err(e),
     };
 
-    match result {
-        Ok(()) => {
-            info!("=== {} {}: OK", job.target, job.path.display());
-            if show_output {
-                info!("{}", logs);
+    (result, logs)
+}
+
+async fn submit_and_poll(
+    client: &Client,
+    creds: &Credentials,
+    target: &Destination,
+    elf: &[u8],
+    args: &RunArgs,
+    opts: &SubmitOptions,
+) -> Outcome {
+    let mut job_id = None;
+    match poll_job(client, creds, target, elf, args, opts, &mut job_id).await {
+        Ok((job, logs, report)) => {
+            let result = match job.status {
+                api::JobStatus::Succeeded => Ok(()),
+                status => match job.failure {
+                    Some(e) => {
+                        let mut msg = format!("job {} finished with status {:?}: {}", job.id, status, e);
+                        if let Some(panic) = &job.panic {
+                            msg = format!("{}, {}", msg, panic);
+                        } else if let Some(location) = fault_location(&job.backtrace) {
+                            msg = format!("{}, at {}", msg, location);
+                        }
+                        Err(anyhow!(msg))
+                    }
+                    None => Err(anyhow!("job {} finished with status {:?}", job.id, status)),
+                },
+            };
+            Outcome {
+                result,
+                logs,
+                report,
+                info: Some(job),
             }
-            (true, job.hash.clone())
         }
         Err(e) => {
-            error!("=== {} {}: FAILED: {}", job.target, job.path.display(), e);
-            error!("{}", logs);
-            (false, String::new())
+            // Keep what the job logged before polling failed, the server has the logs of running jobs too.
+            let logs = match job_id {
+                Some(id) => fetch_logs(client, creds, &id).await.unwrap_or_default(),
+                None => String::new(),
+            };
+            Outcome::new(Err(e), logs)
         }
     }
 }
 
+/// Source file and line the job failed at, from its panic or its backtrace.
+fn failure_location(job: &api::Job) -> Option<(&str, Option<u32>)> {
+    if let Some(panic) = &job.panic {
+        if let Some(file) = &panic.file {
+            return Some((file, panic.line));
+        }
+    }
+    let frame = job.backtrace.iter().find(|f| f.file.is_some())?;
+    Some((frame.file.as_deref()?, frame.line))
+}
+
+/// Source location of the innermost backtrace frame that has one, like `src/main.rs:12:5 (my_crate::main)`.
+fn fault_location(backtrace: &[api::BacktraceFrame]) -> Option<String> {
+    let frame = backtrace.iter().find(|f| f.file.is_some())?;
+    let mut s = frame.file.clone()?;
+    if let Some(line) = frame.line {
+        write!(s, ":{}", line).unwrap();
+        if let Some(column) = frame.column {
+            write!(s, ":{}", column).unwrap();
+        }
+    }
+    write!(s, " ({})", frame.function).unwrap();
+    Some(s)
+}
+
+async fn fetch_logs(client: &Client, creds: &Credentials, id: &str) -> anyhow::Result<String> {
+    let res = send(
+        client
+            .get(format!("{}/jobs/{}/logs", creds.host, id))
+            .bearer_auth(&creds.token),
+        &creds.retry,
+    )
+    .await
+    .context("HTTP request failed")?;
+    Ok(check_response(res).await?.text().await?)
+}
+
+async fn poll_job(
+    client: &Client,
+    creds: &Credentials,
+    target: &Destination,
+    elf: &[u8],
+    args: &RunArgs,
+    opts: &SubmitOptions,
+    job_id: &mut Option<String>,
+) -> anyhow::Result<(api::Job, String, Option<RunReport>)> {
+    let res = send_run(client, creds, target, elf, args, opts.upload)
+        .await
+        .context("HTTP request failed")?;
+    let mut job: api::Job = check_response(res).await?.json().await?;
+    *job_id = Some(job.id.clone());
+
+    let mut cancelled = false;
+    while !job.status.is_finished() {
+        tokio::time::sleep(POLL_INTERVAL).await;
+
+        if !cancelled && *opts.cancel.borrow() {
+            cancelled = true;
+            let res = send(
+                client
+                    .delete(format!("{}/jobs/{}", creds.host, job.id))
+                    .bearer_auth(&creds.token),
+                &creds.retry,
+            )
+            .await
+            .context("HTTP request failed")?;
+            // The job may have finished in the meantime.
+            if res.status() != reqwest::StatusCode::CONFLICT {
+                check_response(res).await?;
+            }
+        }
+
+        let res = send(
+            client
+                .get(format!("{}/jobs/{}", creds.host, job.id))
+                .bearer_auth(&creds.token),
+            &creds.retry,
+        )
+        .await
+        .context("HTTP request failed")?;
+        job = check_response(res).await?.json().await?;
+    }
+
+    let logs = fetch_logs(client, creds, &job.id).await?;
+
+    let mut report = None;
+    if opts.fetch_report {
+        let res = send(
+            client
+                .get(format!("{}/jobs/{}/report", creds.host, job.id))
+                .bearer_auth(&creds.token),
+            &creds.retry,
+        )
+        .await
+        .context("HTTP request failed")?;
+        // Jobs that never got to run, like cancelled ones, have no report.
+        if res.status() != reqwest::StatusCode::NOT_FOUND {
+            report = Some(check_response(res).await?.json().await?);
+        }
+    }
+
+    if let Some(path) = &opts.defmt_path {
+        let res = send(
+            client
+                .get(format!("{}/jobs/{}/defmt", creds.host, job.id))
+                .bearer_auth(&creds.token),
+            &creds.retry,
+        )
+        .await
+        .context("HTTP request failed")?;
+        if res.status() != reqwest::StatusCode::NOT_FOUND {
+            let stream = check_response(res).await?.bytes().await?;
+            if let Err(e) = std::fs::write(path, &stream) {
+                warn!("Failed to write raw defmt stream to {}: {}", path.display(), e);
+            }
+        }
+    }
+
+    Ok((job, logs, report))
+}
+
+/// Send a request, retrying it as configured when it fails for transient reasons.
+///
+/// Failed synchronous runs are answered with 5xx statuses too, so those statuses are only retried when the
+/// reply doesn't come from teleprobe itself, but from a proxy in front of it, or when teleprobe means it's
+/// temporarily unavailable.
+async fn send(req: reqwest::RequestBuilder, retry: &RetryArgs) -> reqwest::Result<reqwest::Response> {
+    let mut attempt = 0;
+    loop {
+        // NOTE (unwrap): request bodies are always in memory, so requests can be cloned.
+        let res = req.try_clone().unwrap().send().await;
+        let delay = match &res {
+            Err(e) if e.is_connect() || e.is_timeout() || e.is_request() => None,
+            Ok(res) if is_transient(res) => retry_after(res),
+            _ => return res,
+        };
+        if attempt >= retry.retries {
+            return res;
+        }
+
+        let delay = delay.unwrap_or_else(|| retry.backoff(attempt));
+        attempt += 1;
+        match &res {
+            Ok(res) => warn!(
+                "Request failed with status code {}, retrying in {:.1}s",
+                res.status().as_u16(),
+                delay.as_secs_f64()
+            ),
+            Err(e) => warn!("Request failed: {}, retrying in {:.1}s", e, delay.as_secs_f64()),
+        }
+        tokio::time::sleep(delay).await;
+    }
+}
+
+fn is_transient(res: &reqwest::Response) -> bool {
+    use reqwest::StatusCode;
+
+    match res.status() {
+        StatusCode::TOO_MANY_REQUESTS | StatusCode::INTERNAL_SERVER_ERROR | StatusCode::SERVICE_UNAVAILABLE => true,
+        status => status.is_server_error() && !res.headers().contains_key(api::VERSION_HEADER),
+    }
+}
+
+fn retry_after(res: &reqwest::Response) -> Option<Duration> {
+    let secs = res
+        .headers()
+        .get(reqwest::header::RETRY_AFTER)?
+        .to_str()
+        .ok()?
+        .parse()
+        .ok()?;
+    Some(Duration::from_secs(secs).min(MAX_RETRY_DELAY))
+}
+
+async fn check_response(res: reqwest::Response) -> anyhow::Result<reqwest::Response> {
+    let status = res.status();
+    if status.is_success() {
+        return Ok(res);
+    }
+
+    bail!(
+        "HTTP request failed with status code: {}: {}: {}",
+        status.as_u16(),
+        status.canonical_reason().unwrap_or("unknown"),
+        error_body(res).await
+    )
+}
+
+/// The body of an error reply: the message of the server's JSON errors, or the text of others, like ones from
+/// a proxy.
+async fn error_body(res: reqwest::Response) -> String {
+    let json = is_json(&res);
+    let body = res.text().await.unwrap_or_else(|_| "empty".to_string());
+    match serde_json::from_str::<api::Error>(&body) {
+        Ok(error) if json => format!("{} ({})", error.message, error.code),
+        _ => body,
+    }
+}
+
+fn is_json(res: &reqwest::Response) -> bool {
+    res.headers()
+        .get(reqwest::header::CONTENT_TYPE)
+        .is_some_and(|t| t.as_bytes().starts_with(b"application/json"))
+}
+
+fn new_run_group() -> String {
+    let mut id = [0u8; 8];
+    // NOTE (unwrap): only fails if the OS RNG is unavailable.
+    orion::util::secure_rand_bytes(&mut id).unwrap();
+    hex::encode(id)
+}
+
 fn load_cache(cache: Option<String>) -> Cache {
     let cache = match cache {
         Some(cache) => cache,
@@ -216,112 +1393,323 @@ fn load_cache(cache: Option<String>) -> Cache {
     }
 }
 
-async fn run(creds: &Credentials, cmd: RunCommand) -> anyhow::Result<()> {
-    let files = if cmd.recursive {
-        let mut files = Vec::new();
+fn collect_files(files: &[String], recursive: bool) -> anyhow::Result<Vec<PathBuf>> {
+    if !recursive {
+        return Ok(files.iter().map(|f| f.into()).collect());
+    }
 
-        for f in cmd.files {
-            for entry in WalkDir::new(f).follow_links(true) {
-                let entry = entry?;
-                if entry.file_type().is_file() {
-                    files.push(entry.path().to_owned())
+    let mut res = Vec::new();
+    for f in files {
+        for entry in WalkDir::new(f).follow_links(true) {
+            let entry = entry?;
+            if entry.file_type().is_file() {
+                res.push(entry.path().to_owned())
+            }
+        }
+    }
+    Ok(res)
+}
+
+fn check(cmd: CheckCommand) -> anyhow::Result<()> {
+    let mut failed = 0usize;
+    let files = collect_files(&cmd.files, cmd.recursive)?;
+    for path in &files {
+        let result = std::fs::read(path).map_err(anyhow::Error::from).and_then(|elf| {
+            let meta = ElfMetadata::parse(&elf)?;
+            crate::run::check_elf(&elf, cmd.chip.as_deref())?;
+            Ok(meta)
+        });
+
+        match result {
+            Ok(meta) => {
+                info!("=== {}: OK", path.display());
+                if meta.target.is_none() && meta.labels.is_none() {
+                    warn!("no target or labels embedded, it can only be run with --target or --selector");
                 }
             }
+            Err(e) => {
+                error!("=== {}: FAILED: {:#}", path.display(), e);
+                failed += 1;
+            }
         }
+    }
+
+    if failed != 0 {
+        log::error!("{} ok, {} failed :(", files.len() - failed, failed);
+        bail!("check failed")
+    } else {
+        log::info!("all {} ok!", files.len());
+        Ok(())
+    }
+}
 
-        files
+async fn run(creds: &Credentials, mut cmd: RunCommand) -> anyhow::Result<()> {
+    if cmd.jobs_per_target == 0 || cmd.max_total_jobs == Some(0) {
+        bail!("`--jobs-per-target` and `--max-total-jobs` must be at least 1");
+    }
+    let files = collect_files(&cmd.files, cmd.recursive)?;
+
+    let client = reqwest::Client::new();
+    let server = ServerFeatures::fetch(&client, creds).await?;
+    server.require(api::feature::ASYNC_JOBS, "--poll", cmd.poll)?;
+    server.require(api::feature::LABELS, "--selector", cmd.selector.is_some())?;
+    server.require(api::feature::REPORTS, "--report-dir", cmd.report_dir.is_some())?;
+    server.require(api::feature::RAW_DEFMT, "--raw-defmt-dir", cmd.raw_defmt_dir.is_some())?;
+    server.require(
+        api::feature::EXCLUDE_TARGETS,
+        "--retry-other-targets",
+        cmd.retry_other_targets > 0,
+    )?;
+    server.require(api::feature::LOG_FILTER, "--log-filter", cmd.log_filter.is_some())?;
+    server.require(api::feature::HOST_TIMESTAMPS, "--host-timestamps", cmd.host_timestamps)?;
+    server.require(
+        api::feature::CANCELLATION,
+        "--fail-fast with --poll",
+        cmd.fail_fast && !cmd.keep_going && cmd.poll,
+    )?;
+    if !server.has(api::feature::CHUNKED_UPLOADS) {
+        // Send whole ELFs in one go instead.
+        cmd.chunk_size = usize::MAX;
+    }
+    // Servers from before `/api/version` would run the empty body sent to find out if they have an image.
+    cmd.images_by_hash = server.version.is_some() && server.has(api::feature::IMAGES_BY_HASH);
+    // Only fetched when needed, so runs don't depend on `/targets` otherwise.
+    let server_targets = if (cmd.selector.is_some() && cmd.all_targets) || cmd.down_targets.is_some() {
+        fetch_targets(&client, creds).await?.targets
     } else {
-        cmd.files.iter().map(|f| f.into()).collect()
+        Vec::new()
+    };
+    let server_version = server.server_version.clone();
+
+    // When fanning out, every ELF runs on each target in service matching the selector.
+    let all_targets = match (&cmd.selector, cmd.all_targets) {
+        (Some(selector), true) => {
+            let (maintenance, targets): (Vec<_>, Vec<_>) = server_targets
+                .iter()
+                .filter(|t| selector.matches(&t.labels))
+                .partition(|t| t.maintenance);
+            for t in maintenance {
+                warn!("Not running on target {}, it's in maintenance", t.name);
+            }
+            let targets: Vec<String> = targets.iter().map(|t| t.name.clone()).collect();
+            if targets.is_empty() {
+                bail!("No targets match selector {}", selector);
+            }
+            info!("Selector {} matches targets: {}", selector, targets.join(", "));
+            Some(targets)
+        }
+        _ => None,
     };
 
+    let max_age = Duration::from_secs(cmd.cache_max_age);
     let before_cache = load_cache(cmd.cache.clone());
-    let mut after_cache = Cache::default();
-    let job_count = files.len();
+    // Entries of other runs sharing the cache are kept until they expire.
+    let mut after_cache = Cache {
+        entries: before_cache
+            .entries
+            .iter()
+            .filter(|e| {
+                before_cache
+                    .get(&e.hash, &e.target, server_version.as_deref(), max_age)
+                    .is_some()
+            })
+            .cloned()
+            .collect(),
+    };
+    let mut job_count = 0;
     let mut jobs_by_target: HashMap<String, Vec<Job>> = HashMap::new();
     let mut skipped_jobs: Vec<_> = Vec::new();
 
     for path in files {
         let elf: Vec<u8> = std::fs::read(&path)?;
-        let (meta, mut hasher) = ElfMetadata::from_elf(&elf)?;
+        let meta = ElfMetadata::parse(&elf)?;
 
-        let target = cmd
-            .target
-            .clone()
-            .or(meta.target)
-            .context("You have to either set --target, or embed it in the ELF using the `teleprobe-meta` crate.")?;
+        let targets = match (&cmd.target, &cmd.selector, &all_targets) {
+            (_, _, Some(names)) => names.iter().cloned().map(Destination::Target).collect(),
+            (Some(name), _, _) => vec![Destination::Target(name.clone())],
+            (None, Some(selector), _) => vec![Destination::Selector(selector.clone())],
+            (None, None, _) => match (&meta.target, &meta.labels) {
+                (Some(name), _) => vec![Destination::Target(name.clone())],
+                (None, Some(selector)) => vec![Destination::Selector(selector.clone())],
+                (None, None) => bail!(
+                    "You have to either set --target or --selector, or embed the target or labels in the ELF using the `teleprobe-meta` crate."
+                ),
+            },
+        };
 
-        hasher.update(target.as_bytes())?;
-        hasher.update(&meta.timeout.unwrap_or_default().to_le_bytes())?;
+        for target in targets {
+            job_count += 1;
 
-        let digest = hasher.finalize()?;
-        let hash = hex::encode(&digest);
+            let mut hasher = elf_hasher(&elf)?;
+            hasher.update(&meta.timeout.unwrap_or_default().to_le_bytes())?;
 
-        if before_cache.files.contains(&hash) {
-            skipped_jobs.push((target, path.clone()));
-            after_cache.files.insert(hash);
+            let digest = hasher.finalize()?;
+            let hash = hex::encode(&digest);
 
-            continue;
-        }
+            let cached = before_cache.get(&hash, &target.to_string(), server_version.as_deref(), max_age);
+            if cached.is_some() {
+                skipped_jobs.push((target, path.clone()));
+                continue;
+            }
 
-        // Override timeout if requested
-        let timeout = match cmd.timeout {
-            Some(_) => cmd.timeout,
-            None => meta.timeout,
-        };
+            // Override timeout if requested
+            let timeout = match cmd.timeout {
+                Some(_) => cmd.timeout,
+                None => meta.timeout,
+            };
 
-        jobs_by_target.entry(target.clone()).or_default().push(Job {
-            path,
-            target,
-            elf,
-            hash,
-            timeout,
-        });
+            jobs_by_target.entry(target.to_string()).or_default().push(Job {
+                path: path.clone(),
+                target,
+                elf: elf.clone(),
+                hash,
+                timeout,
+            });
+        }
     }
 
+    // Don't waste a whole timeout on jobs that can't succeed.
+    let mut down_jobs = Vec::new();
+    jobs_by_target.retain(|name, jobs| {
+        let Some(down_targets) = cmd.down_targets else {
+            return true;
+        };
+        if jobs[0].target.is_up(&server_targets) {
+            return true;
+        }
+        warn!("Target {} is down or in maintenance", name);
+        if down_targets == DownTargets::Warn {
+            return true;
+        }
+        down_jobs.extend(jobs.drain(..).map(|job| (job.target, job.path)));
+        false
+    });
+    job_count -= down_jobs.len();
+
     info!("Running {} jobs across {} targets...", job_count, jobs_by_target.len());
 
+    for (target, path) in &down_jobs {
+        match cmd.down_targets {
+            Some(DownTargets::Fail) => error!("=== {} {}: FAILED: target is down", target, path.display()),
+            _ => warn!("=== {} {}: SKIPPED: target is down", target, path.display()),
+        }
+    }
+
     for (target, path) in &skipped_jobs {
         info!("=== {} {}: SKIPPED", target, path.display());
     }
 
-    let client = reqwest::Client::new();
+    let deadline = cmd
+        .overall_deadline
+        .map(|secs| Instant::now() + Duration::from_secs(secs));
+    let run_group = cmd.run_group.clone().unwrap_or_else(new_run_group);
+    info!("Run group: {}", run_group);
+
+    if let Some(dir) = &cmd.report_dir {
+        std::fs::create_dir_all(dir)?;
+    }
+    if let Some(dir) = &cmd.raw_defmt_dir {
+        std::fs::create_dir_all(dir)?;
+    }
 
+    if cmd.order == Order::ShortestFirst {
+        for jobs in jobs_by_target.values_mut() {
+            jobs.sort_by_key(|job| (job.timeout.is_none(), job.timeout, job.elf.len()));
+        }
+    }
+
+    let started = Instant::now();
+    let (cancel, _) = watch::channel(false);
+    let slots = Semaphore::new(cmd.max_total_jobs.unwrap_or(Semaphore::MAX_PERMITS));
     let results: Vec<_> = stream::iter(jobs_by_target)
-        .flat_map_unordered(None, |(_, jobs)| {
+        .flat_map_unordered(None, |(target, jobs)| {
             let client = &client;
+            let cmd = &cmd;
+            let run_group = &run_group;
+            let slots = &slots;
+            let cancel = &cancel;
             stream::iter(jobs)
-                .map(move |job| run_job(client, creds, job, cmd.show_output))
-                .buffer_unordered(2)
+                .map(move |job| {
+                    let target = target.clone();
+                    async move {
+                        // NOTE (unwrap): the semaphore is never closed.
+                        let _permit = slots.acquire().await.unwrap();
+                        let (row, hash) = run_job(client, creds, job, cmd, run_group, deadline, cancel).await;
+                        (target, row, hash)
+                    }
+                })
+                .buffer_unordered(cmd.jobs_per_target)
         })
         .collect()
         .await;
 
+    if all_targets.is_some() {
+        // Per-board breakdown, to spot board-specific flakiness.
+        let mut by_target: BTreeMap<String, (usize, usize)> = BTreeMap::new();
+        for (target, _) in &skipped_jobs {
+            by_target.entry(target.to_string()).or_default().0 += 1;
+        }
+        for (target, row, _) in &results {
+            let entry = by_target.entry(target.clone()).or_default();
+            match row.status.failed() {
+                false => entry.0 += 1,
+                true => entry.1 += 1,
+            }
+        }
+        for (target, (succeeded, failed)) in by_target {
+            info!("{:20} {} succeeded, {} failed", target, succeeded, failed);
+        }
+    }
+
     let mut succeeded = skipped_jobs.len();
-    let mut failed = 0usize;
-    for (r, hash) in results {
-        match r {
-            true => {
-                after_cache.files.insert(hash);
+    let mut failed = match cmd.down_targets {
+        Some(DownTargets::Fail) => down_jobs.len(),
+        _ => 0,
+    };
+    let mut rows = Vec::new();
+    for (target, path) in skipped_jobs {
+        rows.push(SummaryRow {
+            target: target.to_string(),
+            path,
+            status: SummaryStatus::Cached,
+            duration: None,
+        });
+    }
+    for (target, path) in down_jobs {
+        rows.push(SummaryRow {
+            target: target.to_string(),
+            path,
+            status: match cmd.down_targets {
+                Some(DownTargets::Fail) => SummaryStatus::Down,
+                _ => SummaryStatus::Skipped,
+            },
+            duration: None,
+        });
+    }
+    for (target, row, hash) in results {
+        match row.status.failed() {
+            false => {
+                after_cache.insert(CacheEntry {
+                    hash,
+                    target,
+                    server_version: server_version.clone(),
+                    passed_at: Utc::now(),
+                });
 
                 succeeded += 1
             }
-            false => failed += 1,
+            true => failed += 1,
         }
+        rows.push(row);
     }
+    print_summary(rows, started.elapsed());
 
-    cmd.cache.map(|cache| {
-        let cache_file = match File::create(&cache) {
-            Ok(cache_file) => cache_file,
-            _ => return,
-        };
-
-        match serde_json::to_writer(cache_file, &after_cache) {
-            Ok(_) => println!("saved cache to {}", &cache),
-            Err(_) => println!("failed to saved cache to {}", &cache),
-        };
-
-        // I assume the file is closed when it's dropped here
-    });
+    if let Some(cache) = &cmd.cache {
+        match after_cache.save(cache) {
+            Ok(()) => println!("saved cache to {}", cache),
+            Err(e) => println!("failed to save cache to {}: {:?}", cache, e),
+        }
+    }
 
     if failed != 0 {
         log::error!("{} succeeded, {} failed :(", succeeded, failed);
@@ -332,13 +1720,97 @@ async fn run(creds: &Credentials, cmd: RunCommand) -> anyhow::Result<()> {
     }
 }
 
+/// What the server supports, from `GET /api/version`.
+struct ServerFeatures {
+    /// `None` for servers from before `/api/version`, which are assumed to support what the client asks of them,
+    /// as they were before.
+    version: Option<api::Version>,
+    /// The server's version from the header on its replies, which older servers send too.
+    server_version: Option<String>,
+}
+
+impl ServerFeatures {
+    /// Fail early on servers with an API this client doesn't speak, rather than with confusing errors halfway
+    /// through.
+    async fn fetch(client: &Client, creds: &Credentials) -> anyhow::Result<Self> {
+        let res = send(
+            client
+                .get(format!("{}/api/version", creds.host))
+                .bearer_auth(&creds.token),
+            &creds.retry,
+        )
+        .await
+        .context("HTTP request failed")?;
+        let server_version = res
+            .headers()
+            .get(api::VERSION_HEADER)
+            .and_then(|v| v.to_str().ok())
+            .map(str::to_string);
+        if res.status() == reqwest::StatusCode::NOT_FOUND {
+            log::debug!("Server has no /api/version, assuming it supports all features");
+            return Ok(Self {
+                version: None,
+                server_version,
+            });
+        }
+        let version: api::Version = check_response(res).await?.json().await?;
+        log::debug!(
+            "Server version {}, API version {}, features: {}",
+            version.version.lines().next().unwrap_or_default(),
+            version.api_version,
+            version.features.join(", ")
+        );
+        if version.api_version > api::API_VERSION {
+            bail!(
+                "Server speaks API version {}, but this client only knows up to {}, upgrade teleprobe",
+                version.api_version,
+                api::API_VERSION
+            );
+        }
+        Ok(Self {
+            version: Some(version),
+            server_version,
+        })
+    }
+
+    fn has(&self, feature: &str) -> bool {
+        match &self.version {
+            Some(version) => version.features.iter().any(|f| f == feature),
+            None => true,
+        }
+    }
+
+    /// Fail if `used`, but the server doesn't support `feature`, which `option` needs.
+    fn require(&self, feature: &str, option: &str, used: bool) -> anyhow::Result<()> {
+        if used && !self.has(feature) {
+            bail!(
+                "{} needs the `{}` feature, which the server doesn't support, upgrade the server",
+                option,
+                feature
+            );
+        }
+        Ok(())
+    }
+}
+
+/// The server's targets, and its version.
+async fn fetch_targets(client: &Client, creds: &Credentials) -> anyhow::Result<api::TargetList> {
+    let res = send(
+        client.get(format!("{}/targets", creds.host)).bearer_auth(&creds.token),
+        &creds.retry,
+    )
+    .await
+    .context("HTTP request failed")?;
+    Ok(check_response(res).await?.json().await?)
+}
+
 async fn list_targets(creds: &Credentials) -> anyhow::Result<()> {
     let client = reqwest::Client::new();
-    let res = client
-        .get(format!("{}/targets", creds.host))
-        .bearer_auth(&creds.token)
-        .send()
-        .await?;
+    let res = send(
+        client.get(format!("{}/targets", creds.host)).bearer_auth(&creds.token),
+        &creds.retry,
+    )
+    .await?;
 
     if res.status().is_success() {
         println!("Teleprobe server supports the following targets:");
@@ -360,10 +1832,50 @@ async fn list_targets(creds: &Credentials) -> anyhow::Result<()> {
             res.status().as_u16(),
             res.status().canonical_reason().unwrap_or("unknown")
         );
-        println!(
-            "response body: {}",
-            res.text().await.unwrap_or_else(|_| "empty".to_string())
-        );
+        println!("response body: {}", error_body(res).await);
         bail!("Running failed!");
     }
 }
+
+async fn lease(creds: &Credentials, target: &str, ttl: Option<u64>, renew: bool) -> anyhow::Result<()> {
+    let client = reqwest::Client::new();
+    ServerFeatures::fetch(&client, creds)
+        .await?
+        .require(api::feature::LEASES, "lease", true)?;
+    let url = format!("{}/targets/{}/lease", creds.host, target);
+    let req = match renew {
+        true => client.put(url),
+        false => client.post(url),
+    };
+    let req = match ttl {
+        Some(ttl) => req.query(&[("ttl", ttl)]),
+        None => req,
+    };
+    let res = send(req.bearer_auth(&creds.token), &creds.retry)
+        .await
+        .context("HTTP request failed")?;
+    let lease: api::Lease = check_response(res).await?.json().await?;
+    println!(
+        "Target {} leased by {} until {}",
+        lease.target, lease.holder, lease.expires_at
+    );
+    Ok(())
+}
+
+async fn release(creds: &Credentials, target: &str) -> anyhow::Result<()> {
+    let client = reqwest::Client::new();
+    ServerFeatures::fetch(&client, creds)
+        .await?
+        .require(api::feature::LEASES, "release", true)?;
+    let res = send(
+        client
+            .delete(format!("{}/targets/{}/lease", creds.host, target))
+            .bearer_auth(&creds.token),
+        &creds.retry,
+    )
+    .await
+    .context("HTTP request failed")?;
+    check_response(res).await?;
+    println!("Target {} released", target);
+    Ok(())
+}
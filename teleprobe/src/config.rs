@@ -1,7 +1,19 @@
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fs;
+use std::io::{self, Read};
+use std::net::IpAddr;
+use std::time::Duration;
 
+use anyhow::{bail, Context as _};
+use probe_rs::probe::list::Lister;
 use probe_rs::probe::DebugProbeSelector;
 use serde::{Deserialize, Serialize};
+pub use teleprobe_core::probe::{BoardIdentity, PowerControl};
+
+use crate::auth::claims::ClaimMatcher;
+use crate::probe;
+use crate::run::{FlashOptions, RttOverflowOptions, RunFrom};
+use crate::selector::Selector;
 
 fn default_default_timeout() -> u64 {
     10
@@ -9,6 +21,18 @@ fn default_default_timeout() -> u64 {
 fn default_max_timeout() -> u64 {
     60
 }
+fn default_max_keepalive_timeout() -> u64 {
+    600
+}
+fn default_max_parallel_runs() -> usize {
+    16
+}
+fn default_weight() -> f64 {
+    1.0
+}
+fn default_max_lease_ttl() -> u64 {
+    4 * 60 * 60
+}
 
 #[derive(Clone, Deserialize)]
 pub struct Config {
@@ -18,6 +42,549 @@ pub struct Config {
     pub default_timeout: u64,
     #[serde(default = "default_max_timeout")]
     pub max_timeout: u64,
+    /// Longest a run can last, in seconds, when the firmware keeps extending its timeout with keepalives.
+    #[serde(default = "default_max_keepalive_timeout")]
+    pub max_keepalive_timeout: u64,
+    /// Path to a database where jobs are persisted, so they survive server restarts. Same as `storage: !sled`.
+    #[serde(default)]
+    pub job_store: Option<String>,
+    /// Where jobs, the run history, leases and the audit log are kept. In memory if unset.
+    #[serde(default)]
+    pub storage: Option<StorageConfig>,
+    /// Paths to probe-rs target description YAML files, for chips not built into probe-rs.
+    #[serde(default)]
+    pub chip_descriptions: Vec<String>,
+    #[serde(default)]
+    pub retry: RetryConfig,
+    /// Serve HTTPS instead of HTTP. Required for client certificate authentication.
+    #[serde(default)]
+    pub tls: Option<TlsConfig>,
+    /// Create targets for connected probes that aren't in `targets`.
+    #[serde(default)]
+    pub discovery: Option<DiscoveryConfig>,
+    /// How many runs, each on its own probe, can flash and execute at the same time.
+    #[serde(default = "default_max_parallel_runs")]
+    pub max_parallel_runs: usize,
+    /// Seconds that passing runs are remembered, so the same images submitted again for the same target
+    /// pass right away. Disabled if unset.
+    #[serde(default)]
+    pub result_cache_ttl: Option<u64>,
+    /// Longest a target can be leased for at once, in seconds. Leases can be renewed.
+    #[serde(default = "default_max_lease_ttl")]
+    pub max_lease_ttl: u64,
+    /// Let callers take over targets for interactive gdb sessions.
+    #[serde(default)]
+    pub gdb: Option<GdbConfig>,
+    /// Report jobs as GitHub check runs, for runs that give a repository and commit.
+    #[serde(default)]
+    pub github: Option<GithubConfig>,
+    /// Also serve the API on a unix socket, for tools on the same host. Unix only.
+    #[serde(default)]
+    pub unix_socket: Option<UnixSocketConfig>,
+    /// Run as one of several servers sharing PostgreSQL storage, which all accept runs for all targets.
+    #[serde(default)]
+    pub cluster: Option<ClusterConfig>,
+    /// Let workers register their targets with this server, which lists them along with its own.
+    #[serde(default)]
+    pub workers: Option<WorkersConfig>,
+    /// Run as a worker of another server, registering this one's targets with it.
+    #[serde(default)]
+    pub upstream: Option<UpstreamConfig>,
+    /// Write the logs of each run to a file of its own as it goes.
+    #[serde(default)]
+    pub run_logs: Option<RunLogsConfig>,
+    /// Serve `/metrics` without authentication, for scrapers without a token. They expose target names and job
+    /// counts.
+    #[serde(default)]
+    pub public_metrics: bool,
+}
+
+/// Where to read the server config from.
+#[derive(clap::Parser)]
+pub struct ConfigArgs {
+    /// Path to the config file, or `-` to read it from stdin.
+    #[clap(long = "config", env = "TELEPROBE_CONFIG", default_value = "config.yaml")]
+    path: String,
+
+    /// The whole config as YAML, instead of reading it from a file. Useful for containers.
+    #[clap(long, env = "TELEPROBE_CONFIG_YAML", conflicts_with = "path", hide_env_values = true)]
+    config_yaml: Option<String>,
+}
+
+impl ConfigArgs {
+    pub fn load(&self) -> anyhow::Result<Config> {
+        let (source, data) = match &self.config_yaml {
+            Some(yaml) => ("TELEPROBE_CONFIG_YAML".to_string(), yaml.as_bytes().to_vec()),
+            None if self.path == "-" => {
+                let mut data = Vec::new();
+                io::stdin().read_to_end(&mut data)?;
+                ("stdin".to_string(), data)
+            }
+            None => {
+                let data = fs::read(&self.path).with_context(|| format!("Failed to read config {}", self.path))?;
+                (self.path.clone(), data)
+            }
+        };
+        Config::parse(&data).with_context(|| format!("Invalid config in {}", source))
+    }
+}
+
+impl Config {
+    /// Parse and validate a config. Errors say where in the YAML the problem is.
+    pub fn parse(data: &[u8]) -> anyhow::Result<Self> {
+        let config: Config = serde_yaml::from_slice(data)?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    fn validate(&self) -> anyhow::Result<()> {
+        if self.default_timeout > self.max_timeout {
+            bail!(
+                "default_timeout ({}) is longer than max_timeout ({})",
+                self.default_timeout,
+                self.max_timeout
+            );
+        }
+
+        if self.max_parallel_runs == 0 {
+            bail!("max_parallel_runs: must be at least 1");
+        }
+
+        let mut names = HashSet::new();
+        let mut probes = HashMap::new();
+        for (i, target) in self.targets.iter().enumerate() {
+            if !names.insert(&target.name) {
+                bail!("targets[{}]: duplicate target name `{}`", i, target.name);
+            }
+
+            // Two targets on one probe would have their runs fight over it.
+            let probe = match (&target.usb_path, &target.probe.serial_number) {
+                (Some(path), _) => format!("USB port path {}", path),
+                (None, serial) => format!(
+                    "probe {:04x}:{:04x}:{}",
+                    target.probe.vendor_id,
+                    target.probe.product_id,
+                    serial.as_deref().unwrap_or("*")
+                ),
+            };
+            if let Some(other) = probes.insert(probe.clone(), &target.name) {
+                bail!("targets[{}]: {} is already used by target `{}`", i, probe, other);
+            }
+        }
+
+        for (i, auth) in self.auths.iter().enumerate() {
+            let weights = match auth {
+                Auth::Token(a) => vec![(String::new(), a.weight)],
+                Auth::ClientCert(a) => vec![(String::new(), a.weight)],
+                Auth::Oidc(a) => a
+                    .rules
+                    .iter()
+                    .enumerate()
+                    .map(|(j, r)| (format!(".rules[{}]", j), r.weight))
+                    .collect(),
+            };
+            for (path, weight) in weights {
+                if weight.is_nan() || weight <= 0.0 {
+                    bail!("auths[{}]{}.weight: must be positive", i, path);
+                }
+            }
+
+            let limits = match auth {
+                Auth::Token(a) => vec![(String::new(), &a.rate_limit)],
+                Auth::ClientCert(a) => vec![(String::new(), &a.rate_limit)],
+                Auth::Oidc(a) => a
+                    .rules
+                    .iter()
+                    .enumerate()
+                    .map(|(j, r)| (format!(".rules[{}]", j), &r.rate_limit))
+                    .collect(),
+            };
+            for (path, limit) in limits {
+                let Some(limit) = limit else { continue };
+                for (field, value) in [
+                    ("requests_per_minute", limit.requests_per_minute),
+                    ("run_minutes_per_hour", limit.run_minutes_per_hour),
+                ] {
+                    if value.is_some_and(|v| v.is_nan() || v <= 0.0) {
+                        bail!("auths[{}]{}.rate_limit.{}: must be positive", i, path, field);
+                    }
+                }
+            }
+        }
+
+        if self.job_store.is_some() && self.storage.is_some() {
+            bail!("job_store: can't be set along with `storage`");
+        }
+
+        if let Some(socket) = &self.unix_socket {
+            if u32::from_str_radix(&socket.mode, 8).map_or(true, |mode| mode > 0o777) {
+                bail!("unix_socket.mode: expected octal permissions, like `660`");
+            }
+        }
+
+        if let Some(workers) = &self.workers {
+            if workers.heartbeat_timeout_secs == 0 || workers.deregister_after_secs < workers.heartbeat_timeout_secs {
+                bail!("workers: expected 0 < heartbeat_timeout_secs <= deregister_after_secs");
+            }
+        }
+        if let Some(upstream) = &self.upstream {
+            if !upstream.url.starts_with("http") {
+                bail!("upstream.url: must start with `http`");
+            }
+            if upstream.heartbeat_interval_secs == 0 {
+                bail!("upstream.heartbeat_interval_secs: must be at least 1");
+            }
+            if upstream.max_queued_jobs == 0 || upstream.result_buffer == 0 {
+                bail!("upstream: max_queued_jobs and result_buffer must be at least 1");
+            }
+        }
+
+        if let Some(run_logs) = &self.run_logs {
+            if run_logs.retention_days == 0 || run_logs.max_total_mb == Some(0) {
+                bail!("run_logs: retention_days and max_total_mb must be at least 1");
+            }
+        }
+
+        if let Some(cluster) = &self.cluster {
+            if !matches!(self.storage(), StorageConfig::Postgres { .. }) {
+                bail!("cluster: requires `storage: !postgres`, shared by all servers of the cluster");
+            }
+            if cluster.node.is_empty() {
+                bail!("cluster.node: must not be empty");
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Storage backend of the server's state.
+#[derive(Clone, Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum StorageConfig {
+    /// Lost on restart.
+    #[default]
+    Memory,
+    /// An embedded sled database, in a directory.
+    Sled { path: String },
+    /// A SQLite database file.
+    Sqlite { path: String },
+    /// A PostgreSQL database, like `postgres://teleprobe@db/teleprobe`.
+    Postgres { url: String },
+}
+
+impl Config {
+    /// The configured storage, including the older `job_store`.
+    pub fn storage(&self) -> StorageConfig {
+        match (&self.storage, &self.job_store) {
+            (Some(storage), _) => storage.clone(),
+            (None, Some(path)) => StorageConfig::Sled { path: path.clone() },
+            (None, None) => StorageConfig::Memory,
+        }
+    }
+}
+
+/// Unix socket the API is served on. Whoever can connect to it is let in without a token, with access to all
+/// targets, so the socket's file permissions are what keeps others out.
+#[derive(Clone, Deserialize)]
+pub struct UnixSocketConfig {
+    pub path: String,
+    /// Permissions of the socket file, in octal.
+    #[serde(default = "default_unix_socket_mode")]
+    pub mode: String,
+    /// Group to give the socket file to, so its members can connect.
+    #[serde(default)]
+    pub group: Option<String>,
+}
+
+fn default_unix_socket_mode() -> String {
+    "660".to_string()
+}
+
+/// Membership in a cluster of servers sharing storage. A server runs jobs on the targets whose probes are
+/// attached to it. Jobs it accepts for other targets are left in the storage, for whichever server has the
+/// target's probe to claim.
+#[derive(Clone, Deserialize)]
+pub struct ClusterConfig {
+    /// Name of this server, unique in the cluster.
+    pub node: String,
+    /// How often to look for jobs to claim, in milliseconds.
+    #[serde(default = "default_claim_interval_millis")]
+    pub claim_interval_millis: u64,
+    /// Seconds a job left for other servers may wait to be claimed, before it fails with `probe_not_found`.
+    #[serde(default = "default_claim_timeout_secs")]
+    pub claim_timeout_secs: u64,
+}
+
+fn default_claim_interval_millis() -> u64 {
+    1000
+}
+
+fn default_claim_timeout_secs() -> u64 {
+    300
+}
+
+/// Workers registering with this server, as the API server they work for.
+#[derive(Clone, Deserialize)]
+pub struct WorkersConfig {
+    /// Token of each worker, by name, as `upstream.token` of its config. A token only lets in the worker it's
+    /// for, so workers can't register under each other's names and take over their targets.
+    pub tokens: BTreeMap<String, String>,
+    /// Seconds without a heartbeat after which a worker's targets are down.
+    #[serde(default = "default_heartbeat_timeout_secs")]
+    pub heartbeat_timeout_secs: u64,
+    /// Seconds without a heartbeat after which a worker and its targets are forgotten.
+    #[serde(default = "default_deregister_after_secs")]
+    pub deregister_after_secs: u64,
+}
+
+fn default_heartbeat_timeout_secs() -> u64 {
+    30
+}
+
+fn default_deregister_after_secs() -> u64 {
+    60 * 60
+}
+
+/// The API server this server is a worker of.
+#[derive(Clone, Deserialize)]
+pub struct UpstreamConfig {
+    /// Base URL of the API server, like `https://teleprobe.example.com`.
+    pub url: String,
+    /// This worker's token in `workers.tokens` of the API server's config.
+    pub token: String,
+    /// Name of this worker, unique among the API server's workers. Added to its targets as the `worker` label.
+    pub name: String,
+    /// Labels added to all of this worker's targets.
+    #[serde(default, deserialize_with = "crate::selector::deserialize_labels")]
+    pub labels: BTreeMap<String, String>,
+    #[serde(default = "default_heartbeat_interval_secs")]
+    pub heartbeat_interval_secs: u64,
+    /// Most jobs from the API server this worker takes before it has finished them. They keep running through
+    /// API server outages.
+    #[serde(default = "default_max_queued_jobs")]
+    pub max_queued_jobs: usize,
+    /// Most results of finished jobs kept while the API server can't be reached. The oldest are dropped beyond that.
+    #[serde(default = "default_result_buffer")]
+    pub result_buffer: usize,
+}
+
+fn default_heartbeat_interval_secs() -> u64 {
+    10
+}
+
+fn default_max_queued_jobs() -> usize {
+    16
+}
+
+fn default_result_buffer() -> usize {
+    256
+}
+
+/// Files with the logs of runs, kept longer than the jobs in memory.
+#[derive(Clone, Deserialize)]
+pub struct RunLogsConfig {
+    /// Directory the files are written to, one per run, named after its job ID.
+    pub dir: String,
+    /// Days the files are kept.
+    #[serde(default = "default_run_log_retention_days")]
+    pub retention_days: u64,
+    /// Most the files take in total. The oldest are deleted beyond that. Unlimited if unset.
+    #[serde(default)]
+    pub max_total_mb: Option<u64>,
+    /// Most a single file takes. Runs that log more only get the start of their logs written.
+    #[serde(default = "default_run_log_max_file_mb")]
+    pub max_file_mb: u64,
+}
+
+fn default_run_log_retention_days() -> u64 {
+    30
+}
+
+fn default_run_log_max_file_mb() -> u64 {
+    64
+}
+
+/// Automatic retries of runs that failed because of the probe or flashing, not because of the firmware.
+#[derive(Clone, Deserialize)]
+pub struct RetryConfig {
+    /// How many times to retry. Zero disables retries.
+    #[serde(default)]
+    pub attempts: u32,
+    /// Delay before the first retry. It doubles on every following one.
+    #[serde(default = "default_retry_backoff_millis")]
+    pub backoff_millis: u64,
+}
+
+impl RetryConfig {
+    /// Delay before retrying after the given (zero-based) failed retry count.
+    pub fn backoff(&self, retry: u32) -> Duration {
+        Duration::from_millis(self.backoff_millis.saturating_mul(1 << retry.min(16)))
+    }
+}
+
+impl Default for RetryConfig {
+    fn default() -> Self {
+        Self {
+            attempts: 0,
+            backoff_millis: default_retry_backoff_millis(),
+        }
+    }
+}
+
+fn default_retry_backoff_millis() -> u64 {
+    1000
+}
+
+/// Certificate and private key the server uses for HTTPS, as PEM files.
+#[derive(Clone, Deserialize)]
+pub struct TlsConfig {
+    pub cert: String,
+    pub key: String,
+}
+
+/// Template for targets created for probes found at startup.
+#[derive(Clone, Deserialize)]
+pub struct DiscoveryConfig {
+    /// Target name. `{serial}`, `{vid}` and `{pid}` are replaced with the probe's.
+    #[serde(default = "default_discovery_name")]
+    pub name: String,
+    /// Chip to use when it can't be identified by attaching to it.
+    #[serde(default)]
+    pub chip: Option<String>,
+    #[serde(default, deserialize_with = "crate::selector::deserialize_labels")]
+    pub labels: BTreeMap<String, String>,
+}
+
+/// The GitHub App that check runs are created as.
+#[derive(Clone, Deserialize)]
+pub struct GithubConfig {
+    pub app_id: u64,
+    /// Path to the app's private key, as a PEM file.
+    pub private_key: String,
+    /// For GitHub Enterprise Server, like `https://github.example.com/api/v3`.
+    #[serde(default = "default_github_api_url")]
+    pub api_url: String,
+}
+
+fn default_github_api_url() -> String {
+    "https://api.github.com".to_string()
+}
+
+/// GDB stubs that callers can start on targets, holding off runs until the session ends.
+#[derive(Clone, Deserialize)]
+pub struct GdbConfig {
+    /// probe-rs binary with the GDB stub.
+    #[serde(default = "default_probe_rs")]
+    pub probe_rs: String,
+    /// Address the stubs listen on.
+    #[serde(default = "default_gdb_bind")]
+    pub bind: IpAddr,
+    /// The stub of the n-th target (from zero) listens on `first_port + n`.
+    #[serde(default = "default_gdb_first_port")]
+    pub first_port: u16,
+    /// Longest a session can hold a target, in seconds.
+    #[serde(default = "default_gdb_max_duration")]
+    pub max_duration: u64,
+}
+
+fn default_probe_rs() -> String {
+    "probe-rs".to_string()
+}
+
+fn default_gdb_bind() -> IpAddr {
+    IpAddr::from([127, 0, 0, 1])
+}
+
+fn default_gdb_first_port() -> u16 {
+    3333
+}
+
+fn default_gdb_max_duration() -> u64 {
+    3600
+}
+
+fn default_discovery_name() -> String {
+    "{serial}".to_string()
+}
+
+impl DiscoveryConfig {
+    pub fn target(&self, chip: String, probe: DebugProbeSelector) -> Target {
+        let name = self
+            .name
+            .replace("{serial}", probe.serial_number.as_deref().unwrap_or_default())
+            .replace("{vid}", &format!("{:04x}", probe.vendor_id))
+            .replace("{pid}", &format!("{:04x}", probe.product_id));
+        let mut labels = self.labels.clone();
+        labels.insert("discovered".to_string(), "true".to_string());
+
+        Target {
+            labels,
+            ..Target::new(name, chip, probe)
+        }
+    }
+
+    /// Create targets for all connected probes that no configured target uses, identifying the chip attached to each.
+    pub fn discover(&self, targets: &[Target]) -> Vec<Target> {
+        // A target by USB port path uses whichever probe is plugged in there, which its `probe` may not name fully.
+        let configured: Vec<DebugProbeSelector> = targets
+            .iter()
+            .map(|t| match &t.usb_path {
+                Some(path) => match probe::resolve_usb_path(path, Some(&t.probe)) {
+                    Ok(probe) => probe.selector,
+                    Err(_) => t.probe.clone(),
+                },
+                None => t.probe.clone(),
+            })
+            .collect();
+
+        let mut res = Vec::new();
+        for probe in Lister::new().list_all() {
+            let known = configured.iter().any(|t| {
+                t.vendor_id == probe.vendor_id
+                    && t.product_id == probe.product_id
+                    && t.serial_number
+                        .as_ref()
+                        .map_or(true, |s| Some(s) == probe.serial_number.as_ref())
+            });
+            if known {
+                continue;
+            }
+            let Some(serial) = probe.serial_number.clone() else {
+                log::warn!(
+                    "Not discovering probe {:04x}:{:04x} without a serial number, it can't be told apart from others",
+                    probe.vendor_id,
+                    probe.product_id
+                );
+                continue;
+            };
+
+            let chip = match probe::identify_chip(&probe) {
+                Ok(chip) => chip.name,
+                Err(e) => match &self.chip {
+                    Some(chip) => chip.clone(),
+                    None => {
+                        log::warn!("Not discovering probe {}, failed to identify its chip: {:?}", serial, e);
+                        continue;
+                    }
+                },
+            };
+
+            let selector = DebugProbeSelector {
+                vendor_id: probe.vendor_id,
+                product_id: probe.product_id,
+                serial_number: Some(serial),
+            };
+            let target = self.target(chip, selector);
+            if targets.iter().chain(&res).any(|t| t.name == target.name) {
+                log::warn!("Not discovering target {}, the name is already taken", target.name);
+                continue;
+            }
+            log::info!("Discovered target {} with chip {}", target.name, target.chip);
+            res.push(target);
+        }
+        res
+    }
 }
 
 #[derive(Clone, Deserialize)]
@@ -26,6 +593,8 @@ pub enum Auth {
     Oidc(OidcAuth),
     #[serde(rename = "token")]
     Token(TokenAuth),
+    #[serde(rename = "client_cert")]
+    ClientCert(ClientCertAuth),
 }
 
 impl ToString for Auth {
@@ -33,43 +602,239 @@ impl ToString for Auth {
         match self {
             Auth::Oidc(_) => "OIDC",
             Auth::Token(_) => "Token",
+            Auth::ClientCert(_) => "ClientCert",
         }
         .to_string()
     }
 }
 
 #[derive(Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
 pub struct OidcAuth {
     pub issuer: String,
     pub rules: Vec<OidcAuthRule>,
 }
 
 #[derive(Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
 pub struct OidcAuthRule {
     #[serde(default)]
-    pub claims: HashMap<String, String>,
+    pub claims: HashMap<String, ClaimMatcher>,
+    /// Targets that tokens matching this rule may use. All of them if unset.
+    #[serde(default)]
+    pub targets: Option<Vec<TargetScope>>,
+    #[serde(default)]
+    pub rate_limit: Option<RateLimit>,
+    /// Share of the targets' time when several callers queue jobs on them, relative to the others.
+    #[serde(default = "default_weight")]
+    pub weight: f64,
+    /// May use the `/admin` endpoints, on the targets it may use.
+    #[serde(default)]
+    pub admin: bool,
 }
 
 #[derive(Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
 pub struct TokenAuth {
     pub token: String,
+    /// Targets this token may use. All of them if unset.
+    #[serde(default)]
+    pub targets: Option<Vec<TargetScope>>,
+    #[serde(default)]
+    pub rate_limit: Option<RateLimit>,
+    /// Share of the targets' time when several callers queue jobs on them, relative to the others.
+    #[serde(default = "default_weight")]
+    pub weight: f64,
+    /// May use the `/admin` endpoints, on the targets it may use.
+    #[serde(default)]
+    pub admin: bool,
+    /// GitHub repositories (`owner/name`) that this token may report jobs to as check runs.
+    #[serde(default)]
+    pub github_repos: Vec<String>,
+}
+
+/// Limits on how much callers using one token, OIDC rule or CA can use the server.
+#[derive(Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RateLimit {
+    /// Authenticated requests per minute.
+    #[serde(default)]
+    pub requests_per_minute: Option<f64>,
+    /// Minutes per hour that runs may hold a target.
+    #[serde(default)]
+    pub run_minutes_per_hour: Option<f64>,
+}
+
+/// A target, or group of targets, that an auth grants access to.
+#[derive(Clone, Deserialize)]
+#[serde(untagged, deny_unknown_fields)]
+pub enum TargetScope {
+    /// The target with exactly this name.
+    Name(String),
+    /// All targets matching a label selector.
+    Selector { selector: Selector },
+}
+
+impl TargetScope {
+    pub fn matches(&self, name: &str, labels: &BTreeMap<String, String>) -> bool {
+        match self {
+            TargetScope::Name(n) => n == name,
+            TargetScope::Selector { selector } => selector.matches(labels),
+        }
+    }
+}
+
+/// Accept clients presenting a certificate signed by a CA, without a bearer token.
+#[derive(Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ClientCertAuth {
+    /// Path to the PEM file with the CA certificate(s).
+    pub ca: String,
+    /// Targets that clients with these certificates may use. All of them if unset.
+    #[serde(default)]
+    pub targets: Option<Vec<TargetScope>>,
+    #[serde(default)]
+    pub rate_limit: Option<RateLimit>,
+    /// Share of the targets' time when several callers queue jobs on them, relative to the others.
+    #[serde(default = "default_weight")]
+    pub weight: f64,
+    /// May use the `/admin` endpoints, on the targets it may use.
+    #[serde(default)]
+    pub admin: bool,
+    /// GitHub repositories (`owner/name`) that clients with these certificates may report jobs to as check runs.
+    #[serde(default)]
+    pub github_repos: Vec<String>,
 }
 
 #[derive(Clone, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
 pub struct Target {
     pub name: String,
     pub chip: String,
     pub probe: DebugProbeSelector,
+    /// Physical USB port of the probe, like `1-1.4.2`. Linux only.
+    #[serde(default)]
+    pub usb_path: Option<String>,
     #[serde(default)]
     pub connect_under_reset: bool,
     #[serde(default)]
     pub speed: Option<u32>,
+    /// Use the fastest probe clock the link works at, from `speed` or 24 MHz down.
+    #[serde(default)]
+    pub auto_speed: bool,
     #[serde(default)]
     pub power_reset: bool,
     #[serde(default = "default_cycle_delay_seconds")]
     pub cycle_delay_seconds: f64,
+    /// How to switch the probe's power, for `power_reset` and the power endpoint.
+    #[serde(default)]
+    pub power_control: PowerControl,
     #[serde(default = "default_max_settle_time_millis")]
     pub max_settle_time_millis: u64,
+    /// Times to try attaching to the chip before the run fails.
+    #[serde(default = "default_connect_attempts")]
+    pub connect_attempts: u32,
+    #[serde(default = "default_connect_retry_interval_millis")]
+    pub connect_retry_interval_millis: u64,
+    /// Erase the chip to unlock it if attaching fails because it's read-protected, so boards that got locked heal
+    /// themselves. Supported on nRF52 and nRF91.
+    #[serde(default)]
+    pub auto_recover: bool,
+    /// Keep the probe session open between runs instead of connecting for each of them, to save the USB
+    /// enumeration and attach time. The board isn't power reset between runs then.
+    #[serde(default)]
+    pub keep_session: bool,
+    /// Arbitrary labels, for picking a target with a label selector.
+    #[serde(default, deserialize_with = "crate::selector::deserialize_labels")]
+    pub labels: BTreeMap<String, String>,
+    #[serde(default)]
+    pub flash: FlashOptions,
+    /// Checked before every run, to catch boards that got swapped when rewiring.
+    #[serde(default)]
+    pub identity: Option<BoardIdentity>,
+    /// Serial port whose output is captured into the run logs, for firmware that logs over UART.
+    #[serde(default)]
+    pub uart: Option<UartConfig>,
+    /// Path to a stimulus script, run against the device from the server's CAN, I2C and SPI buses.
+    #[serde(default)]
+    pub stimulus: Option<String>,
+    /// What to do when the firmware stays blocked on a full RTT buffer.
+    #[serde(default)]
+    pub rtt_overflow: RttOverflowOptions,
+    /// Capture a core dump of runs that crash or time out.
+    #[serde(default)]
+    pub core_dump: bool,
+    /// Whether firmware runs from RAM or flash, for chips where it can't be told from the memory map.
+    /// Firmware setting `.teleprobe.run_from` overrides `auto`.
+    #[serde(default)]
+    pub run_from: RunFrom,
+    /// Stop the chip's watchdogs from counting while the core is halted, so they don't reset it while it's being
+    /// flashed and set up. Supported on STM32 F1, F2, F3, F4, F7, G4, H7, L4 and WB, on RP2040, and on nRF52,
+    /// whose watchdog pauses by default. Other chips, including other STM32 families, are run without it.
+    #[serde(default)]
+    pub freeze_watchdogs: bool,
+    /// Turn off access port protection in UICR on nRF52 revisions that turn it back on at every reset, so runs
+    /// don't need to erase the chip to attach. UICR is only written while it's erased.
+    #[serde(default)]
+    pub disable_approtect: bool,
+    /// Measure the most stack the firmware used, into the run report. The firmware must paint its stack, with
+    /// cortex-m-rt's `paint-stack` feature.
+    #[serde(default)]
+    pub stack_usage: bool,
+    /// Guard the end of the firmware's stack with an MPU region, so overflows fault right away instead of
+    /// corrupting memory. Needs a Cortex-M3, M4 or M7, and firmware that doesn't set up the MPU itself.
+    #[serde(default)]
+    pub stack_guard: bool,
+    /// Read the coverage counters of firmware built with `-C instrument-coverage` after runs, into an LLVM profile.
+    #[serde(default)]
+    pub coverage: bool,
+    /// Take the target out of service: no new runs are scheduled on it. Can also be toggled at runtime.
+    #[serde(default)]
+    pub maintenance: bool,
+}
+
+impl Target {
+    /// A target with the defaults for everything but its name, chip and probe.
+    pub fn new(name: String, chip: String, probe: DebugProbeSelector) -> Self {
+        Target {
+            name,
+            chip,
+            probe,
+            usb_path: None,
+            connect_under_reset: false,
+            speed: None,
+            auto_speed: false,
+            power_reset: false,
+            cycle_delay_seconds: default_cycle_delay_seconds(),
+            power_control: PowerControl::default(),
+            max_settle_time_millis: default_max_settle_time_millis(),
+            connect_attempts: default_connect_attempts(),
+            connect_retry_interval_millis: default_connect_retry_interval_millis(),
+            auto_recover: false,
+            keep_session: false,
+            labels: BTreeMap::new(),
+            flash: FlashOptions::default(),
+            identity: None,
+            uart: None,
+            stimulus: None,
+            rtt_overflow: RttOverflowOptions::default(),
+            core_dump: false,
+            run_from: RunFrom::default(),
+            freeze_watchdogs: false,
+            disable_approtect: false,
+            stack_usage: false,
+            stack_guard: false,
+            coverage: false,
+            maintenance: false,
+        }
+    }
+}
+
+#[derive(Clone, Deserialize, Serialize)]
+pub struct UartConfig {
+    /// Device path, like `/dev/ttyUSB0`.
+    pub path: String,
+    pub baud: u32,
 }
 
 fn default_cycle_delay_seconds() -> f64 {
@@ -80,6 +845,14 @@ fn default_max_settle_time_millis() -> u64 {
     60000
 }
 
+fn default_connect_attempts() -> u32 {
+    10
+}
+
+fn default_connect_retry_interval_millis() -> u64 {
+    300
+}
+
 #[derive(Clone, Serialize, Deserialize)]
 pub struct TargetList {
     pub targets: Vec<Target>,
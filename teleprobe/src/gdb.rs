@@ -0,0 +1,61 @@
+//! Interactive debugging sessions, served by probe-rs's GDB stub. It isn't available as a library, so
+//! it runs as a `probe-rs gdb` child process.
+
+use std::net::SocketAddr;
+use std::process::{Child, Command};
+
+use anyhow::Context as _;
+
+use crate::probe;
+
+/// Start a GDB stub for the board behind `opts`, listening on `addr`.
+pub fn spawn(
+    probe_rs: &str,
+    opts: &probe::TargetSpec,
+    chip_descriptions: &[String],
+    addr: SocketAddr,
+) -> anyhow::Result<Child> {
+    let mut cmd = Command::new(probe_rs);
+    cmd.arg("gdb")
+        .arg("--chip")
+        .arg(&opts.chip)
+        .arg("--gdb-connection-string")
+        .arg(addr.to_string());
+
+    let selector = match &opts.usb_path {
+        Some(path) => {
+            let probe = probe::resolve_usb_path(path, opts.probe.as_ref())?;
+            if probe.shared_serial {
+                anyhow::bail!(
+                    "probe at USB port path {} shares its serial number with other probes, which `{} gdb` can't tell \
+                     apart",
+                    path,
+                    probe_rs
+                );
+            }
+            Some(probe.selector)
+        }
+        None => opts.probe.clone(),
+    };
+    if let Some(selector) = selector {
+        let mut s = format!("{:04x}:{:04x}", selector.vendor_id, selector.product_id);
+        if let Some(serial) = &selector.serial_number {
+            s.push(':');
+            s.push_str(serial);
+        }
+        cmd.arg("--probe").arg(s);
+    }
+    if let Some(speed) = opts.speed {
+        cmd.arg("--speed").arg(speed.to_string());
+    }
+    if opts.connect_under_reset {
+        cmd.arg("--connect-under-reset");
+    }
+    for path in chip_descriptions {
+        cmd.arg("--chip-description-path").arg(path);
+    }
+
+    log::debug!("starting gdb stub: {:?}", cmd);
+    cmd.spawn()
+        .with_context(|| format!("failed to start `{} gdb`", probe_rs))
+}
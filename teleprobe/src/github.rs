@@ -0,0 +1,198 @@
+//! GitHub check runs for jobs, so their results show up on pull requests. Teleprobe authenticates as a
+//! GitHub App, which must be installed on the repositories with the "Checks" write permission.
+
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::time::{Duration, Instant};
+
+use anyhow::Context as _;
+use chrono::Utc;
+use jsonwebtoken::{Algorithm, EncodingKey, Header};
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+
+use crate::api::{self, CheckRun};
+use crate::config::GithubConfig;
+
+/// Installation tokens are valid for an hour. Renew them well before that.
+const TOKEN_LIFETIME: Duration = Duration::from_secs(50 * 60);
+/// Longest `output.text` GitHub accepts.
+const MAX_TEXT_LEN: usize = 65535;
+
+pub struct Checks {
+    api_url: String,
+    app_id: String,
+    key: EncodingKey,
+    http: reqwest::Client,
+    /// Installation tokens by repository, and when to renew them.
+    tokens: Mutex<HashMap<String, (String, Instant)>>,
+}
+
+#[derive(Serialize)]
+struct AppClaims {
+    iat: i64,
+    exp: i64,
+    iss: String,
+}
+
+#[derive(Deserialize)]
+struct Id {
+    id: u64,
+}
+
+#[derive(Deserialize)]
+struct Token {
+    token: String,
+}
+
+impl Checks {
+    pub fn new(config: &GithubConfig) -> anyhow::Result<Self> {
+        let pem = std::fs::read(&config.private_key)
+            .with_context(|| format!("Failed to read GitHub App key {}", config.private_key))?;
+        Ok(Self {
+            api_url: config.api_url.trim_end_matches('/').to_string(),
+            app_id: config.app_id.to_string(),
+            key: EncodingKey::from_rsa_pem(&pem).context("Invalid GitHub App key")?,
+            http: reqwest::Client::builder().user_agent("teleprobe").build()?,
+            tokens: Mutex::new(HashMap::new()),
+        })
+    }
+
+    /// Create an in-progress check run on commit `sha` of `repo`.
+    pub async fn create(&self, repo: &str, sha: &str, name: &str, job_id: &str) -> anyhow::Result<CheckRun> {
+        let body = json!({
+            "name": name,
+            "head_sha": sha,
+            "external_id": job_id,
+            "status": "in_progress",
+        });
+        let token = self.token(repo).await?;
+        let res = self
+            .request(reqwest::Method::POST, &format!("/repos/{}/check-runs", repo), &token)
+            .json(&body)
+            .send()
+            .await?
+            .error_for_status()?;
+        let check: Id = res.json().await?;
+        Ok(CheckRun {
+            repo: repo.to_string(),
+            id: check.id,
+        })
+    }
+
+    /// Complete a check run with the result of its job, and the device logs.
+    pub async fn complete(&self, check: &CheckRun, job: &api::Job, logs: &[u8]) -> anyhow::Result<()> {
+        let conclusion = match (job.status, job.failure) {
+            (api::JobStatus::Succeeded, _) => "success",
+            (_, Some(api::RunError::Timeout)) => "timed_out",
+            (_, Some(api::RunError::Cancelled)) => "cancelled",
+            _ => "failure",
+        };
+        let title = match job.failure {
+            Some(failure) => format!("Failed: {}", failure),
+            None => "Passed".to_string(),
+        };
+
+        let mut summary = format!("Job `{}` on target `{}`", job.id, job.target);
+        if let (Some(started_at), Some(finished_at)) = (job.started_at, job.finished_at) {
+            let secs = (finished_at - started_at).num_milliseconds() as f64 / 1000.0;
+            write!(summary, ", ran for {:.1}s", secs).unwrap();
+        }
+        summary.push_str(".\n");
+        if let Some(panic) = &job.panic {
+            write!(summary, "\nFirmware {}.\n", panic).unwrap();
+        }
+        if !job.backtrace.is_empty() {
+            summary.push_str("\nBacktrace:\n```\n");
+            for frame in &job.backtrace {
+                write!(summary, "{}", frame.function).unwrap();
+                if let (Some(file), Some(line)) = (&frame.file, frame.line) {
+                    write!(summary, " at {}:{}", file, line).unwrap();
+                }
+                summary.push('\n');
+            }
+            summary.push_str("```\n");
+        }
+
+        // Keep the end of the logs, where failures are.
+        let logs = String::from_utf8_lossy(logs);
+        let mut start = logs.len().saturating_sub(MAX_TEXT_LEN - 16);
+        while !logs.is_char_boundary(start) {
+            start += 1;
+        }
+        let text = format!("```\n{}\n```", &logs[start..]);
+
+        let body = json!({
+            "status": "completed",
+            "conclusion": conclusion,
+            "completed_at": Utc::now(),
+            "output": {
+                "title": title,
+                "summary": summary,
+                "text": text,
+            },
+        });
+        let token = self.token(&check.repo).await?;
+        self.request(
+            reqwest::Method::PATCH,
+            &format!("/repos/{}/check-runs/{}", check.repo, check.id),
+            &token,
+        )
+        .json(&body)
+        .send()
+        .await?
+        .error_for_status()?;
+        Ok(())
+    }
+
+    fn request(&self, method: reqwest::Method, path: &str, token: &str) -> reqwest::RequestBuilder {
+        self.http
+            .request(method, format!("{}{}", self.api_url, path))
+            .bearer_auth(token)
+            .header("Accept", "application/vnd.github+json")
+    }
+
+    /// Installation token of the app for `repo`.
+    async fn token(&self, repo: &str) -> anyhow::Result<String> {
+        if let Some((token, renew_at)) = self.tokens.lock().get(repo) {
+            if Instant::now() < *renew_at {
+                return Ok(token.clone());
+            }
+        }
+
+        let now = Utc::now().timestamp();
+        let claims = AppClaims {
+            // Backdated against clock drift, as GitHub recommends.
+            iat: now - 60,
+            exp: now + 9 * 60,
+            iss: self.app_id.clone(),
+        };
+        let jwt = jsonwebtoken::encode(&Header::new(Algorithm::RS256), &claims, &self.key)?;
+
+        let installation: Id = self
+            .request(reqwest::Method::GET, &format!("/repos/{}/installation", repo), &jwt)
+            .send()
+            .await?
+            .error_for_status()
+            .with_context(|| format!("GitHub App is not installed on {}", repo))?
+            .json()
+            .await?;
+        let token: Token = self
+            .request(
+                reqwest::Method::POST,
+                &format!("/app/installations/{}/access_tokens", installation.id),
+                &jwt,
+            )
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+
+        self.tokens
+            .lock()
+            .insert(repo.to_string(), (token.token.clone(), Instant::now() + TOKEN_LIFETIME));
+        Ok(token.token)
+    }
+}
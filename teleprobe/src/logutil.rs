@@ -2,20 +2,38 @@ use core::any::Any;
 use core::pin::Pin;
 use std::cell::{Cell, RefCell};
 use std::future::Future;
+use std::io::Write as _;
 use std::panic::{catch_unwind, AssertUnwindSafe, UnwindSafe};
-use std::sync::OnceLock;
+use std::sync::mpsc::Sender;
+use std::sync::{Arc, OnceLock};
 use std::task::{Context, Poll};
 
-use chrono::{DateTime, Utc};
+use chrono::{DateTime, SecondsFormat, Utc};
 use log::{LevelFilter, Log, Record};
+use parking_lot::Mutex;
 use pin_project_lite::pin_project;
 use pretty_env_logger::env_logger::filter::{self, Filter};
 use pretty_env_logger::env_logger::Logger;
 use serde::{Deserialize, Serialize};
+use tokio::sync::mpsc::UnboundedSender;
 
 static LOGGER: OnceLock<CaptureLogger> = OnceLock::new();
 
-pub(crate) fn init() {
+/// Target of records printed as they are, without a level or timestamp, like the client's GitHub Actions
+/// workflow commands, which only work at the start of a line.
+pub const VERBATIM_TARGET: &str = "teleprobe::verbatim";
+
+/// How the process's own logs are printed.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum LogFormat {
+    /// Colored lines, for people.
+    #[default]
+    Pretty,
+    /// One JSON object per line, for log collectors like Loki or Elasticsearch.
+    Json,
+}
+
+pub(crate) fn init(format: LogFormat) {
     let mut inner_logger = pretty_env_logger::formatted_builder();
     inner_logger.parse_filters("trace");
     let inner_logger = inner_logger.build();
@@ -47,6 +65,7 @@ pub(crate) fn init() {
         ui_filter,
         capture_filter,
         logger: inner_logger,
+        format,
     };
     LOGGER.set(logger).map_err(|_| ()).unwrap();
 
@@ -55,24 +74,133 @@ pub(crate) fn init() {
     log_panics::init();
 }
 
-pub fn with_capture<F, R>(f: F) -> (R, Vec<LogEntry>)
+/// Where the records logged for a job are captured. It's handed to [`with_job`] along with the job, so the records
+/// of jobs running side by side never end up in each other's sink.
+#[derive(Clone)]
+pub struct Sink {
+    entries: Arc<Mutex<Vec<LogEntry>>>,
+    /// Gets the entries as they're logged too, for following a run while it goes on.
+    live: Option<UnboundedSender<LogEntry>>,
+}
+
+impl Sink {
+    pub fn new(live: Option<UnboundedSender<LogEntry>>) -> Self {
+        Self {
+            entries: Arc::default(),
+            live,
+        }
+    }
+
+    fn push(&self, entry: LogEntry) {
+        if let Some(live) = &self.live {
+            // The receiver is gone once the run finished, that's fine.
+            let _ = live.send(entry.clone());
+        }
+        self.entries.lock().push(entry);
+    }
+
+    /// Take the entries captured so far.
+    pub fn take(&self) -> Vec<LogEntry> {
+        std::mem::take(&mut *self.entries.lock())
+    }
+}
+
+/// The job a thread runs, its target, and where the records logged for it are captured.
+struct ThreadJob {
+    job_id: String,
+    target_name: String,
+    sink: Sink,
+}
+
+thread_local! {
+    static DEVICE_TAP: RefCell<Option<Sender<String>>> = RefCell::new(None);
+    static JOB: RefCell<Option<ThreadJob>> = RefCell::new(None);
+}
+
+tokio::task_local! {
+    /// ID of the job the task works on, so its log lines can be told apart from other jobs'.
+    static TASK_JOB: String;
+}
+
+/// Run `f`, with the records it logs tagged with the job they're about.
+pub async fn scope_job<F: Future>(job_id: String, f: F) -> F::Output {
+    TASK_JOB.scope(job_id, f).await
+}
+
+/// The job and target the current thread or task works on, if any.
+fn current_job() -> (Option<String>, Option<String>) {
+    let job = JOB.with(|j| j.borrow().as_ref().map(|j| (j.job_id.clone(), j.target_name.clone())));
+    match job {
+        Some((job_id, target_name)) => (Some(job_id), Some(target_name)),
+        None => (TASK_JOB.try_with(|id| id.clone()).ok(), None),
+    }
+}
+
+/// Run `f`, with the records it logs tagged with the job and target they're about, and captured into `sink`.
+pub fn with_job<F, R>(job_id: String, target_name: String, sink: Sink, f: F) -> R
 where
     F: FnOnce() -> R,
 {
-    CAPTURE.with(|c| *c.borrow_mut() = Some(Vec::new()));
+    JOB.with(|j| {
+        *j.borrow_mut() = Some(ThreadJob {
+            job_id,
+            target_name,
+            sink,
+        })
+    });
     let res = f();
-    let entries = CAPTURE.with(|c| c.borrow_mut().take().unwrap());
-    (res, entries)
+    JOB.with(|j| *j.borrow_mut() = None);
+    res
 }
 
-thread_local! {
-    pub static CAPTURE: RefCell<Option<Vec<LogEntry>>> = RefCell::new(None);
+/// Run `f`, sending the device log lines it logs to `tap`.
+pub fn with_device_tap<F, R>(tap: Sender<String>, f: F) -> R
+where
+    F: FnOnce() -> R,
+{
+    DEVICE_TAP.with(|t| *t.borrow_mut() = Some(tap));
+    let res = f();
+    DEVICE_TAP.with(|t| *t.borrow_mut() = None);
+    res
 }
 
 struct CaptureLogger {
     ui_filter: Filter,
     capture_filter: Filter,
     logger: Logger,
+    format: LogFormat,
+}
+
+impl CaptureLogger {
+    fn log_pretty(&self, record: &Record) {
+        match current_job().0 {
+            Some(job_id) => self.logger.log(
+                &Record::builder()
+                    .args(format_args!("[{}] {}", job_id, record.args()))
+                    .level(record.level())
+                    .target(record.target())
+                    .module_path(record.module_path())
+                    .file(record.file())
+                    .line(record.line())
+                    .build(),
+            ),
+            None => self.logger.log(record),
+        }
+    }
+
+    fn log_json(&self, record: &Record) {
+        let (job_id, target_name) = current_job();
+        let line = serde_json::json!({
+            "ts": Utc::now().to_rfc3339_opts(SecondsFormat::Micros, true),
+            "level": record.level().as_str(),
+            "target": record.target(),
+            "job_id": job_id,
+            "target_name": target_name,
+            "msg": record.args().to_string(),
+        });
+        // Like the pretty logger, a failed write to stderr is dropped.
+        let _ = writeln!(std::io::stderr().lock(), "{}", line);
+    }
 }
 
 impl Log for CaptureLogger {
@@ -81,13 +209,29 @@ impl Log for CaptureLogger {
     }
 
     fn log(&self, record: &log::Record) {
+        if record.target() == "device" {
+            DEVICE_TAP.with(|t| {
+                if let Some(tap) = t.borrow().as_ref() {
+                    // The receiver is gone once the script finished, that's fine.
+                    let _ = tap.send(record.args().to_string());
+                }
+            });
+        }
         if self.ui_filter.matches(record) {
-            self.logger.log(record);
+            if record.target() == VERBATIM_TARGET {
+                // Like the pretty logger, a failed write to stderr is dropped.
+                let _ = writeln!(std::io::stderr().lock(), "{}", record.args());
+                return;
+            }
+            match self.format {
+                LogFormat::Pretty => self.log_pretty(record),
+                LogFormat::Json => self.log_json(record),
+            }
         }
         if self.capture_filter.matches(record) {
-            CAPTURE.with(|c| {
-                if let Some(entries) = c.borrow_mut().as_mut() {
-                    entries.push(LogEntry::from_record(record))
+            JOB.with(|j| {
+                if let Some(job) = j.borrow().as_ref() {
+                    job.sink.push(LogEntry::from_record(record));
                 }
             });
         }
@@ -1,15 +1,29 @@
 pub mod api;
 pub mod auth;
+pub mod badge;
+pub mod blobs;
 pub mod client;
 pub mod config;
+pub mod gdb;
+pub mod github;
 pub mod logutil;
-pub mod probe;
-pub mod run;
+pub mod ratelimit;
+pub mod runlog;
+pub mod sched;
 pub mod server;
+pub mod stimulus;
+pub mod storage;
+pub mod telemetry;
+pub mod uart;
+pub mod upstream;
 pub mod util;
+pub mod worker;
+
+pub use teleprobe_core::{probe, run, selector};
 
 include!(concat!(env!("OUT_DIR"), "/meta.rs"));
 
+use anyhow::Context as _;
 use clap::Parser;
 
 #[derive(clap::Parser)]
@@ -22,25 +36,209 @@ enum Cli {
     Server {
         #[clap(long, default_value_t = 8080)]
         port: u16,
+
+        /// Addresses to listen on, as IPs (using `--port`) or IP:port pairs.
+        #[clap(long, value_delimiter = ',', default_value = "0.0.0.0")]
+        bind: Vec<String>,
+
+        #[clap(flatten)]
+        config: config::ConfigArgs,
+
+        /// Format of the server's own logs.
+        #[clap(long, value_enum, default_value_t, env = "TELEPROBE_LOG_FORMAT")]
+        log_format: logutil::LogFormat,
+
+        #[clap(subcommand)]
+        command: Option<ServerCommand>,
     },
     Client(client::Command),
 }
 
+impl Cli {
+    fn log_format(&self) -> logutil::LogFormat {
+        match self {
+            Cli::Server { log_format, .. } => *log_format,
+            _ => logutil::LogFormat::Pretty,
+        }
+    }
+}
+
+#[derive(clap::Subcommand)]
+enum ServerCommand {
+    /// Print aggregated, anonymized stats about past runs as JSON, for sharing with the teleprobe maintainers.
+    /// Reads the job store, so the server must not be running.
+    ExportTelemetry {
+        /// Only include runs from the last N days.
+        #[clap(long, default_value_t = 7)]
+        days: i64,
+    },
+}
+
+#[derive(Clone, Copy, clap::ValueEnum)]
+enum OutputFormat {
+    Text,
+    Json,
+}
+
 #[derive(clap::Subcommand)]
 enum LocalCommand {
-    ListProbes,
+    ListProbes {
+        #[clap(long, value_enum, default_value = "text")]
+        format: OutputFormat,
+
+        /// Attach to each probe's chip briefly, to find out which chip it is, for writing target configs.
+        #[clap(long)]
+        identify: bool,
+    },
     Run {
         /// ELF file to flash+run
-        #[clap(long)]
+        #[clap(long, visible_alias = "image")]
         elf: String,
 
+        /// Format of the image.
+        #[clap(long, value_enum, default_value_t)]
+        format: api::ImageFormat,
+
+        /// Address to load `bin` images at. Defaults to the start of flash.
+        #[clap(long, value_parser = crate::run::parse_address)]
+        base_address: Option<u64>,
+
+        /// probe-rs target description YAML file, for chips not built into probe-rs. Can be repeated.
+        #[clap(long)]
+        chip_description: Vec<String>,
+
+        /// What to do when the firmware stays blocked on a full RTT buffer.
+        #[clap(long, value_enum, default_value_t)]
+        rtt_overflow: crate::run::RttOverflowStrategy,
+
+        /// Whether the firmware runs from RAM or flash. `auto` uses `.teleprobe.run_from`, or the memory region
+        /// the vector table is linked at.
+        #[clap(long, value_enum, default_value_t)]
+        run_from: crate::run::RunFrom,
+
+        /// Stop the chip's watchdogs from counting while the core is halted, so they don't reset it while it's
+        /// being flashed and set up.
+        #[clap(long)]
+        freeze_watchdogs: bool,
+
+        /// Turn off access port protection in UICR on nRF52 revisions that turn it back on at every reset.
+        #[clap(long)]
+        disable_approtect: bool,
+
+        /// Measure the most stack the firmware used, into the report. The firmware must paint its stack, with
+        /// cortex-m-rt's `paint-stack` feature.
+        #[clap(long)]
+        stack_usage: bool,
+
+        /// Guard the end of the firmware's stack with an MPU region, so overflows fault right away.
+        #[clap(long)]
+        stack_guard: bool,
+
+        /// Only print the device logs this `RUST_LOG`-style filter lets through, like `warn,net=trace`.
+        #[clap(long)]
+        log_filter: Option<crate::run::LogFilter>,
+
+        /// Prefix device log lines with the host's wall-clock time, before the device timestamp.
+        #[clap(long)]
+        host_timestamps: bool,
+
+        /// Write the raw defmt stream the firmware sent to this file, to decode it again later, like with
+        /// `defmt-print -e <elf>`.
+        #[clap(long)]
+        raw_defmt: Option<String>,
+
+        /// Write the LLVM coverage profile of firmware built with `-C instrument-coverage` to this `.profraw` file.
+        #[clap(long)]
+        coverage: Option<String>,
+
+        /// Write a JSON report of the run to this file: how it ended, the backtrace, the decoded logs and timings.
+        #[clap(long)]
+        report: Option<String>,
+
+        /// Write an ELF core file to this path if the firmware crashes or times out, for debugging with gdb.
+        #[clap(long)]
+        core_dump: Option<String>,
+
+        /// Keep running: whenever the file changes, flash and run it again, over the same probe session.
+        #[clap(long)]
+        watch: bool,
+
+        #[clap(flatten)]
+        probe: crate::probe::Opts,
+    },
+    /// Attach to the firmware running on a board, without flashing or resetting it, and print its logs until
+    /// it halts.
+    Monitor {
+        /// ELF of the running firmware, to decode its defmt logs with their locations. Without it, RTT up
+        /// channel 0 is printed as text.
+        #[clap(long)]
+        elf: Option<String>,
+
+        /// probe-rs target description YAML file, for chips not built into probe-rs. Can be repeated.
+        #[clap(long)]
+        chip_description: Vec<String>,
+
+        #[clap(flatten)]
+        probe: crate::probe::Opts,
+    },
+    /// Erase the whole chip, unlocking it first if it's read-protected, to recover a board from firmware that
+    /// made it impossible to run on.
+    Erase {
+        /// probe-rs target description YAML file, for chips not built into probe-rs. Can be repeated.
+        #[clap(long)]
+        chip_description: Vec<String>,
+
+        #[clap(flatten)]
+        probe: crate::probe::Opts,
+    },
+    /// Switch the USB port power of a probe, and of the board if it's powered through it. Switching it through
+    /// sysfs, without `--hub`, is Linux only.
+    Power {
+        action: crate::probe::PowerAction,
+
+        /// Seconds the power stays off when cycling it.
+        #[clap(long, default_value = "1")]
+        delay: f64,
+
+        /// The probe to switch (specified by eg. `VID:PID:Serial`, or just `Serial`).
+        #[clap(long, env = "PROBE_RUN_PROBE")]
+        probe: Option<probe_rs::probe::DebugProbeSelector>,
+
+        /// Physical USB port of the probe, like `1-1.4.2`. Needed to power it back on.
+        #[clap(long)]
+        usb_path: Option<String>,
+
+        /// Switch the port with hub requests to this smart hub, by its USB port path like `1-1.4`, instead of
+        /// through sysfs. Works on macOS too, with uhubctl installed.
+        #[clap(long, requires = "hub_port")]
+        hub: Option<String>,
+
+        /// Port of `--hub` the probe is plugged into.
+        #[clap(long)]
+        hub_port: Option<u16>,
+    },
+    /// Serve a board over the GDB remote protocol, with probe-rs's GDB stub, until it's stopped.
+    Gdb {
+        /// Address for gdb to connect to, with `target extended-remote`.
+        #[clap(long, default_value = "127.0.0.1:1337")]
+        bind: std::net::SocketAddr,
+
+        /// probe-rs binary with the GDB stub.
+        #[clap(long, default_value = "probe-rs")]
+        probe_rs: String,
+
+        /// probe-rs target description YAML file, for chips not built into probe-rs. Can be repeated.
+        #[clap(long)]
+        chip_description: Vec<String>,
+
         #[clap(flatten)]
         probe: crate::probe::Opts,
     },
 }
 
 fn main() -> anyhow::Result<()> {
-    logutil::init();
+    let cli = Cli::parse();
+    logutil::init(cli.log_format());
 
     // force capture backtraces
     //std::env::set_var("RUST_BACKTRACE", "1");
@@ -50,24 +248,207 @@ fn main() -> anyhow::Result<()> {
         .build()
         .unwrap();
     let local = tokio::task::LocalSet::new();
-    local.block_on(&rt, async_main())
+    local.block_on(&rt, async_main(cli))
 }
 
-async fn async_main() -> anyhow::Result<()> {
-    let cli = Cli::parse();
-
+async fn async_main(cli: Cli) -> anyhow::Result<()> {
     match cli {
         Cli::Local { command } => match command {
-            LocalCommand::ListProbes => crate::probe::list(),
-            LocalCommand::Run { elf, probe } => {
-                let elf = std::fs::read(elf)?;
-                let mut sess = crate::probe::connect(&probe)?;
+            LocalCommand::ListProbes { format, identify } => {
+                let probes = crate::probe::list(identify);
+                match format {
+                    OutputFormat::Json => println!("{}", serde_json::to_string_pretty(&probes)?),
+                    OutputFormat::Text if probes.is_empty() => println!("No probe found!"),
+                    OutputFormat::Text => {
+                        for probe in probes {
+                            println!("{}", probe);
+                        }
+                    }
+                }
+                Ok(())
+            }
+            LocalCommand::Run {
+                elf,
+                format,
+                base_address,
+                chip_description,
+                rtt_overflow,
+                run_from,
+                freeze_watchdogs,
+                disable_approtect,
+                stack_usage,
+                stack_guard,
+                log_filter,
+                host_timestamps,
+                raw_defmt,
+                coverage,
+                report: report_path,
+                core_dump,
+                watch,
+                probe,
+            } => {
+                for path in &chip_description {
+                    crate::probe::load_chip_description(path)?;
+                }
+                let mut sess = crate::probe::connect(&probe.into())?;
+
+                let run_once = |sess: &mut probe_rs::Session| -> anyhow::Result<()> {
+                    let image = crate::run::Image {
+                        data: std::fs::read(&elf)?.into(),
+                        format,
+                        base_address,
+                    };
+                    let mut opts = crate::run::Options::default();
+                    opts.rtt_overflow.strategy = rtt_overflow;
+                    opts.core_dump = core_dump.is_some();
+                    opts.run_from = run_from;
+                    opts.freeze_watchdogs = freeze_watchdogs;
+                    opts.disable_approtect = disable_approtect;
+                    opts.stack_usage = stack_usage;
+                    opts.stack_guard = stack_guard;
+                    opts.coverage = coverage.is_some();
+                    opts.log_filter = log_filter.clone();
+                    opts.host_timestamps = host_timestamps;
+                    opts.raw_defmt = raw_defmt.is_some();
+                    let report = crate::run::run_image(sess, &image, opts);
+                    log::debug!("timings: {:?}", report.timings);
+                    if let Some(path) = &report_path {
+                        std::fs::write(path, serde_json::to_vec_pretty(&report)?)
+                            .with_context(|| format!("failed to write report to {}", path))?;
+                    }
+                    if let (Some(path), Some(dump)) = (&core_dump, &report.core_dump) {
+                        std::fs::write(path, dump).with_context(|| format!("failed to write core dump to {}", path))?;
+                        log::info!("core dump written to {}", path);
+                    }
+                    if let (Some(path), Some(stream)) = (&raw_defmt, &report.defmt_raw) {
+                        std::fs::write(path, stream)
+                            .with_context(|| format!("failed to write raw defmt stream to {}", path))?;
+                    }
+                    if let (Some(path), Some(profile)) = (&coverage, &report.coverage) {
+                        std::fs::write(path, profile)
+                            .with_context(|| format!("failed to write coverage profile to {}", path))?;
+                        log::info!("coverage profile written to {}", path);
+                    }
+                    match report.error {
+                        Some(e) => Err(anyhow::Error::msg(e)),
+                        None => Ok(()),
+                    }
+                };
 
-                let opts = crate::run::Options::default();
-                crate::run::run(&mut sess, &elf, opts)
+                if !watch {
+                    return run_once(&mut sess);
+                }
+                loop {
+                    let modified = modified_time(&elf);
+                    if let Err(e) = run_once(&mut sess) {
+                        log::error!("run failed: {:?}", e);
+                    }
+                    log::info!("watching {} for changes...", elf);
+                    wait_for_change(&elf, modified);
+                }
+            }
+            LocalCommand::Monitor {
+                elf,
+                chip_description,
+                probe,
+            } => {
+                for path in &chip_description {
+                    crate::probe::load_chip_description(path)?;
+                }
+                let mut sess = crate::probe::attach(&probe.into())?;
+                let opts = crate::run::Options {
+                    attach: true,
+                    ..Default::default()
+                };
+                match elf {
+                    Some(elf) => {
+                        let report = crate::run::run(&mut sess, &std::fs::read(elf)?, opts);
+                        match report.error {
+                            Some(e) => Err(anyhow::Error::msg(e)),
+                            None => Ok(()),
+                        }
+                    }
+                    None => crate::run::monitor_text(&mut sess, &opts),
+                }
+            }
+            LocalCommand::Erase {
+                chip_description,
+                probe,
+            } => {
+                for path in &chip_description {
+                    crate::probe::load_chip_description(path)?;
+                }
+                crate::probe::erase(&probe.into())
+            }
+            LocalCommand::Power {
+                action,
+                delay,
+                probe,
+                usb_path,
+                hub,
+                hub_port,
+            } => {
+                let control = match (hub, hub_port) {
+                    (Some(hub), Some(port)) => crate::probe::PowerControl::Uhubctl { hub, port },
+                    _ => crate::probe::PowerControl::Sysfs,
+                };
+                crate::probe::set_power(probe.as_ref(), usb_path.as_deref(), &control, action, delay)
+            }
+            LocalCommand::Gdb {
+                bind,
+                probe_rs,
+                chip_description,
+                probe,
+            } => {
+                let mut child = crate::gdb::spawn(&probe_rs, &probe.into(), &chip_description, bind)?;
+                log::info!("gdb stub listening, connect with `target extended-remote {}`", bind);
+                let status = child.wait()?;
+                if !status.success() {
+                    anyhow::bail!("gdb stub exited with {}", status);
+                }
+                Ok(())
             }
         },
-        Cli::Server { port } => crate::server::serve(port).await,
+        Cli::Server {
+            port,
+            bind,
+            config,
+            command,
+            ..
+        } => {
+            let config = config.load()?;
+            match command {
+                None => {
+                    let bind = bind
+                        .iter()
+                        .map(|b| crate::server::parse_bind_addr(b, port))
+                        .collect::<anyhow::Result<_>>()?;
+                    crate::server::serve(config, bind).await
+                }
+                Some(ServerCommand::ExportTelemetry { days }) => crate::telemetry::export(config, days),
+            }
+        }
         Cli::Client(cmd) => client::main(cmd).await,
     }
 }
+
+/// How often `local run --watch` checks the file, and how long it must stay unchanged before it's run.
+const WATCH_INTERVAL: std::time::Duration = std::time::Duration::from_millis(300);
+
+fn modified_time(path: &str) -> Option<std::time::SystemTime> {
+    std::fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
+/// Block until the modification time of `path` is no longer `since`, and has then settled, so a
+/// linker still writing the file isn't caught halfway.
+fn wait_for_change(path: &str, since: Option<std::time::SystemTime>) {
+    let mut last = since;
+    loop {
+        std::thread::sleep(WATCH_INTERVAL);
+        let now = modified_time(path);
+        if now.is_some() && now != since && now == last {
+            return;
+        }
+        last = now;
+    }
+}
@@ -0,0 +1,109 @@
+use std::time::{Duration, Instant};
+
+/// A token bucket: holds up to `capacity` tokens, refilled continuously at `per_second`.
+#[derive(Clone)]
+pub struct TokenBucket {
+    capacity: f64,
+    per_second: f64,
+    tokens: f64,
+    updated: Instant,
+}
+
+impl TokenBucket {
+    /// A full bucket, refilling its whole capacity over `period`.
+    pub fn new(capacity: f64, period: Duration) -> Self {
+        Self {
+            capacity,
+            per_second: capacity / period.as_secs_f64(),
+            tokens: capacity,
+            updated: Instant::now(),
+        }
+    }
+
+    fn refill(&mut self) {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.per_second).min(self.capacity);
+        self.updated = now;
+    }
+
+    /// Take one token. If there's none left, returns how long until there will be.
+    pub fn take(&mut self) -> Result<(), Duration> {
+        self.check()?;
+        self.tokens -= 1.0;
+        Ok(())
+    }
+
+    /// Check there's some of the bucket left, without taking anything. If there isn't,
+    /// returns how long until there will be.
+    pub fn check(&mut self) -> Result<(), Duration> {
+        self.refill();
+        let needed = self.capacity.min(1.0);
+        if self.tokens >= needed {
+            return Ok(());
+        }
+        Err(Duration::try_from_secs_f64((needed - self.tokens) / self.per_second).unwrap_or(Duration::MAX))
+    }
+
+    /// Take `amount` tokens after the fact. This can leave the bucket in debt, which
+    /// then has to be paid back before `take` or `check` succeed again.
+    pub fn charge(&mut self, amount: f64) {
+        self.refill();
+        self.tokens -= amount;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn take_until_empty() {
+        let mut bucket = TokenBucket::new(2.0, Duration::from_secs(60));
+        assert!(bucket.take().is_ok());
+        assert!(bucket.take().is_ok());
+        let wait = bucket.take().unwrap_err();
+        assert!(
+            wait > Duration::from_secs(29) && wait <= Duration::from_secs(30),
+            "{:?}",
+            wait
+        );
+        assert!(bucket.check().is_err());
+    }
+
+    #[test]
+    fn charge_into_debt() {
+        let mut bucket = TokenBucket::new(10.0, Duration::from_secs(3600));
+        bucket.charge(15.0);
+        // 6 tokens short, at one every 6 minutes.
+        let wait = bucket.check().unwrap_err();
+        assert!(
+            wait > Duration::from_secs(2150) && wait <= Duration::from_secs(2160),
+            "{:?}",
+            wait
+        );
+    }
+
+    #[test]
+    fn fractional_capacity() {
+        // Less than a token in total: checking needs a full bucket rather than a whole token.
+        let mut bucket = TokenBucket::new(0.5, Duration::from_secs(60));
+        assert!(bucket.check().is_ok());
+        bucket.charge(0.5);
+        let wait = bucket.check().unwrap_err();
+        assert!(
+            wait > Duration::from_secs(59) && wait <= Duration::from_secs(60),
+            "{:?}",
+            wait
+        );
+    }
+
+    #[test]
+    fn refills_over_time() {
+        let mut bucket = TokenBucket::new(1.0, Duration::from_millis(20));
+        assert!(bucket.take().is_ok());
+        assert!(bucket.take().is_err());
+        std::thread::sleep(Duration::from_millis(40));
+        assert!(bucket.take().is_ok());
+    }
+}
@@ -0,0 +1,372 @@
+//! Logs of runs, each in a file of its own, written as the run goes. They outlive the jobs the server keeps in
+//! memory, so they can still be fetched after the caller disconnected or the server restarted.
+
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc;
+use std::time::{Duration, SystemTime};
+
+use anyhow::Context;
+use log::warn;
+
+use crate::config::RunLogsConfig;
+
+/// Line a run's log ends with once it hit `max_file_mb`.
+const TRUNCATED: &[u8] = b"... run log truncated, it hit the size limit\n";
+
+/// The run log directory. Writes are done on a thread of their own, in order, so they neither hold up the server
+/// nor need its lock. Reading and pruning block, and are meant for `spawn_blocking`.
+#[derive(Clone)]
+pub struct RunLogs {
+    dir: PathBuf,
+    retention: Duration,
+    max_total_bytes: Option<u64>,
+    writes: mpsc::Sender<WriteOp>,
+}
+
+enum WriteOp {
+    /// Replace what's in a run's log from `offset` on.
+    At { id: String, offset: u64, data: Vec<u8> },
+    /// Add to the end of a run's log.
+    Append { id: String, data: Vec<u8> },
+    /// Replace a run's log with its final one, and close it.
+    Finish { id: String, data: Vec<u8> },
+}
+
+impl RunLogs {
+    pub fn open(config: &RunLogsConfig) -> anyhow::Result<Self> {
+        fs::create_dir_all(&config.dir).with_context(|| format!("failed to create run log dir {}", config.dir))?;
+        let dir = PathBuf::from(&config.dir);
+        let (writes, rx) = mpsc::channel();
+        let mut writer = Writer {
+            dir: dir.clone(),
+            max_file_bytes: config.max_file_mb * 1024 * 1024,
+            open: HashMap::new(),
+        };
+        std::thread::Builder::new()
+            .name("run logs".to_string())
+            .spawn(move || {
+                for op in rx {
+                    writer.apply(op);
+                }
+            })?;
+        Ok(Self {
+            dir,
+            retention: Duration::from_secs(config.retention_days * 24 * 60 * 60),
+            max_total_bytes: config.max_total_mb.map(|mb| mb * 1024 * 1024),
+            writes,
+        })
+    }
+
+    fn send(&self, op: WriteOp) {
+        // The writer only exits with the server.
+        let _ = self.writes.send(op);
+    }
+
+    /// Replace what's in a run's log from `offset` on, like when a worker relays its logs again from there, or an
+    /// attempt starts over from the logs of the earlier ones.
+    pub fn write_at(&self, id: &str, offset: usize, data: &[u8]) {
+        self.send(WriteOp::At {
+            id: id.to_string(),
+            offset: offset as u64,
+            data: data.to_vec(),
+        });
+    }
+
+    /// Add to the end of a run's log.
+    pub fn append(&self, id: &str, data: &[u8]) {
+        self.send(WriteOp::Append {
+            id: id.to_string(),
+            data: data.to_vec(),
+        });
+    }
+
+    /// Replace a run's log with its final one, like when its logs are sorted together with the UART and stimulus
+    /// lines.
+    pub fn finish(&self, id: &str, data: Vec<u8>) {
+        self.send(WriteOp::Finish {
+            id: id.to_string(),
+            data,
+        });
+    }
+
+    pub fn read(&self, id: &str) -> io::Result<Option<Vec<u8>>> {
+        let Some(path) = log_path(&self.dir, id) else {
+            return Ok(None);
+        };
+        match fs::read(path) {
+            Ok(data) => Ok(Some(data)),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Delete the logs older than the retention, then the oldest ones until the rest fit in `max_total_mb`.
+    pub fn prune(&self) -> io::Result<()> {
+        let now = SystemTime::now();
+        let mut files = Vec::new();
+        for entry in fs::read_dir(&self.dir)? {
+            let entry = entry?;
+            let meta = entry.metadata()?;
+            if !meta.is_file() || entry.path().extension().map_or(true, |e| e != "log") {
+                continue;
+            }
+            let modified = meta.modified()?;
+            if now.duration_since(modified).unwrap_or_default() > self.retention {
+                remove(entry.path());
+            } else {
+                files.push((modified, meta.len(), entry.path()));
+            }
+        }
+
+        let Some(max) = self.max_total_bytes else { return Ok(()) };
+        files.sort();
+        let mut total: u64 = files.iter().map(|(_, len, _)| len).sum();
+        for (_, len, path) in files {
+            if total <= max {
+                break;
+            }
+            remove(path);
+            total -= len;
+        }
+        Ok(())
+    }
+}
+
+/// Job IDs are hex, anything else isn't one and mustn't get near the filesystem.
+fn log_path(dir: &Path, id: &str) -> Option<PathBuf> {
+    let valid = !id.is_empty() && id.chars().all(|c| c.is_ascii_alphanumeric());
+    valid.then(|| dir.join(format!("{}.log", id)))
+}
+
+/// The log file of a running job, kept open until it finishes.
+struct OpenLog {
+    file: File,
+    len: u64,
+    truncated: bool,
+}
+
+struct Writer {
+    dir: PathBuf,
+    max_file_bytes: u64,
+    open: HashMap<String, OpenLog>,
+}
+
+impl Writer {
+    fn apply(&mut self, op: WriteOp) {
+        let (id, result) = match op {
+            WriteOp::At { id, offset, data } => {
+                let result = self.write(&id, Some(offset), &data);
+                (id, result)
+            }
+            WriteOp::Append { id, data } => {
+                let result = self.write(&id, None, &data);
+                (id, result)
+            }
+            WriteOp::Finish { id, data } => {
+                let result = self.write(&id, Some(0), &data);
+                self.open.remove(&id);
+                (id, result)
+            }
+        };
+        if let Err(e) = result {
+            warn!("Failed to write run log of job {}: {}", id, e);
+            // Opened again by the next write.
+            self.open.remove(&id);
+        }
+    }
+
+    /// Write `data` at `offset`, or the end, dropping what's after it, and past the size limit.
+    fn write(&mut self, id: &str, offset: Option<u64>, data: &[u8]) -> io::Result<()> {
+        let Some(path) = log_path(&self.dir, id) else {
+            return Ok(());
+        };
+        let log = match self.open.remove(id) {
+            Some(log) => log,
+            None => {
+                let file = OpenOptions::new().create(true).write(true).truncate(false).open(path)?;
+                let len = file.metadata()?.len();
+                OpenLog {
+                    file,
+                    len,
+                    truncated: len >= self.max_file_bytes,
+                }
+            }
+        };
+        let log = self.open.entry(id.to_string()).or_insert(log);
+
+        // Past the size limit, the file already has all it keeps, and the marker after it.
+        if let Some(offset) = offset.filter(|o| *o < log.len.min(self.max_file_bytes)) {
+            log.file.set_len(offset)?;
+            log.len = offset;
+            log.truncated = offset >= self.max_file_bytes;
+        }
+        if log.truncated {
+            return Ok(());
+        }
+        log.file.seek(SeekFrom::Start(log.len))?;
+        let room = self.max_file_bytes.saturating_sub(log.len) as usize;
+        log.file.write_all(&data[..data.len().min(room)])?;
+        log.len += data.len().min(room) as u64;
+        if data.len() > room {
+            log.file.write_all(TRUNCATED)?;
+            log.len += TRUNCATED.len() as u64;
+            log.truncated = true;
+        }
+        Ok(())
+    }
+}
+
+fn remove(path: PathBuf) {
+    if let Err(e) = fs::remove_file(&path) {
+        warn!("Failed to remove run log {}: {}", path.display(), e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// An empty directory of the test's own.
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("teleprobe-runlog-{}-{}", std::process::id(), name));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn writer(dir: &Path, max_file_bytes: u64) -> Writer {
+        Writer {
+            dir: dir.to_path_buf(),
+            max_file_bytes,
+            open: HashMap::new(),
+        }
+    }
+
+    fn run_logs(dir: &Path, retention: Duration, max_total_bytes: Option<u64>) -> RunLogs {
+        RunLogs {
+            dir: dir.to_path_buf(),
+            retention,
+            max_total_bytes,
+            writes: mpsc::channel().0,
+        }
+    }
+
+    fn at(id: &str, offset: u64, data: &[u8]) -> WriteOp {
+        WriteOp::At {
+            id: id.to_string(),
+            offset,
+            data: data.to_vec(),
+        }
+    }
+
+    #[test]
+    fn write_at_and_append() {
+        let dir = temp_dir("write");
+        let logs = run_logs(&dir, Duration::MAX, None);
+        let mut writer = writer(&dir, 1024);
+        writer.apply(at("abc", 0, b"hello\n"));
+        writer.apply(WriteOp::Append {
+            id: "abc".to_string(),
+            data: b"world\n".to_vec(),
+        });
+        assert_eq!(logs.read("abc").unwrap().unwrap(), b"hello\nworld\n");
+
+        // Relayed again from an earlier offset, the rest is replaced.
+        writer.apply(at("abc", 6, b"again\n"));
+        assert_eq!(logs.read("abc").unwrap().unwrap(), b"hello\nagain\n");
+
+        writer.apply(WriteOp::Finish {
+            id: "abc".to_string(),
+            data: b"final\n".to_vec(),
+        });
+        assert!(writer.open.is_empty());
+        assert_eq!(logs.read("abc").unwrap().unwrap(), b"final\n");
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn size_limit() {
+        let dir = temp_dir("limit");
+        let logs = run_logs(&dir, Duration::MAX, None);
+        let mut writer = writer(&dir, 8);
+        writer.apply(at("abc", 0, b"0123456789"));
+        writer.apply(at("abc", 10, b"more"));
+        assert_eq!(
+            logs.read("abc").unwrap().unwrap(),
+            [&b"01234567"[..], TRUNCATED].concat()
+        );
+
+        // Going back below the limit starts writing again.
+        writer.apply(at("abc", 4, b"ab"));
+        assert_eq!(logs.read("abc").unwrap().unwrap(), b"0123ab");
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn invalid_ids() {
+        let dir = temp_dir("invalid");
+        let logs = run_logs(&dir, Duration::MAX, None);
+        let mut writer = writer(&dir, 1024);
+        writer.apply(at("../abc", 0, b"hello\n"));
+        writer.apply(at("", 0, b"hello\n"));
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 0);
+        assert!(logs.read("../abc").unwrap().is_none());
+        assert!(logs.read("def").unwrap().is_none());
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn prune_by_retention() {
+        let dir = temp_dir("retention");
+        let logs = run_logs(&dir, Duration::from_secs(60 * 60), None);
+        fs::write(dir.join("old.log"), b"old").unwrap();
+        fs::write(dir.join("new.log"), b"new").unwrap();
+        fs::write(dir.join("other.txt"), b"other").unwrap();
+        let old = SystemTime::now() - Duration::from_secs(2 * 60 * 60);
+        File::options()
+            .write(true)
+            .open(dir.join("old.log"))
+            .unwrap()
+            .set_modified(old)
+            .unwrap();
+        File::options()
+            .write(true)
+            .open(dir.join("other.txt"))
+            .unwrap()
+            .set_modified(old)
+            .unwrap();
+
+        logs.prune().unwrap();
+        assert!(logs.read("old").unwrap().is_none());
+        assert!(logs.read("new").unwrap().is_some());
+        assert!(dir.join("other.txt").exists());
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn prune_by_total_size() {
+        let dir = temp_dir("total");
+        let logs = run_logs(&dir, Duration::from_secs(60 * 60), Some(10));
+        let now = SystemTime::now();
+        for (i, id) in ["a", "b", "c"].into_iter().enumerate() {
+            let path = dir.join(format!("{}.log", id));
+            fs::write(&path, b"12345").unwrap();
+            let modified = now - Duration::from_secs(60 * (3 - i as u64));
+            File::options()
+                .write(true)
+                .open(path)
+                .unwrap()
+                .set_modified(modified)
+                .unwrap();
+        }
+
+        logs.prune().unwrap();
+        assert!(logs.read("a").unwrap().is_none());
+        assert!(logs.read("b").unwrap().is_some());
+        assert!(logs.read("c").unwrap().is_some());
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,173 @@
+//! Order in which jobs waiting for a target get it, so callers sharing the farm take turns instead of
+//! running in submission order. Each caller gets runs in proportion to its weight: a caller that queued
+//! 200 jobs gets one run, then one of another caller's 2 jobs runs, and so on.
+
+use std::collections::HashMap;
+
+use tokio::sync::oneshot;
+
+/// The jobs waiting for one target.
+#[derive(Default)]
+pub struct Queue {
+    /// Whether a job has the target.
+    busy: bool,
+    /// In submission order.
+    waiting: Vec<Waiter>,
+    /// Runs each caller got on the target, divided by its weight.
+    served: HashMap<String, f64>,
+}
+
+struct Waiter {
+    caller: String,
+    weight: f64,
+    turn: oneshot::Sender<()>,
+}
+
+impl Queue {
+    /// Ask for the target. Returns `None` if it's free, and taken now, or a receiver that fires once it's
+    /// the caller's turn. Either way, the target must be handed on with `leave` when done with it.
+    pub fn enter(&mut self, caller: &str, weight: f64) -> Option<oneshot::Receiver<()>> {
+        if !self.busy {
+            self.busy = true;
+            self.charge(caller, weight);
+            return None;
+        }
+
+        // A caller that just started waiting gets in line with the others, instead of catching up on
+        // all the runs it didn't need while it was away.
+        if !self.waiting.iter().any(|w| w.caller == caller) {
+            let floor = self
+                .waiting
+                .iter()
+                .map(|w| self.served(&w.caller))
+                .min_by(|a, b| a.total_cmp(b));
+            if let Some(floor) = floor {
+                let served = self.served.entry(caller.to_string()).or_default();
+                *served = served.max(floor);
+            }
+        }
+
+        let (tx, rx) = oneshot::channel();
+        self.waiting.push(Waiter {
+            caller: caller.to_string(),
+            weight,
+            turn: tx,
+        });
+        Some(rx)
+    }
+
+    /// Hand the target to the waiting caller that got the fewest runs for its weight, oldest job first.
+    pub fn leave(&mut self) {
+        loop {
+            let next = self
+                .waiting
+                .iter()
+                .enumerate()
+                .min_by(|(i, a), (j, b)| self.served(&a.caller).total_cmp(&self.served(&b.caller)).then(i.cmp(j)))
+                .map(|(i, _)| i);
+            let Some(i) = next else {
+                // Nobody is behind anyone while the target is free, so the tally starts over.
+                self.busy = false;
+                self.served.clear();
+                return;
+            };
+
+            let waiter = self.waiting.remove(i);
+            // Jobs that stopped waiting, like cancelled synchronous runs, are skipped.
+            if waiter.turn.send(()).is_ok() {
+                self.charge(&waiter.caller, waiter.weight);
+                return;
+            }
+        }
+    }
+
+    /// Whether no job has the target or waits for it, so the queue can be dropped.
+    pub fn is_idle(&self) -> bool {
+        !self.busy && self.waiting.is_empty()
+    }
+
+    fn served(&self, caller: &str) -> f64 {
+        self.served.get(caller).copied().unwrap_or_default()
+    }
+
+    fn charge(&mut self, caller: &str, weight: f64) {
+        *self.served.entry(caller.to_string()).or_default() += 1.0 / weight;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Hand the target on, and return which of the receivers got it.
+    fn next(queue: &mut Queue, waiting: &mut [(&str, oneshot::Receiver<()>)]) -> String {
+        queue.leave();
+        let turns: Vec<&str> = waiting
+            .iter_mut()
+            .filter_map(|(caller, rx)| rx.try_recv().is_ok().then_some(*caller))
+            .collect();
+        assert_eq!(turns.len(), 1, "one job gets the target");
+        turns[0].to_string()
+    }
+
+    #[test]
+    fn free_target_is_taken_right_away() {
+        let mut queue = Queue::default();
+        assert!(queue.enter("a", 1.0).is_none());
+        assert!(!queue.is_idle());
+        queue.leave();
+        assert!(queue.is_idle());
+        assert!(queue.enter("b", 1.0).is_none());
+    }
+
+    #[test]
+    fn callers_take_turns() {
+        let mut queue = Queue::default();
+        assert!(queue.enter("a", 1.0).is_none());
+        let mut waiting: Vec<_> = ["a", "a", "a", "b"]
+            .into_iter()
+            .map(|c| (c, queue.enter(c, 1.0).unwrap()))
+            .collect();
+
+        let order: Vec<String> = (0..4).map(|_| next(&mut queue, &mut waiting)).collect();
+        assert_eq!(order, ["a", "b", "a", "a"]);
+    }
+
+    #[test]
+    fn turns_follow_weights() {
+        let mut queue = Queue::default();
+        assert!(queue.enter("x", 1.0).is_none());
+        let mut waiting = Vec::new();
+        for _ in 0..4 {
+            waiting.push(("a", queue.enter("a", 2.0).unwrap()));
+        }
+        for _ in 0..4 {
+            waiting.push(("b", queue.enter("b", 1.0).unwrap()));
+        }
+
+        let order: Vec<String> = (0..6).map(|_| next(&mut queue, &mut waiting)).collect();
+        assert_eq!(order, ["a", "b", "a", "a", "b", "a"]);
+    }
+
+    #[test]
+    fn jobs_that_stopped_waiting_are_skipped() {
+        let mut queue = Queue::default();
+        assert!(queue.enter("a", 1.0).is_none());
+        drop(queue.enter("a", 1.0).unwrap());
+        let mut waiting = vec![("b", queue.enter("b", 1.0).unwrap())];
+
+        assert_eq!(next(&mut queue, &mut waiting), "b");
+        queue.leave();
+        assert!(queue.is_idle());
+    }
+
+    #[test]
+    fn tally_starts_over_once_idle() {
+        let mut queue = Queue::default();
+        for _ in 0..3 {
+            assert!(queue.enter("a", 1.0).is_none());
+            queue.leave();
+        }
+        assert!(queue.served.is_empty());
+    }
+}
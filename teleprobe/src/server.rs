@@ -1,106 +1,278 @@
-use std::collections::HashMap;
+use std::cell::RefCell;
+use std::collections::{BTreeMap, HashMap, VecDeque};
 use std::fmt::Write;
-use std::fs;
-use std::sync::Arc;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{mpsc, Arc};
 use std::time::{Duration, Instant};
 
 use anyhow::{anyhow, bail};
-use bytes::Bytes;
-use log::{error, info};
+use base64::Engine as _;
+use bytes::{BufMut, Bytes, BytesMut};
+use chrono::{SecondsFormat, Utc};
+use futures::{FutureExt, TryStreamExt};
+use log::{error, info, warn};
 use parking_lot::Mutex;
 use probe_rs::probe::list::Lister;
+use probe_rs::probe::DebugProbeInfo;
 use serde::{Deserialize, Serialize};
-use tokio::sync::Mutex as AsyncMutex;
-use tokio::task::spawn_blocking;
+use teleprobe_core::{RunReport, Runner};
+use tokio::sync::{oneshot, Mutex as AsyncMutex};
 use warp::hyper::StatusCode;
-use warp::reply::{html, with_status};
+use warp::multipart::FormData;
+use warp::reply::{html, with_status, WithStatus};
 use warp::{Filter, Rejection, Reply};
 
 use crate::auth::oidc;
 use crate::auth::oidc::Client;
 use crate::config::{Auth, Config, OidcAuthRule};
-use crate::{api, probe, run};
+use crate::ratelimit::TokenBucket;
+use crate::selector::Selector;
+use crate::storage::{self, Storage};
+use crate::{api, badge, blobs, config, github, probe, run, runlog, sched, stimulus, uart, upstream, worker};
 
-fn run_firmware_on_device(elf: Bytes, probe: probe::Opts, timeout: Duration) -> anyhow::Result<()> {
-    let mut sess = probe::connect(&probe)?;
+/// Log targets of the auxiliary log streams merged into the device logs.
+const AUX_LOG_TARGETS: [&str; 2] = [uart::TARGET, stimulus::TARGET];
 
-    let opts = run::Options {
-        deadline: Some(Instant::now() + timeout),
-        ..Default::default()
-    };
-    run::run(&mut sess, &elf, opts)?;
+thread_local! {
+    /// Runner of the target this probe worker thread runs on, with its probe session kept open, for
+    /// `keep_session` targets, and the [`run_settings`] of the target it was made for.
+    static KEPT_RUNNER: RefCell<Option<(Vec<u8>, Runner)>> = RefCell::new(None);
+}
 
-    Ok(())
+/// Close the probe session kept open on this worker thread, before something else uses the probe.
+fn close_kept_session() {
+    KEPT_RUNNER.with(|runner| drop(runner.borrow_mut().take()));
 }
 
-async fn run_with_log_capture(elf: Bytes, probe: probe::Opts, timeout: Duration) -> (bool, Vec<u8>) {
-    let (ok, entries) = spawn_blocking(move || {
-        crate::logutil::with_capture(|| match run_firmware_on_device(elf, probe, timeout) {
-            Ok(()) => true,
-            Err(e) => {
-                error!("Run failed: {:?}", e);
-                false
+/// `live` gets the captured log lines as they're logged. The auxiliary log streams are only merged in at the end.
+async fn run_with_log_capture(
+    pool: &worker::Pool,
+    image: run::Image,
+    probe: probe::TargetSpec,
+    mut opts: run::Options,
+    target: config::Target,
+    host_timestamps: bool,
+    job_id: &str,
+    mut live: impl FnMut(&[u8]),
+) -> (RunReport, Vec<u8>) {
+    let name = target.name.clone();
+    let (tap, mut tapped) = tokio::sync::mpsc::unbounded_channel();
+    let sink = crate::logutil::Sink::new(Some(tap));
+    let job_id = job_id.to_string();
+    let run_sink = sink.clone();
+    let run = pool.run(&name, move || {
+        let settings = run_settings(&target);
+        crate::logutil::with_job(job_id, target.name.clone(), run_sink, || {
+            let capture = target.uart.and_then(|uart| match uart::Capture::start(&uart) {
+                Ok(capture) => Some(capture),
+                Err(e) => {
+                    warn!("Failed to open UART {}: {:?}", uart.path, e);
+                    None
+                }
+            });
+
+            let (tap, device_logs) = mpsc::channel();
+            let execution = target.stimulus.and_then(|path| match stimulus::Script::load(&path) {
+                Ok(script) => {
+                    let (started_tx, started_rx) = mpsc::channel();
+                    opts.started = Some(started_tx);
+                    Some(stimulus::Execution::start(script, started_rx, device_logs))
+                }
+                Err(e) => {
+                    warn!("Failed to load stimulus script {}: {:?}", path, e);
+                    None
+                }
+            });
+
+            let report = crate::logutil::with_device_tap(tap, || {
+                if !target.keep_session {
+                    return Runner::new(probe).run(&image, opts);
+                }
+                KEPT_RUNNER.with(|kept| {
+                    let mut kept = kept.borrow_mut();
+                    // The session was opened with the old settings, like another probe or speed.
+                    if kept.as_ref().is_some_and(|(s, _)| *s != settings) {
+                        info!("Target settings changed, closing the kept probe session");
+                        *kept = None;
+                    }
+                    let (_, runner) = kept.get_or_insert_with(|| (settings, Runner::new(probe).keep_session(true)));
+                    runner.run(&image, opts)
+                })
+            });
+            if let Some(e) = &report.error {
+                error!("Run failed: {}", e);
             }
+
+            let mut aux_entries = capture.map(|c| c.finish()).unwrap_or_default();
+            aux_entries.extend(execution.map(|e| e.finish()).unwrap_or_default());
+            (report, aux_entries)
         })
-    })
-    .await
-    .unwrap();
+    });
+    let mut follow_line = |entry: &crate::logutil::LogEntry| {
+        let mut line = String::new();
+        format_entry(&mut line, entry, host_timestamps);
+        live(line.as_bytes());
+    };
+    let (report, aux_entries) = {
+        let follow = async {
+            while let Some(entry) = tapped.recv().await {
+                follow_line(&entry);
+            }
+        };
+        futures::pin_mut!(run, follow);
+        // The sink keeps the channel open, so following only stops with the run.
+        match futures::future::select(run, follow).await {
+            futures::future::Either::Left((res, _)) => res,
+            futures::future::Either::Right(((), run)) => run.await,
+        }
+    }
+    .unwrap_or_else(|e| {
+        error!("Run on target {} failed: {}", name, e);
+        // The probe's thread outlives the panic, but the state of the probe isn't known anymore.
+        let report = RunReport {
+            failure: Some(api::RunError::ProbeNotFound),
+            error: Some(e.to_string()),
+            ..Default::default()
+        };
+        (report, Vec::new())
+    });
+    // What was logged last, like the run's error, may still be queued.
+    while let Ok(entry) = tapped.try_recv() {
+        follow_line(&entry);
+    }
+    let mut entries = sink.take();
+
+    // Stable, so entries logged at the same time keep their order.
+    entries.extend(aux_entries);
+    entries.sort_by_key(|e| e.timestamp);
 
     let mut res = String::new();
     for entry in entries {
-        writeln!(&mut res, "{} - {}", entry.level, entry.message).unwrap();
+        format_entry(&mut res, &entry, host_timestamps);
+    }
+    (report, res.into_bytes())
+}
+
+fn format_entry(res: &mut String, entry: &crate::logutil::LogEntry, host_timestamps: bool) {
+    if host_timestamps {
+        write!(res, "{} ", entry.timestamp.to_rfc3339_opts(SecondsFormat::Micros, true)).unwrap();
+    }
+    match entry.module_path.as_deref().filter(|m| AUX_LOG_TARGETS.contains(m)) {
+        Some(aux) => writeln!(res, "{} - {}: {}", entry.level, aux, entry.message).unwrap(),
+        None => writeln!(res, "{} - {}", entry.level, entry.message).unwrap(),
     }
-    (ok, res.into_bytes())
 }
 
 macro_rules! reject {
-    (StatusCode::$code:ident, $($x:tt)*) => {
-        return Ok(with_status(
-            format!($($x)*).as_bytes().to_vec(),
-            StatusCode::$code,
-        ))
+    (StatusCode::$status:ident, code = $code:literal, $($x:tt)*) => {
+        return Err(warp::reject::custom(ApiError::new(StatusCode::$status, $code, format!($($x)*))))
+    };
+    (StatusCode::$status:ident, $($x:tt)*) => {
+        return Err(warp::reject::custom(ApiError::new(
+            StatusCode::$status,
+            default_error_code(StatusCode::$status),
+            format!($($x)*),
+        )))
     };
     ($($x:tt)*) => {
         reject!(StatusCode::BAD_REQUEST, $($x)*)
     };
 }
 
-fn check_auth_token(oidc_client: Option<&Client>, token: &str, auth: &Auth) -> Result<(), anyhow::Error> {
+/// An authenticated caller, and what it's allowed to do.
+#[derive(Clone)]
+struct Caller {
+    /// The auth, or OIDC rule, that let the caller in. Rate limits are shared by all callers with the same ID.
+    id: String,
+    /// Targets the caller may use. All of them if `None`.
+    targets: Option<Vec<config::TargetScope>>,
+    rate_limit: Option<config::RateLimit>,
+    /// Share of the targets' time, when other callers queue jobs too.
+    weight: f64,
+    /// May use the `/admin` endpoints.
+    admin: bool,
+    /// GitHub repositories the caller may report jobs to as check runs. For OIDC, the token's `repository` claim.
+    github_repos: Vec<String>,
+}
+
+impl Caller {
+    fn allows(&self, name: &str, labels: &BTreeMap<String, String>) -> bool {
+        match &self.targets {
+            None => true,
+            Some(scopes) => scopes.iter().any(|s| s.matches(name, labels)),
+        }
+    }
+
+    /// Whether the caller may see a job, its logs and what its run left behind: it submitted it, or may use its
+    /// target. Jobs on targets that are gone are checked by their name alone.
+    fn may_see(&self, job: &api::Job, targets: &[config::Target]) -> bool {
+        if job.caller.is_some() && job.caller.as_deref() == Some(self.id.as_str()) {
+            return true;
+        }
+        match targets.iter().find(|t| t.name == job.target) {
+            Some(target) => self.allows(&target.name, &target.labels),
+            None => self.allows(&job.target, &BTreeMap::new()),
+        }
+    }
+
+    fn may_report_to(&self, repo: &str) -> bool {
+        self.github_repos.iter().any(|r| r.eq_ignore_ascii_case(repo))
+    }
+}
+
+async fn check_auth_token(
+    oidc_client: Option<&Client>,
+    token: &str,
+    index: usize,
+    auth: &Auth,
+) -> Result<Caller, anyhow::Error> {
     match auth {
         Auth::Token(auth) => {
             if token != auth.token {
                 bail!("Incorrect token")
             }
-            Ok(())
+            Ok(Caller {
+                id: format!("token #{}", index),
+                targets: auth.targets.clone(),
+                rate_limit: auth.rate_limit.clone(),
+                weight: auth.weight,
+                admin: auth.admin,
+                github_repos: auth.github_repos.clone(),
+            })
         }
         Auth::Oidc(auth) => {
             if let Some(client) = &oidc_client {
-                let claims: HashMap<String, serde_json::Value> = match client.validate_token(token) {
+                let claims: HashMap<String, serde_json::Value> = match client.validate_token(token).await {
                     Ok(x) => x,
                     Err(e) => bail!("Bad token: {}", e),
                 };
 
-                let claims: HashMap<String, String> = claims
-                    .into_iter()
-                    .filter_map(|(k, v)| match v {
-                        serde_json::Value::String(s) => Some((k, s)),
-                        _ => None,
-                    })
-                    .collect();
-
-                if !auth
+                match auth
                     .rules
                     .iter()
-                    .any(|r: &OidcAuthRule| r.claims.iter().all(|(k, v)| claims.get(k) == Some(v)))
+                    .position(|r: &OidcAuthRule| r.claims.iter().all(|(k, m)| m.matches(claims.get(k))))
                 {
-                    bail!("No oidc claims rule matched");
+                    Some(i) => Ok(Caller {
+                        id: format!("oidc #{} rule #{}", index, i),
+                        targets: auth.rules[i].targets.clone(),
+                        rate_limit: auth.rules[i].rate_limit.clone(),
+                        weight: auth.rules[i].weight,
+                        admin: auth.rules[i].admin,
+                        github_repos: claims
+                            .get("repository")
+                            .and_then(|r| r.as_str())
+                            .map(str::to_string)
+                            .into_iter()
+                            .collect(),
+                    }),
+                    None => bail!("No oidc claims rule matched"),
                 }
-
-                Ok(())
             } else {
                 bail!("Attempted to use OIDC auth when OIDC was not configured.")
             }
         }
+        Auth::ClientCert(_) => bail!("Client certificate auth doesn't use tokens"),
     }
 }
 
@@ -115,21 +287,201 @@ struct Unauthorized;
 
 impl warp::reject::Reject for Unauthorized {}
 
-async fn check_auth(auth_header: String, cx: Arc<Mutex<Context>>) -> Result<(), Rejection> {
+#[derive(Debug)]
+struct RateLimited {
+    retry_after: Duration,
+}
+
+impl warp::reject::Reject for RateLimited {}
+
+#[derive(Debug)]
+struct UnsupportedEncoding(String);
+
+impl warp::reject::Reject for UnsupportedEncoding {}
+
+#[derive(Debug)]
+struct BadUpload(String);
+
+impl warp::reject::Reject for BadUpload {}
+
+/// An error reply, from `reject!`.
+#[derive(Debug, Clone)]
+struct ApiError {
+    status: StatusCode,
+    body: api::Error,
+    /// The run the error is about, sent back in the job ID header.
+    job_id: Option<String>,
+}
+
+impl warp::reject::Reject for ApiError {}
+
+impl ApiError {
+    fn new(status: StatusCode, code: &str, message: String) -> Self {
+        Self {
+            status,
+            body: api::Error {
+                code: code.to_string(),
+                message,
+                details: None,
+            },
+            job_id: None,
+        }
+    }
+
+    fn details(mut self, details: serde_json::Value) -> Self {
+        self.body.details = Some(details);
+        self
+    }
+
+    fn reply(&self) -> warp::reply::Response {
+        let reply = with_status(warp::reply::json(&self.body), self.status);
+        match &self.job_id {
+            Some(id) => warp::reply::with_header(reply, api::JOB_ID_HEADER, id).into_response(),
+            None => reply.into_response(),
+        }
+    }
+}
+
+/// Error code of `reject!`s that don't give one.
+fn default_error_code(status: StatusCode) -> &'static str {
+    match status {
+        StatusCode::BAD_REQUEST => "bad_request",
+        StatusCode::FORBIDDEN => "forbidden",
+        StatusCode::NOT_FOUND => "not_found",
+        StatusCode::CONFLICT => "conflict",
+        StatusCode::PRECONDITION_FAILED => "precondition_failed",
+        StatusCode::SERVICE_UNAVAILABLE => "unavailable",
+        _ => "internal_error",
+    }
+}
+
+/// Turns rejections into JSON error replies: rate limiting ones into `429 Too Many Requests`, upload decoding
+/// ones into `415 Unsupported Media Type` or `400 Bad Request`, and failed auth into `401 Unauthorized`.
+async fn handle_rejection(rejection: Rejection) -> Result<warp::reply::Response, Rejection> {
+    if let Some(error) = rejection.find::<ApiError>() {
+        return Ok(error.reply());
+    }
+    if let Some(RateLimited { retry_after }) = rejection.find() {
+        let secs = retry_after.as_secs_f64().ceil().max(1.0) as u64;
+        let reply = ApiError::new(
+            StatusCode::TOO_MANY_REQUESTS,
+            "rate_limited",
+            format!("Rate limit exceeded, retry in {}s", secs),
+        )
+        .details(serde_json::json!({ "retry_after_secs": secs }))
+        .reply();
+        return Ok(warp::reply::with_header(reply, "Retry-After", secs.to_string()).into_response());
+    }
+    if let Some(UnsupportedEncoding(encoding)) = rejection.find() {
+        let reply = ApiError::new(
+            StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            "unsupported_encoding",
+            format!("Unsupported Content-Encoding: {}", encoding),
+        )
+        .reply();
+        return Ok(warp::reply::with_header(reply, "Accept-Encoding", ACCEPTED_ENCODINGS).into_response());
+    }
+    if let Some(BadUpload(msg)) = rejection.find() {
+        return Ok(ApiError::new(StatusCode::BAD_REQUEST, "bad_upload", msg.clone()).reply());
+    }
+    if rejection.find::<BadAuthHeaderFormat>().is_some() {
+        let message = "Expected an `Authorization: Bearer <token>` header".to_string();
+        return Ok(ApiError::new(StatusCode::UNAUTHORIZED, "bad_auth_header", message).reply());
+    }
+    if rejection.find::<Unauthorized>().is_some() {
+        let message = "Token or OIDC claims not accepted".to_string();
+        return Ok(ApiError::new(StatusCode::UNAUTHORIZED, "unauthorized", message).reply());
+    }
+    if let Some(e) = rejection.find::<warp::reject::InvalidQuery>() {
+        return Ok(ApiError::new(StatusCode::BAD_REQUEST, "bad_query", e.to_string()).reply());
+    }
+    if rejection.is_not_found() {
+        return Ok(ApiError::new(StatusCode::NOT_FOUND, "not_found", "No such endpoint".to_string()).reply());
+    }
+    Err(rejection)
+}
+
+/// Request extension of requests that came in on the unix socket.
+#[derive(Clone, Copy)]
+struct UnixSocket;
+
+/// Request extension of requests whose connection presented a client certificate, with the path of the CA of the
+/// `client_cert` auth that verified it.
+#[derive(Clone)]
+struct ClientCert {
+    ca: String,
+}
+
+async fn check_auth(
+    auth_header: Option<String>,
+    unix_socket: Option<UnixSocket>,
+    client_cert: Option<ClientCert>,
+    cx: Arc<Mutex<Context>>,
+) -> Result<Caller, Rejection> {
+    let caller = match unix_socket {
+        // The socket's file permissions already decided who can connect.
+        Some(UnixSocket) => Caller {
+            id: "unix_socket".to_string(),
+            targets: None,
+            rate_limit: None,
+            weight: 1.0,
+            admin: true,
+            github_repos: Vec::new(),
+        },
+        None => authenticate(auth_header, client_cert, cx.clone()).await?,
+    };
+    if let Err(retry_after) = cx.lock().limit_request(&caller) {
+        info!("Rate limited request from {}", caller.id);
+        return Err(warp::reject::custom(RateLimited { retry_after }));
+    }
+    Ok(caller)
+}
+
+async fn authenticate(
+    auth_header: Option<String>,
+    client_cert: Option<ClientCert>,
+    cx: Arc<Mutex<Context>>,
+) -> Result<Caller, Rejection> {
+    // Validating OIDC tokens might have to fetch keys, so don't hold the lock for it.
+    let (auths, oidc_client) = {
+        let context = cx.lock();
+        (context.config.auths.clone(), context.oidc_client.clone())
+    };
+
+    let Some(auth_header) = auth_header else {
+        // Clients without a certificate get through the TLS handshake too, for token auth, so only the ones that
+        // presented one can do without a token. They get the targets and limits of the auth whose CA signed it.
+        let client_cert = client_cert.and_then(|cert| {
+            auths.iter().enumerate().find_map(|(i, a)| match a {
+                Auth::ClientCert(auth) if auth.ca == cert.ca => Some((i, auth)),
+                _ => None,
+            })
+        });
+        return match client_cert {
+            Some((i, auth)) => {
+                info!("Auth method ClientCert #{} succeeded.", i);
+                Ok(Caller {
+                    id: format!("client_cert #{}", i),
+                    targets: auth.targets.clone(),
+                    rate_limit: auth.rate_limit.clone(),
+                    weight: auth.weight,
+                    admin: auth.admin,
+                    github_repos: auth.github_repos.clone(),
+                })
+            }
+            None => Err(warp::reject::custom(BadAuthHeaderFormat)),
+        };
+    };
     let token = match auth_header.strip_prefix("Bearer ") {
         Some(t) => t,
         None => return Err(warp::reject::custom(BadAuthHeaderFormat)),
     };
 
-    let mut found = false;
-
-    let context = cx.lock();
-    for (i, auth) in context.config.auths.iter().enumerate() {
-        match check_auth_token(context.oidc_client.as_ref(), token, auth) {
-            Ok(()) => {
-                found = true;
+    for (i, auth) in auths.iter().enumerate() {
+        match check_auth_token(oidc_client.as_ref(), token, i, auth).await {
+            Ok(caller) => {
                 info!("Auth method {} #{} succeeded.", auth.to_string(), i);
-                break;
+                return Ok(caller);
             }
             Err(e) => {
                 info!("Auth method {} #{} failed: {}", auth.to_string(), i, e)
@@ -137,194 +489,4347 @@ async fn check_auth(auth_header: String, cx: Arc<Mutex<Context>>) -> Result<(),
         }
     }
 
-    if !found {
-        return Err(warp::reject::custom(Unauthorized));
+    Err(warp::reject::custom(Unauthorized))
+}
+
+/// Authenticates and rate limits the request, and extracts the caller.
+fn caller_filter(cx: Arc<Mutex<Context>>) -> impl Filter<Extract = (Caller,), Error = Rejection> + Clone {
+    let with_context = warp::any().map(move || cx.clone());
+    warp::header::optional("Authorization")
+        .and(warp::ext::optional::<UnixSocket>())
+        .and(warp::ext::optional::<ClientCert>())
+        .and(with_context)
+        .and_then(check_auth)
+}
+
+async fn check_admin(caller: Caller) -> Result<Caller, Rejection> {
+    if !caller.admin {
+        reject!(
+            StatusCode::FORBIDDEN,
+            code = "admin_required",
+            "Only admins may use this endpoint"
+        );
     }
+    Ok(caller)
+}
 
-    Ok(())
+/// Like [`caller_filter`], but only lets in callers whose auth has `admin: true`.
+fn admin_filter(cx: Arc<Mutex<Context>>) -> impl Filter<Extract = (Caller,), Error = Rejection> + Clone {
+    caller_filter(cx).and_then(check_admin)
 }
 
 fn check_auth_filter(cx: Arc<Mutex<Context>>) -> impl Filter<Extract = (), Error = Rejection> + Clone {
+    caller_filter(cx).map(|_: Caller| ()).untuple_one()
+}
+
+async fn check_worker_auth(
+    path: warp::path::FullPath,
+    auth_header: Option<String>,
+    cx: Arc<Mutex<Context>>,
+) -> Result<(), Rejection> {
+    let Some(tokens) = cx.lock().config.workers.as_ref().map(|w| w.tokens.clone()) else {
+        reject!(
+            StatusCode::NOT_FOUND,
+            code = "workers_disabled",
+            "Server doesn't take workers"
+        );
+    };
+    // Worker routes are all under `/workers/{name}`, with the name as it's extracted by `warp::path!`.
+    let name = path.as_str().split('/').nth(2).unwrap_or_default();
+    match auth_header.as_deref().and_then(|h| h.strip_prefix("Bearer ")) {
+        Some(t) if tokens.get(name).is_some_and(|token| t == token) => Ok(()),
+        Some(_) => Err(warp::reject::custom(Unauthorized)),
+        None => Err(warp::reject::custom(BadAuthHeaderFormat)),
+    }
+}
+
+async fn check_metrics_auth(
+    auth_header: Option<String>,
+    unix_socket: Option<UnixSocket>,
+    client_cert: Option<ClientCert>,
+    cx: Arc<Mutex<Context>>,
+) -> Result<(), Rejection> {
+    if cx.lock().config.public_metrics {
+        return Ok(());
+    }
+    check_auth(auth_header, unix_socket, client_cert, cx).await.map(|_| ())
+}
+
+/// Lets in any caller with `public_metrics` in the config, and otherwise only authenticated ones.
+fn metrics_auth_filter(cx: Arc<Mutex<Context>>) -> impl Filter<Extract = (), Error = Rejection> + Clone {
     let with_context = warp::any().map(move || cx.clone());
-    warp::header("Authorization")
+    warp::header::optional("Authorization")
+        .and(warp::ext::optional::<UnixSocket>())
+        .and(warp::ext::optional::<ClientCert>())
         .and(with_context)
-        .and_then(check_auth)
+        .and_then(check_metrics_auth)
+        .untuple_one()
+}
+
+/// Lets in the worker named in the path, with its token in `workers.tokens` of the config.
+fn worker_auth_filter(cx: Arc<Mutex<Context>>) -> impl Filter<Extract = (), Error = Rejection> + Clone {
+    let with_context = warp::any().map(move || cx.clone());
+    warp::path::full()
+        .and(warp::header::optional("Authorization"))
+        .and(with_context)
+        .and_then(check_worker_auth)
         .untuple_one()
 }
 
+/// Unknown parameters are rejected, so a misspelled one, like `base_addr`, fails instead of being ignored.
 #[derive(Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
 struct RunArgs {
     #[serde(default)]
     timeout: Option<u64>,
+    /// Return immediately with a job ID instead of waiting for the run to finish.
+    #[serde(default, rename = "async")]
+    asynchronous: bool,
+    #[serde(default)]
+    run_group: Option<String>,
+    /// Label selector, for runs that aren't submitted to a specific target.
+    #[serde(default)]
+    selector: Option<String>,
+    /// Comma-separated targets the selector mustn't pick, like ones the run already failed on.
+    #[serde(default)]
+    exclude: Option<String>,
+    /// Format of the uploaded image, if it's not a multipart upload.
+    #[serde(default)]
+    format: api::ImageFormat,
+    /// Load address for `bin` images, in hex (`0x0800_0000`) or decimal.
+    #[serde(default)]
+    base_address: Option<String>,
+    /// Hex BLAKE2b-256 hash of the image. With an empty body, runs the image uploaded earlier with this hash,
+    /// in a run request or with `POST /blobs`.
+    #[serde(default)]
+    elf_hash: Option<String>,
+    /// Name of the test, for the GitHub check run.
+    #[serde(default)]
+    name: Option<String>,
+    /// Run even if the same images passed on the target recently, instead of reusing that result.
+    #[serde(default)]
+    no_cache: bool,
+    /// `owner/name` of the GitHub repository to report the job to as a check run, with `github_sha`.
+    #[serde(default)]
+    github_repo: Option<String>,
+    /// Commit the check run is created on.
+    #[serde(default)]
+    github_sha: Option<String>,
+    /// Probe clock speed in kHz, instead of the target's. The most the link is tried at, with `auto_speed`.
+    #[serde(default)]
+    speed: Option<u32>,
+    /// `RUST_LOG`-style filter of the device logs returned, like `warn,net=trace`.
+    #[serde(default)]
+    log_filter: Option<String>,
+    /// Prefix each line of the logs returned with the host's wall-clock time when it was logged.
+    #[serde(default)]
+    host_timestamps: bool,
+    /// Keep the raw defmt stream, for `GET /jobs/{id}/defmt`.
+    #[serde(default)]
+    raw_defmt: bool,
 }
 
-async fn handle_run(name: String, args: RunArgs, elf: Bytes, cx: Arc<Mutex<Context>>) -> Result<impl Reply, Rejection> {
-    let target = {
-        let context = cx.lock();
-        match context.config.targets.iter().find(|t| t.name == name) {
-            Some(x) => x.clone(),
-            None => reject!(StatusCode::NOT_FOUND, "Target not found: {}", name),
-        }
-    };
+#[derive(Deserialize)]
+struct BlobArgs {
+    /// Hex BLAKE2b-256 hash of the whole image.
+    hash: String,
+    size: usize,
+}
 
-    let target_mutex = cx
-        .lock()
-        .target_locks
-        .entry(target.name.clone())
-        .or_insert_with(|| Arc::new(AsyncMutex::new(())))
-        .clone();
+#[derive(Deserialize)]
+struct ChunkArgs {
+    offset: usize,
+}
 
-    let _target_guard = target_mutex.lock().await;
+/// Most TLS handshakes done at once, with client certificate auth.
+const MAX_TLS_HANDSHAKES: usize = 64;
 
-    let probe = probe::Opts {
-        chip: target.chip.clone(),
-        connect_under_reset: target.connect_under_reset,
-        probe: Some(target.probe.clone()),
-        speed: target.speed,
-        power_reset: target.power_reset,
-        cycle_delay_seconds: target.cycle_delay_seconds,
-        max_settle_time_millis: target.max_settle_time_millis,
-    };
+/// How long finished jobs are kept around for polling.
+const JOB_RETENTION_SECS: i64 = 60 * 60;
 
-    let timeout = {
-        let config = &mut cx.lock().config;
-        Duration::from_secs(args.timeout.unwrap_or(config.default_timeout).min(config.max_timeout))
-    };
+const DRAIN_POLL_INTERVAL: Duration = Duration::from_secs(1);
 
-    let (ok, logs) = run_with_log_capture(elf, probe, timeout).await;
-    let status = if ok { StatusCode::OK } else { StatusCode::BAD_REQUEST };
+/// How often leases check whether they were released or ran out.
+const LEASE_POLL_INTERVAL: Duration = Duration::from_secs(1);
 
-    Ok(with_status(logs, status))
-}
+/// How often gdb sessions check whether their stub exited, or they should end.
+const GDB_POLL_INTERVAL: Duration = Duration::from_secs(1);
 
-fn targets(cx: Arc<Mutex<Context>>) -> api::TargetList {
-    let targets = cx.lock().config.targets.clone();
-    let mut res = Vec::new();
-    let up_probes = Lister::new().list_all();
+/// How often workers are checked for missed heartbeats.
+const WORKER_SWEEP_INTERVAL: Duration = Duration::from_secs(1);
 
-    for target in targets {
-        let is_up = up_probes.iter().any(|probe| {
-            probe.vendor_id == target.probe.vendor_id
-                && probe.product_id == target.probe.product_id
-                && target
-                    .probe
-                    .serial_number
-                    .as_ref()
-                    .map(|s| Some(s) == probe.serial_number.as_ref())
-                    .unwrap_or(true)
-        });
-        res.push(api::Target {
-            name: target.name,
-            chip: target.chip,
-            probe: target.probe,
-            connect_under_reset: target.connect_under_reset,
-            speed: target.speed,
-            up: is_up,
-            power_reset: target.power_reset,
-            cycle_delay_seconds: target.cycle_delay_seconds,
-            max_settle_time_millis: target.max_settle_time_millis,
-        });
+/// How often waiting workers, and synchronous runs on their targets, check on the jobs.
+const WORKER_POLL_INTERVAL: Duration = Duration::from_millis(200);
+
+/// Longest a worker's poll for jobs is held open, in seconds.
+const MAX_WORKER_POLL_SECS: u64 = 60;
+
+/// How often workers relay the logs of the jobs they run to the API server.
+const LOG_RELAY_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How often old run log files are deleted.
+const RUN_LOG_PRUNE_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+/// How often the audit log and run history older than `HISTORY_RETENTION_DAYS` are pruned from the storage.
+const HISTORY_PRUNE_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+/// Claim intervals after which a server of the cluster that didn't report its targets is taken as gone.
+const NODE_REPORT_INTERVALS: u64 = 5;
+
+/// How long run results are kept for the status badges.
+const HISTORY_RETENTION_DAYS: i64 = 7;
+
+#[derive(Clone)]
+struct Job {
+    info: api::Job,
+    logs: Vec<u8>,
+    /// ID of the caller that submitted the job, to charge its run time to.
+    caller: Option<String>,
+    /// The caller's weight, for scheduling.
+    weight: f64,
+    /// Report of the last run attempt. Not persisted.
+    report: Option<RunReport>,
+    /// Set to cancel the job. Not persisted.
+    cancel: Arc<AtomicBool>,
+    /// What the caller asked to get back from the run.
+    output: OutputArgs,
+    /// Images of a job on a worker's target, until it finishes. After a restart, they're loaded from the storage
+    /// instead. Not persisted.
+    images: Option<(run::Image, Vec<run::Image>)>,
+    /// What the worker relayed of the run report and its artifacts so far, by kind. Not persisted.
+    relayed: HashMap<String, Vec<u8>>,
+}
+
+impl Job {
+    /// A job loaded from storage, without what isn't persisted.
+    fn stored(info: api::Job, logs: Vec<u8>) -> Self {
+        let output = OutputArgs {
+            // It was checked when the job was submitted.
+            log_filter: info.log_filter.as_deref().and_then(|f| f.parse().ok()),
+            host_timestamps: info.host_timestamps,
+            raw_defmt: info.raw_defmt,
+        };
+        Self {
+            caller: info.caller.clone(),
+            weight: info.weight.unwrap_or(1.0),
+            info,
+            logs,
+            report: None,
+            cancel: Arc::default(),
+            output,
+            images: None,
+            relayed: HashMap::new(),
+        }
     }
+}
 
-    api::TargetList { targets: res }
+/// What a caller asked to get back from a run, besides the plain logs.
+#[derive(Clone, Default)]
+struct OutputArgs {
+    /// Filter of the device logs.
+    log_filter: Option<run::LogFilter>,
+    /// Whether log lines get the host time.
+    host_timestamps: bool,
+    /// Whether the raw defmt stream is kept.
+    raw_defmt: bool,
 }
 
-async fn handle_list_targets(cx: Arc<Mutex<Context>>) -> Result<impl Reply, Rejection> {
-    let targets = targets(cx);
+impl OutputArgs {
+    /// Whether the run's output is the plain one, that cached results have.
+    fn is_plain(&self) -> bool {
+        self.log_filter.is_none() && !self.host_timestamps && !self.raw_defmt
+    }
+}
 
-    Ok(with_status(
-        // NOTE (unwrap): error in this call is caused by programmer error and should never be caused by the user data
-        serde_json::to_vec_pretty(&targets).unwrap(),
-        StatusCode::OK,
-    ))
+/// A job's turn on a target, among the jobs queued on it. Dropping it hands the target to the next job,
+/// even if the turn didn't come yet.
+struct Turn {
+    cx: Arc<Mutex<Context>>,
+    target: String,
+    /// Fires when the turn comes.
+    pending: Option<oneshot::Receiver<()>>,
 }
 
-async fn handle_home(cx: Arc<Mutex<Context>>) -> Result<impl Reply, Rejection> {
-    let targets = targets(cx);
+impl Turn {
+    async fn wait(cx: &Arc<Mutex<Context>>, target: &str, caller: &str, weight: f64) -> Self {
+        let pending = cx
+            .lock()
+            .queues
+            .entry(target.to_string())
+            .or_default()
+            .enter(caller, weight);
+        let mut turn = Turn {
+            cx: cx.clone(),
+            target: target.to_string(),
+            pending,
+        };
+        if let Some(pending) = &mut turn.pending {
+            // The sender is only dropped after sending, or after this receiver is gone.
+            let _ = pending.await;
+        }
+        turn.pending = None;
+        turn
+    }
+}
 
-    let mut res = String::new();
+impl Drop for Turn {
+    fn drop(&mut self) {
+        // Stopped waiting: pass the turn on only if it came in the meantime.
+        if let Some(mut pending) = self.pending.take() {
+            pending.close();
+            if pending.try_recv().is_err() {
+                return;
+            }
+        }
+        if let Some(queue) = self.cx.lock().queues.get_mut(&self.target) {
+            queue.leave();
+        }
+    }
+}
 
-    write!(&mut res, "<html>").unwrap();
-    write!(&mut res, "<head><title>Teleprobe Status</title></head>").unwrap();
-    write!(&mut res, "<body>").unwrap();
-    write!(&mut res, "<h1>Teleprobe Status</h1>").unwrap();
-    write!(&mut res, "<table>").unwrap();
-    write!(&mut res, "<tr>").unwrap();
-    write!(&mut res, "<th>Name</th>").unwrap();
-    write!(&mut res, "<th>Chip</th>").unwrap();
-    write!(&mut res, "<th>Up</th>").unwrap();
-    write!(&mut res, "</tr>").unwrap();
-
-    for target in targets.targets {
-        write!(&mut res, "<tr>").unwrap();
-        write!(&mut res, "<td>{}</td>", target.name).unwrap();
-        write!(&mut res, "<td>{}</td>", target.chip).unwrap();
-        write!(&mut res, "<td>{}</td>", target.up).unwrap();
-        write!(&mut res, "</tr>").unwrap();
-    }
-    write!(&mut res, "</table>").unwrap();
-    write!(
-        &mut res,
-        "<br><br> -- <a href=\"https://github.com/embassy-rs/teleprobe\">Teleprobe</a> version {}",
-        crate::meta::LONG_VERSION
-    )
-    .unwrap();
-    write!(&mut res, "</body></html>").unwrap();
+/// Cancels a synchronous run when dropped, which warp does when the client disconnects.
+struct CancelOnDrop(Arc<AtomicBool>);
 
-    Ok(html(res))
+impl Drop for CancelOnDrop {
+    fn drop(&mut self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
 }
 
-#[derive(Clone)]
-struct Context {
-    oidc_client: Option<oidc::Client>,
-    config: Config,
-    target_locks: HashMap<String, Arc<AsyncMutex<()>>>,
+/// The body of a run request: a single image, or a `multipart/form-data` form with one image per part.
+enum Upload {
+    Single(Bytes),
+    Multipart(FormData),
 }
 
-pub async fn serve(port: u16) -> anyhow::Result<()> {
-    let config = fs::read("config.yaml")?;
-    let config: Config = serde_yaml::from_slice(&config)?;
+/// Largest body of a worker's registration or heartbeat.
+const MAX_WORKER_MESSAGE: u64 = 1024 * 1024;
+/// Largest body of a job result a worker hands back, which has the job's logs.
+const MAX_WORKER_RESULT: u64 = 64 * 1024 * 1024;
 
-    // TODO support none or multiple oidc issuers.
-    let oidc_client = match config.auths.iter().find_map(|a| match a {
-        Auth::Oidc(o) => Some(o),
-        _ => None,
-    }) {
-        Some(auth) => Some(oidc::Client::new_autodiscover(&auth.issuer).await.unwrap()),
-        None => None,
-    };
+/// Largest chunk of a chunked upload, as sent.
+const MAX_CHUNK: u64 = 16 * 1024 * 1024;
 
-    let context: Arc<Mutex<Context>> = Arc::new(Mutex::new(Context {
-        oidc_client,
-        config,
-        target_locks: HashMap::new(),
-    }));
+/// `Content-Encoding`s of single image uploads the server can decompress.
+const ACCEPTED_ENCODINGS: &str = "gzip, zstd";
+/// Largest a compressed body may grow to when decompressed, as large as the largest image the blob store keeps.
+const MAX_DECOMPRESSED_BODY: usize = 256 * 1024 * 1024;
 
-    let target_run: _ = warp::path!("targets" / String / "run")
-        .and(warp::post())
-        .and(check_auth_filter(context.clone()))
-        .and(warp::query())
+fn upload_filter() -> impl Filter<Extract = (Upload,), Error = Rejection> + Clone {
+    warp::header::optional::<String>("content-encoding")
+        .and(
+            warp::multipart::form()
+                .max_length(None)
+                .map(Upload::Multipart)
+                .or(warp::body::bytes().map(Upload::Single))
+                .unify(),
+        )
+        .and_then(|encoding: Option<String>, upload: Upload| async move {
+            match upload {
+                Upload::Single(data) => decode_body(encoding.as_deref(), data).map(Upload::Single),
+                Upload::Multipart(_) if encoding.as_deref().is_some_and(|e| e != "identity") => {
+                    Err(warp::reject::custom(UnsupportedEncoding(encoding.unwrap())))
+                }
+                upload => Ok(upload),
+            }
+        })
+}
+
+/// A request body, decompressed according to its `Content-Encoding`.
+fn body_filter() -> impl Filter<Extract = (Bytes,), Error = Rejection> + Clone {
+    warp::header::optional::<String>("content-encoding")
         .and(warp::body::bytes())
-        .and(with_val(context.clone()))
-        .and_then(handle_run);
+        .and_then(|encoding: Option<String>, data: Bytes| async move { decode_body(encoding.as_deref(), data) })
+}
 
-    let list_targets: _ = warp::path!("targets")
-        .and(warp::get())
-        .and(check_auth_filter(context.clone()))
-        .and(with_val(context.clone()))
-        .and_then(handle_list_targets);
+fn decode_body(encoding: Option<&str>, data: Bytes) -> Result<Bytes, Rejection> {
+    use std::io::Read;
 
-    let home: _ = warp::path!()
-        .and(warp::get())
-        .and(with_val(context.clone()))
-        .and_then(handle_home);
+    // Read one byte past the limit, to tell bodies that are too large from those that are exactly at it.
+    let limit = MAX_DECOMPRESSED_BODY as u64 + 1;
+    let mut out = Vec::new();
+    let decoded = match encoding {
+        None | Some("identity") => return Ok(data),
+        Some("gzip") => flate2::read::GzDecoder::new(&data[..])
+            .take(limit)
+            .read_to_end(&mut out),
+        Some("zstd") => zstd::Decoder::new(&data[..]).and_then(|d| d.take(limit).read_to_end(&mut out)),
+        Some(encoding) => return Err(warp::reject::custom(UnsupportedEncoding(encoding.to_string()))),
+    };
+    match decoded {
+        Ok(_) if out.len() > MAX_DECOMPRESSED_BODY => Err(warp::reject::custom(BadUpload(format!(
+            "Decompressed upload is larger than {} bytes",
+            MAX_DECOMPRESSED_BODY
+        )))),
+        Ok(_) => Ok(out.into()),
+        Err(e) => Err(warp::reject::custom(BadUpload(format!(
+            "Failed to decompress {} upload: {}",
+            encoding.unwrap_or_default(),
+            e
+        )))),
+    }
+}
 
-    info!("Listening on :{}", port);
-    warp::serve(target_run.or(list_targets).or(home))
-        .run(([0, 0, 0, 0], port))
-        .await;
+/// Read the uploaded images, named by their form field. The last one is the one that's run.
+///
+/// Multipart parts are flashed in the order they're sent. Their format is taken from the file
+/// extension (`.hex`, `.bin`, anything else is an ELF), and `bin` parts are loaded at the
+/// address in the field name, like `0x08010000`.
+async fn read_images(upload: Upload, args: &RunArgs) -> anyhow::Result<Vec<(String, run::Image)>> {
+    let form = match upload {
+        Upload::Single(data) => {
+            let base_address = match args.base_address.as_deref().map(run::parse_address) {
+                Some(Ok(_)) if args.format != api::ImageFormat::Bin => {
+                    bail!("`base_address` is only supported for `bin` images")
+                }
+                Some(Ok(addr)) => Some(addr),
+                Some(Err(e)) => bail!("Invalid base address: {}", e),
+                None => None,
+            };
+            let image = run::Image {
+                data,
+                format: args.format,
+                base_address,
+            };
+            return Ok(vec![("image".to_string(), image)]);
+        }
+        Upload::Multipart(form) => form,
+    };
 
-    Ok(())
+    let images: Vec<(String, run::Image)> = form
+        .and_then(|part| async move {
+            let name = part.name().to_string();
+            let format = match part.filename().and_then(|f| f.rsplit_once('.')) {
+                Some((_, "hex" | "ihex")) => api::ImageFormat::Hex,
+                Some((_, "bin")) => api::ImageFormat::Bin,
+                _ => api::ImageFormat::Elf,
+            };
+            let base_address = match format {
+                api::ImageFormat::Bin => run::parse_address(&name).ok(),
+                _ => None,
+            };
+            let data = part
+                .stream()
+                .try_fold(BytesMut::new(), |mut buf, chunk| async move {
+                    buf.put(chunk);
+                    Ok(buf)
+                })
+                .await?;
+            let image = run::Image {
+                data: data.freeze(),
+                format,
+                base_address,
+            };
+            Ok::<_, warp::Error>((name, image))
+        })
+        .try_collect()
+        .await?;
+
+    if images.is_empty() {
+        bail!("Multipart upload contains no images");
+    }
+    Ok(images)
+}
+
+fn new_job_id() -> String {
+    let mut id = [0u8; 8];
+    // NOTE (unwrap): only fails if the OS RNG is unavailable.
+    orion::util::secure_rand_bytes(&mut id).unwrap();
+    hex::encode(id)
+}
+
+async fn handle_run(
+    name: String,
+    caller: Caller,
+    args: RunArgs,
+    upload: Upload,
+    cx: Arc<Mutex<Context>>,
+) -> Result<impl Reply, Rejection> {
+    let target = {
+        let context = cx.lock();
+        match context.run_targets().into_iter().find(|t| t.name == name) {
+            Some(x) => x,
+            None => reject!(
+                StatusCode::NOT_FOUND,
+                code = "target_not_found",
+                "Target not found: {}",
+                name
+            ),
+        }
+    };
+    if !caller.allows(&target.name, &target.labels) {
+        reject!(
+            StatusCode::FORBIDDEN,
+            code = "target_not_allowed",
+            "Not allowed to use target: {}",
+            name
+        );
+    }
+    if target.maintenance {
+        reject!(
+            StatusCode::CONFLICT,
+            code = "target_in_maintenance",
+            "Target is in maintenance: {}",
+            name
+        );
+    }
+
+    start_run(target, caller, args, upload, cx).await
+}
+
+async fn handle_run_selector(
+    caller: Caller,
+    args: RunArgs,
+    upload: Upload,
+    cx: Arc<Mutex<Context>>,
+) -> Result<impl Reply, Rejection> {
+    let selector: Selector = match args.selector.as_deref().map(str::parse) {
+        Some(Ok(selector)) => selector,
+        Some(Err(e)) => reject!("Invalid selector: {}", e),
+        None => reject!("Missing `selector` query parameter"),
+    };
+
+    let exclude: Vec<&str> = args.exclude.as_deref().map_or(Vec::new(), |e| e.split(',').collect());
+    let target = {
+        let context = cx.lock();
+        match context.pick_target(&selector, &caller, &exclude) {
+            Some(x) => x,
+            None => reject!(
+                StatusCode::NOT_FOUND,
+                code = "no_matching_target",
+                "No target in service matches selector: {}",
+                selector
+            ),
+        }
+    };
+
+    start_run(target, caller, args, upload, cx).await
+}
+
+/// Start a run, with its job ID picked right away, so all the server logs about it carry it.
+async fn start_run(
+    target: config::Target,
+    caller: Caller,
+    args: RunArgs,
+    upload: Upload,
+    cx: Arc<Mutex<Context>>,
+) -> Result<impl Reply, Rejection> {
+    let id = new_job_id();
+    let reply = crate::logutil::scope_job(id.clone(), accept_run(id.clone(), target, caller, args, upload, cx))
+        .await
+        // Errors carry the job ID too, so they can be found in the server logs.
+        .map_err(|rejection| match rejection.find::<ApiError>() {
+            Some(error) => warp::reject::custom(ApiError {
+                job_id: Some(id.clone()),
+                ..error.clone()
+            }),
+            None => rejection,
+        })?;
+    Ok(warp::reply::with_header(reply, api::JOB_ID_HEADER, id))
+}
+
+async fn accept_run(
+    id: String,
+    mut target: config::Target,
+    caller: Caller,
+    args: RunArgs,
+    upload: Upload,
+    cx: Arc<Mutex<Context>>,
+) -> Result<WithStatus<Vec<u8>>, Rejection> {
+    if cx.lock().draining {
+        reject!(
+            StatusCode::SERVICE_UNAVAILABLE,
+            code = "draining",
+            "Server is draining and not accepting new runs"
+        );
+    }
+    if let Err(retry_after) = cx.lock().limit_run(&caller) {
+        info!("Rate limited run from {}", caller.id);
+        return Err(warp::reject::custom(RateLimited { retry_after }));
+    }
+
+    let timeout = {
+        let config = &mut cx.lock().config;
+        args.timeout.unwrap_or(config.default_timeout).min(config.max_timeout)
+    };
+    if let Some(speed) = args.speed {
+        target.speed = Some(speed);
+    }
+    if let Some(repo) = &args.github_repo {
+        if !caller.may_report_to(repo) {
+            reject!(
+                StatusCode::FORBIDDEN,
+                code = "github_repo_not_allowed",
+                "Not allowed to report to GitHub repository: {}",
+                repo
+            );
+        }
+    }
+    let output = OutputArgs {
+        log_filter: match args.log_filter.as_deref().map(str::parse::<run::LogFilter>).transpose() {
+            Ok(x) => x,
+            Err(e) => reject!("Invalid log filter: {:#}", e),
+        },
+        host_timestamps: args.host_timestamps,
+        raw_defmt: args.raw_defmt,
+    };
+
+    // Keep single images around by hash, so the same binary doesn't have to be uploaded again for other targets.
+    let blobs = cx.lock().blobs.clone();
+    let upload = match (upload, &args.elf_hash) {
+        (Upload::Single(data), Some(hash)) if data.is_empty() => match blobs.get(hash) {
+            Some(data) => Upload::Single(data),
+            None => reject!(
+                StatusCode::PRECONDITION_FAILED,
+                code = "unknown_image",
+                "Unknown image hash: {}",
+                hash
+            ),
+        },
+        (Upload::Single(data), hash) if !data.is_empty() => {
+            let digest = blobs.insert(data.clone());
+            if hash.as_ref().is_some_and(|h| *h != digest) {
+                reject!("Image doesn't match `elf_hash`, it hashes to {}", digest);
+            }
+            Upload::Single(data)
+        }
+        (upload, _) => upload,
+    };
+
+    let mut images = match read_images(upload, &args).await {
+        Ok(x) => x,
+        Err(e) => reject!("{}", e),
+    };
+    // NOTE (unwrap): read_images never returns an empty list.
+    let (_, image) = images.pop().unwrap();
+
+    // Catch misbuilt binaries here, instead of with a confusing failure or a timeout once the run starts.
+    if image.format == api::ImageFormat::Elf {
+        if let Err(e) = run::check_elf(&image.data, Some(&target.chip)) {
+            reject!(StatusCode::BAD_REQUEST, code = "invalid_elf", "Invalid ELF: {:#}", e);
+        }
+    }
+
+    let preload_info = images
+        .iter()
+        .map(|(name, image)| api::PreloadImage {
+            name: name.clone(),
+            format: image.format,
+            base_address: image.base_address,
+        })
+        .collect();
+    let preload: Vec<run::Image> = images.into_iter().map(|(_, image)| image).collect();
+
+    // In a cluster, jobs for targets attached to other servers are left in the storage for them to claim. When
+    // no server has the target, the job runs here, and fails right away like outside of a cluster.
+    let cluster = cx.lock().config.cluster.clone();
+    let node = cluster.as_ref().map(|c| c.node.clone());
+    let worker = cx.lock().target_worker(&target.name);
+    let remote = match &cluster {
+        Some(cluster) if worker.is_none() && !target_up(target.clone()).await => {
+            other_node_has(&cx, cluster, &target.name).await
+        }
+        _ => false,
+    };
+
+    // Cached results only have the plain logs.
+    let cached = match args.no_cache || !output.is_plain() || remote {
+        true => None,
+        false => cx.lock().cached_result(&result_key(&target, timeout, &image, &preload)),
+    };
+
+    let job = api::Job {
+        id,
+        target: target.name.clone(),
+        status: api::JobStatus::Queued,
+        timeout,
+        run_group: args.run_group,
+        failure: None,
+        format: image.format,
+        base_address: image.base_address,
+        preload: preload_info,
+        elf_hash: Some(blobs::hash(&image.data)),
+        log_filter: args.log_filter.clone(),
+        host_timestamps: args.host_timestamps,
+        raw_defmt: args.raw_defmt,
+        caller: Some(caller.id.clone()),
+        weight: Some(caller.weight),
+        cached_from: None,
+        node: node.filter(|_| !remote),
+        worker: worker.clone(),
+        timings: api::PhaseTimings::default(),
+        backtrace: Vec::new(),
+        panic: None,
+        check_request: match (&args.github_repo, &args.github_sha) {
+            (Some(repo), Some(sha)) => Some(api::CheckRequest {
+                repo: repo.clone(),
+                sha: sha.clone(),
+                name: match &args.name {
+                    Some(name) => format!("teleprobe: {} on {}", name, target.name),
+                    None => format!("teleprobe: {}", target.name),
+                },
+            }),
+            _ => None,
+        },
+        check_run: None,
+        created_at: Utc::now(),
+        started_at: None,
+        finished_at: None,
+    };
+    if remote {
+        let storage = cx.lock().storage.clone();
+        if let Err(e) = storage.call(insert_job(&job, &image, &preload)).await {
+            reject!(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "Failed to store job for the server with target {}: {:#}",
+                target.name,
+                e
+            );
+        }
+        info!("Job {} left for the server with target {}", job.id, target.name);
+        // NOTE (unwrap): jobs are only left for other servers in a cluster.
+        let cluster = cluster.unwrap();
+        spawn_claim_timeout(cx.clone(), job.id.clone(), &cluster);
+        if args.asynchronous {
+            return Ok(with_status(
+                // NOTE (unwrap): error in this call is caused by programmer error and should never be caused by the user data
+                serde_json::to_vec_pretty(&job).unwrap(),
+                StatusCode::ACCEPTED,
+            ));
+        }
+        return wait_claimed(cx, job.id, cluster.node).await;
+    }
+
+    let timeout = Duration::from_secs(timeout);
+    let cancel = Arc::new(AtomicBool::new(false));
+    {
+        let mut context = cx.lock();
+        context.prune_jobs();
+        let what = format!("persist job {}", job.id);
+        context.storage.write(what, insert_job(&job, &image, &preload));
+        context.jobs.insert(
+            job.id.clone(),
+            Job {
+                info: job.clone(),
+                logs: Vec::new(),
+                caller: Some(caller.id),
+                weight: caller.weight,
+                report: None,
+                cancel: cancel.clone(),
+                output,
+                images: worker.is_some().then(|| (image.clone(), preload.clone())),
+                relayed: HashMap::new(),
+            },
+        );
+    }
+
+    // Check runs are completed from the job in memory, which only the server running it has.
+    spawn_check_run(cx.clone(), job.id.clone());
+
+    if let Some(cached) = cached {
+        info!(
+            "Job {} on target {} passed as job {} already, reusing its result",
+            job.id, target.name, cached.job_id
+        );
+        let mut logs = format!(
+            "INFO - same images passed as job {}, reusing its result\n",
+            cached.job_id
+        )
+        .into_bytes();
+        logs.extend_from_slice(&cached.logs);
+        let mut context = cx.lock();
+        context.update_job(&job.id, |job| {
+            let now = Utc::now();
+            job.info.status = api::JobStatus::Succeeded;
+            job.info.started_at = Some(now);
+            job.info.finished_at = Some(now);
+            job.info.cached_from = Some(cached.job_id.clone());
+            job.logs = logs.clone();
+        });
+        context.complete_check(&job.id);
+
+        if args.asynchronous {
+            return Ok(with_status(
+                // NOTE (unwrap): error in this call is caused by programmer error and should never be caused by the user data
+                serde_json::to_vec_pretty(&context.jobs[&job.id].info).unwrap(),
+                StatusCode::ACCEPTED,
+            ));
+        }
+        return Ok(with_status(logs, StatusCode::OK));
+    }
+
+    if let Some(worker) = worker {
+        info!("Job {} queued on worker {} for target {}", job.id, worker, target.name);
+        if let Some(w) = cx.lock().registered_workers.get_mut(&worker) {
+            w.pending.push_back(job.id.clone());
+        }
+        if args.asynchronous {
+            return Ok(with_status(
+                // NOTE (unwrap): error in this call is caused by programmer error and should never be caused by the user data
+                serde_json::to_vec_pretty(&job).unwrap(),
+                StatusCode::ACCEPTED,
+            ));
+        }
+        return wait_on_worker(cx, job.id).await;
+    }
+
+    if args.asynchronous {
+        info!("Job {} queued on target {}", job.id, target.name);
+        tokio::spawn(crate::logutil::scope_job(
+            job.id.clone(),
+            run_job(cx, job.id.clone(), target, image, preload, timeout),
+        ));
+
+        return Ok(with_status(
+            // NOTE (unwrap): error in this call is caused by programmer error and should never be caused by the user data
+            serde_json::to_vec_pretty(&job).unwrap(),
+            StatusCode::ACCEPTED,
+        ));
+    }
+
+    let _cancel = CancelOnDrop(cancel);
+    let (result, logs) = run_job(cx, job.id, target, image, preload, timeout).await;
+    let status = match result {
+        Ok(()) => StatusCode::OK,
+        // NOTE (unwrap): all the statuses returned by `http_status` are valid.
+        Err(e) => StatusCode::from_u16(e.http_status()).unwrap(),
+    };
+
+    Ok(with_status(logs, status))
+}
+
+/// Store a newly submitted job, along with its images, as a storage call.
+fn insert_job(
+    job: &api::Job,
+    image: &run::Image,
+    preload: &[run::Image],
+) -> impl FnOnce(&dyn Storage) -> anyhow::Result<()> + Send + 'static {
+    let (job, elf) = (job.clone(), image.data.clone());
+    let preload: Vec<Bytes> = preload.iter().map(|image| image.data.clone()).collect();
+    move |storage| {
+        let preload: Vec<&[u8]> = preload.iter().map(|data| &data[..]).collect();
+        storage.insert(&job, &elf, &preload)
+    }
+}
+
+/// Create the GitHub check run a job asked for in the background, so a slow GitHub API doesn't hold up the run,
+/// and report the job's result to it once it's finished.
+fn spawn_check_run(cx: Arc<Mutex<Context>>, id: String) {
+    let (github, request) = {
+        let context = cx.lock();
+        let request = context
+            .jobs
+            .get(&id)
+            .filter(|job| job.info.check_run.is_none())
+            .and_then(|job| job.info.check_request.clone());
+        match (context.github.clone(), request) {
+            (Some(github), Some(request)) => (github, request),
+            _ => return,
+        }
+    };
+    tokio::spawn(async move {
+        let check = match github.create(&request.repo, &request.sha, &request.name, &id).await {
+            Ok(check) => check,
+            Err(e) => {
+                warn!("Failed to create GitHub check run for job {}: {:?}", id, e);
+                return;
+            }
+        };
+        let mut context = cx.lock();
+        context.update_job(&id, |job| job.info.check_run = Some(check));
+        // The job may have finished while the check run was created.
+        if context.jobs.get(&id).is_some_and(|job| job.info.status.is_finished()) {
+            context.complete_check(&id);
+        }
+    });
+}
+
+/// Fail a job left for other servers of the cluster if none of them claims it in time, like when the server with
+/// its target went away.
+fn spawn_claim_timeout(cx: Arc<Mutex<Context>>, id: String, cluster: &config::ClusterConfig) {
+    let (timeout, node) = (Duration::from_secs(cluster.claim_timeout_secs), cluster.node.clone());
+    tokio::spawn(async move {
+        tokio::time::sleep(timeout).await;
+        let msg = format!("no server of the cluster claimed the job within {}s", timeout.as_secs());
+        fail_unclaimed(&cx, &id, &node, api::RunError::ProbeNotFound, &msg);
+    });
+}
+
+/// Fail a job left for other servers of the cluster, unless one of them claimed it already. It's claimed for
+/// `node` first, so no other server picks it up in the meantime.
+fn fail_unclaimed(cx: &Mutex<Context>, id: &str, node: &str, failure: api::RunError, msg: &str) {
+    let (id, node, msg) = (id.to_string(), node.to_string(), msg.to_string());
+    let what = format!("fail unclaimed job {}", id);
+    cx.lock().storage.write(what, move |storage| {
+        if !storage.claim(&id, &node)? {
+            return Ok(());
+        }
+        let Some(mut job) = storage.job(&id)? else {
+            return Ok(());
+        };
+        warn!("Job {}: {}", id, msg);
+        job.node = Some(node);
+        job.status = api::JobStatus::Failed;
+        job.failure = Some(failure);
+        job.finished_at = Some(Utc::now());
+        storage.save(&job, format!("ERROR - {}\n", msg).as_bytes())
+    });
+}
+
+/// Cancels a job left for other servers of the cluster when dropped before it finished, which warp does when the
+/// client of a synchronous run disconnects. A job another server claimed already can't be cancelled from here,
+/// like with `DELETE /jobs/{id}`, and runs to its end.
+struct CancelUnclaimedOnDrop {
+    cx: Arc<Mutex<Context>>,
+    id: String,
+    node: String,
+    finished: bool,
+}
+
+impl Drop for CancelUnclaimedOnDrop {
+    fn drop(&mut self) {
+        if !self.finished {
+            fail_unclaimed(
+                &self.cx,
+                &self.id,
+                &self.node,
+                api::RunError::Cancelled,
+                "job cancelled",
+            );
+        }
+    }
+}
+
+/// A job of another server of the cluster, or one left for it, from the shared storage.
+async fn shared_job(cx: &Mutex<Context>, id: &str) -> Option<api::Job> {
+    cx.lock().config.cluster.as_ref()?;
+    stored_job(cx, id).await
+}
+
+/// A job from the storage, like one that's been pruned from memory.
+async fn stored_job(cx: &Mutex<Context>, id: &str) -> Option<api::Job> {
+    let storage = cx.lock().storage.clone();
+    let job_id = id.to_string();
+    match storage.call(move |storage| storage.job(&job_id)).await {
+        Ok(job) => job,
+        Err(e) => {
+            error!("Failed to load job {} from store: {:?}", id, e);
+            None
+        }
+    }
+}
+
+/// Whether another server of the cluster reported `target` up lately, so it can claim jobs on it.
+async fn other_node_has(cx: &Mutex<Context>, cluster: &config::ClusterConfig, target: &str) -> bool {
+    let max_age = cluster.claim_interval_millis * NODE_REPORT_INTERVALS;
+    let since = Utc::now() - chrono::Duration::milliseconds(max_age as i64);
+    let storage = cx.lock().storage.clone();
+    match storage.call(|storage| storage.load_nodes()).await {
+        Ok(nodes) => nodes
+            .iter()
+            .any(|n| n.node != cluster.node && n.reported_at >= since && n.targets.iter().any(|t| t == target)),
+        // Leave the job for the others anyway, it fails once the claim timeout is up.
+        Err(e) => {
+            warn!("Failed to load the targets of the cluster's servers: {:?}", e);
+            true
+        }
+    }
+}
+
+/// Wait for a job left for another server of the cluster to finish, and reply like a synchronous run here would.
+async fn wait_claimed(cx: Arc<Mutex<Context>>, id: String, node: String) -> Result<WithStatus<Vec<u8>>, Rejection> {
+    let mut cancel = CancelUnclaimedOnDrop {
+        cx: cx.clone(),
+        id: id.clone(),
+        node,
+        finished: false,
+    };
+    let interval = Duration::from_millis(
+        cx.lock()
+            .config
+            .cluster
+            .as_ref()
+            .map_or(1000, |c| c.claim_interval_millis),
+    );
+    let storage = cx.lock().storage.clone();
+    loop {
+        tokio::time::sleep(interval).await;
+        let job_id = id.clone();
+        let result = storage
+            .call(move |storage| Ok((storage.job(&job_id)?, storage.logs(&job_id)?.unwrap_or_default())))
+            .await;
+        let (job, logs) = match result {
+            Ok((Some(job), logs)) if job.status.is_finished() => (job, logs),
+            Ok((Some(_), _)) => continue,
+            Ok((None, _)) => {
+                cancel.finished = true;
+                reject!(
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    "Job disappeared from storage: {}",
+                    id
+                )
+            }
+            Err(e) => {
+                warn!("Failed to check on job {}: {:?}", id, e);
+                continue;
+            }
+        };
+        let status = match job.failure {
+            None => StatusCode::OK,
+            // NOTE (unwrap): all the statuses returned by `http_status` are valid.
+            Some(e) => StatusCode::from_u16(e.http_status()).unwrap(),
+        };
+        cancel.finished = true;
+        return Ok(with_status(logs, status));
+    }
+}
+
+/// Cancels a synchronous run on a worker's target when dropped before it finished, which warp does when the
+/// client disconnects. Like with `DELETE /jobs/{id}`, the job is finished right away, and whatever the worker
+/// hands back for it later is dropped.
+struct CancelWorkerJobOnDrop {
+    cx: Arc<Mutex<Context>>,
+    id: String,
+    finished: bool,
+}
+
+impl Drop for CancelWorkerJobOnDrop {
+    fn drop(&mut self) {
+        if self.finished {
+            return;
+        }
+        let mut context = self.cx.lock();
+        if let Some(job) = context.jobs.get(&self.id) {
+            job.cancel.store(true, Ordering::Relaxed);
+        }
+        context.finish_cancelled(&self.id);
+    }
+}
+
+/// Wait for a job on a worker's target to finish, and reply like a synchronous run here would.
+async fn wait_on_worker(cx: Arc<Mutex<Context>>, id: String) -> Result<WithStatus<Vec<u8>>, Rejection> {
+    let mut cancel = CancelWorkerJobOnDrop {
+        cx: cx.clone(),
+        id: id.clone(),
+        finished: false,
+    };
+    loop {
+        tokio::time::sleep(WORKER_POLL_INTERVAL).await;
+        let job = cx.lock().jobs.get(&id).map(|job| (job.info.clone(), job.logs.clone()));
+        let Some((info, logs)) = job else {
+            cancel.finished = true;
+            reject!(StatusCode::INTERNAL_SERVER_ERROR, "Job disappeared: {}", id);
+        };
+        if !info.status.is_finished() {
+            continue;
+        }
+        cancel.finished = true;
+        let status = match info.failure {
+            None => StatusCode::OK,
+            // NOTE (unwrap): all the statuses returned by `http_status` are valid.
+            Some(e) => StatusCode::from_u16(e.http_status()).unwrap(),
+        };
+        return Ok(with_status(logs, status));
+    }
+}
+
+/// Claim jobs that other servers of the cluster left for targets attached to this one, and run them. The targets
+/// are reported to the other servers too, so they only leave jobs for targets one of them has.
+fn spawn_claimer(cx: Arc<Mutex<Context>>, cluster: config::ClusterConfig) {
+    let storage = cx.lock().storage.clone();
+    tokio::spawn(async move {
+        loop {
+            tokio::time::sleep(Duration::from_millis(cluster.claim_interval_millis)).await;
+            let draining = cx.lock().draining;
+            let up: Vec<String> = match draining {
+                true => Vec::new(),
+                false => targets(cx.clone())
+                    .await
+                    .targets
+                    .into_iter()
+                    .filter(|t| t.up && !t.maintenance)
+                    .map(|t| t.name)
+                    .collect(),
+            };
+            let report = api::NodeTargets {
+                node: cluster.node.clone(),
+                targets: up.clone(),
+                reported_at: Utc::now(),
+            };
+            storage.write("report the targets of this server to the cluster", move |storage| {
+                storage.save_node(&report)
+            });
+            if up.is_empty() {
+                continue;
+            }
+            let jobs = match storage.call(move |storage| storage.unclaimed(&up)).await {
+                Ok(jobs) => jobs,
+                Err(e) => {
+                    warn!("Failed to look for jobs to claim: {:?}", e);
+                    continue;
+                }
+            };
+            for mut info in jobs {
+                let (id, node) = (info.id.clone(), cluster.node.clone());
+                match storage.call(move |storage| storage.claim(&id, &node)).await {
+                    Ok(true) => {}
+                    // Another server was faster.
+                    Ok(false) => continue,
+                    Err(e) => {
+                        warn!("Failed to claim job {}: {:?}", info.id, e);
+                        continue;
+                    }
+                }
+                info!("Claimed job {} on target {}", info.id, info.target);
+                let id = info.id.clone();
+                info.node = Some(cluster.node.clone());
+                cx.lock().jobs.insert(id.clone(), Job::stored(info, Vec::new()));
+                resume_job(cx.clone(), id);
+            }
+        }
+    });
+}
+
+async fn run_job(
+    cx: Arc<Mutex<Context>>,
+    id: String,
+    target: config::Target,
+    image: run::Image,
+    preload: Vec<run::Image>,
+    timeout: Duration,
+) -> (Result<(), api::RunError>, Vec<u8>) {
+    let (caller, weight, output) = match cx.lock().jobs.get(&id) {
+        Some(job) => (job.caller.clone().unwrap_or_default(), job.weight, job.output.clone()),
+        None => (String::new(), 1.0, OutputArgs::default()),
+    };
+    let _turn = Turn::wait(&cx, &target.name, &caller, weight).await;
+
+    let target_mutex = cx.lock().target_lock(&target.name);
+    let _target_guard = target_mutex.lock().await;
+
+    let cancel = {
+        let mut context = cx.lock();
+        let cancel: Arc<AtomicBool> = context.jobs.get(&id).map(|job| job.cancel.clone()).unwrap_or_default();
+        if cancel.load(Ordering::Relaxed) {
+            context.finish_cancelled(&id);
+            let logs = context.jobs.get(&id).map(|job| job.logs.clone()).unwrap_or_default();
+            return (Err(api::RunError::Cancelled), logs);
+        }
+        cancel
+    };
+
+    cx.lock().update_job(&id, |job| {
+        job.info.status = api::JobStatus::Running;
+        job.info.started_at = Some(Utc::now());
+    });
+
+    let (retry, max_keepalive_timeout, pool, run_logs) = {
+        let context = cx.lock();
+        let config = &context.config;
+        (
+            config.retry.clone(),
+            Duration::from_secs(config.max_keepalive_timeout),
+            context.workers.clone(),
+            context.run_logs.clone(),
+        )
+    };
+    let mut logs = Vec::new();
+    let mut attempt = 0;
+    let result = loop {
+        let probe = target_spec(&target);
+        let start = Instant::now();
+        let opts = run::Options {
+            deadline: Some(start + timeout),
+            flash: target.flash.clone(),
+            preload: preload.clone(),
+            rtt_overflow: target.rtt_overflow.clone(),
+            core_dump: target.core_dump,
+            run_from: target.run_from,
+            freeze_watchdogs: target.freeze_watchdogs,
+            disable_approtect: target.disable_approtect,
+            stack_usage: target.stack_usage,
+            stack_guard: target.stack_guard,
+            coverage: target.coverage,
+            log_filter: output.log_filter.clone(),
+            raw_defmt: output.raw_defmt,
+            cancel: Some(cancel.clone()),
+            keepalive: Some(run::Keepalive {
+                extension: timeout,
+                max_deadline: start + max_keepalive_timeout,
+            }),
+            ..Default::default()
+        };
+        // The logs of the earlier attempts, followed by this one's as they come.
+        if let Some(job) = cx.lock().jobs.get_mut(&id) {
+            job.logs = logs.clone();
+        }
+        if let Some(run_logs) = &run_logs {
+            run_logs.write_at(&id, 0, &logs);
+        }
+        let (report, attempt_logs) = run_with_log_capture(
+            &pool,
+            image.clone(),
+            probe,
+            opts,
+            target.clone(),
+            output.host_timestamps,
+            &id,
+            |line| {
+                if let Some(job) = cx.lock().jobs.get_mut(&id) {
+                    job.logs.extend_from_slice(line);
+                }
+                if let Some(run_logs) = &run_logs {
+                    run_logs.append(&id, line);
+                }
+            },
+        )
+        .await;
+        logs.extend_from_slice(&attempt_logs);
+        let result = match report.failure {
+            Some(kind) => Err(kind),
+            None => Ok(()),
+        };
+        {
+            let mut context = cx.lock();
+            context.record_timings(&target.name, &report.timings);
+            if let Some(dump) = &report.core_dump {
+                context.save_core_dump(&id, dump);
+            }
+            context.update_job(&id, |job| {
+                job.info.timings = report.timings.clone();
+                job.info.backtrace = report.backtrace.clone();
+                job.info.panic = report.panic.clone();
+                job.report = Some(report);
+            });
+        }
+
+        // Probe and flashing failures are often USB glitches that go away on their own,
+        // but retrying a firmware failure would only hide flaky tests.
+        let transient = matches!(result, Err(api::RunError::ProbeNotFound | api::RunError::FlashError));
+        if !transient || attempt >= retry.attempts || cancel.load(Ordering::Relaxed) {
+            break result;
+        }
+
+        let backoff = retry.backoff(attempt);
+        attempt += 1;
+        info!("Job {} failed with {:?}, retrying in {:?}", id, result, backoff);
+        let note = format!(
+            "--- retrying after {:?} failure, attempt {} of {} ---\n",
+            result.unwrap_err(),
+            attempt,
+            retry.attempts
+        );
+        logs.extend_from_slice(note.as_bytes());
+        tokio::time::sleep(backoff).await;
+    };
+    let ok = result.is_ok();
+    let key = (ok && output.is_plain()).then(|| result_key(&target, timeout.as_secs(), &image, &preload));
+
+    {
+        let mut context = cx.lock();
+        if let Some(key) = key {
+            context.cache_result(key, &id, &logs);
+        }
+        context.update_job(&id, |job| {
+            job.info.status = if ok {
+                api::JobStatus::Succeeded
+            } else {
+                api::JobStatus::Failed
+            };
+            job.info.failure = result.err();
+            job.info.finished_at = Some(Utc::now());
+            job.logs = logs.clone();
+        });
+        context.record_run(&id);
+        context.complete_check(&id);
+    }
+
+    (result, logs)
+}
+
+/// The settings of a target that change how runs on it go, like the probe, its speed and the stimulus script,
+/// serialized to tell when they changed.
+fn run_settings(target: &config::Target) -> Vec<u8> {
+    // Whether the target is in service, and how it's selected, don't change how runs on it go.
+    let settings = config::Target {
+        labels: BTreeMap::new(),
+        maintenance: false,
+        ..target.clone()
+    };
+    // NOTE (unwrap): error in this call is caused by programmer error and should never be caused by the user data
+    serde_json::to_vec(&settings).unwrap()
+}
+
+/// Key of a run in the result cache: the target with the settings it ran with, the timeout, and the images
+/// flashed on it.
+fn result_key(target: &config::Target, timeout: u64, image: &run::Image, preload: &[run::Image]) -> String {
+    let mut key = format!("{} {} {}", target.name, blobs::hash(&run_settings(target)), timeout);
+    for image in preload.iter().chain([image]) {
+        write!(
+            key,
+            " {}:{:?}:{:?}",
+            blobs::hash(&image.data),
+            image.format,
+            image.base_address
+        )
+        .unwrap();
+    }
+    key
+}
+
+/// Re-queue a job that was loaded from the store but hadn't finished before the server stopped, or that was
+/// claimed from another server of the cluster.
+fn resume_job(cx: Arc<Mutex<Context>>, id: String) {
+    let (info, target, storage) = {
+        let context = cx.lock();
+        let Some(job) = context.jobs.get(&id) else { return };
+        let target = context
+            .config
+            .targets
+            .iter()
+            .find(|t| t.name == job.info.target)
+            .cloned();
+        (job.info.clone(), target, context.storage.clone())
+    };
+    let Some(target) = target else {
+        return cx.lock().fail_job(
+            &id,
+            api::RunError::ProbeNotFound,
+            "Target no longer exists, job dropped after restart",
+        );
+    };
+
+    tokio::spawn(crate::logutil::scope_job(id.clone(), async move {
+        let (image, preload) = match load_images(&storage, &info).await {
+            Ok(Some(images)) => images,
+            Ok(None) => {
+                return cx.lock().fail_job(
+                    &id,
+                    api::RunError::Cancelled,
+                    "ELF missing from job store, job dropped after restart",
+                )
+            }
+            Err(e) => {
+                return cx.lock().fail_job(
+                    &id,
+                    api::RunError::Cancelled,
+                    &format!("Failed to load ELF from job store: {:?}", e),
+                )
+            }
+        };
+        info!("Job {} resumed on target {}", id, target.name);
+        spawn_check_run(cx.clone(), id.clone());
+        let timeout = Duration::from_secs(info.timeout);
+        run_job(cx, id, target, image, preload, timeout).await;
+    }));
+}
+
+/// Load the images of a job from the storage, if they're still there.
+async fn load_images(
+    storage: &storage::Handle,
+    info: &api::Job,
+) -> anyhow::Result<Option<(run::Image, Vec<run::Image>)>> {
+    let (id, count) = (info.id.clone(), info.preload.len());
+    let images = storage
+        .call(move |storage| Ok(storage.elf(&id)?.zip(storage.preload(&id, count)?)))
+        .await?;
+    Ok(images.map(|(elf, preload)| {
+        let image = run::Image {
+            data: Bytes::from(elf),
+            format: info.format,
+            base_address: info.base_address,
+        };
+        let preload = info
+            .preload
+            .iter()
+            .zip(preload)
+            .map(|(info, data)| run::Image {
+                data: Bytes::from(data),
+                format: info.format,
+                base_address: info.base_address,
+            })
+            .collect();
+        (image, preload)
+    }))
+}
+
+fn target_spec(target: &config::Target) -> probe::TargetSpec {
+    probe::TargetSpec {
+        chip: target.chip.clone(),
+        connect_under_reset: target.connect_under_reset,
+        probe: Some(target.probe.clone()),
+        usb_path: target.usb_path.clone(),
+        speed: target.speed,
+        auto_speed: target.auto_speed,
+        power_reset: target.power_reset,
+        cycle_delay_seconds: target.cycle_delay_seconds,
+        power_control: target.power_control.clone(),
+        max_settle_time_millis: target.max_settle_time_millis,
+        connect_attempts: target.connect_attempts,
+        connect_retry_interval_millis: target.connect_retry_interval_millis,
+        auto_recover: target.auto_recover,
+        identity: target.identity.clone(),
+    }
+}
+
+/// Reject callers that may not see job `id` as if it didn't exist. Jobs the server has forgotten, and can't find
+/// in the storage either, only callers allowed on all targets may look for.
+async fn check_job_access(cx: &Mutex<Context>, caller: &Caller, id: &str) -> Result<(), Rejection> {
+    let info = cx.lock().jobs.get(id).map(|job| job.info.clone());
+    let info = match info {
+        Some(info) => Some(info),
+        None => stored_job(cx, id).await,
+    };
+    let allowed = match info {
+        Some(info) => caller.may_see(&info, &cx.lock().run_targets()),
+        None => caller.targets.is_none(),
+    };
+    if !allowed {
+        reject!(StatusCode::NOT_FOUND, code = "job_not_found", "Job not found: {}", id);
+    }
+    Ok(())
+}
+
+async fn handle_job_status(id: String, caller: Caller, cx: Arc<Mutex<Context>>) -> Result<impl Reply, Rejection> {
+    let info = cx.lock().jobs.get(&id).map(|job| job.info.clone());
+    let info = match info {
+        Some(info) => info,
+        None => match shared_job(&cx, &id).await {
+            Some(info) => info,
+            None => reject!(StatusCode::NOT_FOUND, code = "job_not_found", "Job not found: {}", id),
+        },
+    };
+    if !caller.may_see(&info, &cx.lock().run_targets()) {
+        reject!(StatusCode::NOT_FOUND, code = "job_not_found", "Job not found: {}", id);
+    }
+
+    Ok(with_status(
+        // NOTE (unwrap): error in this call is caused by programmer error and should never be caused by the user data
+        serde_json::to_vec_pretty(&info).unwrap(),
+        StatusCode::OK,
+    ))
+}
+
+/// Cancel a job. Queued jobs fail right away, running ones as soon as their run notices.
+async fn handle_job_cancel(id: String, caller: Caller, cx: Arc<Mutex<Context>>) -> Result<impl Reply, Rejection> {
+    if !cx.lock().jobs.contains_key(&id) {
+        match shared_job(&cx, &id).await {
+            Some(info) => reject!(
+                StatusCode::CONFLICT,
+                code = "job_on_other_node",
+                "Job belongs to another server of the cluster{}: {}",
+                info.node.map(|n| format!(", {}", n)).unwrap_or_default(),
+                id
+            ),
+            None => reject!(StatusCode::NOT_FOUND, code = "job_not_found", "Job not found: {}", id),
+        }
+    }
+
+    let mut context = cx.lock();
+    let (queued, cancel) = match context.jobs.get(&id) {
+        Some(job) if job.caller.as_ref().is_some_and(|c| *c != caller.id) => {
+            reject!(StatusCode::FORBIDDEN, "Job was submitted by another caller: {}", id)
+        }
+        Some(job) if job.info.status.is_finished() => reject!(StatusCode::CONFLICT, "Job already finished: {}", id),
+        // Workers can't be reached, so their jobs are finished right away, like queued ones. Whatever the
+        // worker hands back later is dropped.
+        Some(job) => (
+            job.info.status == api::JobStatus::Queued || job.info.worker.is_some(),
+            job.cancel.clone(),
+        ),
+        // Pruned while looking for it in the storage.
+        None => reject!(StatusCode::NOT_FOUND, code = "job_not_found", "Job not found: {}", id),
+    };
+
+    info!("Job {} cancelled by {}", id, caller.id);
+    let target = context.jobs[&id].info.target.clone();
+    context.audit(&caller.id, "cancel", Some(&target), Some(&id));
+    cancel.store(true, Ordering::Relaxed);
+    if queued {
+        context.finish_cancelled(&id);
+    }
+
+    Ok(with_status(
+        // NOTE (unwrap): error in this call is caused by programmer error and should never be caused by the user data
+        serde_json::to_vec_pretty(&context.jobs[&id].info).unwrap(),
+        StatusCode::OK,
+    ))
+}
+
+/// The run log file of a job, if run logs are written and it has one.
+async fn read_run_log(cx: &Mutex<Context>, id: &str) -> Option<std::io::Result<Option<Vec<u8>>>> {
+    let (run_logs, id) = (cx.lock().run_logs.clone()?, id.to_string());
+    match tokio::task::spawn_blocking(move || run_logs.read(&id)).await {
+        Ok(res) => Some(res),
+        Err(e) => Some(Err(std::io::Error::other(e))),
+    }
+}
+
+async fn handle_job_logs(id: String, caller: Caller, cx: Arc<Mutex<Context>>) -> Result<impl Reply, Rejection> {
+    check_job_access(&cx, &caller, &id).await?;
+    let logs = cx.lock().jobs.get(&id).map(|job| job.logs.clone());
+    let logs = match logs {
+        Some(logs) => logs,
+        // Other servers only store the logs once the job is finished.
+        None if shared_job(&cx, &id).await.is_some() => {
+            let (storage, job_id) = (cx.lock().storage.clone(), id.clone());
+            let logs = storage.call(move |storage| storage.logs(&job_id)).await;
+            logs.ok().flatten().unwrap_or_default()
+        }
+        // Forgotten jobs, or from before a restart.
+        None => match read_run_log(&cx, &id).await {
+            Some(Ok(Some(logs))) => logs,
+            Some(Err(e)) => reject!(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "Failed to read run log of job {}: {}",
+                id,
+                e
+            ),
+            _ => reject!(StatusCode::NOT_FOUND, code = "job_not_found", "Job not found: {}", id),
+        },
+    };
+
+    Ok(with_status(logs, StatusCode::OK))
+}
+
+async fn handle_job_report(id: String, caller: Caller, cx: Arc<Mutex<Context>>) -> Result<impl Reply, Rejection> {
+    check_job_access(&cx, &caller, &id).await?;
+    let context = cx.lock();
+    let report = match context.jobs.get(&id) {
+        Some(Job {
+            report: Some(report), ..
+        }) => report,
+        Some(_) => reject!(StatusCode::NOT_FOUND, "Job has no report: {}", id),
+        None => reject!(StatusCode::NOT_FOUND, code = "job_not_found", "Job not found: {}", id),
+    };
+
+    Ok(with_status(
+        // NOTE (unwrap): error in this call is caused by programmer error and should never be caused by the user data
+        serde_json::to_vec_pretty(report).unwrap(),
+        StatusCode::OK,
+    ))
+}
+
+/// Start a chunked upload, or find out where to resume one.
+async fn handle_blob_start(caller: Caller, args: BlobArgs, cx: Arc<Mutex<Context>>) -> Result<impl Reply, Rejection> {
+    let blobs = cx.lock().blobs.clone();
+    match blobs.start(&args.hash, args.size) {
+        Ok(progress) => {
+            if progress.offset == 0 {
+                info!("Upload of {} ({} bytes) started by {}", args.hash, args.size, caller.id);
+            }
+            Ok(blob_reply(args.hash, progress))
+        }
+        Err(e) => reject!("{}", e),
+    }
+}
+
+/// Append a chunk to an upload. Any error means the client should ask `POST /blobs` where to resume.
+async fn handle_blob_chunk(
+    hash: String,
+    caller: Caller,
+    args: ChunkArgs,
+    chunk: Bytes,
+    cx: Arc<Mutex<Context>>,
+) -> Result<impl Reply, Rejection> {
+    let blobs = cx.lock().blobs.clone();
+    match blobs.write(&hash, args.offset, &chunk) {
+        Ok(progress) => {
+            if progress.offset == progress.size {
+                info!("Upload of {} finished by {}", hash, caller.id);
+            }
+            Ok(blob_reply(hash, progress))
+        }
+        Err(e) => reject!(StatusCode::CONFLICT, "{}", e),
+    }
+}
+
+fn blob_reply(hash: String, progress: blobs::Progress) -> WithStatus<Vec<u8>> {
+    let upload = api::BlobUpload {
+        hash,
+        size: progress.size,
+        offset: progress.offset,
+    };
+    with_status(
+        // NOTE (unwrap): error in this call is caused by programmer error and should never be caused by the user data
+        serde_json::to_vec_pretty(&upload).unwrap(),
+        StatusCode::OK,
+    )
+}
+
+async fn handle_job_core_dump(id: String, caller: Caller, cx: Arc<Mutex<Context>>) -> Result<impl Reply, Rejection> {
+    check_job_access(&cx, &caller, &id).await?;
+    let (known, dump, storage) = {
+        let context = cx.lock();
+        let dump = match context.jobs.get(&id) {
+            Some(Job {
+                report: Some(RunReport {
+                    core_dump: Some(dump), ..
+                }),
+                ..
+            }) => Some(dump.clone()),
+            _ => None,
+        };
+        (context.jobs.contains_key(&id), dump, context.storage.clone())
+    };
+    let job_id = id.clone();
+    let dump = match dump {
+        Some(dump) => Some(dump),
+        // Forgotten jobs, from before a restart, or run by other servers.
+        None => match storage.call(move |storage| storage.core_dump(&job_id)).await {
+            Ok(dump) => dump,
+            Err(e) => reject!(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "Failed to load core dump of job {}: {:?}",
+                id,
+                e
+            ),
+        },
+    };
+
+    match dump {
+        Some(dump) => Ok(with_status(dump, StatusCode::OK)),
+        None if known || shared_job(&cx, &id).await.is_some() => {
+            reject!(StatusCode::NOT_FOUND, "Job has no core dump: {}", id)
+        }
+        None => reject!(StatusCode::NOT_FOUND, code = "job_not_found", "Job not found: {}", id),
+    }
+}
+
+async fn handle_job_coverage(id: String, caller: Caller, cx: Arc<Mutex<Context>>) -> Result<impl Reply, Rejection> {
+    check_job_access(&cx, &caller, &id).await?;
+    let context = cx.lock();
+    let profile = match context.jobs.get(&id) {
+        Some(Job {
+            report: Some(RunReport {
+                coverage: Some(profile),
+                ..
+            }),
+            ..
+        }) => profile,
+        Some(_) => reject!(StatusCode::NOT_FOUND, "Job has no coverage profile: {}", id),
+        None => reject!(StatusCode::NOT_FOUND, code = "job_not_found", "Job not found: {}", id),
+    };
+
+    Ok(with_status(profile.clone(), StatusCode::OK))
+}
+
+async fn handle_job_defmt(id: String, caller: Caller, cx: Arc<Mutex<Context>>) -> Result<impl Reply, Rejection> {
+    check_job_access(&cx, &caller, &id).await?;
+    let context = cx.lock();
+    let stream = match context.jobs.get(&id) {
+        Some(Job {
+            report: Some(RunReport {
+                defmt_raw: Some(stream),
+                ..
+            }),
+            ..
+        }) => stream,
+        Some(_) => reject!(StatusCode::NOT_FOUND, "Job has no raw defmt stream: {}", id),
+        None => reject!(StatusCode::NOT_FOUND, code = "job_not_found", "Job not found: {}", id),
+    };
+
+    Ok(with_status(stream.clone(), StatusCode::OK))
+}
+
+#[derive(Deserialize)]
+struct GdbArgs {
+    /// In seconds. Defaults to, and is capped at, the configured `max_duration`.
+    duration: Option<u64>,
+}
+
+/// Start a gdb session on a target, once the job running on it, if any, is done. Runs wait for the
+/// session to end. On a target the caller leased, it starts right away and ends with the lease.
+async fn handle_gdb_start(
+    name: String,
+    caller: Caller,
+    args: GdbArgs,
+    cx: Arc<Mutex<Context>>,
+) -> Result<impl Reply, Rejection> {
+    let (gdb, index, target, chip_descriptions, target_mutex, lease_left) = {
+        let mut context = cx.lock();
+        let Some(gdb) = context.config.gdb.clone() else {
+            reject!(StatusCode::NOT_FOUND, "GDB sessions are not enabled on this server");
+        };
+        let Some(index) = context.config.targets.iter().position(|t| t.name == name) else {
+            reject!(
+                StatusCode::NOT_FOUND,
+                code = "target_not_found",
+                "Target not found: {}",
+                name
+            );
+        };
+        let target = context.config.targets[index].clone();
+        if !caller.allows(&target.name, &target.labels) {
+            reject!(
+                StatusCode::FORBIDDEN,
+                code = "target_not_allowed",
+                "Not allowed to use target: {}",
+                name
+            );
+        }
+        if context.gdb_sessions.contains_key(&name) {
+            reject!(StatusCode::CONFLICT, "Target already has a gdb session: {}", name);
+        }
+        let lease_left = match context.leases.get(&name) {
+            Some(lease) if lease.info.holder != caller.id => {
+                reject!(StatusCode::CONFLICT, "Target is leased by another caller: {}", name)
+            }
+            Some(lease) => Some(lease.expires.saturating_duration_since(Instant::now())),
+            None => None,
+        };
+        let chip_descriptions = context.config.chip_descriptions.clone();
+        (
+            gdb,
+            index,
+            target,
+            chip_descriptions,
+            context.target_lock(&name),
+            lease_left,
+        )
+    };
+    let Some(port) = u16::try_from(index).ok().and_then(|i| gdb.first_port.checked_add(i)) else {
+        reject!(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "No gdb port left for target: {}",
+            name
+        );
+    };
+
+    // The lease already holds the target.
+    let guard = match lease_left {
+        Some(_) => None,
+        None => Some(target_mutex.lock_owned().await),
+    };
+    // Another session may have started while waiting for the target.
+    if cx.lock().gdb_sessions.contains_key(&name) {
+        reject!(StatusCode::CONFLICT, "Target already has a gdb session: {}", name);
+    }
+    let pool = cx.lock().workers.clone();
+    if let Err(e) = pool.run(&name, close_kept_session).await {
+        warn!("Failed to close the kept probe session of target {}: {}", name, e);
+    }
+
+    let addr = SocketAddr::new(gdb.bind, port);
+    let child = match crate::gdb::spawn(&gdb.probe_rs, &target_spec(&target), &chip_descriptions, addr) {
+        Ok(child) => child,
+        Err(e) => reject!(StatusCode::INTERNAL_SERVER_ERROR, "Failed to start gdb stub: {:?}", e),
+    };
+
+    // Under a lease, the session also ends with the lease, which renewing it pushes back.
+    let duration = args.duration.unwrap_or(gdb.max_duration).min(gdb.max_duration);
+    let ends_at = Utc::now() + chrono::Duration::seconds(duration as i64);
+    let expires_at = match lease_left {
+        Some(left) => ends_at.min(Utc::now() + chrono::Duration::seconds(left.as_secs() as i64)),
+        None => ends_at,
+    };
+    let session = GdbSession {
+        info: api::GdbSession {
+            target: name.clone(),
+            port,
+            expires_at,
+        },
+        caller: caller.id.clone(),
+        stop: Arc::default(),
+        leased: lease_left.is_some(),
+        ends_at,
+    };
+    info!("GDB session on target {} started by {}, on {}", name, caller.id, addr);
+    let info = session.info.clone();
+    let stop = session.stop.clone();
+    cx.lock().gdb_sessions.insert(name.clone(), session);
+
+    tokio::spawn(async move {
+        let mut child = child;
+        let deadline = Instant::now() + Duration::from_secs(duration);
+        loop {
+            tokio::time::sleep(GDB_POLL_INTERVAL).await;
+            match child.try_wait() {
+                Ok(None) => {}
+                Ok(Some(status)) => {
+                    warn!("GDB stub for target {} exited with {}", name, status);
+                    break;
+                }
+                Err(e) => {
+                    error!("Failed to check on gdb stub for target {}: {:?}", name, e);
+                    break;
+                }
+            }
+            if stop.load(Ordering::Relaxed) || Instant::now() >= deadline {
+                let _ = child.kill();
+                let _ = child.wait();
+                break;
+            }
+        }
+        info!("GDB session on target {} ended", name);
+        cx.lock().gdb_sessions.remove(&name);
+        drop(guard);
+    });
+
+    Ok(with_status(
+        // NOTE (unwrap): error in this call is caused by programmer error and should never be caused by the user data
+        serde_json::to_vec_pretty(&info).unwrap(),
+        StatusCode::OK,
+    ))
+}
+
+async fn handle_gdb_stop(name: String, caller: Caller, cx: Arc<Mutex<Context>>) -> Result<impl Reply, Rejection> {
+    let context = cx.lock();
+    let session = match context.gdb_sessions.get(&name) {
+        Some(s) if s.caller != caller.id => {
+            reject!(
+                StatusCode::FORBIDDEN,
+                "GDB session was started by another caller: {}",
+                name
+            )
+        }
+        Some(s) => s,
+        None => reject!(StatusCode::NOT_FOUND, "No gdb session on target: {}", name),
+    };
+
+    info!("GDB session on target {} stopped by {}", name, caller.id);
+    session.stop.store(true, Ordering::Relaxed);
+
+    Ok(with_status(
+        // NOTE (unwrap): error in this call is caused by programmer error and should never be caused by the user data
+        serde_json::to_vec_pretty(&session.info).unwrap(),
+        StatusCode::OK,
+    ))
+}
+
+#[derive(Deserialize)]
+struct LeaseArgs {
+    /// In seconds. Defaults to, and is capped at, the configured `max_lease_ttl`.
+    ttl: Option<u64>,
+}
+
+impl LeaseArgs {
+    fn ttl(&self, config: &Config) -> Duration {
+        Duration::from_secs(self.ttl.unwrap_or(config.max_lease_ttl).min(config.max_lease_ttl))
+    }
+}
+
+/// Lease a target, so no runs use it until the lease is released or runs out. Waits for the job running
+/// on it, if any, to finish.
+async fn handle_lease_start(
+    name: String,
+    caller: Caller,
+    args: LeaseArgs,
+    cx: Arc<Mutex<Context>>,
+) -> Result<impl Reply, Rejection> {
+    let (ttl, target_mutex) = {
+        let mut context = cx.lock();
+        let Some(target) = context.config.targets.iter().find(|t| t.name == name) else {
+            reject!(
+                StatusCode::NOT_FOUND,
+                code = "target_not_found",
+                "Target not found: {}",
+                name
+            );
+        };
+        if !caller.allows(&target.name, &target.labels) {
+            reject!(
+                StatusCode::FORBIDDEN,
+                code = "target_not_allowed",
+                "Not allowed to use target: {}",
+                name
+            );
+        }
+        if let Some(lease) = context.leases.get(&name) {
+            reject!(
+                StatusCode::CONFLICT,
+                "Target {} is leased by {} until {}",
+                name,
+                lease.info.holder,
+                lease.info.expires_at
+            );
+        }
+        (args.ttl(&context.config), context.target_lock(&name))
+    };
+
+    let guard = target_mutex.lock_owned().await;
+    // Whoever holds the lease gets the probe to themselves.
+    let pool = cx.lock().workers.clone();
+    if let Err(e) = pool.run(&name, close_kept_session).await {
+        warn!("Failed to close the kept probe session of target {}: {}", name, e);
+    }
+
+    let info = {
+        let mut context = cx.lock();
+        // Someone else may have leased it while waiting for the target.
+        if let Some(lease) = context.leases.get(&name) {
+            reject!(
+                StatusCode::CONFLICT,
+                "Target {} is leased by {} until {}",
+                name,
+                lease.info.holder,
+                lease.info.expires_at
+            );
+        }
+        let lease = Lease {
+            info: api::Lease {
+                target: name.clone(),
+                holder: caller.id.clone(),
+                expires_at: expires_at(ttl),
+            },
+            expires: Instant::now() + ttl,
+        };
+        info!("Target {} leased by {} for {:?}", name, caller.id, ttl);
+        let info = lease.info.clone();
+        let lease = info.clone();
+        context
+            .storage
+            .write(format!("persist lease of target {}", name), move |storage| {
+                storage.save_lease(&lease)
+            });
+        context.audit(&caller.id, "lease", Some(&name), None);
+        context.leases.insert(name.clone(), lease);
+        info
+    };
+
+    spawn_lease_expiry(cx, name, guard);
+
+    Ok(with_status(
+        // NOTE (unwrap): error in this call is caused by programmer error and should never be caused by the user data
+        serde_json::to_vec_pretty(&info).unwrap(),
+        StatusCode::OK,
+    ))
+}
+
+/// Extend a lease to `ttl` from now.
+async fn handle_lease_renew(
+    name: String,
+    caller: Caller,
+    args: LeaseArgs,
+    cx: Arc<Mutex<Context>>,
+) -> Result<impl Reply, Rejection> {
+    let mut context = cx.lock();
+    let ttl = args.ttl(&context.config);
+    let lease = match context.leases.get_mut(&name) {
+        Some(lease) if lease.info.holder != caller.id => {
+            reject!(StatusCode::FORBIDDEN, "Target is leased by another caller: {}", name)
+        }
+        Some(lease) => lease,
+        None => reject!(StatusCode::NOT_FOUND, "Target is not leased: {}", name),
+    };
+    lease.expires = Instant::now() + ttl;
+    lease.info.expires_at = expires_at(ttl);
+    let info = lease.info.clone();
+    let lease = info.clone();
+    context
+        .storage
+        .write(format!("persist lease of target {}", name), move |storage| {
+            storage.save_lease(&lease)
+        });
+    if let Some(session) = context.gdb_sessions.get_mut(&name).filter(|s| s.leased) {
+        session.info.expires_at = session.ends_at.min(info.expires_at);
+    }
+
+    Ok(with_status(
+        // NOTE (unwrap): error in this call is caused by programmer error and should never be caused by the user data
+        serde_json::to_vec_pretty(&info).unwrap(),
+        StatusCode::OK,
+    ))
+}
+
+async fn handle_lease_release(name: String, caller: Caller, cx: Arc<Mutex<Context>>) -> Result<impl Reply, Rejection> {
+    let mut context = cx.lock();
+    let lease = match context.leases.get(&name) {
+        Some(lease) if lease.info.holder != caller.id => {
+            reject!(StatusCode::FORBIDDEN, "Target is leased by another caller: {}", name)
+        }
+        Some(lease) => lease.info.clone(),
+        None => reject!(StatusCode::NOT_FOUND, "Target is not leased: {}", name),
+    };
+    info!("Lease of target {} released by {}", name, caller.id);
+    context.remove_lease(&name);
+    context.audit(&caller.id, "lease_release", Some(&name), None);
+
+    Ok(with_status(
+        // NOTE (unwrap): error in this call is caused by programmer error and should never be caused by the user data
+        serde_json::to_vec_pretty(&lease).unwrap(),
+        StatusCode::OK,
+    ))
+}
+
+/// Hold the target for a lease until it's released or runs out.
+fn spawn_lease_expiry(cx: Arc<Mutex<Context>>, name: String, guard: tokio::sync::OwnedMutexGuard<()>) {
+    tokio::spawn(async move {
+        loop {
+            tokio::time::sleep(LEASE_POLL_INTERVAL).await;
+            let mut context = cx.lock();
+            match context.leases.get(&name) {
+                Some(lease) if lease.expires > Instant::now() => continue,
+                Some(_) => {
+                    info!("Lease of target {} expired", name);
+                    context.remove_lease(&name);
+                }
+                None => {}
+            }
+            break;
+        }
+        drop(guard);
+    });
+}
+
+/// Take a lease from storage back, after a restart, if it hasn't run out in the meantime.
+fn restore_lease(cx: Arc<Mutex<Context>>, info: api::Lease) {
+    let mut context = cx.lock();
+    let left = (info.expires_at - Utc::now()).to_std();
+    let known = context.config.targets.iter().any(|t| t.name == info.target);
+    let (Ok(left), true) = (left, known) else {
+        context.remove_lease(&info.target);
+        return;
+    };
+    // NOTE (unwrap): nothing else has used the target yet.
+    let guard = context.target_lock(&info.target).try_lock_owned().unwrap();
+    info!("Lease of target {} by {} restored", info.target, info.holder);
+    let name = info.target.clone();
+    context.leases.insert(
+        name.clone(),
+        Lease {
+            info,
+            expires: Instant::now() + left,
+        },
+    );
+    drop(context);
+    spawn_lease_expiry(cx, name, guard);
+}
+
+fn expires_at(ttl: Duration) -> chrono::DateTime<Utc> {
+    Utc::now() + chrono::Duration::seconds(ttl.as_secs() as i64)
+}
+
+async fn handle_group(id: String, caller: Caller, cx: Arc<Mutex<Context>>) -> Result<impl Reply, Rejection> {
+    let context = cx.lock();
+    let targets = context.run_targets();
+    let mut jobs: Vec<&api::Job> = context
+        .jobs
+        .values()
+        .map(|j| &j.info)
+        .filter(|j| j.run_group.as_deref() == Some(id.as_str()) && caller.may_see(j, &targets))
+        .collect();
+    if jobs.is_empty() {
+        reject!(StatusCode::NOT_FOUND, "Group not found: {}", id);
+    }
+    jobs.sort_by_key(|j| j.created_at);
+
+    let count = |status| jobs.iter().filter(|j| j.status == status).count();
+    let queued = count(api::JobStatus::Queued);
+    let running = count(api::JobStatus::Running);
+    let succeeded = count(api::JobStatus::Succeeded);
+    let failed = count(api::JobStatus::Failed);
+
+    let status = if queued + running != 0 {
+        if running + succeeded + failed != 0 {
+            api::JobStatus::Running
+        } else {
+            api::JobStatus::Queued
+        }
+    } else if failed != 0 {
+        api::JobStatus::Failed
+    } else {
+        api::JobStatus::Succeeded
+    };
+
+    let duration_secs = jobs
+        .iter()
+        .filter_map(|j| Some((j.finished_at? - j.started_at?).num_milliseconds() as f64 / 1000.0))
+        .sum();
+
+    let group = api::Group {
+        id: id.clone(),
+        status,
+        jobs: jobs.len(),
+        queued,
+        running,
+        succeeded,
+        failed,
+        duration_secs,
+        failures: jobs
+            .iter()
+            .filter(|j| j.status == api::JobStatus::Failed)
+            .map(|j| (*j).clone())
+            .collect(),
+    };
+
+    Ok(with_status(
+        // NOTE (unwrap): error in this call is caused by programmer error and should never be caused by the user data
+        serde_json::to_vec_pretty(&group).unwrap(),
+        StatusCode::OK,
+    ))
+}
+
+/// Put targets whose probe turns out to be the same physical one as an earlier target's in maintenance, since runs
+/// on both would fight over it. Config validation catches targets naming the same probe the same way. This catches
+/// the ones naming it differently, like by serial number and by USB port path. Only called at startup, since the
+/// config isn't reloaded, and workers' targets are on other hosts.
+fn check_probe_conflicts(targets: &mut [config::Target]) {
+    let mut probes: HashMap<(u16, u16, String), String> = HashMap::new();
+    for target in targets {
+        let probe = match &target.usb_path {
+            Some(path) => match probe::resolve_usb_path(path, Some(&target.probe)) {
+                // Sharing its serial number, it's opened by port path, and config validation catches targets with
+                // the same one.
+                Ok(probe) if probe.shared_serial => continue,
+                Ok(probe) => probe.selector,
+                // Not plugged in, or not a probe. Runs will fail on it anyway.
+                Err(_) => continue,
+            },
+            None => target.probe.clone(),
+        };
+        let Some(serial) = probe.serial_number else { continue };
+
+        let key = (probe.vendor_id, probe.product_id, serial);
+        if let Some(other) = probes.get(&key) {
+            error!(
+                "Target {} uses the same probe as target {}, putting it in maintenance",
+                target.name, other
+            );
+            target.maintenance = true;
+            continue;
+        }
+        probes.insert(key, target.name.clone());
+    }
+}
+
+/// A worker's target, as far as this server needs to know it to take runs for it.
+fn worker_target(target: &api::Target) -> config::Target {
+    config::Target {
+        usb_path: target.usb_path.clone(),
+        connect_under_reset: target.connect_under_reset,
+        speed: target.speed,
+        power_reset: target.power_reset,
+        cycle_delay_seconds: target.cycle_delay_seconds,
+        max_settle_time_millis: target.max_settle_time_millis,
+        labels: target.labels.clone(),
+        maintenance: target.maintenance,
+        ..config::Target::new(target.name.clone(), target.chip.clone(), target.probe.clone())
+    }
+}
+
+/// Whether the target's probe is plugged in, out of the probes found on this host.
+fn is_up(target: &config::Target, up_probes: &[DebugProbeInfo]) -> bool {
+    match &target.usb_path {
+        Some(path) => probe::resolve_usb_path(path, Some(&target.probe)).is_ok(),
+        None => up_probes.iter().any(|probe| {
+            probe.vendor_id == target.probe.vendor_id
+                && probe.product_id == target.probe.product_id
+                && target
+                    .probe
+                    .serial_number
+                    .as_ref()
+                    .map(|s| Some(s) == probe.serial_number.as_ref())
+                    .unwrap_or(true)
+        }),
+    }
+}
+
+/// Whether the target's probe is plugged in. Looking blocks on USB, so it's done off the runtime.
+async fn target_up(target: config::Target) -> bool {
+    // NOTE (unwrap): only fails if looking panicked, which is passed on.
+    tokio::task::spawn_blocking(move || is_up(&target, &Lister::new().list_all()))
+        .await
+        .unwrap()
+}
+
+/// The server's own targets, and whether their probes are plugged in. Looking blocks on USB, so it's done off the
+/// runtime.
+async fn targets(cx: Arc<Mutex<Context>>) -> api::TargetList {
+    let targets = cx.lock().config.targets.clone();
+    // NOTE (unwrap): only fails if looking panicked, which is passed on.
+    tokio::task::spawn_blocking(move || list_targets(targets))
+        .await
+        .unwrap()
+}
+
+fn list_targets(targets: Vec<config::Target>) -> api::TargetList {
+    let mut res = Vec::new();
+    let up_probes = Lister::new().list_all();
+
+    for target in targets {
+        let up = is_up(&target, &up_probes);
+        res.push(api::Target {
+            name: target.name,
+            chip: target.chip,
+            probe: target.probe,
+            usb_path: target.usb_path,
+            connect_under_reset: target.connect_under_reset,
+            speed: target.speed,
+            up,
+            maintenance: target.maintenance,
+            power_reset: target.power_reset,
+            cycle_delay_seconds: target.cycle_delay_seconds,
+            max_settle_time_millis: target.max_settle_time_millis,
+            labels: target.labels,
+        });
+    }
+
+    api::TargetList { targets: res }
+}
+
+async fn handle_list_targets(caller: Caller, cx: Arc<Mutex<Context>>) -> Result<impl Reply, Rejection> {
+    let mut targets = targets(cx.clone()).await;
+    targets.targets.extend(cx.lock().worker_targets());
+    targets.targets.retain(|t| caller.allows(&t.name, &t.labels));
+
+    Ok(with_status(
+        // NOTE (unwrap): error in this call is caused by programmer error and should never be caused by the user data
+        serde_json::to_vec_pretty(&targets).unwrap(),
+        StatusCode::OK,
+    ))
+}
+
+async fn handle_badge(name: String, cx: Arc<Mutex<Context>>) -> Result<warp::reply::Response, Rejection> {
+    let context = cx.lock();
+    if !context.config.targets.iter().any(|t| t.name == name) {
+        reject!(
+            StatusCode::NOT_FOUND,
+            code = "target_not_found",
+            "Target not found: {}",
+            name
+        );
+    }
+
+    let cutoff = Utc::now() - chrono::Duration::days(HISTORY_RETENTION_DAYS);
+    let runs: Vec<_> = context
+        .history
+        .iter()
+        .filter(|r| r.target == name && r.finished_at > cutoff)
+        .collect();
+    let passed = runs.iter().filter(|r| r.status == api::JobStatus::Succeeded).count();
+
+    let (message, color) = match runs.iter().max_by_key(|r| r.finished_at) {
+        None => ("no runs".to_string(), badge::GREY),
+        Some(last) => {
+            let rate = passed * 100 / runs.len();
+            match last.status {
+                api::JobStatus::Succeeded => (format!("passing, {}% 7d", rate), badge::GREEN),
+                _ => (format!("failing, {}% 7d", rate), badge::RED),
+            }
+        }
+    };
+    drop(context);
+
+    let svg = badge::render(&name, &message, color);
+    let reply = warp::reply::with_header(svg, "Content-Type", "image/svg+xml");
+    let reply = warp::reply::with_header(reply, "Cache-Control", "public, max-age=60");
+    Ok(reply.into_response())
+}
+
+#[derive(Serialize)]
+struct DrainStatus {
+    draining: bool,
+    active_jobs: usize,
+}
+
+/// Prometheus metrics.
+async fn handle_metrics(cx: Arc<Mutex<Context>>) -> Result<impl Reply, Rejection> {
+    let context = cx.lock();
+
+    let mut res = String::new();
+    writeln!(res, "# HELP teleprobe_phase_seconds Time spent in each phase of a run.").unwrap();
+    writeln!(res, "# TYPE teleprobe_phase_seconds summary").unwrap();
+    for ((target, phase), (sum, count)) in &context.phase_seconds {
+        let labels = format!("target=\"{}\",phase=\"{}\"", target, phase);
+        writeln!(res, "teleprobe_phase_seconds_sum{{{}}} {}", labels, sum).unwrap();
+        writeln!(res, "teleprobe_phase_seconds_count{{{}}} {}", labels, count).unwrap();
+    }
+
+    Ok(warp::reply::with_header(
+        res,
+        "Content-Type",
+        "text/plain; version=0.0.4",
+    ))
+}
+
+/// Rejects admins limited to some targets, for endpoints acting on the whole server.
+fn check_server_admin(caller: &Caller) -> Result<(), Rejection> {
+    if caller.targets.is_some() {
+        reject!(
+            StatusCode::FORBIDDEN,
+            code = "admin_required",
+            "Only admins of all targets may use this endpoint"
+        );
+    }
+    Ok(())
+}
+
+async fn handle_drain(caller: Caller, cx: Arc<Mutex<Context>>) -> Result<impl Reply, Rejection> {
+    check_server_admin(&caller)?;
+    let mut context = cx.lock();
+    if !context.draining {
+        info!("Drain requested, no longer accepting new runs");
+        context.draining = true;
+        context.audit(&caller.id, "drain", None, None);
+    }
+
+    let status = DrainStatus {
+        draining: context.draining,
+        active_jobs: context.active_jobs(),
+    };
+
+    Ok(with_status(
+        // NOTE (unwrap): error in this call is caused by programmer error and should never be caused by the user data
+        serde_json::to_vec_pretty(&status).unwrap(),
+        StatusCode::OK,
+    ))
+}
+
+/// The audit log of the last `HISTORY_RETENTION_DAYS`, oldest first.
+async fn handle_audit(caller: Caller, cx: Arc<Mutex<Context>>) -> Result<impl Reply, Rejection> {
+    check_server_admin(&caller)?;
+    let since = Utc::now() - chrono::Duration::days(HISTORY_RETENTION_DAYS);
+    let storage = cx.lock().storage.clone();
+    let events = match storage.call(move |storage| storage.load_audit(since)).await {
+        Ok(events) => events,
+        Err(e) => reject!(StatusCode::INTERNAL_SERVER_ERROR, "Failed to load audit log: {:?}", e),
+    };
+
+    Ok(with_status(
+        // NOTE (unwrap): error in this call is caused by programmer error and should never be caused by the user data
+        serde_json::to_vec_pretty(&events).unwrap(),
+        StatusCode::OK,
+    ))
+}
+
+/// Put a target in maintenance, or back in service. Jobs already queued on it still run.
+async fn handle_maintenance(
+    name: String,
+    caller: Caller,
+    maintenance: bool,
+    cx: Arc<Mutex<Context>>,
+) -> Result<impl Reply, Rejection> {
+    let mut context = cx.lock();
+    let Some(target) = context.config.targets.iter_mut().find(|t| t.name == name) else {
+        reject!(
+            StatusCode::NOT_FOUND,
+            code = "target_not_found",
+            "Target not found: {}",
+            name
+        );
+    };
+    if !caller.allows(&target.name, &target.labels) {
+        reject!(
+            StatusCode::FORBIDDEN,
+            code = "target_not_allowed",
+            "Not allowed to use target: {}",
+            name
+        );
+    }
+    if target.maintenance != maintenance {
+        match maintenance {
+            true => info!("Target {} put in maintenance", name),
+            false => info!("Target {} back in service", name),
+        }
+        target.maintenance = maintenance;
+        let action = match maintenance {
+            true => "maintenance_start",
+            false => "maintenance_end",
+        };
+        context.audit(&caller.id, action, Some(&name), None);
+    }
+    drop(context);
+
+    let mut targets = targets(cx).await;
+    targets.targets.retain(|t| t.name == name);
+    Ok(with_status(
+        // NOTE (unwrap): error in this call is caused by programmer error and should never be caused by the user data
+        serde_json::to_vec_pretty(&targets.targets[0]).unwrap(),
+        StatusCode::OK,
+    ))
+}
+
+/// Erase a target's chip, unlocking it first if needed, to recover it from firmware that broke it. Waits for the
+/// job running on it, if any, to finish.
+async fn handle_erase(name: String, caller: Caller, cx: Arc<Mutex<Context>>) -> Result<impl Reply, Rejection> {
+    let (target, target_mutex, pool) = {
+        let mut context = cx.lock();
+        let Some(target) = context.config.targets.iter().find(|t| t.name == name).cloned() else {
+            reject!(
+                StatusCode::NOT_FOUND,
+                code = "target_not_found",
+                "Target not found: {}",
+                name
+            );
+        };
+        if !caller.allows(&target.name, &target.labels) {
+            reject!(
+                StatusCode::FORBIDDEN,
+                code = "target_not_allowed",
+                "Not allowed to use target: {}",
+                name
+            );
+        }
+        context.audit(&caller.id, "erase", Some(&name), None);
+        (target, context.target_lock(&name), context.workers.clone())
+    };
+
+    let _target_guard = target_mutex.lock().await;
+    info!("Erasing target {}", name);
+    let probe = target_spec(&target);
+    let res = pool
+        .run(&name, move || {
+            close_kept_session();
+            probe::erase(&probe)
+        })
+        .await
+        .and_then(|res| res);
+    if let Err(e) = res {
+        reject!(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "Failed to erase target {}: {:?}",
+            name,
+            e
+        );
+    }
+    info!("Target {} erased", name);
+
+    Ok(with_status(
+        format!("Target {} erased", name).into_bytes(),
+        StatusCode::OK,
+    ))
+}
+
+#[derive(Deserialize)]
+struct PowerArgs {
+    action: probe::PowerAction,
+    /// Seconds the power stays off when cycling it. Defaults to the target's `cycle_delay_seconds`.
+    delay: Option<f64>,
+}
+
+/// Switch the USB power of a target's probe, to bring back a wedged board. Waits for the job running on it, if
+/// any, to finish.
+async fn handle_power(
+    name: String,
+    caller: Caller,
+    args: PowerArgs,
+    cx: Arc<Mutex<Context>>,
+) -> Result<impl Reply, Rejection> {
+    let (target, target_mutex, pool) = {
+        let mut context = cx.lock();
+        let Some(target) = context.config.targets.iter().find(|t| t.name == name).cloned() else {
+            reject!(
+                StatusCode::NOT_FOUND,
+                code = "target_not_found",
+                "Target not found: {}",
+                name
+            );
+        };
+        if !caller.allows(&target.name, &target.labels) {
+            reject!(
+                StatusCode::FORBIDDEN,
+                code = "target_not_allowed",
+                "Not allowed to use target: {}",
+                name
+            );
+        }
+        context.audit(&caller.id, "power", Some(&name), None);
+        (target, context.target_lock(&name), context.workers.clone())
+    };
+
+    let _target_guard = target_mutex.lock().await;
+    info!("Power {:?} on target {}", args.action, name);
+    let delay = args.delay.unwrap_or(target.cycle_delay_seconds);
+    let res = pool
+        .run(&name, move || {
+            close_kept_session();
+            probe::set_power(
+                Some(&target.probe),
+                target.usb_path.as_deref(),
+                &target.power_control,
+                args.action,
+                delay,
+            )
+        })
+        .await
+        .and_then(|res| res);
+    if let Err(e) = res {
+        reject!(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "Failed to switch power of target {}: {:?}",
+            name,
+            e
+        );
+    }
+
+    Ok(with_status(
+        format!("Target {} power {:?}", name, args.action).into_bytes(),
+        StatusCode::OK,
+    ))
+}
+
+/// Register a worker's targets, replacing the ones it registered before.
+async fn handle_worker_register(
+    name: String,
+    registration: api::WorkerRegistration,
+    cx: Arc<Mutex<Context>>,
+) -> Result<impl Reply, Rejection> {
+    let mut context = cx.lock();
+    for target in &registration.targets {
+        let taken = context.config.targets.iter().any(|t| t.name == target.name)
+            || context
+                .registered_workers
+                .iter()
+                .any(|(n, w)| *n != name && w.targets.iter().any(|t| t.name == target.name));
+        if taken {
+            reject!(
+                StatusCode::CONFLICT,
+                code = "target_name_taken",
+                "Another worker or the server has a target named {}",
+                target.name
+            );
+        }
+    }
+
+    let mut targets = registration.targets;
+    for target in &mut targets {
+        target.labels.insert("worker".to_string(), name.clone());
+    }
+    info!(
+        "Worker {} registered with {} targets, running teleprobe {}",
+        name,
+        targets.len(),
+        registration.version
+    );
+    // Jobs handed to the worker that it doesn't have anymore, like after it restarted, are handed out again.
+    if let Some(jobs) = &registration.jobs {
+        let lost: Vec<String> = context
+            .jobs
+            .values()
+            .filter(|j| j.info.status == api::JobStatus::Running && j.info.worker.as_deref() == Some(name.as_str()))
+            .filter(|j| !jobs.contains(&j.info.id))
+            .map(|j| j.info.id.clone())
+            .collect();
+        for id in lost {
+            warn!("Worker {} no longer has job {}, handing it out again", name, id);
+            context.update_job(&id, |job| {
+                job.info.status = api::JobStatus::Queued;
+                job.info.started_at = None;
+                job.logs.clear();
+                job.relayed.clear();
+            });
+        }
+    }
+
+    // Jobs waiting for the worker stay queued when it registers again, and so do the ones that waited for it
+    // before this server restarted.
+    let mut pending = context
+        .registered_workers
+        .remove(&name)
+        .map(|w| w.pending)
+        .unwrap_or_default();
+    let mut waiting: Vec<_> = context
+        .jobs
+        .values()
+        .filter(|j| j.info.status == api::JobStatus::Queued && j.info.worker.as_deref() == Some(name.as_str()))
+        .filter(|j| !pending.contains(&j.info.id))
+        .map(|j| (j.info.created_at, j.info.id.clone()))
+        .collect();
+    waiting.sort();
+    pending.extend(waiting.into_iter().map(|(_, id)| id));
+    context.registered_workers.insert(
+        name.clone(),
+        RegisteredWorker {
+            version: registration.version,
+            targets,
+            last_heartbeat: Utc::now(),
+            alive: true,
+            pending,
+        },
+    );
+    let worker = context.registered_workers[&name].info(&name);
+
+    Ok(with_status(
+        // NOTE (unwrap): error in this call is caused by programmer error and should never be caused by the user data
+        serde_json::to_vec_pretty(&worker).unwrap(),
+        StatusCode::OK,
+    ))
+}
+
+async fn handle_worker_heartbeat(
+    name: String,
+    heartbeat: api::Heartbeat,
+    cx: Arc<Mutex<Context>>,
+) -> Result<impl Reply, Rejection> {
+    let mut context = cx.lock();
+    let Some(worker) = context.registered_workers.get_mut(&name) else {
+        reject!(
+            StatusCode::NOT_FOUND,
+            code = "worker_not_registered",
+            "Worker not registered: {}",
+            name
+        );
+    };
+    if !worker.alive {
+        info!("Worker {} is back", name);
+    }
+    worker.last_heartbeat = Utc::now();
+    worker.alive = true;
+    for target in &mut worker.targets {
+        target.up = heartbeat.up.contains(&target.name);
+    }
+
+    Ok(with_status(Vec::new(), StatusCode::NO_CONTENT))
+}
+
+async fn handle_list_workers(cx: Arc<Mutex<Context>>) -> Result<impl Reply, Rejection> {
+    let context = cx.lock();
+    let mut workers: Vec<_> = context
+        .registered_workers
+        .iter()
+        .map(|(name, worker)| worker.info(name))
+        .collect();
+    workers.sort_by(|a, b| a.name.cmp(&b.name));
+
+    Ok(with_status(
+        // NOTE (unwrap): error in this call is caused by programmer error and should never be caused by the user data
+        serde_json::to_vec_pretty(&api::WorkerList { workers }).unwrap(),
+        StatusCode::OK,
+    ))
+}
+
+/// Mark workers without recent heartbeats as dead, so their targets are down, and forget the ones that stay silent
+/// for long.
+fn spawn_worker_sweeper(cx: Arc<Mutex<Context>>, config: config::WorkersConfig) {
+    let started = Utc::now();
+    tokio::spawn(async move {
+        loop {
+            tokio::time::sleep(WORKER_SWEEP_INTERVAL).await;
+            let now = Utc::now();
+            let timeout = chrono::Duration::seconds(config.heartbeat_timeout_secs as i64);
+            let deregister_after = chrono::Duration::seconds(config.deregister_after_secs as i64);
+            let mut context = cx.lock();
+            let mut gone = Vec::new();
+            context.registered_workers.retain(|name, worker| {
+                let silent = now - worker.last_heartbeat;
+                if silent > deregister_after {
+                    warn!(
+                        "Worker {} deregistered, no heartbeat since {}",
+                        name, worker.last_heartbeat
+                    );
+                    gone.push(name.clone());
+                    return false;
+                }
+                if silent > timeout && worker.alive {
+                    warn!("Worker {} went silent, its targets are down", name);
+                    worker.alive = false;
+                }
+                true
+            });
+            // Workers with jobs from before this server restarted get as long to register again.
+            let gone_since_start = now - started > deregister_after;
+            let registered = &context.registered_workers;
+            let orphans: Vec<String> = context
+                .jobs
+                .values()
+                .filter(|j| !j.info.status.is_finished())
+                .filter(|j| {
+                    j.info
+                        .worker
+                        .as_ref()
+                        .is_some_and(|w| gone.contains(w) || (gone_since_start && !registered.contains_key(w)))
+                })
+                .map(|j| j.info.id.clone())
+                .collect();
+            for id in orphans {
+                context.fail_job(
+                    &id,
+                    api::RunError::ProbeNotFound,
+                    "Worker went away before the job finished",
+                );
+            }
+        }
+    });
+}
+
+/// Delete old run log files, now and then periodically.
+fn spawn_run_log_pruner(cx: Arc<Mutex<Context>>) {
+    let Some(run_logs) = cx.lock().run_logs.clone() else {
+        return;
+    };
+    tokio::spawn(async move {
+        loop {
+            let pruning = run_logs.clone();
+            match tokio::task::spawn_blocking(move || pruning.prune()).await {
+                Ok(Err(e)) => warn!("Failed to prune run logs: {}", e),
+                Err(e) => warn!("Failed to prune run logs: {}", e),
+                Ok(Ok(())) => {}
+            }
+            tokio::time::sleep(RUN_LOG_PRUNE_INTERVAL).await;
+        }
+    });
+}
+
+/// Forget the audit log and run history older than `HISTORY_RETENTION_DAYS` from the storage, now and then
+/// periodically.
+fn spawn_pruner(cx: Arc<Mutex<Context>>) {
+    let storage = cx.lock().storage.clone();
+    tokio::spawn(async move {
+        loop {
+            let cutoff = Utc::now() - chrono::Duration::days(HISTORY_RETENTION_DAYS);
+            storage.write("prune audit log and run history", move |storage| {
+                storage.prune_audit(cutoff)?;
+                storage.prune_history(cutoff)
+            });
+            tokio::time::sleep(HISTORY_PRUNE_INTERVAL).await;
+        }
+    });
+}
+
+/// Register this server's targets with the API server it's a worker of, and keep sending heartbeats. Registers
+/// again whenever the API server doesn't know this worker, like after it restarted.
+fn spawn_upstream(cx: Arc<Mutex<Context>>, config: config::UpstreamConfig) -> anyhow::Result<()> {
+    let upstream = Arc::new(upstream::Upstream::new(&config)?);
+    let interval = Duration::from_secs(config.heartbeat_interval_secs);
+    tokio::spawn(poll_upstream(cx.clone(), upstream.clone(), config.clone(), interval));
+    tokio::spawn(deliver_upstream_results(cx.clone(), upstream.clone(), interval));
+    tokio::spawn(async move {
+        let mut registered = false;
+        loop {
+            let targets = targets(cx.clone()).await.targets;
+            let result = match registered {
+                false => {
+                    let targets = targets
+                        .into_iter()
+                        .map(|mut t| {
+                            t.labels.extend(config.labels.clone());
+                            t
+                        })
+                        .collect();
+                    let jobs = cx.lock().upstream_jobs();
+                    upstream.register(targets, jobs).await.map(|()| true)
+                }
+                true => {
+                    let up = targets.into_iter().filter(|t| t.up).map(|t| t.name).collect();
+                    upstream.heartbeat(up).await
+                }
+            };
+            cx.lock().upstream_reachable = result.is_ok();
+            match result {
+                Ok(true) if !registered => {
+                    info!("Registered as worker {} with {}", config.name, upstream.url());
+                    registered = true;
+                }
+                Ok(true) => {}
+                Ok(false) => {
+                    warn!(
+                        "API server {} doesn't know this worker, registering again",
+                        upstream.url()
+                    );
+                    registered = false;
+                    continue;
+                }
+                Err(e) => warn!("Failed to reach API server {}: {:#}", upstream.url(), e),
+            }
+            tokio::time::sleep(interval).await;
+        }
+    });
+    Ok(())
+}
+
+/// Fetch jobs from the API server this server is a worker of, and run them. Stops taking jobs while
+/// `max_queued_jobs` of them are unfinished.
+async fn poll_upstream(
+    cx: Arc<Mutex<Context>>,
+    upstream: Arc<upstream::Upstream>,
+    config: config::UpstreamConfig,
+    retry_interval: Duration,
+) {
+    loop {
+        if cx.lock().draining {
+            tokio::time::sleep(DRAIN_POLL_INTERVAL).await;
+            continue;
+        }
+        if cx.lock().active_jobs() >= config.max_queued_jobs {
+            tokio::time::sleep(WORKER_POLL_INTERVAL).await;
+            continue;
+        }
+        match upstream.next_job(upstream::POLL_WAIT).await {
+            Ok(Some(job)) => {
+                tokio::spawn(crate::logutil::scope_job(
+                    job.job.id.clone(),
+                    run_upstream_job(cx.clone(), upstream.clone(), job, config.result_buffer),
+                ));
+            }
+            Ok(None) => {}
+            Err(e) => {
+                warn!("Failed to poll API server {} for jobs: {:#}", upstream.url(), e);
+                tokio::time::sleep(retry_interval).await;
+            }
+        }
+    }
+}
+
+/// Run a job the API server handed out, and queue its result to be handed back.
+async fn run_upstream_job(
+    cx: Arc<Mutex<Context>>,
+    upstream: Arc<upstream::Upstream>,
+    job: api::WorkerJob,
+    result_buffer: usize,
+) {
+    let engine = &base64::engine::general_purpose::STANDARD;
+    let images = engine.decode(&job.image).and_then(|image| {
+        let preload = job
+            .preload
+            .iter()
+            .map(|p| engine.decode(p))
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok((image, preload))
+    });
+    let log_filter = job.log_filter.as_deref().map(str::parse::<run::LogFilter>).transpose();
+
+    let mut info = job.job;
+    let id = info.id.clone();
+    info.status = api::JobStatus::Queued;
+    info.started_at = None;
+    // It's on a target of this server here.
+    info.worker = None;
+    let (format, base_address, preload_info) = (info.format, info.base_address, info.preload.clone());
+    let timeout = Duration::from_secs(info.timeout);
+    let target = {
+        let mut context = cx.lock();
+        context.prune_jobs();
+        let target = context.config.targets.iter().find(|t| t.name == info.target).cloned();
+        let mut local = Job::stored(info, Vec::new());
+        local.output = OutputArgs {
+            log_filter: log_filter.as_ref().ok().cloned().flatten(),
+            host_timestamps: job.host_timestamps,
+            raw_defmt: job.raw_defmt,
+        };
+        context.jobs.insert(id.clone(), local);
+        target
+    };
+
+    match (target, images, log_filter) {
+        (Some(target), Ok((image, preload)), Ok(_)) => {
+            info!("Job {} from the API server queued on target {}", id, target.name);
+            let image = run::Image {
+                data: Bytes::from(image),
+                format,
+                base_address,
+            };
+            let preload = preload_info
+                .iter()
+                .zip(preload)
+                .map(|(info, data)| run::Image {
+                    data: Bytes::from(data),
+                    format: info.format,
+                    base_address: info.base_address,
+                })
+                .collect();
+            let relay = tokio::spawn(relay_logs(cx.clone(), upstream.clone(), id.clone()));
+            run_job(cx.clone(), id.clone(), target, image, preload, timeout).await;
+            relay.abort();
+        }
+        (None, _, _) => cx
+            .lock()
+            .fail_job(&id, api::RunError::ProbeNotFound, "Target not found on the worker"),
+        (_, Err(e), _) => cx.lock().fail_job(
+            &id,
+            api::RunError::TestFailed,
+            &format!("Invalid image from the API server: {}", e),
+        ),
+        (_, _, Err(e)) => cx
+            .lock()
+            .fail_job(&id, api::RunError::TestFailed, &format!("Invalid log filter: {:#}", e)),
+    }
+
+    let mut context = cx.lock();
+    let Some(job) = context.jobs.get(&id) else {
+        return;
+    };
+    let result = UpstreamResult {
+        info: job.info.clone(),
+        logs: job.logs.clone(),
+        report: job.report.clone(),
+    };
+    if context.upstream_results.len() >= result_buffer {
+        if let Some(dropped) = context.upstream_results.pop_front() {
+            error!(
+                "Result buffer full, dropped the result of job {} for API server {}",
+                dropped.info.id,
+                upstream.url()
+            );
+        }
+    }
+    context.upstream_results.push_back(result);
+}
+
+/// Hand back the results of finished jobs to the API server, in order. While it can't be reached, they're kept and
+/// retried.
+async fn deliver_upstream_results(
+    cx: Arc<Mutex<Context>>,
+    upstream: Arc<upstream::Upstream>,
+    retry_interval: Duration,
+) {
+    loop {
+        let next = cx.lock().upstream_results.front().cloned();
+        let Some(result) = next else {
+            tokio::time::sleep(WORKER_POLL_INTERVAL).await;
+            continue;
+        };
+        let id = result.info.id.clone();
+        match deliver_upstream_result(&upstream, result).await {
+            Ok(delivered) => {
+                if !delivered {
+                    warn!(
+                        "API server {} no longer knows job {}, dropped its result",
+                        upstream.url(),
+                        id
+                    );
+                }
+                let mut context = cx.lock();
+                if context.upstream_results.front().is_some_and(|r| r.info.id == id) {
+                    context.upstream_results.pop_front();
+                }
+            }
+            Err(e) => {
+                warn!(
+                    "Failed to hand back job {} to API server {}, retrying: {:#}",
+                    id,
+                    upstream.url(),
+                    e
+                );
+                tokio::time::sleep(retry_interval).await;
+            }
+        }
+    }
+}
+
+/// Relay the report and artifacts of a finished job, then its result. All of it can be sent again if any of it
+/// fails.
+async fn deliver_upstream_result(upstream: &upstream::Upstream, result: UpstreamResult) -> anyhow::Result<bool> {
+    let id = &result.info.id;
+    if let Some(report) = result.report {
+        let artifacts = [
+            // NOTE (unwrap): error in this call is caused by programmer error and should never be caused by the user data
+            (api::relay::REPORT, Some(serde_json::to_vec(&report).unwrap())),
+            (api::relay::CORE_DUMP, report.core_dump),
+            (api::relay::COVERAGE, report.coverage),
+            (api::relay::DEFMT, report.defmt_raw),
+        ];
+        for (kind, data) in artifacts {
+            let Some(data) = data else { continue };
+            if !upstream.relay(id, kind, 0, &data).await? {
+                return Ok(false);
+            }
+        }
+    }
+    upstream.submit_result(&result.info, &result.logs).await
+}
+
+/// Relay the logs of a job from the API server while it runs, so callers following it there see them as they come.
+async fn relay_logs(cx: Arc<Mutex<Context>>, upstream: Arc<upstream::Upstream>, id: String) {
+    // What the API server has. The logs are rewritten at the end of each attempt, so what changed is relayed
+    // from where they start to differ.
+    let mut relayed = Vec::new();
+    loop {
+        tokio::time::sleep(LOG_RELAY_INTERVAL).await;
+        let Some(logs) = cx.lock().jobs.get(&id).map(|job| job.logs.clone()) else {
+            return;
+        };
+        let offset = relayed.iter().zip(&logs).take_while(|(a, b)| a == b).count();
+        if offset == logs.len() && offset == relayed.len() {
+            continue;
+        }
+        match upstream.relay(&id, api::relay::LOGS, offset, &logs[offset..]).await {
+            Ok(true) => relayed = logs,
+            Ok(false) => return,
+            Err(e) => warn!(
+                "Failed to relay logs of job {} to API server {}: {:#}",
+                id,
+                upstream.url(),
+                e
+            ),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct WorkerPollArgs {
+    /// Longest to wait for a job, in seconds.
+    #[serde(default)]
+    wait: u64,
+}
+
+/// Hand the next job waiting for a worker to it, waiting up to `wait` seconds for one.
+async fn handle_worker_poll(
+    name: String,
+    args: WorkerPollArgs,
+    cx: Arc<Mutex<Context>>,
+) -> Result<WithStatus<Vec<u8>>, Rejection> {
+    let deadline = Instant::now() + Duration::from_secs(args.wait.min(MAX_WORKER_POLL_SECS));
+    loop {
+        loop {
+            let (id, images, info, storage) = {
+                let mut context = cx.lock();
+                let Some(worker) = context.registered_workers.get_mut(&name) else {
+                    reject!(
+                        StatusCode::NOT_FOUND,
+                        code = "worker_not_registered",
+                        "Worker not registered: {}",
+                        name
+                    );
+                };
+                let Some(id) = worker.pending.pop_front() else { break };
+                // Cancelled while it waited.
+                match context.jobs.get(&id).filter(|job| !job.info.status.is_finished()) {
+                    Some(job) => (id, job.images.clone(), job.info.clone(), context.storage.clone()),
+                    None => continue,
+                }
+            };
+
+            // Jobs from before a restart only have their images in the storage.
+            let (image, preload) = match images {
+                Some(images) => images,
+                None => match load_images(&storage, &info).await {
+                    Ok(Some(images)) => images,
+                    Ok(None) => {
+                        cx.lock()
+                            .fail_job(&id, api::RunError::Cancelled, "ELF missing from job store");
+                        continue;
+                    }
+                    Err(e) => {
+                        cx.lock().fail_job(
+                            &id,
+                            api::RunError::Cancelled,
+                            &format!("Failed to load ELF from job store: {:?}", e),
+                        );
+                        continue;
+                    }
+                },
+            };
+            if let Some(job) = cx.lock().hand_out(&id, &image, &preload) {
+                info!("Job {} handed to worker {}", id, name);
+                return Ok(with_status(
+                    // NOTE (unwrap): error in this call is caused by programmer error and should never be caused by the user data
+                    serde_json::to_vec_pretty(&job).unwrap(),
+                    StatusCode::OK,
+                ));
+            }
+        }
+        if Instant::now() >= deadline {
+            return Ok(with_status(Vec::new(), StatusCode::NO_CONTENT));
+        }
+        tokio::time::sleep(WORKER_POLL_INTERVAL).await;
+    }
+}
+
+/// Take part of the logs or artifacts of a job a worker runs, replacing what it relayed from `offset` on.
+async fn handle_worker_relay(
+    name: String,
+    id: String,
+    kind: String,
+    args: ChunkArgs,
+    chunk: Bytes,
+    cx: Arc<Mutex<Context>>,
+) -> Result<impl Reply, Rejection> {
+    if !api::relay::ALL.contains(&kind.as_str()) {
+        reject!("Unknown kind of relayed data: {}", kind);
+    }
+    let mut context = cx.lock();
+    let job = match context.jobs.get_mut(&id) {
+        Some(job) if job.info.worker.as_deref() == Some(name.as_str()) => job,
+        _ => reject!(
+            StatusCode::NOT_FOUND,
+            code = "job_not_found",
+            "No job {} on worker {}",
+            id,
+            name
+        ),
+    };
+    // The result has the final logs, and cancelled jobs keep theirs.
+    if job.info.status.is_finished() {
+        return Ok(with_status(Vec::new(), StatusCode::NO_CONTENT));
+    }
+
+    let is_logs = kind == api::relay::LOGS;
+    let data = match is_logs {
+        true => &mut job.logs,
+        false => job.relayed.entry(kind).or_default(),
+    };
+    if args.offset > data.len() {
+        reject!(
+            StatusCode::CONFLICT,
+            "Chunk at {} past the {} bytes relayed so far",
+            args.offset,
+            data.len()
+        );
+    }
+    data.truncate(args.offset);
+    data.extend_from_slice(&chunk);
+    if let Some(run_logs) = context.run_logs.as_ref().filter(|_| is_logs) {
+        run_logs.write_at(&id, args.offset, &chunk);
+    }
+    Ok(with_status(Vec::new(), StatusCode::NO_CONTENT))
+}
+
+/// Take the result of a job a worker ran.
+async fn handle_worker_result(
+    name: String,
+    id: String,
+    result: api::WorkerJobResult,
+    cx: Arc<Mutex<Context>>,
+) -> Result<impl Reply, Rejection> {
+    let logs = match base64::engine::general_purpose::STANDARD.decode(&result.logs) {
+        Ok(logs) => logs,
+        Err(e) => reject!("Invalid logs: {}", e),
+    };
+    let info = result.job;
+    if !info.status.is_finished() {
+        reject!("Job isn't finished: {}", id);
+    }
+
+    let mut context = cx.lock();
+    let target = match context.jobs.get(&id) {
+        Some(job) if job.info.worker.as_deref() == Some(name.as_str()) => job.info.target.clone(),
+        _ => reject!(
+            StatusCode::NOT_FOUND,
+            code = "job_not_found",
+            "No job {} on worker {}",
+            id,
+            name
+        ),
+    };
+    // Cancelled while it ran.
+    if context.jobs[&id].info.status.is_finished() {
+        return Ok(with_status(Vec::new(), StatusCode::NO_CONTENT));
+    }
+
+    info!("Job {} finished on worker {}: {:?}", id, name, info.status);
+    let report = {
+        // NOTE (unwrap): the job was looked up above.
+        let relayed = &mut context.jobs.get_mut(&id).unwrap().relayed;
+        let report = relayed
+            .remove(api::relay::REPORT)
+            .map(|r| serde_json::from_slice::<RunReport>(&r));
+        match report {
+            Some(Ok(mut report)) => {
+                report.core_dump = relayed.remove(api::relay::CORE_DUMP);
+                report.coverage = relayed.remove(api::relay::COVERAGE);
+                report.defmt_raw = relayed.remove(api::relay::DEFMT);
+                Some(report)
+            }
+            Some(Err(e)) => {
+                warn!("Invalid report of job {} from worker {}: {}", id, name, e);
+                None
+            }
+            None => None,
+        }
+    };
+    context.record_timings(&target, &info.timings);
+    if let Some(dump) = report.as_ref().and_then(|r| r.core_dump.as_ref()) {
+        context.save_core_dump(&id, dump);
+    }
+    context.update_job(&id, |job| {
+        job.relayed.clear();
+        job.report = report;
+        job.info.status = info.status;
+        job.info.failure = info.failure;
+        job.info.timings = info.timings.clone();
+        job.info.backtrace = info.backtrace;
+        job.info.panic = info.panic;
+        job.info.started_at = info.started_at.or(job.info.started_at);
+        job.info.finished_at = Some(info.finished_at.unwrap_or_else(Utc::now));
+        job.logs = logs;
+    });
+    context.record_run(&id);
+    context.complete_check(&id);
+
+    Ok(with_status(Vec::new(), StatusCode::NO_CONTENT))
+}
+
+#[cfg(unix)]
+fn spawn_sigterm_handler(cx: Arc<Mutex<Context>>) -> anyhow::Result<()> {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut sigterm = signal(SignalKind::terminate())?;
+    tokio::spawn(async move {
+        sigterm.recv().await;
+        info!("Received SIGTERM, no longer accepting new runs");
+        cx.lock().draining = true;
+    });
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn spawn_sigterm_handler(_cx: Arc<Mutex<Context>>) -> anyhow::Result<()> {
+    Ok(())
+}
+
+/// Resolves once a drain was requested and all jobs have finished.
+async fn drained(cx: Arc<Mutex<Context>>) {
+    loop {
+        {
+            let context = cx.lock();
+            if context.draining && context.active_jobs() == 0 {
+                break;
+            }
+        }
+        tokio::time::sleep(DRAIN_POLL_INTERVAL).await;
+    }
+    info!("All jobs finished, shutting down");
+}
+
+const DASHBOARD_HTML: &str = include_str!("../static/dashboard.html");
+const DASHBOARD_JS: &str = include_str!("../static/dashboard.js");
+
+/// How many of the latest runs per target are shown on the dashboard.
+const DASHBOARD_HISTORY: usize = 10;
+
+async fn handle_home() -> Result<impl Reply, Rejection> {
+    Ok(html(DASHBOARD_HTML))
+}
+
+async fn handle_dashboard_js() -> Result<impl Reply, Rejection> {
+    Ok(warp::reply::with_header(
+        DASHBOARD_JS,
+        "Content-Type",
+        "application/javascript",
+    ))
+}
+
+async fn handle_version() -> Result<impl Reply, Rejection> {
+    let version = api::Version {
+        version: crate::meta::LONG_VERSION.to_string(),
+        api_version: api::API_VERSION,
+        features: api::feature::ALL.iter().map(|f| f.to_string()).collect(),
+    };
+    Ok(warp::reply::json(&version))
+}
+
+/// Liveness: the server is up and answering requests.
+async fn handle_healthz() -> Result<impl Reply, Rejection> {
+    Ok(with_status("ok\n", StatusCode::OK))
+}
+
+/// Readiness: the server can take runs. 503 with the reasons when it can't.
+async fn handle_readyz(cx: Arc<Mutex<Context>>) -> Result<impl Reply, Rejection> {
+    let targets = targets(cx.clone()).await.targets;
+    let mut problems = Vec::new();
+    {
+        let context = cx.lock();
+        if context.draining {
+            problems.push("draining".to_string());
+        }
+        let oidc = context.config.auths.iter().any(|a| matches!(a, Auth::Oidc(_)));
+        if oidc && !context.oidc_client.as_ref().is_some_and(|c| c.has_keys()) {
+            problems.push("no OIDC keys fetched from the issuer".to_string());
+        }
+        // Servers without targets of their own only hand out runs to workers.
+        if !targets.is_empty() && !targets.iter().any(|t| t.up) {
+            problems.push("none of the targets' probes are plugged in".to_string());
+        }
+        if context.config.upstream.is_some() && !context.upstream_reachable {
+            problems.push("API server not reachable".to_string());
+        }
+    }
+
+    let readiness = api::Readiness {
+        ready: problems.is_empty(),
+        problems,
+    };
+    let status = match readiness.ready {
+        true => StatusCode::OK,
+        false => StatusCode::SERVICE_UNAVAILABLE,
+    };
+    Ok(with_status(
+        // NOTE (unwrap): error in this call is caused by programmer error and should never be caused by the user data
+        serde_json::to_vec_pretty(&readiness).unwrap(),
+        status,
+    ))
+}
+
+async fn handle_status(cx: Arc<Mutex<Context>>) -> Result<impl Reply, Rejection> {
+    let mut targets = targets(cx.clone()).await;
+    let context = cx.lock();
+    targets.targets.extend(context.worker_targets());
+
+    let targets = targets
+        .targets
+        .into_iter()
+        .map(|target| {
+            let jobs = context.jobs.values().filter(|j| j.info.target == target.name);
+            let running = jobs
+                .clone()
+                .find(|j| j.info.status == api::JobStatus::Running)
+                .map(|j| api::RunningJob {
+                    id: j.info.id.clone(),
+                    status: j.info.status,
+                    started_at: j.info.started_at,
+                });
+            let queued = jobs.filter(|j| j.info.status == api::JobStatus::Queued).count();
+            let mut history: Vec<_> = context
+                .history
+                .iter()
+                .filter(|r| r.target == target.name)
+                .cloned()
+                .collect();
+            history.sort_by(|a, b| b.finished_at.cmp(&a.finished_at));
+            history.truncate(DASHBOARD_HISTORY);
+
+            api::TargetStatus {
+                name: target.name,
+                chip: target.chip,
+                up: target.up,
+                maintenance: target.maintenance,
+                running,
+                queued,
+                history,
+            }
+        })
+        .collect();
+
+    let status = api::Status {
+        version: crate::meta::LONG_VERSION.to_string(),
+        draining: context.draining,
+        targets,
+    };
+    drop(context);
+
+    let reply = with_status(
+        // NOTE (unwrap): error in this call is caused by programmer error and should never be caused by the user data
+        serde_json::to_vec_pretty(&status).unwrap(),
+        StatusCode::OK,
+    );
+    Ok(warp::reply::with_header(reply, "Content-Type", "application/json"))
+}
+
+struct Context {
+    oidc_client: Option<oidc::Client>,
+    config: Config,
+    target_locks: HashMap<String, Arc<AsyncMutex<()>>>,
+    workers: Arc<worker::Pool>,
+    /// Recently uploaded images, for runs that only send a hash.
+    blobs: Arc<blobs::Store>,
+    jobs: HashMap<String, Job>,
+    history: Vec<api::RunRecord>,
+    storage: storage::Handle,
+    /// When set, new runs are rejected and the server exits once all jobs are done.
+    draining: bool,
+    /// Total time and number of runs per target and run phase, since the server started.
+    phase_seconds: BTreeMap<(String, &'static str), (f64, u64)>,
+    /// Rate limiting state per caller ID.
+    rate_limits: HashMap<String, CallerLimits>,
+    /// Running gdb sessions, by target name.
+    gdb_sessions: HashMap<String, GdbSession>,
+    /// Leased targets, by name.
+    leases: HashMap<String, Lease>,
+    github: Option<Arc<github::Checks>>,
+    /// Jobs waiting for each target, by target name.
+    queues: HashMap<String, sched::Queue>,
+    /// Recent passing runs, by `result_key`.
+    results: HashMap<String, CachedResult>,
+    /// Workers that registered their targets with this server, by name.
+    registered_workers: HashMap<String, RegisteredWorker>,
+    /// Results of jobs from the API server this server is a worker of, yet to be handed back, oldest first.
+    upstream_results: VecDeque<UpstreamResult>,
+    /// Whether the last registration or heartbeat got through to the API server this server is a worker of.
+    upstream_reachable: bool,
+    /// Files the logs of runs are written to, if configured.
+    run_logs: Option<runlog::RunLogs>,
+}
+
+/// A finished job from the API server, to hand back to it.
+#[derive(Clone)]
+struct UpstreamResult {
+    info: api::Job,
+    logs: Vec<u8>,
+    report: Option<RunReport>,
+}
+
+struct RegisteredWorker {
+    version: String,
+    /// With the `worker` label, and whether they're up as of the last heartbeat.
+    targets: Vec<api::Target>,
+    last_heartbeat: chrono::DateTime<Utc>,
+    /// Whether the last heartbeat came within `heartbeat_timeout_secs`.
+    alive: bool,
+    /// IDs of the jobs waiting to be handed to the worker, oldest first.
+    pending: VecDeque<String>,
+}
+
+impl RegisteredWorker {
+    fn info(&self, name: &str) -> api::Worker {
+        api::Worker {
+            name: name.to_string(),
+            version: self.version.clone(),
+            alive: self.alive,
+            last_heartbeat: self.last_heartbeat,
+            targets: self.targets.iter().map(|t| t.name.clone()).collect(),
+        }
+    }
+}
+
+#[derive(Clone)]
+struct CachedResult {
+    job_id: String,
+    logs: Vec<u8>,
+    at: Instant,
+}
+
+#[derive(Clone)]
+struct GdbSession {
+    info: api::GdbSession,
+    /// ID of the caller that started it.
+    caller: String,
+    stop: Arc<AtomicBool>,
+    /// Whether it was started under a lease, and ends with it.
+    leased: bool,
+    /// When it ends at the latest, leased or not.
+    ends_at: chrono::DateTime<Utc>,
+}
+
+#[derive(Clone)]
+struct Lease {
+    info: api::Lease,
+    /// When the lease runs out, unless it's renewed.
+    expires: Instant,
+}
+
+#[derive(Clone)]
+struct CallerLimits {
+    requests: Option<TokenBucket>,
+    /// In minutes.
+    run_time: Option<TokenBucket>,
+}
+
+impl Context {
+    /// The lock runs and gdb sessions hold while they use a target.
+    fn target_lock(&mut self, name: &str) -> Arc<AsyncMutex<()>> {
+        self.target_locks
+            .entry(name.to_string())
+            .or_insert_with(|| Arc::new(AsyncMutex::new(())))
+            .clone()
+    }
+
+    /// Pick the least busy target matching the selector, out of those in service that the caller may use and
+    /// that aren't excluded. Leased targets are only picked when all matching targets are.
+    fn pick_target(&self, selector: &Selector, caller: &Caller, exclude: &[&str]) -> Option<config::Target> {
+        self.run_targets()
+            .into_iter()
+            .filter(|t| !t.maintenance && selector.matches(&t.labels) && caller.allows(&t.name, &t.labels))
+            .filter(|t| !exclude.contains(&t.name.as_str()))
+            .min_by_key(|t| {
+                let busy = self
+                    .jobs
+                    .values()
+                    .filter(|j| j.info.target == t.name && !j.info.status.is_finished())
+                    .count();
+                (self.leases.contains_key(&t.name), busy)
+            })
+    }
+
+    /// Number of jobs that are queued or running.
+    fn active_jobs(&self) -> usize {
+        self.jobs.values().filter(|j| !j.info.status.is_finished()).count()
+    }
+
+    /// Jobs taken from the API server this server is a worker of that it still has: the unfinished ones, and the
+    /// ones whose result wasn't handed back yet.
+    fn upstream_jobs(&self) -> Vec<String> {
+        let unfinished = self.jobs.values().filter(|j| !j.info.status.is_finished());
+        let results = self.upstream_results.iter().map(|r| r.info.id.clone());
+        unfinished.map(|j| j.info.id.clone()).chain(results).collect()
+    }
+
+    /// Forget finished jobs older than `JOB_RETENTION_SECS`.
+    fn prune_jobs(&mut self) {
+        let cutoff = Utc::now() - chrono::Duration::seconds(JOB_RETENTION_SECS);
+        let storage = &self.storage;
+        self.jobs.retain(|id, job| {
+            let keep = !job.info.status.is_finished() || job.info.finished_at.map_or(true, |t| t > cutoff);
+            if !keep {
+                let id = id.clone();
+                storage.write(format!("remove job {} from store", id), move |storage| {
+                    storage.remove(&id)
+                });
+            }
+            keep
+        });
+        self.queues.retain(|_, queue| !queue.is_idle());
+    }
+
+    /// Modify a job, and save the result to storage.
+    fn update_job(&mut self, id: &str, f: impl FnOnce(&mut Job)) {
+        let Some(job) = self.jobs.get_mut(id) else { return };
+        f(job);
+
+        // The stores only keep the logs of finished jobs.
+        let finished = job.info.status.is_finished();
+        let (info, logs) = (job.info.clone(), finished.then(|| job.logs.clone()).unwrap_or_default());
+        if finished {
+            job.images = None;
+        }
+        self.storage
+            .write(format!("persist job {}", id), move |storage| storage.save(&info, &logs));
+        if let Some(run_logs) = self.run_logs.as_ref().filter(|_| finished) {
+            run_logs.finish(id, self.jobs[id].logs.clone());
+        }
+    }
+
+    /// Store the core dump of a job's run, so it's still there after the job is forgotten or the server restarts.
+    fn save_core_dump(&self, id: &str, dump: &[u8]) {
+        let (job_id, dump) = (id.to_string(), dump.to_vec());
+        self.storage
+            .write(format!("persist core dump of job {}", id), move |storage| {
+                storage.save_core_dump(&job_id, &dump)
+            });
+    }
+
+    /// The targets runs can be submitted to: this server's, and those of the workers that are alive.
+    fn run_targets(&self) -> Vec<config::Target> {
+        let mut targets = self.config.targets.clone();
+        for worker in self.registered_workers.values().filter(|w| w.alive) {
+            targets.extend(worker.targets.iter().map(worker_target));
+        }
+        targets
+    }
+
+    /// The worker whose target this is, if it isn't one of this server's.
+    fn target_worker(&self, name: &str) -> Option<String> {
+        if self.config.targets.iter().any(|t| t.name == name) {
+            return None;
+        }
+        self.registered_workers
+            .iter()
+            .find(|(_, w)| w.targets.iter().any(|t| t.name == name))
+            .map(|(n, _)| n.clone())
+    }
+
+    /// A job to hand to its worker, with its images. Marks it running, since the worker starts it as soon as the
+    /// target is free.
+    fn hand_out(&mut self, id: &str, image: &run::Image, preload: &[run::Image]) -> Option<api::WorkerJob> {
+        let job = self.jobs.get(id)?;
+        // Cancelled while it waited.
+        if job.info.status.is_finished() {
+            return None;
+        }
+        let output = job.output.clone();
+        self.update_job(id, |job| {
+            job.info.status = api::JobStatus::Running;
+            job.info.started_at = Some(Utc::now());
+        });
+
+        let engine = &base64::engine::general_purpose::STANDARD;
+        Some(api::WorkerJob {
+            job: self.jobs[id].info.clone(),
+            image: engine.encode(&image.data),
+            preload: preload.iter().map(|image| engine.encode(&image.data)).collect(),
+            log_filter: output.log_filter.map(|f| f.to_string()),
+            host_timestamps: output.host_timestamps,
+            raw_defmt: output.raw_defmt,
+        })
+    }
+
+    /// Targets of the registered workers. Those of workers that went silent are down.
+    fn worker_targets(&self) -> Vec<api::Target> {
+        let mut targets = Vec::new();
+        for worker in self.registered_workers.values() {
+            targets.extend(worker.targets.iter().cloned().map(|mut t| {
+                t.up &= worker.alive;
+                t
+            }));
+        }
+        targets.sort_by(|a, b| a.name.cmp(&b.name));
+        targets
+    }
+
+    /// End a lease, and the gdb session started under it, which would keep holding the probe.
+    fn remove_lease(&mut self, target: &str) {
+        self.leases.remove(target);
+        if let Some(session) = self.gdb_sessions.get(target).filter(|s| s.leased) {
+            info!("Ending gdb session on target {} with its lease", target);
+            session.stop.store(true, Ordering::Relaxed);
+        }
+        let target = target.to_string();
+        self.storage.write(
+            format!("remove lease of target {} from store", target),
+            move |storage| storage.remove_lease(&target),
+        );
+    }
+
+    /// Add an action to the audit log. Old actions are pruned by [`spawn_pruner`].
+    fn audit(&self, caller: &str, action: &str, target: Option<&str>, job_id: Option<&str>) {
+        let event = api::AuditEvent {
+            at: Utc::now(),
+            caller: caller.to_string(),
+            action: action.to_string(),
+            target: target.map(str::to_string),
+            job_id: job_id.map(str::to_string),
+        };
+        self.storage
+            .write("persist audit event", move |storage| storage.add_audit(&event));
+    }
+
+    fn limits(&mut self, caller: &Caller) -> Option<&mut CallerLimits> {
+        let limit = caller.rate_limit.as_ref()?;
+        Some(self.rate_limits.entry(caller.id.clone()).or_insert_with(|| {
+            CallerLimits {
+                requests: limit
+                    .requests_per_minute
+                    .map(|n| TokenBucket::new(n, Duration::from_secs(60))),
+                run_time: limit
+                    .run_minutes_per_hour
+                    .map(|n| TokenBucket::new(n, Duration::from_secs(60 * 60))),
+            }
+        }))
+    }
+
+    /// Count a request against the caller's rate limit. Fails with how long to wait if it's exceeded.
+    fn limit_request(&mut self, caller: &Caller) -> Result<(), Duration> {
+        match self.limits(caller).and_then(|l| l.requests.as_mut()) {
+            Some(bucket) => bucket.take(),
+            None => Ok(()),
+        }
+    }
+
+    /// Check the caller has run time left. It's only charged once the run finishes.
+    fn limit_run(&mut self, caller: &Caller) -> Result<(), Duration> {
+        match self.limits(caller).and_then(|l| l.run_time.as_mut()) {
+            Some(bucket) => bucket.check(),
+            None => Ok(()),
+        }
+    }
+
+    /// Add a finished job to the run history.
+    fn record_run(&mut self, id: &str) {
+        let Some(job) = self.jobs.get(id) else { return };
+        let Some(finished_at) = job.info.finished_at else {
+            return;
+        };
+
+        if let (Some(caller), Some(started_at)) = (&job.caller, job.info.started_at) {
+            let minutes = (finished_at - started_at).num_milliseconds() as f64 / 60_000.0;
+            if let Some(bucket) = self.rate_limits.get_mut(caller).and_then(|l| l.run_time.as_mut()) {
+                bucket.charge(minutes);
+            }
+        }
+        let record = api::RunRecord {
+            job_id: job.info.id.clone(),
+            target: job.info.target.clone(),
+            status: job.info.status,
+            failure: job.info.failure,
+            timings: job.info.timings.clone(),
+            started_at: job.info.started_at,
+            finished_at,
+        };
+
+        let cutoff = Utc::now() - chrono::Duration::days(HISTORY_RETENTION_DAYS);
+        self.history.retain(|r| r.finished_at > cutoff);
+        let persisted = record.clone();
+        self.storage
+            .write("persist run history", move |storage| storage.add_history(&persisted));
+        self.history.push(record);
+    }
+
+    fn record_timings(&mut self, target: &str, timings: &api::PhaseTimings) {
+        for (phase, secs) in timings.phases() {
+            if let Some(secs) = secs {
+                let entry = self.phase_seconds.entry((target.to_string(), phase)).or_default();
+                entry.0 += secs;
+                entry.1 += 1;
+            }
+        }
+    }
+
+    /// Fail a cancelled job that didn't get to run, if it isn't finished already.
+    fn finish_cancelled(&mut self, id: &str) {
+        let mut finished = false;
+        self.update_job(id, |job| {
+            if !job.info.status.is_finished() {
+                job.info.status = api::JobStatus::Failed;
+                job.info.failure = Some(api::RunError::Cancelled);
+                job.info.finished_at = Some(Utc::now());
+                job.logs = b"ERROR - job cancelled\n".to_vec();
+                finished = true;
+            }
+        });
+        if finished {
+            self.complete_check(id);
+        }
+    }
+
+    /// A passing run with the result `key`, if there was one within the result cache TTL.
+    fn cached_result(&mut self, key: &str) -> Option<CachedResult> {
+        let ttl = Duration::from_secs(self.config.result_cache_ttl?);
+        self.results.retain(|_, r| r.at.elapsed() < ttl);
+        self.results.get(key).cloned()
+    }
+
+    fn cache_result(&mut self, key: String, id: &str, logs: &[u8]) {
+        if self.config.result_cache_ttl.is_none() {
+            return;
+        }
+        let result = CachedResult {
+            job_id: id.to_string(),
+            logs: logs.to_vec(),
+            at: Instant::now(),
+        };
+        self.results.insert(key, result);
+    }
+
+    /// Report the result of a finished job to its GitHub check run, if it has one, in the background.
+    fn complete_check(&self, id: &str) {
+        let (Some(github), Some(job)) = (&self.github, self.jobs.get(id)) else {
+            return;
+        };
+        let Some(check) = job.info.check_run.clone() else {
+            return;
+        };
+        let github = github.clone();
+        let (info, logs) = (job.info.clone(), job.logs.clone());
+        tokio::spawn(async move {
+            if let Err(e) = github.complete(&check, &info, &logs).await {
+                warn!("Failed to complete GitHub check run for job {}: {:?}", info.id, e);
+            }
+        });
+    }
+
+    /// Fail a job that couldn't run, or whose run was lost, with `failure`.
+    fn fail_job(&mut self, id: &str, failure: api::RunError, msg: &str) {
+        error!("Job {}: {}", id, msg);
+        self.update_job(id, |job| {
+            job.info.status = api::JobStatus::Failed;
+            job.info.failure = Some(failure);
+            job.info.finished_at = Some(Utc::now());
+            job.logs = format!("ERROR - {}\n", msg).into_bytes();
+        });
+    }
+}
+
+pub async fn serve(mut config: Config, bind: Vec<SocketAddr>) -> anyhow::Result<()> {
+    let client_ca = client_ca(&config)?;
+    if !client_ca.is_empty() && config.tls.is_none() {
+        bail!("Client certificate auth requires `tls` to be configured.");
+    }
+    let tls = config.tls.clone();
+    let unix_socket = config.unix_socket.clone();
+    for path in &config.chip_descriptions {
+        probe::load_chip_description(path)?;
+    }
+    if let Some(discovery) = &config.discovery {
+        let discovered = discovery.discover(&config.targets);
+        config.targets.extend(discovered);
+    }
+    check_probe_conflicts(&mut config.targets);
+
+    // TODO support none or multiple oidc issuers.
+    let oidc_client = match config.auths.iter().find_map(|a| match a {
+        Auth::Oidc(o) => Some(o),
+        _ => None,
+    }) {
+        Some(auth) => {
+            let client = oidc::Client::new_autodiscover(&auth.issuer).await.unwrap();
+            client.spawn_refresh();
+            Some(client)
+        }
+        None => None,
+    };
+
+    let store = storage::open(&config.storage())?;
+    let run_logs = config.run_logs.as_ref().map(runlog::RunLogs::open).transpose()?;
+
+    let mut jobs = HashMap::new();
+    let mut history = Vec::new();
+    let mut pending = Vec::new();
+    let mut leases = Vec::new();
+    if store.is_persistent() {
+        history = store.load_history(Utc::now() - chrono::Duration::days(HISTORY_RETENTION_DAYS))?;
+
+        let node = config.cluster.as_ref().map(|c| c.node.as_str());
+        for mut info in store.load()? {
+            // Other servers' jobs are theirs to resume, and unclaimed ones get claimed like new ones.
+            if node.is_some() && info.node.as_deref() != node {
+                continue;
+            }
+            let logs = store.logs(&info.id)?.unwrap_or_default();
+            // Jobs on workers' targets wait for their worker to register again, and the ones it took for the
+            // result it keeps until it can hand it back.
+            if !info.status.is_finished() && info.worker.is_none() {
+                // Jobs that were running when the server stopped are started over.
+                info.status = api::JobStatus::Queued;
+                info.started_at = None;
+                pending.push(info.id.clone());
+            }
+            jobs.insert(info.id.clone(), Job::stored(info, logs));
+        }
+        info!("Loaded {} jobs from store, {} pending", jobs.len(), pending.len());
+        leases = store.load_leases()?;
+    }
+
+    let github = match &config.github {
+        Some(github) => Some(Arc::new(github::Checks::new(github)?)),
+        None => None,
+    };
+
+    let workers = Arc::new(worker::Pool::new(config.max_parallel_runs));
+    let context: Arc<Mutex<Context>> = Arc::new(Mutex::new(Context {
+        oidc_client,
+        config,
+        target_locks: HashMap::new(),
+        workers,
+        blobs: Arc::new(blobs::Store::default()),
+        jobs,
+        history,
+        storage: storage::Handle::spawn(store)?,
+        draining: false,
+        phase_seconds: BTreeMap::new(),
+        rate_limits: HashMap::new(),
+        gdb_sessions: HashMap::new(),
+        leases: HashMap::new(),
+        github,
+        queues: HashMap::new(),
+        results: HashMap::new(),
+        registered_workers: HashMap::new(),
+        upstream_results: VecDeque::new(),
+        upstream_reachable: false,
+        run_logs,
+    }));
+
+    for lease in leases {
+        restore_lease(context.clone(), lease);
+    }
+    for id in pending {
+        resume_job(context.clone(), id);
+    }
+    let (cluster, workers, upstream) = {
+        let config = &context.lock().config;
+        (config.cluster.clone(), config.workers.clone(), config.upstream.clone())
+    };
+    if let Some(cluster) = cluster {
+        spawn_claimer(context.clone(), cluster);
+    }
+    if let Some(workers) = workers {
+        spawn_worker_sweeper(context.clone(), workers);
+    }
+    if let Some(upstream) = upstream {
+        spawn_upstream(context.clone(), upstream)?;
+    }
+    spawn_run_log_pruner(context.clone());
+    spawn_pruner(context.clone());
+
+    let target_run: _ = warp::path!("targets" / String / "run")
+        .and(warp::post())
+        .and(caller_filter(context.clone()))
+        .and(warp::query())
+        .and(upload_filter())
+        .and(with_val(context.clone()))
+        .and_then(handle_run);
+
+    let selector_run: _ = warp::path!("run")
+        .and(warp::post())
+        .and(caller_filter(context.clone()))
+        .and(warp::query())
+        .and(upload_filter())
+        .and(with_val(context.clone()))
+        .and_then(handle_run_selector);
+
+    let list_targets: _ = warp::path!("targets")
+        .and(warp::get())
+        .and(caller_filter(context.clone()))
+        .and(with_val(context.clone()))
+        .and_then(handle_list_targets);
+
+    let job_status: _ = warp::path!("jobs" / String)
+        .and(warp::get())
+        .and(caller_filter(context.clone()))
+        .and(with_val(context.clone()))
+        .and_then(handle_job_status);
+
+    let job_cancel: _ = warp::path!("jobs" / String)
+        .and(warp::delete())
+        .and(caller_filter(context.clone()))
+        .and(with_val(context.clone()))
+        .and_then(handle_job_cancel);
+
+    let job_logs: _ = warp::path!("jobs" / String / "logs")
+        .and(warp::get())
+        .and(caller_filter(context.clone()))
+        .and(with_val(context.clone()))
+        .and_then(handle_job_logs);
+
+    let job_report: _ = warp::path!("jobs" / String / "report")
+        .and(warp::get())
+        .and(caller_filter(context.clone()))
+        .and(with_val(context.clone()))
+        .and_then(handle_job_report);
+
+    let job_core_dump: _ = warp::path!("jobs" / String / "core")
+        .and(warp::get())
+        .and(caller_filter(context.clone()))
+        .and(with_val(context.clone()))
+        .and_then(handle_job_core_dump);
+
+    let job_coverage: _ = warp::path!("jobs" / String / "coverage")
+        .and(warp::get())
+        .and(caller_filter(context.clone()))
+        .and(with_val(context.clone()))
+        .and_then(handle_job_coverage);
+
+    let job_defmt: _ = warp::path!("jobs" / String / "defmt")
+        .and(warp::get())
+        .and(caller_filter(context.clone()))
+        .and(with_val(context.clone()))
+        .and_then(handle_job_defmt);
+
+    let blob_start: _ = warp::path!("blobs")
+        .and(warp::post())
+        .and(caller_filter(context.clone()))
+        .and(warp::query())
+        .and(with_val(context.clone()))
+        .and_then(handle_blob_start);
+
+    let blob_chunk: _ = warp::path!("blobs" / String)
+        .and(warp::patch())
+        .and(caller_filter(context.clone()))
+        .and(warp::query())
+        .and(warp::body::content_length_limit(MAX_CHUNK))
+        .and(body_filter())
+        .and(with_val(context.clone()))
+        .and_then(handle_blob_chunk);
+
+    let gdb_start: _ = warp::path!("targets" / String / "gdb")
+        .and(warp::post())
+        .and(caller_filter(context.clone()))
+        .and(warp::query())
+        .and(with_val(context.clone()))
+        .and_then(handle_gdb_start);
+
+    let gdb_stop: _ = warp::path!("targets" / String / "gdb")
+        .and(warp::delete())
+        .and(caller_filter(context.clone()))
+        .and(with_val(context.clone()))
+        .and_then(handle_gdb_stop);
+
+    let lease_start: _ = warp::path!("targets" / String / "lease")
+        .and(warp::post())
+        .and(caller_filter(context.clone()))
+        .and(warp::query())
+        .and(with_val(context.clone()))
+        .and_then(handle_lease_start);
+
+    let lease_renew: _ = warp::path!("targets" / String / "lease")
+        .and(warp::put())
+        .and(caller_filter(context.clone()))
+        .and(warp::query())
+        .and(with_val(context.clone()))
+        .and_then(handle_lease_renew);
+
+    let lease_release: _ = warp::path!("targets" / String / "lease")
+        .and(warp::delete())
+        .and(caller_filter(context.clone()))
+        .and(with_val(context.clone()))
+        .and_then(handle_lease_release);
+
+    let group: _ = warp::path!("groups" / String)
+        .and(warp::get())
+        .and(caller_filter(context.clone()))
+        .and(with_val(context.clone()))
+        .and_then(handle_group);
+
+    let badge: _ = warp::path!("targets" / String / "badge.svg")
+        .and(warp::get())
+        .and(with_val(context.clone()))
+        .and_then(handle_badge);
+
+    let metrics: _ = warp::path!("metrics")
+        .and(warp::get())
+        .and(metrics_auth_filter(context.clone()))
+        .and(with_val(context.clone()))
+        .and_then(handle_metrics);
+
+    let drain: _ = warp::path!("admin" / "drain")
+        .and(warp::post())
+        .and(admin_filter(context.clone()))
+        .and(with_val(context.clone()))
+        .and_then(handle_drain);
+
+    let maintenance_start: _ = warp::path!("admin" / "targets" / String / "maintenance")
+        .and(warp::post())
+        .and(admin_filter(context.clone()))
+        .and(with_val(true))
+        .and(with_val(context.clone()))
+        .and_then(handle_maintenance);
+
+    let maintenance_end: _ = warp::path!("admin" / "targets" / String / "maintenance")
+        .and(warp::delete())
+        .and(admin_filter(context.clone()))
+        .and(with_val(false))
+        .and(with_val(context.clone()))
+        .and_then(handle_maintenance);
+
+    let erase: _ = warp::path!("admin" / "targets" / String / "erase")
+        .and(warp::post())
+        .and(admin_filter(context.clone()))
+        .and(with_val(context.clone()))
+        .and_then(handle_erase);
+
+    let power: _ = warp::path!("admin" / "targets" / String / "power")
+        .and(warp::post())
+        .and(admin_filter(context.clone()))
+        .and(warp::query())
+        .and(with_val(context.clone()))
+        .and_then(handle_power);
+
+    let audit: _ = warp::path!("admin" / "audit")
+        .and(warp::get())
+        .and(admin_filter(context.clone()))
+        .and(with_val(context.clone()))
+        .and_then(handle_audit);
+
+    let worker_register: _ = warp::path!("workers" / String)
+        .and(warp::put())
+        .and(worker_auth_filter(context.clone()))
+        .and(warp::body::content_length_limit(MAX_WORKER_MESSAGE))
+        .and(warp::body::json())
+        .and(with_val(context.clone()))
+        .and_then(handle_worker_register);
+
+    let worker_heartbeat: _ = warp::path!("workers" / String / "heartbeat")
+        .and(warp::post())
+        .and(worker_auth_filter(context.clone()))
+        .and(warp::body::content_length_limit(MAX_WORKER_MESSAGE))
+        .and(warp::body::json())
+        .and(with_val(context.clone()))
+        .and_then(handle_worker_heartbeat);
+
+    let worker_poll: _ = warp::path!("workers" / String / "jobs")
+        .and(warp::get())
+        .and(worker_auth_filter(context.clone()))
+        .and(warp::query())
+        .and(with_val(context.clone()))
+        .and_then(handle_worker_poll);
+
+    let worker_relay: _ = warp::path!("workers" / String / "jobs" / String / "relay" / String)
+        .and(warp::post())
+        .and(worker_auth_filter(context.clone()))
+        .and(warp::query())
+        .and(body_filter())
+        .and(with_val(context.clone()))
+        .and_then(handle_worker_relay);
+
+    let worker_result: _ = warp::path!("workers" / String / "jobs" / String / "result")
+        .and(warp::post())
+        .and(worker_auth_filter(context.clone()))
+        .and(warp::body::content_length_limit(MAX_WORKER_RESULT))
+        .and(warp::body::json())
+        .and(with_val(context.clone()))
+        .and_then(handle_worker_result);
+
+    let list_workers: _ = warp::path!("workers")
+        .and(warp::get())
+        .and(check_auth_filter(context.clone()))
+        .and(with_val(context.clone()))
+        .and_then(handle_list_workers);
+
+    let status: _ = warp::path!("status.json")
+        .and(warp::get())
+        .and(with_val(context.clone()))
+        .and_then(handle_status);
+
+    let version: _ = warp::path!("api" / "version").and(warp::get()).and_then(handle_version);
+
+    let healthz: _ = warp::path!("healthz").and(warp::get()).and_then(handle_healthz);
+
+    let readyz: _ = warp::path!("readyz")
+        .and(warp::get())
+        .and(with_val(context.clone()))
+        .and_then(handle_readyz);
+
+    let dashboard_js: _ = warp::path!("dashboard.js")
+        .and(warp::get())
+        .and_then(handle_dashboard_js);
+
+    let home: _ = warp::path!().and(warp::get()).and_then(handle_home);
+
+    spawn_sigterm_handler(context.clone())?;
+
+    let routes = target_run
+        .or(selector_run)
+        .or(list_targets)
+        .or(job_status)
+        .or(job_cancel)
+        .or(job_logs)
+        .or(job_report)
+        .or(job_core_dump)
+        .or(job_coverage)
+        .or(job_defmt)
+        .or(blob_start)
+        .or(blob_chunk)
+        .or(gdb_start)
+        .or(gdb_stop)
+        .or(lease_start)
+        .or(lease_renew)
+        .or(lease_release)
+        .or(group)
+        .or(badge)
+        .or(metrics)
+        .or(drain)
+        .or(maintenance_start)
+        .or(maintenance_end)
+        .or(erase)
+        .or(power)
+        .or(audit)
+        .or(worker_register)
+        .or(worker_heartbeat)
+        .or(worker_poll)
+        .or(worker_relay)
+        .or(worker_result)
+        .or(list_workers)
+        .or(status)
+        .or(version)
+        .or(healthz)
+        .or(readyz)
+        .or(dashboard_js)
+        .or(home)
+        .recover(handle_rejection)
+        .with(warp::reply::with::header(
+            api::VERSION_HEADER,
+            crate::meta::CARGO_VERSION,
+        ));
+
+    let mut servers = Vec::new();
+    for addr in bind {
+        let shutdown = drained(context.clone());
+        let server = match &tls {
+            Some(tls) if !client_ca.is_empty() => {
+                serve_tls_client_auth(addr, tls, &client_ca, routes.clone(), shutdown)?
+            }
+            Some(tls) => {
+                let server = warp::serve(routes.clone())
+                    .tls()
+                    .cert_path(&tls.cert)
+                    .key_path(&tls.key);
+                let (addr, server) = server.bind_with_graceful_shutdown(addr, shutdown);
+                info!("Listening on {} (HTTPS)", addr);
+                server.boxed_local()
+            }
+            None => {
+                let (addr, server) = warp::serve(routes.clone()).bind_with_graceful_shutdown(addr, shutdown);
+                info!("Listening on {}", addr);
+                server.boxed_local()
+            }
+        };
+        servers.push(server);
+    }
+    if let Some(socket) = &unix_socket {
+        servers.push(serve_unix_socket(socket, routes.clone(), drained(context.clone()))?);
+    }
+    futures::future::join_all(servers).await;
+
+    // Let the writes of the last jobs reach the storage.
+    let storage = context.lock().storage.clone();
+    storage.call(|_| Ok(())).await
+}
+
+/// Serve `routes` over HTTPS, asking clients for a certificate signed by one of the CAs of `client_ca`, by path,
+/// and marking the requests of the ones that present one with [`ClientCert`]. Clients without one are let in too,
+/// for token and OIDC auth.
+///
+/// warp's own TLS server can only require certificates, and doesn't tell requests whether there was one.
+fn serve_tls_client_auth<F>(
+    addr: SocketAddr,
+    tls: &config::TlsConfig,
+    client_ca: &[(String, Vec<u8>)],
+    routes: F,
+    shutdown: impl std::future::Future<Output = ()> + Send + 'static,
+) -> anyhow::Result<futures::future::LocalBoxFuture<'static, ()>>
+where
+    F: Filter<Error = Rejection> + Clone + Send + Sync + 'static,
+    F::Extract: Reply,
+{
+    use anyhow::Context as _;
+    use futures::StreamExt;
+    use tokio_rustls::rustls::server::danger::ClientCertVerifier as _;
+    use tokio_rustls::rustls::server::WebPkiClientVerifier;
+    use tokio_rustls::rustls::{RootCertStore, ServerConfig};
+    use warp::hyper::service::{make_service_fn, service_fn, Service};
+
+    // The handshake accepts certificates of any of the CAs. Which one signed it is found out afterwards, with a
+    // verifier for each.
+    let mut roots = RootCertStore::empty();
+    let mut verifiers = Vec::new();
+    for (path, pem) in client_ca {
+        let mut ca_roots = RootCertStore::empty();
+        for cert in rustls_pemfile::certs(&mut &pem[..]) {
+            let cert = cert.with_context(|| format!("Invalid CA {}", path))?;
+            roots.add(cert.clone())?;
+            ca_roots.add(cert)?;
+        }
+        verifiers.push((path.clone(), WebPkiClientVerifier::builder(Arc::new(ca_roots)).build()?));
+    }
+    let verifier = WebPkiClientVerifier::builder(Arc::new(roots))
+        .allow_unauthenticated()
+        .build()?;
+    let cert = std::fs::read(&tls.cert).with_context(|| format!("Failed to read {}", tls.cert))?;
+    let certs = rustls_pemfile::certs(&mut &cert[..]).collect::<Result<Vec<_>, _>>()?;
+    let key = std::fs::read(&tls.key).with_context(|| format!("Failed to read {}", tls.key))?;
+    let key = rustls_pemfile::private_key(&mut &key[..])?.ok_or_else(|| anyhow!("No private key in {}", tls.key))?;
+    let mut server_config = ServerConfig::builder()
+        .with_client_cert_verifier(verifier)
+        .with_single_cert(certs, key)?;
+    server_config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+    let acceptor = tokio_rustls::TlsAcceptor::from(Arc::new(server_config));
+
+    let listener = std::net::TcpListener::bind(addr).with_context(|| format!("Failed to listen on {}", addr))?;
+    listener.set_nonblocking(true)?;
+    let listener = tokio::net::TcpListener::from_std(listener)?;
+    let addr = listener.local_addr()?;
+
+    // Handshakes run concurrently, so a slow client doesn't hold up the others. Failed ones only drop their
+    // connection.
+    let incoming = futures::stream::unfold(listener, |listener| async move {
+        let conn = listener.accept().await.map(|(stream, _)| stream);
+        Some((conn, listener))
+    })
+    .map(move |conn| {
+        let acceptor = acceptor.clone();
+        async move { acceptor.accept(conn?).await }
+    })
+    .buffer_unordered(MAX_TLS_HANDSHAKES)
+    .filter_map(|res| async move {
+        match res {
+            Ok(stream) => Some(Ok::<_, std::io::Error>(stream)),
+            Err(e) => {
+                log::debug!("TLS handshake failed: {}", e);
+                None
+            }
+        }
+    });
+    let service = warp::service(routes);
+    let make_service = make_service_fn(move |conn: &tokio_rustls::server::TlsStream<tokio::net::TcpStream>| {
+        let client_cert = conn.get_ref().1.peer_certificates().and_then(|chain| {
+            let (end_entity, intermediates) = chain.split_first()?;
+            let now = tokio_rustls::rustls::pki_types::UnixTime::now();
+            let (ca, _) = verifiers
+                .iter()
+                .find(|(_, v)| v.verify_client_cert(end_entity, intermediates, now).is_ok())?;
+            Some(ClientCert { ca: ca.clone() })
+        });
+        let service = service.clone();
+        async move {
+            Ok::<_, std::convert::Infallible>(service_fn(move |mut req| {
+                if let Some(client_cert) = &client_cert {
+                    req.extensions_mut().insert(client_cert.clone());
+                }
+                service.clone().call(req)
+            }))
+        }
+    });
+    let server = warp::hyper::Server::builder(warp::hyper::server::accept::from_stream(incoming))
+        .serve(make_service)
+        .with_graceful_shutdown(shutdown);
+    info!("Listening on {} (HTTPS, client certificates)", addr);
+    Ok(server
+        .map(|res| {
+            if let Err(e) = res {
+                error!("HTTPS server failed: {}", e);
+            }
+        })
+        .boxed_local())
+}
+
+/// Serve `routes` on a unix socket, marking its requests with [`UnixSocket`].
+#[cfg(unix)]
+fn serve_unix_socket<F>(
+    config: &config::UnixSocketConfig,
+    routes: F,
+    shutdown: impl std::future::Future<Output = ()> + Send + 'static,
+) -> anyhow::Result<futures::future::LocalBoxFuture<'static, ()>>
+where
+    F: Filter<Error = Rejection> + Clone + Send + Sync + 'static,
+    F::Extract: Reply,
+{
+    use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+
+    use anyhow::Context as _;
+    use warp::hyper::service::{make_service_fn, service_fn, Service};
+
+    // Left behind by a server that didn't shut down cleanly.
+    if std::fs::symlink_metadata(&config.path).is_ok_and(|m| m.file_type().is_socket()) {
+        std::fs::remove_file(&config.path)?;
+    }
+    let listener = tokio::net::UnixListener::bind(&config.path)
+        .with_context(|| format!("Failed to listen on unix socket {}", config.path))?;
+    // NOTE (unwrap): validated with the config.
+    let mode = u32::from_str_radix(&config.mode, 8).unwrap();
+    std::fs::set_permissions(&config.path, std::fs::Permissions::from_mode(mode))?;
+    if let Some(group) = &config.group {
+        std::os::unix::fs::chown(&config.path, None, Some(group_id(group)?))
+            .with_context(|| format!("Failed to give unix socket {} to group {}", config.path, group))?;
+    }
+
+    let incoming = futures::stream::unfold(listener, |listener| async move {
+        let conn = listener.accept().await.map(|(stream, _)| stream);
+        Some((conn, listener))
+    });
+    let service = warp::service(routes);
+    let make_service = make_service_fn(move |_| {
+        let service = service.clone();
+        async move {
+            Ok::<_, std::convert::Infallible>(service_fn(move |mut req| {
+                req.extensions_mut().insert(UnixSocket);
+                service.clone().call(req)
+            }))
+        }
+    });
+    let server = warp::hyper::Server::builder(warp::hyper::server::accept::from_stream(incoming))
+        .serve(make_service)
+        .with_graceful_shutdown(shutdown);
+    info!("Listening on unix socket {}", config.path);
+    Ok(server
+        .map(|res| {
+            if let Err(e) = res {
+                error!("Unix socket server failed: {}", e);
+            }
+        })
+        .boxed_local())
+}
+
+#[cfg(not(unix))]
+fn serve_unix_socket<F>(
+    _config: &config::UnixSocketConfig,
+    _routes: F,
+    _shutdown: impl std::future::Future<Output = ()> + Send + 'static,
+) -> anyhow::Result<futures::future::LocalBoxFuture<'static, ()>> {
+    bail!("unix_socket is only supported on unix")
+}
+
+/// ID of the group named `name`.
+#[cfg(unix)]
+fn group_id(name: &str) -> anyhow::Result<u32> {
+    let cname = std::ffi::CString::new(name)?;
+    // SAFETY: getgrnam returns null or a pointer to a static struct, which is read before any other call.
+    let group = unsafe { libc::getgrnam(cname.as_ptr()) };
+    if group.is_null() {
+        bail!("No such group: {}", name);
+    }
+    Ok(unsafe { (*group).gr_gid })
+}
+
+/// Parse a `--bind` address: a socket address, or an IP address to listen on with the default port.
+pub fn parse_bind_addr(s: &str, port: u16) -> anyhow::Result<SocketAddr> {
+    if let Ok(addr) = s.parse() {
+        return Ok(addr);
+    }
+    match s.parse::<IpAddr>() {
+        Ok(ip) => Ok(SocketAddr::new(ip, port)),
+        Err(_) => bail!("Invalid bind address `{}`, expected an IP or IP:port", s),
+    }
+}
+
+/// Paths and contents of the CA certificates of all client certificate auths.
+fn client_ca(config: &Config) -> anyhow::Result<Vec<(String, Vec<u8>)>> {
+    let mut res = Vec::new();
+    for auth in &config.auths {
+        if let Auth::ClientCert(auth) = auth {
+            let ca = std::fs::read(&auth.ca).map_err(|e| anyhow!("Failed to read CA {}: {}", auth.ca, e))?;
+            res.push((auth.ca.clone(), ca));
+        }
+    }
+    Ok(res)
 }
 
 fn with_val<T: Clone + Send>(val: T) -> impl Filter<Extract = (T,), Error = std::convert::Infallible> + Clone {
@@ -0,0 +1,323 @@
+//! Scripted stimulus sent to the device under test from the server's own buses, for closed-loop tests
+//! of CAN, I2C and SPI communication.
+
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{Receiver, RecvTimeoutError};
+use std::sync::Arc;
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+use anyhow::bail;
+use chrono::Utc;
+use serde::Deserialize;
+
+use crate::logutil::LogEntry;
+
+/// Log target of the script's output.
+pub const TARGET: &str = "stimulus";
+
+const POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+#[derive(Clone, Deserialize)]
+pub struct Script {
+    pub steps: Vec<Step>,
+}
+
+impl Script {
+    pub fn load(path: &str) -> anyhow::Result<Self> {
+        let data = std::fs::read(path)?;
+        Ok(serde_yaml::from_slice(&data)?)
+    }
+}
+
+/// One step of a script. Steps run in order, each once its conditions are met.
+#[derive(Clone, Deserialize)]
+pub struct Step {
+    /// Wait this long after the previous step, or after the firmware started for the first one.
+    #[serde(default)]
+    pub delay_millis: u64,
+    /// Then wait for a device log line containing this.
+    #[serde(default)]
+    pub wait_for_log: Option<String>,
+    #[serde(flatten)]
+    pub action: Action,
+}
+
+#[derive(Clone, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Action {
+    /// Send a frame on a SocketCAN interface, like `can0`.
+    CanSend {
+        interface: String,
+        id: u32,
+        #[serde(default)]
+        extended: bool,
+        data: Vec<u8>,
+    },
+    /// Write bytes to an I2C device, on a bus like `/dev/i2c-1`.
+    I2cWrite { bus: String, address: u16, data: Vec<u8> },
+    /// Read bytes from an I2C device, optionally writing a register address first.
+    I2cRead {
+        bus: String,
+        address: u16,
+        #[serde(default)]
+        register: Option<u8>,
+        len: usize,
+    },
+    /// Write bytes to a spidev device, like `/dev/spidev0.0`.
+    SpiWrite { device: String, data: Vec<u8> },
+    /// Read bytes from a spidev device.
+    SpiRead { device: String, len: usize },
+}
+
+/// A script running in a background thread.
+pub struct Execution {
+    stop: Arc<AtomicBool>,
+    thread: JoinHandle<Vec<LogEntry>>,
+}
+
+impl Execution {
+    /// Start running the script once `started` fires. `device_logs` receives the device's log lines.
+    pub fn start(script: Script, started: Receiver<()>, device_logs: Receiver<String>) -> Self {
+        let stop = Arc::new(AtomicBool::new(false));
+        let thread = std::thread::spawn({
+            let stop = stop.clone();
+            move || {
+                let mut runner = Runner {
+                    stop,
+                    device_logs,
+                    entries: Vec::new(),
+                };
+                runner.run(&script, started);
+                runner.entries
+            }
+        });
+        Self { stop, thread }
+    }
+
+    /// Stop the script if it's still running, and return its output.
+    pub fn finish(self) -> Vec<LogEntry> {
+        self.stop.store(true, Ordering::Relaxed);
+        self.thread.join().unwrap_or_default()
+    }
+}
+
+struct Runner {
+    stop: Arc<AtomicBool>,
+    device_logs: Receiver<String>,
+    entries: Vec<LogEntry>,
+}
+
+impl Runner {
+    fn run(&mut self, script: &Script, started: Receiver<()>) {
+        loop {
+            match started.recv_timeout(POLL_INTERVAL) {
+                Ok(()) => break,
+                Err(RecvTimeoutError::Timeout) if !self.stopped() => continue,
+                // The run ended before the firmware started.
+                Err(_) => return,
+            }
+        }
+
+        for (i, step) in script.steps.iter().enumerate() {
+            let ready = Instant::now() + Duration::from_millis(step.delay_millis);
+            while Instant::now() < ready {
+                if self.stopped() {
+                    return;
+                }
+                std::thread::sleep(POLL_INTERVAL.min(ready.saturating_duration_since(Instant::now())));
+            }
+            if let Some(text) = &step.wait_for_log {
+                loop {
+                    match self.device_logs.recv_timeout(POLL_INTERVAL) {
+                        Ok(line) if line.contains(text.as_str()) => break,
+                        Ok(_) => {}
+                        Err(RecvTimeoutError::Timeout) if !self.stopped() => {}
+                        Err(_) => return,
+                    }
+                }
+            }
+
+            match perform(&step.action) {
+                Ok(msg) => self.log("INFO", format!("step {}: {}", i, msg)),
+                Err(e) => {
+                    self.log("ERROR", format!("step {} failed: {:#}", i, e));
+                    return;
+                }
+            }
+            // Only lines logged after this step count for the next one.
+            while self.device_logs.try_recv().is_ok() {}
+        }
+    }
+
+    fn stopped(&self) -> bool {
+        self.stop.load(Ordering::Relaxed)
+    }
+
+    fn log(&mut self, level: &str, message: String) {
+        self.entries.push(LogEntry {
+            message,
+            level: level.to_string(),
+            module_path: Some(TARGET.to_string()),
+            file: None,
+            line: None,
+            timestamp: Utc::now(),
+        });
+    }
+}
+
+fn perform(action: &Action) -> anyhow::Result<String> {
+    match action {
+        Action::CanSend {
+            interface,
+            id,
+            extended,
+            data,
+        } => {
+            if data.len() > 8 {
+                bail!("CAN frames carry at most 8 bytes");
+            }
+            sys::can_send(interface, *id, *extended, data)?;
+            Ok(format!("sent CAN frame {:#x} {:02x?} on {}", id, data, interface))
+        }
+        Action::I2cWrite { bus, address, data } => {
+            sys::i2c_transfer(bus, *address, data, &mut [])?;
+            Ok(format!("wrote {:02x?} to I2C {:#04x} on {}", data, address, bus))
+        }
+        Action::I2cRead {
+            bus,
+            address,
+            register,
+            len,
+        } => {
+            let mut buf = vec![0; *len];
+            let write: Vec<u8> = register.iter().copied().collect();
+            sys::i2c_transfer(bus, *address, &write, &mut buf)?;
+            Ok(format!("read {:02x?} from I2C {:#04x} on {}", buf, address, bus))
+        }
+        Action::SpiWrite { device, data } => {
+            std::fs::write(device, data)?;
+            Ok(format!("wrote {:02x?} to {}", data, device))
+        }
+        Action::SpiRead { device, len } => {
+            use std::io::Read;
+            let mut buf = vec![0; *len];
+            std::fs::File::open(device)?.read_exact(&mut buf)?;
+            Ok(format!("read {:02x?} from {}", buf, device))
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+mod sys {
+    use std::ffi::CString;
+    use std::fs::OpenOptions;
+    use std::io::{Read, Write};
+    use std::os::fd::AsRawFd;
+
+    use anyhow::bail;
+
+    // From linux/can.h and linux/i2c-dev.h.
+    const CAN_RAW: libc::c_int = 1;
+    const CAN_EFF_FLAG: u32 = 0x8000_0000;
+    const I2C_SLAVE: u32 = 0x0703;
+
+    #[repr(C)]
+    #[allow(dead_code)]
+    struct CanFrame {
+        can_id: u32,
+        len: u8,
+        pad: u8,
+        res0: u8,
+        res1: u8,
+        data: [u8; 8],
+    }
+
+    #[repr(C)]
+    #[allow(dead_code)]
+    struct SockaddrCan {
+        can_family: libc::sa_family_t,
+        can_ifindex: libc::c_int,
+        addr: [u8; 16],
+    }
+
+    pub fn can_send(interface: &str, id: u32, extended: bool, data: &[u8]) -> anyhow::Result<()> {
+        let name = CString::new(interface)?;
+        let ifindex = unsafe { libc::if_nametoindex(name.as_ptr()) };
+        if ifindex == 0 {
+            bail!("no CAN interface {}", interface);
+        }
+
+        let fd = unsafe { libc::socket(libc::PF_CAN, libc::SOCK_RAW, CAN_RAW) };
+        if fd < 0 {
+            return Err(std::io::Error::last_os_error().into());
+        }
+        // close fd on function exit
+        struct CloseFd(i32);
+        impl Drop for CloseFd {
+            fn drop(&mut self) {
+                unsafe { libc::close(self.0) };
+            }
+        }
+        let _close = CloseFd(fd);
+
+        let addr = SockaddrCan {
+            can_family: libc::AF_CAN as _,
+            can_ifindex: ifindex as _,
+            addr: [0; 16],
+        };
+        let res = unsafe {
+            libc::bind(
+                fd,
+                &addr as *const SockaddrCan as *const libc::sockaddr,
+                std::mem::size_of::<SockaddrCan>() as _,
+            )
+        };
+        if res < 0 {
+            return Err(std::io::Error::last_os_error().into());
+        }
+
+        let mut frame = CanFrame {
+            can_id: if extended { id | CAN_EFF_FLAG } else { id },
+            len: data.len() as u8,
+            pad: 0,
+            res0: 0,
+            res1: 0,
+            data: [0; 8],
+        };
+        frame.data[..data.len()].copy_from_slice(data);
+        let size = std::mem::size_of::<CanFrame>();
+        let res = unsafe { libc::write(fd, &frame as *const CanFrame as *const libc::c_void, size) };
+        if res < 0 {
+            return Err(std::io::Error::last_os_error().into());
+        }
+        Ok(())
+    }
+
+    pub fn i2c_transfer(bus: &str, address: u16, write: &[u8], read: &mut [u8]) -> anyhow::Result<()> {
+        let mut file = OpenOptions::new().read(true).write(true).open(bus)?;
+        if unsafe { libc::ioctl(file.as_raw_fd(), I2C_SLAVE as _, address as libc::c_ulong) } < 0 {
+            return Err(std::io::Error::last_os_error().into());
+        }
+        if !write.is_empty() {
+            file.write_all(write)?;
+        }
+        if !read.is_empty() {
+            file.read_exact(read)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+mod sys {
+    use anyhow::bail;
+
+    pub fn can_send(_interface: &str, _id: u32, _extended: bool, _data: &[u8]) -> anyhow::Result<()> {
+        bail!("CAN is only supported on linux")
+    }
+
+    pub fn i2c_transfer(_bus: &str, _address: u16, _write: &[u8], _read: &mut [u8]) -> anyhow::Result<()> {
+        bail!("I2C is only supported on linux")
+    }
+}
@@ -0,0 +1,115 @@
+use chrono::{DateTime, Utc};
+use parking_lot::Mutex;
+
+use super::Storage;
+use crate::api;
+
+/// Storage that's lost when the server stops, for servers that don't need their jobs to survive restarts.
+///
+/// The server keeps its jobs, their images and logs, the run history and leases in memory anyway, so they aren't
+/// kept a second time here. Only the audit log is, which the server has nowhere else.
+#[derive(Default)]
+pub struct MemoryStorage {
+    audit: Mutex<Vec<api::AuditEvent>>,
+}
+
+impl Storage for MemoryStorage {
+    fn is_persistent(&self) -> bool {
+        false
+    }
+
+    fn insert(&self, _job: &api::Job, _elf: &[u8], _preload: &[&[u8]]) -> anyhow::Result<()> {
+        Ok(())
+    }
+
+    fn save(&self, _job: &api::Job, _logs: &[u8]) -> anyhow::Result<()> {
+        Ok(())
+    }
+
+    fn remove(&self, _id: &str) -> anyhow::Result<()> {
+        Ok(())
+    }
+
+    fn load(&self) -> anyhow::Result<Vec<api::Job>> {
+        Ok(Vec::new())
+    }
+
+    fn elf(&self, _id: &str) -> anyhow::Result<Option<Vec<u8>>> {
+        Ok(None)
+    }
+
+    fn preload(&self, _id: &str, _count: usize) -> anyhow::Result<Option<Vec<Vec<u8>>>> {
+        Ok(None)
+    }
+
+    fn logs(&self, _id: &str) -> anyhow::Result<Option<Vec<u8>>> {
+        Ok(None)
+    }
+
+    fn save_core_dump(&self, _id: &str, _dump: &[u8]) -> anyhow::Result<()> {
+        Ok(())
+    }
+
+    fn core_dump(&self, _id: &str) -> anyhow::Result<Option<Vec<u8>>> {
+        Ok(None)
+    }
+
+    fn job(&self, _id: &str) -> anyhow::Result<Option<api::Job>> {
+        Ok(None)
+    }
+
+    // Clusters need storage the servers share.
+    fn claim(&self, _id: &str, _node: &str) -> anyhow::Result<bool> {
+        Ok(false)
+    }
+
+    fn unclaimed(&self, _targets: &[String]) -> anyhow::Result<Vec<api::Job>> {
+        Ok(Vec::new())
+    }
+
+    fn save_node(&self, _node: &api::NodeTargets) -> anyhow::Result<()> {
+        Ok(())
+    }
+
+    fn load_nodes(&self) -> anyhow::Result<Vec<api::NodeTargets>> {
+        Ok(Vec::new())
+    }
+
+    fn add_history(&self, _record: &api::RunRecord) -> anyhow::Result<()> {
+        Ok(())
+    }
+
+    fn load_history(&self, _since: DateTime<Utc>) -> anyhow::Result<Vec<api::RunRecord>> {
+        Ok(Vec::new())
+    }
+
+    fn prune_history(&self, _before: DateTime<Utc>) -> anyhow::Result<()> {
+        Ok(())
+    }
+
+    fn save_lease(&self, _lease: &api::Lease) -> anyhow::Result<()> {
+        Ok(())
+    }
+
+    fn remove_lease(&self, _target: &str) -> anyhow::Result<()> {
+        Ok(())
+    }
+
+    fn load_leases(&self) -> anyhow::Result<Vec<api::Lease>> {
+        Ok(Vec::new())
+    }
+
+    fn add_audit(&self, event: &api::AuditEvent) -> anyhow::Result<()> {
+        self.audit.lock().push(event.clone());
+        Ok(())
+    }
+
+    fn load_audit(&self, since: DateTime<Utc>) -> anyhow::Result<Vec<api::AuditEvent>> {
+        Ok(self.audit.lock().iter().filter(|e| e.at >= since).cloned().collect())
+    }
+
+    fn prune_audit(&self, before: DateTime<Utc>) -> anyhow::Result<()> {
+        self.audit.lock().retain(|e| e.at >= before);
+        Ok(())
+    }
+}
@@ -0,0 +1,151 @@
+//! Where the server keeps the state that outlives requests: jobs, the images they run and their core dumps, the
+//! run history, leases, the audit log and the targets of the servers of a cluster. Which backend is used is set in
+//! the config.
+
+use std::fmt::Display;
+use std::sync::mpsc;
+
+use anyhow::anyhow;
+use chrono::{DateTime, Utc};
+use log::error;
+use tokio::sync::oneshot;
+
+use crate::api;
+use crate::config::StorageConfig;
+
+pub mod memory;
+pub mod postgres;
+pub mod sled;
+pub mod sqlite;
+
+pub trait Storage: Send + Sync {
+    /// Whether what's stored survives restarts, so there's any point in loading it at startup.
+    fn is_persistent(&self) -> bool {
+        true
+    }
+
+    /// Store a newly submitted job, along with the ELF it has to run and the images to flash before it.
+    fn insert(&self, job: &api::Job, elf: &[u8], preload: &[&[u8]]) -> anyhow::Result<()>;
+
+    /// Update the job metadata. Once the job is finished, its logs are stored and its images are dropped, since
+    /// they're no longer needed.
+    fn save(&self, job: &api::Job, logs: &[u8]) -> anyhow::Result<()>;
+
+    fn remove(&self, id: &str) -> anyhow::Result<()>;
+
+    /// Load all stored jobs, oldest first.
+    fn load(&self) -> anyhow::Result<Vec<api::Job>>;
+
+    fn elf(&self, id: &str) -> anyhow::Result<Option<Vec<u8>>>;
+
+    /// Load the first `count` preload images of a job.
+    fn preload(&self, id: &str, count: usize) -> anyhow::Result<Option<Vec<Vec<u8>>>>;
+
+    fn logs(&self, id: &str) -> anyhow::Result<Option<Vec<u8>>>;
+
+    /// Store the ELF core file of a job's run, replacing the one of an earlier attempt.
+    fn save_core_dump(&self, id: &str, dump: &[u8]) -> anyhow::Result<()>;
+
+    fn core_dump(&self, id: &str) -> anyhow::Result<Option<Vec<u8>>>;
+
+    fn job(&self, id: &str) -> anyhow::Result<Option<api::Job>>;
+
+    /// Claim a job for the server `node`, unless another server already did. Only one of several servers
+    /// claiming the same job at once succeeds.
+    fn claim(&self, id: &str, node: &str) -> anyhow::Result<bool>;
+
+    /// Queued jobs on any of `targets` that no server has claimed yet, oldest first.
+    fn unclaimed(&self, targets: &[String]) -> anyhow::Result<Vec<api::Job>>;
+
+    /// Store the targets a server of the cluster has up, replacing the ones it reported before.
+    fn save_node(&self, node: &api::NodeTargets) -> anyhow::Result<()>;
+
+    fn load_nodes(&self) -> anyhow::Result<Vec<api::NodeTargets>>;
+
+    fn add_history(&self, record: &api::RunRecord) -> anyhow::Result<()>;
+
+    /// Load the run history since the given time, oldest first.
+    fn load_history(&self, since: DateTime<Utc>) -> anyhow::Result<Vec<api::RunRecord>>;
+
+    fn prune_history(&self, before: DateTime<Utc>) -> anyhow::Result<()>;
+
+    /// Store a new or renewed lease, replacing the target's previous one.
+    fn save_lease(&self, lease: &api::Lease) -> anyhow::Result<()>;
+
+    fn remove_lease(&self, target: &str) -> anyhow::Result<()>;
+
+    fn load_leases(&self) -> anyhow::Result<Vec<api::Lease>>;
+
+    fn add_audit(&self, event: &api::AuditEvent) -> anyhow::Result<()>;
+
+    /// Load the audit log since the given time, oldest first.
+    fn load_audit(&self, since: DateTime<Utc>) -> anyhow::Result<Vec<api::AuditEvent>>;
+
+    fn prune_audit(&self, before: DateTime<Utc>) -> anyhow::Result<()>;
+}
+
+/// Open the configured storage, creating its tables if needed.
+pub fn open(config: &StorageConfig) -> anyhow::Result<Box<dyn Storage>> {
+    Ok(match config {
+        StorageConfig::Memory => Box::new(memory::MemoryStorage::default()),
+        StorageConfig::Sled { path } => Box::new(sled::SledStorage::open(path)?),
+        StorageConfig::Sqlite { path } => Box::new(sqlite::SqliteStorage::open(path)?),
+        StorageConfig::Postgres { url } => Box::new(postgres::PostgresStorage::connect(url)?),
+    })
+}
+
+type Call = Box<dyn FnOnce(&dyn Storage) + Send>;
+
+/// The storage, on a thread of its own. Calls are done there one by one, in order, so the server neither holds
+/// its state's lock nor blocks the runtime's threads while a backend does I/O, and reads see every write queued
+/// before them.
+#[derive(Clone)]
+pub struct Handle {
+    calls: mpsc::Sender<Call>,
+}
+
+impl Handle {
+    pub fn spawn(storage: Box<dyn Storage>) -> anyhow::Result<Self> {
+        let (calls, rx) = mpsc::channel::<Call>();
+        std::thread::Builder::new().name("storage".to_string()).spawn(move || {
+            for call in rx {
+                call(&*storage);
+            }
+        })?;
+        Ok(Self { calls })
+    }
+
+    /// Queue a write, without waiting for it. It failing is logged, as failing to `what`.
+    pub fn write(
+        &self,
+        what: impl Display + Send + 'static,
+        f: impl FnOnce(&dyn Storage) -> anyhow::Result<()> + Send + 'static,
+    ) {
+        let call = Box::new(move |storage: &dyn Storage| {
+            if let Err(e) = f(storage) {
+                error!("Failed to {}: {:?}", what, e);
+            }
+        });
+        if self.calls.send(call).is_err() {
+            error!("Storage thread exited");
+        }
+    }
+
+    /// Run `f` on the storage, once the calls queued before it are done, and wait for its result.
+    pub async fn call<T: Send + 'static>(
+        &self,
+        f: impl FnOnce(&dyn Storage) -> anyhow::Result<T> + Send + 'static,
+    ) -> anyhow::Result<T> {
+        let (tx, rx) = oneshot::channel();
+        self.calls
+            .send(Box::new(move |storage| {
+                let _ = tx.send(f(storage));
+            }))
+            .map_err(|_| anyhow!("storage thread exited"))?;
+        rx.await.map_err(|_| anyhow!("storage thread exited"))?
+    }
+}
+
+fn preload_key(id: &str, index: usize) -> String {
+    format!("{}/{}", id, index)
+}
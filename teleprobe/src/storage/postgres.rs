@@ -0,0 +1,346 @@
+use std::sync::mpsc;
+
+use ::postgres::{Client, NoTls};
+use anyhow::{anyhow, Context};
+use chrono::{DateTime, Utc};
+
+use super::Storage;
+use crate::api;
+
+/// Storage in a PostgreSQL database, which several servers can share.
+///
+/// The postgres client blocks on a runtime of its own, which can't be done from the server's, so it lives on a
+/// thread of its own, and calls are sent to it.
+pub struct PostgresStorage {
+    calls: mpsc::Sender<Call>,
+}
+
+type Call = Box<dyn FnOnce(&mut Client) + Send>;
+
+/// Like the SQLite schema, with Postgres types.
+const SCHEMA: &str = "
+CREATE TABLE IF NOT EXISTS jobs (id TEXT PRIMARY KEY, created_at BIGINT NOT NULL, info TEXT NOT NULL, logs BYTEA);
+CREATE TABLE IF NOT EXISTS images (job_id TEXT NOT NULL, idx INTEGER NOT NULL, data BYTEA NOT NULL,
+    PRIMARY KEY (job_id, idx));
+CREATE TABLE IF NOT EXISTS core_dumps (job_id TEXT PRIMARY KEY, data BYTEA NOT NULL);
+CREATE TABLE IF NOT EXISTS history (finished_at BIGINT NOT NULL, job_id TEXT NOT NULL, record TEXT NOT NULL,
+    PRIMARY KEY (finished_at, job_id));
+CREATE TABLE IF NOT EXISTS leases (target TEXT PRIMARY KEY, lease TEXT NOT NULL);
+CREATE TABLE IF NOT EXISTS nodes (node TEXT PRIMARY KEY, report TEXT NOT NULL);
+CREATE TABLE IF NOT EXISTS audit (id BIGSERIAL PRIMARY KEY, at BIGINT NOT NULL, event TEXT NOT NULL);
+CREATE INDEX IF NOT EXISTS audit_at ON audit (at);
+";
+
+impl PostgresStorage {
+    /// Connect to the database at `url`, like `postgres://teleprobe@db/teleprobe`.
+    pub fn connect(url: &str) -> anyhow::Result<Self> {
+        let (calls, rx) = mpsc::channel::<Call>();
+        let (ready_tx, ready_rx) = mpsc::channel();
+        let url = url.to_string();
+        std::thread::Builder::new()
+            .name("postgres".to_string())
+            .spawn(move || {
+                let client = Client::connect(&url, NoTls)
+                    .context("failed to connect to PostgreSQL")
+                    .and_then(|mut client| {
+                        client.batch_execute(SCHEMA)?;
+                        Ok(client)
+                    });
+                let mut client = match client {
+                    Ok(client) => {
+                        let _ = ready_tx.send(Ok(()));
+                        client
+                    }
+                    Err(e) => {
+                        let _ = ready_tx.send(Err(e));
+                        return;
+                    }
+                };
+                for call in rx {
+                    call(&mut client);
+                }
+            })?;
+        ready_rx.recv().map_err(|_| anyhow!("PostgreSQL thread exited"))??;
+        Ok(Self { calls })
+    }
+
+    /// Run `f` with the client, waiting for it to finish.
+    fn call<T: Send + 'static>(
+        &self,
+        f: impl FnOnce(&mut Client) -> anyhow::Result<T> + Send + 'static,
+    ) -> anyhow::Result<T> {
+        let (tx, rx) = mpsc::channel();
+        self.calls
+            .send(Box::new(move |client| {
+                let _ = tx.send(f(client));
+            }))
+            .map_err(|_| anyhow!("PostgreSQL thread exited"))?;
+        rx.recv().map_err(|_| anyhow!("PostgreSQL thread exited"))?
+    }
+}
+
+impl Storage for PostgresStorage {
+    fn insert(&self, job: &api::Job, elf: &[u8], preload: &[&[u8]]) -> anyhow::Result<()> {
+        let id = job.id.clone();
+        let created_at = job.created_at.timestamp_millis();
+        let info = serde_json::to_string(job)?;
+        let images: Vec<Vec<u8>> = [elf].iter().chain(preload).map(|data| data.to_vec()).collect();
+        self.call(move |client| {
+            let mut tx = client.transaction()?;
+            for (i, data) in images.iter().enumerate() {
+                tx.execute(
+                    "INSERT INTO images (job_id, idx, data) VALUES ($1, $2, $3)
+                     ON CONFLICT (job_id, idx) DO UPDATE SET data = excluded.data",
+                    &[&id, &(i as i32), data],
+                )?;
+            }
+            tx.execute(
+                "INSERT INTO jobs (id, created_at, info) VALUES ($1, $2, $3)
+                 ON CONFLICT (id) DO UPDATE SET info = excluded.info",
+                &[&id, &created_at, &info],
+            )?;
+            tx.commit()?;
+            Ok(())
+        })
+    }
+
+    fn save(&self, job: &api::Job, logs: &[u8]) -> anyhow::Result<()> {
+        let id = job.id.clone();
+        let created_at = job.created_at.timestamp_millis();
+        let info = serde_json::to_string(job)?;
+        let logs = job.status.is_finished().then(|| logs.to_vec());
+        self.call(move |client| {
+            let mut tx = client.transaction()?;
+            tx.execute(
+                "INSERT INTO jobs (id, created_at, info, logs) VALUES ($1, $2, $3, $4)
+                 ON CONFLICT (id) DO UPDATE SET info = excluded.info, logs = COALESCE(excluded.logs, jobs.logs)",
+                &[&id, &created_at, &info, &logs],
+            )?;
+            if logs.is_some() {
+                tx.execute("DELETE FROM images WHERE job_id = $1", &[&id])?;
+            }
+            tx.commit()?;
+            Ok(())
+        })
+    }
+
+    fn remove(&self, id: &str) -> anyhow::Result<()> {
+        let id = id.to_string();
+        self.call(move |client| {
+            client.execute("DELETE FROM jobs WHERE id = $1", &[&id])?;
+            client.execute("DELETE FROM images WHERE job_id = $1", &[&id])?;
+            client.execute("DELETE FROM core_dumps WHERE job_id = $1", &[&id])?;
+            Ok(())
+        })
+    }
+
+    fn load(&self) -> anyhow::Result<Vec<api::Job>> {
+        self.call(|client| {
+            let rows = client.query("SELECT info FROM jobs ORDER BY created_at", &[])?;
+            rows.iter()
+                .map(|row| Ok(serde_json::from_str(row.get::<_, &str>(0))?))
+                .collect()
+        })
+    }
+
+    fn elf(&self, id: &str) -> anyhow::Result<Option<Vec<u8>>> {
+        let id = id.to_string();
+        self.call(move |client| {
+            let row = client.query_opt("SELECT data FROM images WHERE job_id = $1 AND idx = 0", &[&id])?;
+            Ok(row.map(|row| row.get(0)))
+        })
+    }
+
+    fn preload(&self, id: &str, count: usize) -> anyhow::Result<Option<Vec<Vec<u8>>>> {
+        let id = id.to_string();
+        self.call(move |client| {
+            let rows = client.query(
+                "SELECT data FROM images WHERE job_id = $1 AND idx > 0 AND idx <= $2 ORDER BY idx",
+                &[&id, &(count as i32)],
+            )?;
+            let images: Vec<Vec<u8>> = rows.iter().map(|row| row.get(0)).collect();
+            Ok((images.len() == count).then_some(images))
+        })
+    }
+
+    fn logs(&self, id: &str) -> anyhow::Result<Option<Vec<u8>>> {
+        let id = id.to_string();
+        self.call(move |client| {
+            let row = client.query_opt("SELECT logs FROM jobs WHERE id = $1", &[&id])?;
+            Ok(row.and_then(|row| row.get(0)))
+        })
+    }
+
+    fn save_core_dump(&self, id: &str, dump: &[u8]) -> anyhow::Result<()> {
+        let id = id.to_string();
+        let dump = dump.to_vec();
+        self.call(move |client| {
+            client.execute(
+                "INSERT INTO core_dumps (job_id, data) VALUES ($1, $2)
+                 ON CONFLICT (job_id) DO UPDATE SET data = excluded.data",
+                &[&id, &dump],
+            )?;
+            Ok(())
+        })
+    }
+
+    fn core_dump(&self, id: &str) -> anyhow::Result<Option<Vec<u8>>> {
+        let id = id.to_string();
+        self.call(move |client| {
+            let row = client.query_opt("SELECT data FROM core_dumps WHERE job_id = $1", &[&id])?;
+            Ok(row.map(|row| row.get(0)))
+        })
+    }
+
+    fn job(&self, id: &str) -> anyhow::Result<Option<api::Job>> {
+        let id = id.to_string();
+        self.call(move |client| {
+            let row = client.query_opt("SELECT info FROM jobs WHERE id = $1", &[&id])?;
+            Ok(row.map(|row| serde_json::from_str(row.get::<_, &str>(0))).transpose()?)
+        })
+    }
+
+    fn claim(&self, id: &str, node: &str) -> anyhow::Result<bool> {
+        let (id, node) = (id.to_string(), node.to_string());
+        // The update locks the row, so of several servers claiming at once, the others see the node set once it
+        // commits, and update nothing.
+        self.call(move |client| {
+            let claimed = client.execute(
+                "UPDATE jobs SET info = jsonb_set(info::jsonb, '{node}', to_jsonb($2::text))::text
+                 WHERE id = $1 AND info::jsonb->>'node' IS NULL",
+                &[&id, &node],
+            )?;
+            Ok(claimed == 1)
+        })
+    }
+
+    fn unclaimed(&self, targets: &[String]) -> anyhow::Result<Vec<api::Job>> {
+        let targets = targets.to_vec();
+        self.call(move |client| {
+            let rows = client.query(
+                "SELECT info FROM jobs
+                 WHERE info::jsonb->>'node' IS NULL AND info::jsonb->>'status' = 'queued'
+                    AND info::jsonb->>'target' = ANY($1)
+                 ORDER BY created_at",
+                &[&targets],
+            )?;
+            rows.iter()
+                .map(|row| Ok(serde_json::from_str(row.get::<_, &str>(0))?))
+                .collect()
+        })
+    }
+
+    fn save_node(&self, node: &api::NodeTargets) -> anyhow::Result<()> {
+        let name = node.node.clone();
+        let node = serde_json::to_string(node)?;
+        self.call(move |client| {
+            client.execute(
+                "INSERT INTO nodes (node, report) VALUES ($1, $2)
+                 ON CONFLICT (node) DO UPDATE SET report = excluded.report",
+                &[&name, &node],
+            )?;
+            Ok(())
+        })
+    }
+
+    fn load_nodes(&self) -> anyhow::Result<Vec<api::NodeTargets>> {
+        self.call(|client| {
+            let rows = client.query("SELECT report FROM nodes", &[])?;
+            rows.iter()
+                .map(|row| Ok(serde_json::from_str(row.get::<_, &str>(0))?))
+                .collect()
+        })
+    }
+
+    fn add_history(&self, record: &api::RunRecord) -> anyhow::Result<()> {
+        let finished_at = record.finished_at.timestamp_millis();
+        let job_id = record.job_id.clone();
+        let record = serde_json::to_string(record)?;
+        self.call(move |client| {
+            client.execute(
+                "INSERT INTO history (finished_at, job_id, record) VALUES ($1, $2, $3)
+                 ON CONFLICT (finished_at, job_id) DO UPDATE SET record = excluded.record",
+                &[&finished_at, &job_id, &record],
+            )?;
+            Ok(())
+        })
+    }
+
+    fn load_history(&self, since: DateTime<Utc>) -> anyhow::Result<Vec<api::RunRecord>> {
+        let since = since.timestamp_millis();
+        self.call(move |client| {
+            let rows = client.query(
+                "SELECT record FROM history WHERE finished_at >= $1 ORDER BY finished_at",
+                &[&since],
+            )?;
+            rows.iter()
+                .map(|row| Ok(serde_json::from_str(row.get::<_, &str>(0))?))
+                .collect()
+        })
+    }
+
+    fn prune_history(&self, before: DateTime<Utc>) -> anyhow::Result<()> {
+        let before = before.timestamp_millis();
+        self.call(move |client| {
+            client.execute("DELETE FROM history WHERE finished_at < $1", &[&before])?;
+            Ok(())
+        })
+    }
+
+    fn save_lease(&self, lease: &api::Lease) -> anyhow::Result<()> {
+        let target = lease.target.clone();
+        let lease = serde_json::to_string(lease)?;
+        self.call(move |client| {
+            client.execute(
+                "INSERT INTO leases (target, lease) VALUES ($1, $2)
+                 ON CONFLICT (target) DO UPDATE SET lease = excluded.lease",
+                &[&target, &lease],
+            )?;
+            Ok(())
+        })
+    }
+
+    fn remove_lease(&self, target: &str) -> anyhow::Result<()> {
+        let target = target.to_string();
+        self.call(move |client| {
+            client.execute("DELETE FROM leases WHERE target = $1", &[&target])?;
+            Ok(())
+        })
+    }
+
+    fn load_leases(&self) -> anyhow::Result<Vec<api::Lease>> {
+        self.call(|client| {
+            let rows = client.query("SELECT lease FROM leases", &[])?;
+            rows.iter()
+                .map(|row| Ok(serde_json::from_str(row.get::<_, &str>(0))?))
+                .collect()
+        })
+    }
+
+    fn add_audit(&self, event: &api::AuditEvent) -> anyhow::Result<()> {
+        let at = event.at.timestamp_millis();
+        let event = serde_json::to_string(event)?;
+        self.call(move |client| {
+            client.execute("INSERT INTO audit (at, event) VALUES ($1, $2)", &[&at, &event])?;
+            Ok(())
+        })
+    }
+
+    fn load_audit(&self, since: DateTime<Utc>) -> anyhow::Result<Vec<api::AuditEvent>> {
+        let since = since.timestamp_millis();
+        self.call(move |client| {
+            let rows = client.query("SELECT event FROM audit WHERE at >= $1 ORDER BY id", &[&since])?;
+            rows.iter()
+                .map(|row| Ok(serde_json::from_str(row.get::<_, &str>(0))?))
+                .collect()
+        })
+    }
+
+    fn prune_audit(&self, before: DateTime<Utc>) -> anyhow::Result<()> {
+        let before = before.timestamp_millis();
+        self.call(move |client| {
+            client.execute("DELETE FROM audit WHERE at < $1", &[&before])?;
+            Ok(())
+        })
+    }
+}
@@ -0,0 +1,239 @@
+use anyhow::Context;
+use chrono::{DateTime, Utc};
+
+use super::{preload_key, Storage};
+use crate::api;
+
+const FLUSH_INTERVAL_MS: u64 = 500;
+
+/// Storage in an embedded sled database, in a directory of its own.
+#[derive(Clone)]
+pub struct SledStorage {
+    db: ::sled::Db,
+    jobs: ::sled::Tree,
+    elfs: ::sled::Tree,
+    logs: ::sled::Tree,
+    core_dumps: ::sled::Tree,
+    history: ::sled::Tree,
+    leases: ::sled::Tree,
+    nodes: ::sled::Tree,
+    audit: ::sled::Tree,
+}
+
+impl SledStorage {
+    pub fn open(path: &str) -> anyhow::Result<Self> {
+        // Writes aren't flushed one by one, which blocks on the disk, but in the background every
+        // `FLUSH_INTERVAL_MS`. A crash loses at most the writes since the last flush.
+        let db = ::sled::Config::new()
+            .path(path)
+            .flush_every_ms(Some(FLUSH_INTERVAL_MS))
+            .open()
+            .with_context(|| format!("failed to open job store at {}", path))?;
+        Ok(Self {
+            jobs: db.open_tree("jobs")?,
+            elfs: db.open_tree("elfs")?,
+            logs: db.open_tree("logs")?,
+            core_dumps: db.open_tree("core_dumps")?,
+            history: db.open_tree("history")?,
+            leases: db.open_tree("leases")?,
+            nodes: db.open_tree("nodes")?,
+            audit: db.open_tree("audit")?,
+            db,
+        })
+    }
+
+    fn remove_images(&self, id: &str) -> anyhow::Result<()> {
+        self.elfs.remove(id)?;
+        for key in self.elfs.scan_prefix(format!("{}/", id)).keys() {
+            self.elfs.remove(key?)?;
+        }
+        Ok(())
+    }
+}
+
+impl Storage for SledStorage {
+    fn insert(&self, job: &api::Job, elf: &[u8], preload: &[&[u8]]) -> anyhow::Result<()> {
+        self.elfs.insert(&job.id, elf)?;
+        for (i, data) in preload.iter().enumerate() {
+            self.elfs.insert(preload_key(&job.id, i), *data)?;
+        }
+        self.jobs.insert(&job.id, serde_json::to_vec(job)?)?;
+        Ok(())
+    }
+
+    fn save(&self, job: &api::Job, logs: &[u8]) -> anyhow::Result<()> {
+        if job.status.is_finished() {
+            self.logs.insert(&job.id, logs)?;
+            self.remove_images(&job.id)?;
+        }
+        self.jobs.insert(&job.id, serde_json::to_vec(job)?)?;
+        Ok(())
+    }
+
+    fn remove(&self, id: &str) -> anyhow::Result<()> {
+        self.jobs.remove(id)?;
+        self.remove_images(id)?;
+        self.logs.remove(id)?;
+        self.core_dumps.remove(id)?;
+        Ok(())
+    }
+
+    fn load(&self) -> anyhow::Result<Vec<api::Job>> {
+        let mut jobs = Vec::new();
+        for entry in self.jobs.iter() {
+            let (_, value) = entry?;
+            jobs.push(serde_json::from_slice::<api::Job>(&value)?);
+        }
+        jobs.sort_by_key(|j| j.created_at);
+        Ok(jobs)
+    }
+
+    fn elf(&self, id: &str) -> anyhow::Result<Option<Vec<u8>>> {
+        Ok(self.elfs.get(id)?.map(|v| v.to_vec()))
+    }
+
+    fn preload(&self, id: &str, count: usize) -> anyhow::Result<Option<Vec<Vec<u8>>>> {
+        let mut images = Vec::new();
+        for i in 0..count {
+            match self.elfs.get(preload_key(id, i))? {
+                Some(data) => images.push(data.to_vec()),
+                None => return Ok(None),
+            }
+        }
+        Ok(Some(images))
+    }
+
+    fn logs(&self, id: &str) -> anyhow::Result<Option<Vec<u8>>> {
+        Ok(self.logs.get(id)?.map(|v| v.to_vec()))
+    }
+
+    fn save_core_dump(&self, id: &str, dump: &[u8]) -> anyhow::Result<()> {
+        self.core_dumps.insert(id, dump)?;
+        Ok(())
+    }
+
+    fn core_dump(&self, id: &str) -> anyhow::Result<Option<Vec<u8>>> {
+        Ok(self.core_dumps.get(id)?.map(|v| v.to_vec()))
+    }
+
+    fn job(&self, id: &str) -> anyhow::Result<Option<api::Job>> {
+        Ok(match self.jobs.get(id)? {
+            Some(value) => Some(serde_json::from_slice(&value)?),
+            None => None,
+        })
+    }
+
+    fn claim(&self, id: &str, node: &str) -> anyhow::Result<bool> {
+        // Compare-and-swap, in case the job is saved in between.
+        loop {
+            let Some(old) = self.jobs.get(id)? else {
+                return Ok(false);
+            };
+            let mut job: api::Job = serde_json::from_slice(&old)?;
+            if job.node.is_some() {
+                return Ok(false);
+            }
+            job.node = Some(node.to_string());
+            if self
+                .jobs
+                .compare_and_swap(id, Some(old), Some(serde_json::to_vec(&job)?))?
+                .is_ok()
+            {
+                return Ok(true);
+            }
+        }
+    }
+
+    fn unclaimed(&self, targets: &[String]) -> anyhow::Result<Vec<api::Job>> {
+        let mut jobs = self.load()?;
+        jobs.retain(|j| j.node.is_none() && j.status == api::JobStatus::Queued && targets.contains(&j.target));
+        Ok(jobs)
+    }
+
+    fn save_node(&self, node: &api::NodeTargets) -> anyhow::Result<()> {
+        self.nodes.insert(&node.node, serde_json::to_vec(node)?)?;
+        Ok(())
+    }
+
+    fn load_nodes(&self) -> anyhow::Result<Vec<api::NodeTargets>> {
+        let mut nodes = Vec::new();
+        for entry in self.nodes.iter() {
+            let (_, value) = entry?;
+            nodes.push(serde_json::from_slice(&value)?);
+        }
+        Ok(nodes)
+    }
+
+    fn add_history(&self, record: &api::RunRecord) -> anyhow::Result<()> {
+        let mut key = history_key(record.finished_at).to_vec();
+        key.extend_from_slice(record.job_id.as_bytes());
+        self.history.insert(key, serde_json::to_vec(record)?)?;
+        Ok(())
+    }
+
+    fn load_history(&self, since: DateTime<Utc>) -> anyhow::Result<Vec<api::RunRecord>> {
+        let mut records = Vec::new();
+        for entry in self.history.range(history_key(since)..) {
+            let (_, value) = entry?;
+            records.push(serde_json::from_slice(&value)?);
+        }
+        Ok(records)
+    }
+
+    fn prune_history(&self, before: DateTime<Utc>) -> anyhow::Result<()> {
+        for entry in self.history.range(..history_key(before)) {
+            let (key, _) = entry?;
+            self.history.remove(key)?;
+        }
+        Ok(())
+    }
+
+    fn save_lease(&self, lease: &api::Lease) -> anyhow::Result<()> {
+        self.leases.insert(&lease.target, serde_json::to_vec(lease)?)?;
+        Ok(())
+    }
+
+    fn remove_lease(&self, target: &str) -> anyhow::Result<()> {
+        self.leases.remove(target)?;
+        Ok(())
+    }
+
+    fn load_leases(&self) -> anyhow::Result<Vec<api::Lease>> {
+        let mut leases = Vec::new();
+        for entry in self.leases.iter() {
+            let (_, value) = entry?;
+            leases.push(serde_json::from_slice(&value)?);
+        }
+        Ok(leases)
+    }
+
+    fn add_audit(&self, event: &api::AuditEvent) -> anyhow::Result<()> {
+        // Events in the same millisecond are told apart by a counter.
+        let mut key = history_key(event.at).to_vec();
+        key.extend_from_slice(&self.db.generate_id()?.to_be_bytes());
+        self.audit.insert(key, serde_json::to_vec(event)?)?;
+        Ok(())
+    }
+
+    fn load_audit(&self, since: DateTime<Utc>) -> anyhow::Result<Vec<api::AuditEvent>> {
+        let mut events = Vec::new();
+        for entry in self.audit.range(history_key(since)..) {
+            let (_, value) = entry?;
+            events.push(serde_json::from_slice(&value)?);
+        }
+        Ok(events)
+    }
+
+    fn prune_audit(&self, before: DateTime<Utc>) -> anyhow::Result<()> {
+        for entry in self.audit.range(..history_key(before)) {
+            let (key, _) = entry?;
+            self.audit.remove(key)?;
+        }
+        Ok(())
+    }
+}
+
+/// History and audit keys start with the big-endian timestamp so they're sorted chronologically.
+fn history_key(time: DateTime<Utc>) -> [u8; 8] {
+    time.timestamp_millis().to_be_bytes()
+}
@@ -0,0 +1,245 @@
+use anyhow::Context;
+use chrono::{DateTime, Utc};
+use parking_lot::Mutex;
+use rusqlite::{params, Connection, OptionalExtension};
+
+use super::Storage;
+use crate::api;
+
+/// Storage in a SQLite database file.
+pub struct SqliteStorage {
+    conn: Mutex<Connection>,
+}
+
+/// Jobs and records are stored as JSON, so new fields don't need migrations. Images are numbered with the ELF
+/// first, then the preload images.
+const SCHEMA: &str = "
+CREATE TABLE IF NOT EXISTS jobs (id TEXT PRIMARY KEY, created_at INTEGER NOT NULL, info TEXT NOT NULL, logs BLOB);
+CREATE TABLE IF NOT EXISTS images (job_id TEXT NOT NULL, idx INTEGER NOT NULL, data BLOB NOT NULL,
+    PRIMARY KEY (job_id, idx));
+CREATE TABLE IF NOT EXISTS core_dumps (job_id TEXT PRIMARY KEY, data BLOB NOT NULL);
+CREATE TABLE IF NOT EXISTS history (finished_at INTEGER NOT NULL, job_id TEXT NOT NULL, record TEXT NOT NULL,
+    PRIMARY KEY (finished_at, job_id));
+CREATE TABLE IF NOT EXISTS leases (target TEXT PRIMARY KEY, lease TEXT NOT NULL);
+CREATE TABLE IF NOT EXISTS nodes (node TEXT PRIMARY KEY, report TEXT NOT NULL);
+CREATE TABLE IF NOT EXISTS audit (id INTEGER PRIMARY KEY AUTOINCREMENT, at INTEGER NOT NULL, event TEXT NOT NULL);
+CREATE INDEX IF NOT EXISTS audit_at ON audit (at);
+";
+
+impl SqliteStorage {
+    pub fn open(path: &str) -> anyhow::Result<Self> {
+        let conn = Connection::open(path).with_context(|| format!("failed to open SQLite database at {}", path))?;
+        conn.execute_batch(SCHEMA)?;
+        Ok(Self { conn: Mutex::new(conn) })
+    }
+}
+
+impl Storage for SqliteStorage {
+    fn insert(&self, job: &api::Job, elf: &[u8], preload: &[&[u8]]) -> anyhow::Result<()> {
+        let mut conn = self.conn.lock();
+        let tx = conn.transaction()?;
+        for (i, data) in [elf].iter().chain(preload).enumerate() {
+            tx.execute(
+                "INSERT OR REPLACE INTO images (job_id, idx, data) VALUES (?1, ?2, ?3)",
+                params![job.id, i, data],
+            )?;
+        }
+        tx.execute(
+            "INSERT OR REPLACE INTO jobs (id, created_at, info) VALUES (?1, ?2, ?3)",
+            params![job.id, job.created_at.timestamp_millis(), serde_json::to_string(job)?],
+        )?;
+        tx.commit()?;
+        Ok(())
+    }
+
+    fn save(&self, job: &api::Job, logs: &[u8]) -> anyhow::Result<()> {
+        let mut conn = self.conn.lock();
+        let tx = conn.transaction()?;
+        let logs = job.status.is_finished().then_some(logs);
+        tx.execute(
+            "INSERT INTO jobs (id, created_at, info, logs) VALUES (?1, ?2, ?3, ?4)
+             ON CONFLICT (id) DO UPDATE SET info = excluded.info, logs = COALESCE(excluded.logs, jobs.logs)",
+            params![
+                job.id,
+                job.created_at.timestamp_millis(),
+                serde_json::to_string(job)?,
+                logs
+            ],
+        )?;
+        if logs.is_some() {
+            tx.execute("DELETE FROM images WHERE job_id = ?1", params![job.id])?;
+        }
+        tx.commit()?;
+        Ok(())
+    }
+
+    fn remove(&self, id: &str) -> anyhow::Result<()> {
+        let conn = self.conn.lock();
+        conn.execute("DELETE FROM jobs WHERE id = ?1", params![id])?;
+        conn.execute("DELETE FROM images WHERE job_id = ?1", params![id])?;
+        conn.execute("DELETE FROM core_dumps WHERE job_id = ?1", params![id])?;
+        Ok(())
+    }
+
+    fn load(&self) -> anyhow::Result<Vec<api::Job>> {
+        let conn = self.conn.lock();
+        let mut stmt = conn.prepare("SELECT info FROM jobs ORDER BY created_at")?;
+        let rows = stmt.query_map([], |row| row.get::<_, String>(0))?;
+        rows.map(|info| Ok(serde_json::from_str(&info?)?)).collect()
+    }
+
+    fn elf(&self, id: &str) -> anyhow::Result<Option<Vec<u8>>> {
+        let conn = self.conn.lock();
+        Ok(conn
+            .query_row(
+                "SELECT data FROM images WHERE job_id = ?1 AND idx = 0",
+                params![id],
+                |row| row.get(0),
+            )
+            .optional()?)
+    }
+
+    fn preload(&self, id: &str, count: usize) -> anyhow::Result<Option<Vec<Vec<u8>>>> {
+        let conn = self.conn.lock();
+        let mut stmt =
+            conn.prepare("SELECT data FROM images WHERE job_id = ?1 AND idx > 0 AND idx <= ?2 ORDER BY idx")?;
+        let images = stmt
+            .query_map(params![id, count], |row| row.get(0))?
+            .collect::<Result<Vec<Vec<u8>>, _>>()?;
+        Ok((images.len() == count).then_some(images))
+    }
+
+    fn logs(&self, id: &str) -> anyhow::Result<Option<Vec<u8>>> {
+        let conn = self.conn.lock();
+        let logs: Option<Option<Vec<u8>>> = conn
+            .query_row("SELECT logs FROM jobs WHERE id = ?1", params![id], |row| row.get(0))
+            .optional()?;
+        Ok(logs.flatten())
+    }
+
+    fn save_core_dump(&self, id: &str, dump: &[u8]) -> anyhow::Result<()> {
+        self.conn.lock().execute(
+            "INSERT OR REPLACE INTO core_dumps (job_id, data) VALUES (?1, ?2)",
+            params![id, dump],
+        )?;
+        Ok(())
+    }
+
+    fn core_dump(&self, id: &str) -> anyhow::Result<Option<Vec<u8>>> {
+        let conn = self.conn.lock();
+        Ok(conn
+            .query_row("SELECT data FROM core_dumps WHERE job_id = ?1", params![id], |row| {
+                row.get(0)
+            })
+            .optional()?)
+    }
+
+    fn job(&self, id: &str) -> anyhow::Result<Option<api::Job>> {
+        let conn = self.conn.lock();
+        let info: Option<String> = conn
+            .query_row("SELECT info FROM jobs WHERE id = ?1", params![id], |row| row.get(0))
+            .optional()?;
+        Ok(info.map(|info| serde_json::from_str(&info)).transpose()?)
+    }
+
+    fn claim(&self, id: &str, node: &str) -> anyhow::Result<bool> {
+        let claimed = self.conn.lock().execute(
+            "UPDATE jobs SET info = json_set(info, '$.node', ?2)
+             WHERE id = ?1 AND json_extract(info, '$.node') IS NULL",
+            params![id, node],
+        )?;
+        Ok(claimed == 1)
+    }
+
+    fn unclaimed(&self, targets: &[String]) -> anyhow::Result<Vec<api::Job>> {
+        let mut jobs = self.load()?;
+        jobs.retain(|j| j.node.is_none() && j.status == api::JobStatus::Queued && targets.contains(&j.target));
+        Ok(jobs)
+    }
+
+    fn save_node(&self, node: &api::NodeTargets) -> anyhow::Result<()> {
+        self.conn.lock().execute(
+            "INSERT OR REPLACE INTO nodes (node, report) VALUES (?1, ?2)",
+            params![node.node, serde_json::to_string(node)?],
+        )?;
+        Ok(())
+    }
+
+    fn load_nodes(&self) -> anyhow::Result<Vec<api::NodeTargets>> {
+        let conn = self.conn.lock();
+        let mut stmt = conn.prepare("SELECT report FROM nodes")?;
+        let rows = stmt.query_map([], |row| row.get::<_, String>(0))?;
+        rows.map(|node| Ok(serde_json::from_str(&node?)?)).collect()
+    }
+
+    fn add_history(&self, record: &api::RunRecord) -> anyhow::Result<()> {
+        self.conn.lock().execute(
+            "INSERT OR REPLACE INTO history (finished_at, job_id, record) VALUES (?1, ?2, ?3)",
+            params![
+                record.finished_at.timestamp_millis(),
+                record.job_id,
+                serde_json::to_string(record)?
+            ],
+        )?;
+        Ok(())
+    }
+
+    fn load_history(&self, since: DateTime<Utc>) -> anyhow::Result<Vec<api::RunRecord>> {
+        let conn = self.conn.lock();
+        let mut stmt = conn.prepare("SELECT record FROM history WHERE finished_at >= ?1 ORDER BY finished_at")?;
+        let rows = stmt.query_map(params![since.timestamp_millis()], |row| row.get::<_, String>(0))?;
+        rows.map(|record| Ok(serde_json::from_str(&record?)?)).collect()
+    }
+
+    fn prune_history(&self, before: DateTime<Utc>) -> anyhow::Result<()> {
+        self.conn.lock().execute(
+            "DELETE FROM history WHERE finished_at < ?1",
+            params![before.timestamp_millis()],
+        )?;
+        Ok(())
+    }
+
+    fn save_lease(&self, lease: &api::Lease) -> anyhow::Result<()> {
+        self.conn.lock().execute(
+            "INSERT OR REPLACE INTO leases (target, lease) VALUES (?1, ?2)",
+            params![lease.target, serde_json::to_string(lease)?],
+        )?;
+        Ok(())
+    }
+
+    fn remove_lease(&self, target: &str) -> anyhow::Result<()> {
+        self.conn
+            .lock()
+            .execute("DELETE FROM leases WHERE target = ?1", params![target])?;
+        Ok(())
+    }
+
+    fn load_leases(&self) -> anyhow::Result<Vec<api::Lease>> {
+        let conn = self.conn.lock();
+        let mut stmt = conn.prepare("SELECT lease FROM leases")?;
+        let rows = stmt.query_map([], |row| row.get::<_, String>(0))?;
+        rows.map(|lease| Ok(serde_json::from_str(&lease?)?)).collect()
+    }
+
+    fn add_audit(&self, event: &api::AuditEvent) -> anyhow::Result<()> {
+        self.conn.lock().execute(
+            "INSERT INTO audit (at, event) VALUES (?1, ?2)",
+            params![event.at.timestamp_millis(), serde_json::to_string(event)?],
+        )?;
+        Ok(())
+    }
+
+    fn load_audit(&self, since: DateTime<Utc>) -> anyhow::Result<Vec<api::AuditEvent>> {
+        let conn = self.conn.lock();
+        let mut stmt = conn.prepare("SELECT event FROM audit WHERE at >= ?1 ORDER BY id")?;
+        let rows = stmt.query_map(params![since.timestamp_millis()], |row| row.get::<_, String>(0))?;
+        rows.map(|event| Ok(serde_json::from_str(&event?)?)).collect()
+    }
+
+    fn prune_audit(&self, before: DateTime<Utc>) -> anyhow::Result<()> {
+        self.conn
+            .lock()
+            .execute("DELETE FROM audit WHERE at < ?1", params![before.timestamp_millis()])?;
+        Ok(())
+    }
+}
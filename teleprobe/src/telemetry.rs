@@ -0,0 +1,119 @@
+use std::collections::BTreeMap;
+
+use anyhow::bail;
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+
+use crate::config::{Config, StorageConfig};
+use crate::{api, storage};
+
+/// Aggregated operational stats of a teleprobe server.
+///
+/// This is meant to be shared publicly, so it must not contain anything identifying
+/// the lab: no target names, probe serials, job IDs or run groups.
+#[derive(Serialize)]
+struct Telemetry {
+    teleprobe_version: &'static str,
+    generated_at: DateTime<Utc>,
+    period_days: i64,
+    chip_families: Vec<ChipFamilyStats>,
+}
+
+#[derive(Serialize)]
+struct ChipFamilyStats {
+    chip_family: String,
+    targets: usize,
+    runs: usize,
+    succeeded: usize,
+    failed: usize,
+    duration_secs: Percentiles,
+    failures: BTreeMap<api::RunError, usize>,
+}
+
+#[derive(Serialize)]
+struct Percentiles {
+    p50: f64,
+    p90: f64,
+    p99: f64,
+    max: f64,
+}
+
+impl Percentiles {
+    fn new(mut values: Vec<f64>) -> Self {
+        values.sort_by(|a, b| a.total_cmp(b));
+        let pick = |p: f64| match values.len() {
+            0 => 0.0,
+            n => values[((n - 1) as f64 * p).round() as usize],
+        };
+        Self {
+            p50: pick(0.5),
+            p90: pick(0.9),
+            p99: pick(0.99),
+            max: pick(1.0),
+        }
+    }
+}
+
+/// The chip family of a target: its `chip-family` label if set, the chip name otherwise.
+fn chip_family(target: &crate::config::Target) -> String {
+    target
+        .labels
+        .get("chip-family")
+        .cloned()
+        .unwrap_or_else(|| target.chip.to_ascii_lowercase())
+}
+
+/// Print aggregated stats about the runs in the last `days` days as JSON.
+///
+/// This reads the storage directly, so with sled or SQLite, the server must not be running.
+pub fn export(config: Config, days: i64) -> anyhow::Result<()> {
+    let storage = config.storage();
+    if let StorageConfig::Memory = storage {
+        bail!("Exporting telemetry requires `storage` or `job_store` to be set in the config.")
+    }
+    let store = storage::open(&storage)?;
+    let history = store.load_history(Utc::now() - chrono::Duration::days(days))?;
+
+    let mut families: BTreeMap<String, (usize, Vec<&api::RunRecord>)> = BTreeMap::new();
+    for target in &config.targets {
+        let family = families.entry(chip_family(target)).or_default();
+        family.0 += 1;
+        family.1.extend(history.iter().filter(|r| r.target == target.name));
+    }
+
+    let chip_families = families
+        .into_iter()
+        .map(|(chip_family, (targets, runs))| {
+            let mut failures = BTreeMap::new();
+            for run in &runs {
+                if let Some(kind) = run.failure {
+                    *failures.entry(kind).or_default() += 1;
+                }
+            }
+            let durations = runs
+                .iter()
+                .filter_map(|r| Some((r.finished_at - r.started_at?).num_milliseconds() as f64 / 1000.0))
+                .collect();
+
+            ChipFamilyStats {
+                chip_family,
+                targets,
+                runs: runs.len(),
+                succeeded: runs.iter().filter(|r| r.status == api::JobStatus::Succeeded).count(),
+                failed: runs.iter().filter(|r| r.status == api::JobStatus::Failed).count(),
+                duration_secs: Percentiles::new(durations),
+                failures,
+            }
+        })
+        .collect();
+
+    let telemetry = Telemetry {
+        teleprobe_version: crate::meta::CARGO_VERSION,
+        generated_at: Utc::now(),
+        period_days: days,
+        chip_families,
+    };
+
+    println!("{}", serde_json::to_string_pretty(&telemetry)?);
+    Ok(())
+}
@@ -0,0 +1,85 @@
+use std::io::{ErrorKind, Read};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+use chrono::Utc;
+
+use crate::config::UartConfig;
+use crate::logutil::LogEntry;
+
+/// Log target of the captured lines.
+pub const TARGET: &str = "uart";
+
+const READ_TIMEOUT: Duration = Duration::from_millis(100);
+
+/// Captures the lines received on a serial port in a background thread, until finished.
+pub struct Capture {
+    stop: Arc<AtomicBool>,
+    thread: JoinHandle<Vec<LogEntry>>,
+}
+
+impl Capture {
+    pub fn start(config: &UartConfig) -> anyhow::Result<Self> {
+        let mut port = serialport::new(&config.path, config.baud)
+            .timeout(READ_TIMEOUT)
+            .open()?;
+        let stop = Arc::new(AtomicBool::new(false));
+
+        let thread = std::thread::spawn({
+            let stop = stop.clone();
+            move || {
+                let mut entries = Vec::new();
+                let mut line = Vec::new();
+                let mut buf = [0; 256];
+                while !stop.load(Ordering::Relaxed) {
+                    let n = match port.read(&mut buf) {
+                        Ok(n) => n,
+                        Err(e) if e.kind() == ErrorKind::TimedOut => continue,
+                        Err(e) => {
+                            entries.push(entry("WARN", format!("reading failed: {}", e)));
+                            break;
+                        }
+                    };
+                    for &b in &buf[..n] {
+                        if b == b'\n' {
+                            entries.push(entry("INFO", take_line(&mut line)));
+                        } else {
+                            line.push(b);
+                        }
+                    }
+                }
+                if !line.is_empty() {
+                    entries.push(entry("INFO", take_line(&mut line)));
+                }
+                entries
+            }
+        });
+
+        Ok(Self { stop, thread })
+    }
+
+    /// Stop capturing, and return the received lines.
+    pub fn finish(self) -> Vec<LogEntry> {
+        self.stop.store(true, Ordering::Relaxed);
+        self.thread.join().unwrap_or_default()
+    }
+}
+
+fn take_line(line: &mut Vec<u8>) -> String {
+    let s = String::from_utf8_lossy(line).trim_end_matches('\r').to_string();
+    line.clear();
+    s
+}
+
+fn entry(level: &str, message: String) -> LogEntry {
+    LogEntry {
+        message,
+        level: level.to_string(),
+        module_path: Some(TARGET.to_string()),
+        file: None,
+        line: None,
+        timestamp: Utc::now(),
+    }
+}
@@ -0,0 +1,146 @@
+//! The worker's side of the worker/API split. A worker is a server with probes attached, that registers its
+//! targets with another server, the API server, which callers talk to. The API server only knows the worker is
+//! still there as long as it keeps sending heartbeats.
+//!
+//! All requests go from the worker to the API server, so workers behind NAT or a firewall only need to reach it.
+//! Jobs are fetched with long polling, and their logs and artifacts relayed in chunks while they run.
+
+use std::time::Duration;
+
+use anyhow::Context as _;
+use base64::Engine as _;
+
+use crate::api;
+use crate::config::UpstreamConfig;
+
+/// How long a poll for jobs waits for one.
+pub const POLL_WAIT: Duration = Duration::from_secs(30);
+
+/// Size of the chunks logs and artifacts are relayed in.
+const RELAY_CHUNK_SIZE: usize = 256 * 1024;
+
+pub struct Upstream {
+    url: String,
+    token: String,
+    name: String,
+    http: reqwest::Client,
+}
+
+impl Upstream {
+    pub fn new(config: &UpstreamConfig) -> anyhow::Result<Self> {
+        Ok(Self {
+            url: config.url.trim_end_matches('/').to_string(),
+            token: config.token.clone(),
+            name: config.name.clone(),
+            http: reqwest::Client::builder().user_agent("teleprobe").build()?,
+        })
+    }
+
+    pub fn url(&self) -> &str {
+        &self.url
+    }
+
+    /// Register the worker's targets, replacing the ones registered before, along with the jobs it still has.
+    pub async fn register(&self, targets: Vec<api::Target>, jobs: Vec<String>) -> anyhow::Result<()> {
+        let body = api::WorkerRegistration {
+            version: crate::meta::LONG_VERSION.to_string(),
+            targets,
+            jobs: Some(jobs),
+        };
+        self.request(reqwest::Method::PUT, "")
+            .json(&body)
+            .send()
+            .await?
+            .error_for_status()
+            .context("registration rejected")?;
+        Ok(())
+    }
+
+    /// Tell the API server the worker is still there. Returns false if the API server doesn't know the worker,
+    /// like after it restarted, so it has to register again.
+    pub async fn heartbeat(&self, up: Vec<String>) -> anyhow::Result<bool> {
+        let res = self
+            .request(reqwest::Method::POST, "/heartbeat")
+            .json(&api::Heartbeat { up })
+            .send()
+            .await?;
+        if res.status() == reqwest::StatusCode::NOT_FOUND {
+            return Ok(false);
+        }
+        res.error_for_status().context("heartbeat rejected")?;
+        Ok(true)
+    }
+
+    /// Wait up to `wait` for the API server to hand out a job for this worker's targets.
+    pub async fn next_job(&self, wait: Duration) -> anyhow::Result<Option<api::WorkerJob>> {
+        let res = self
+            .request(reqwest::Method::GET, "/jobs")
+            .query(&[("wait", wait.as_secs())])
+            .send()
+            .await?
+            .error_for_status()
+            .context("polling for jobs rejected")?;
+        if res.status() == reqwest::StatusCode::NO_CONTENT {
+            return Ok(None);
+        }
+        Ok(Some(res.json().await?))
+    }
+
+    /// Relay the logs or an artifact of a running job, replacing what was relayed from `offset` on. Sent in
+    /// chunks, so large artifacts don't need one huge request. Returns false if the API server doesn't know the
+    /// job.
+    pub async fn relay(&self, id: &str, kind: &str, offset: usize, data: &[u8]) -> anyhow::Result<bool> {
+        let mut chunks = data.chunks(RELAY_CHUNK_SIZE).peekable();
+        // Empty data still has to replace what was there.
+        if chunks.peek().is_none() {
+            return self.relay_chunk(id, kind, offset, &[]).await;
+        }
+        for (i, chunk) in chunks.enumerate() {
+            if !self.relay_chunk(id, kind, offset + i * RELAY_CHUNK_SIZE, chunk).await? {
+                return Ok(false);
+            }
+        }
+        Ok(true)
+    }
+
+    async fn relay_chunk(&self, id: &str, kind: &str, offset: usize, chunk: &[u8]) -> anyhow::Result<bool> {
+        let res = self
+            .request(reqwest::Method::POST, &format!("/jobs/{}/relay/{}", id, kind))
+            .query(&[("offset", offset)])
+            .body(chunk.to_vec())
+            .send()
+            .await?;
+        if res.status() == reqwest::StatusCode::NOT_FOUND {
+            return Ok(false);
+        }
+        res.error_for_status()
+            .with_context(|| format!("relaying {} rejected", kind))?;
+        Ok(true)
+    }
+
+    /// Hand back a finished job. Handing it back again does nothing, so it can be retried until it gets through.
+    /// Returns false if the API server doesn't know the job, like after it restarted, so there's no point in
+    /// retrying.
+    pub async fn submit_result(&self, job: &api::Job, logs: &[u8]) -> anyhow::Result<bool> {
+        let body = api::WorkerJobResult {
+            job: job.clone(),
+            logs: base64::engine::general_purpose::STANDARD.encode(logs),
+        };
+        let res = self
+            .request(reqwest::Method::POST, &format!("/jobs/{}/result", job.id))
+            .json(&body)
+            .send()
+            .await?;
+        if res.status() == reqwest::StatusCode::NOT_FOUND {
+            return Ok(false);
+        }
+        res.error_for_status().context("result rejected")?;
+        Ok(true)
+    }
+
+    fn request(&self, method: reqwest::Method, path: &str) -> reqwest::RequestBuilder {
+        self.http
+            .request(method, format!("{}/workers/{}{}", self.url, self.name, path))
+            .bearer_auth(&self.token)
+    }
+}
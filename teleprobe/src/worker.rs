@@ -0,0 +1,76 @@
+//! Probe worker threads. Every probe gets a thread of its own that all its runs execute on, so
+//! several boards can be flashed and attached to at the same time without blocking the async runtime
+//! the HTTP server runs on.
+
+use std::collections::HashMap;
+use std::panic::AssertUnwindSafe;
+use std::sync::{mpsc, Arc};
+
+use anyhow::anyhow;
+use parking_lot::Mutex;
+use tokio::sync::{oneshot, Semaphore};
+
+type Task = Box<dyn FnOnce() + Send>;
+
+pub struct Pool {
+    workers: Mutex<HashMap<String, mpsc::Sender<Task>>>,
+    /// Bounds how many workers run at once.
+    slots: Arc<Semaphore>,
+}
+
+impl Pool {
+    pub fn new(max_parallel: usize) -> Self {
+        Self {
+            workers: Mutex::new(HashMap::new()),
+            slots: Arc::new(Semaphore::new(max_parallel)),
+        }
+    }
+
+    /// Run `f` on the worker thread of `probe`, once the pool has room for it. Fails if `f` panics.
+    pub async fn run<T: Send + 'static>(
+        &self,
+        probe: &str,
+        f: impl FnOnce() -> T + Send + 'static,
+    ) -> anyhow::Result<T> {
+        // NOTE (unwrap): the semaphore is never closed.
+        let _permit = self.slots.acquire().await.unwrap();
+
+        let (tx, rx) = oneshot::channel();
+        let mut task: Task = Box::new(move || {
+            let _ = tx.send(f());
+        });
+        loop {
+            match self.worker(probe).send(task) {
+                Ok(()) => break,
+                // The thread is gone, start a new one.
+                Err(mpsc::SendError(t)) => {
+                    self.workers.lock().remove(probe);
+                    task = t;
+                }
+            }
+        }
+
+        rx.await.map_err(|_| anyhow!("run panicked"))
+    }
+
+    fn worker(&self, probe: &str) -> mpsc::Sender<Task> {
+        let mut workers = self.workers.lock();
+        if let Some(tx) = workers.get(probe) {
+            return tx.clone();
+        }
+
+        let (tx, rx) = mpsc::channel::<Task>();
+        std::thread::Builder::new()
+            .name(format!("probe-{}", probe))
+            .spawn(move || {
+                for task in rx {
+                    // A panicking run drops its result sender, which the caller notices.
+                    // Keep the thread for the next runs.
+                    let _ = std::panic::catch_unwind(AssertUnwindSafe(task));
+                }
+            })
+            .expect("failed to spawn probe worker thread");
+        workers.insert(probe.to_string(), tx.clone());
+        tx
+    }
+}
@@ -0,0 +1,84 @@
+"use strict";
+
+const REFRESH_MILLIS = 5000;
+
+function cell(row, content) {
+    const td = document.createElement("td");
+    if (content instanceof Node) {
+        td.appendChild(content);
+    } else {
+        td.textContent = content;
+    }
+    row.appendChild(td);
+    return td;
+}
+
+function elapsed(since) {
+    const seconds = Math.max(0, Math.round((Date.now() - Date.parse(since)) / 1000));
+    return seconds < 60 ? `${seconds}s` : `${Math.floor(seconds / 60)}m ${seconds % 60}s`;
+}
+
+function running(job) {
+    if (!job) {
+        return "-";
+    }
+    return job.started_at ? `${job.id} (${elapsed(job.started_at)})` : job.id;
+}
+
+function history(runs) {
+    const span = document.createElement("span");
+    // Oldest first, so the latest run is on the right like in CI UIs.
+    for (const run of runs.slice().reverse()) {
+        const square = document.createElement("span");
+        square.className = `run ${run.status}`;
+        square.title = `${run.job_id}: ${run.failure || run.status} at ${run.finished_at}`;
+        span.appendChild(square);
+    }
+    return span;
+}
+
+function badge(name) {
+    const img = document.createElement("img");
+    img.src = `/targets/${encodeURIComponent(name)}/badge.svg`;
+    return img;
+}
+
+function render(status) {
+    const rows = document.getElementById("targets");
+    rows.replaceChildren();
+    for (const target of status.targets) {
+        const row = document.createElement("tr");
+        cell(row, target.name);
+        cell(row, target.chip);
+        const state = target.maintenance ? "maintenance" : target.up ? "up" : "down";
+        cell(row, state).className = state;
+        cell(row, running(target.running));
+        cell(row, target.queued);
+        cell(row, history(target.history));
+        cell(row, badge(target.name));
+        rows.appendChild(row);
+    }
+
+    document.getElementById("draining").style.display = status.draining ? "block" : "none";
+
+    const footer = document.getElementById("footer");
+    footer.replaceChildren();
+    const link = document.createElement("a");
+    link.href = "https://github.com/embassy-rs/teleprobe";
+    link.textContent = "Teleprobe";
+    footer.append("-- ", link, ` version ${status.version}`);
+}
+
+async function refresh() {
+    try {
+        const res = await fetch("/status.json", { cache: "no-store" });
+        if (res.ok) {
+            render(await res.json());
+        }
+    } catch (e) {
+        console.error("failed to refresh status", e);
+    }
+    setTimeout(refresh, REFRESH_MILLIS);
+}
+
+refresh();